@@ -0,0 +1,49 @@
+//! alpha_over_rgba（AVX2 有効時は SIMD）とスカラー版の比較
+//!
+//! SIMD 版を計測するには AVX2 を有効にしてビルドする:
+//! `RUSTFLAGS="-C target-cpu=native" cargo bench --bench blend`
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use image::{Rgba, RgbaImage};
+use layered_nft_gen::blend::{alpha_over_rgba, alpha_over_rgba_scalar};
+use std::hint::black_box;
+
+const SIZE: u32 = 3000;
+
+/// 透明・半透明・不透明の画素が混ざったレイヤー
+fn layer(seed: u32) -> RgbaImage {
+    RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+        let v = x.wrapping_mul(31).wrapping_add(y.wrapping_mul(17)).wrapping_add(seed);
+        let alpha = match v % 4 {
+            0 => 0,
+            1 => 255,
+            _ => (v >> 3) as u8,
+        };
+        Rgba([v as u8, (v >> 8) as u8, (v >> 16) as u8, alpha])
+    })
+}
+
+fn bench_alpha_over(c: &mut Criterion) {
+    let base = layer(1);
+    let overlay = layer(2);
+    let mut group = c.benchmark_group("alpha_over_3000x3000");
+    group.sample_size(10);
+    group.bench_function("scalar", |b| {
+        b.iter_batched_ref(
+            || base.clone(),
+            |img| alpha_over_rgba_scalar(img, black_box(&overlay)),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("alpha_over_rgba", |b| {
+        b.iter_batched_ref(
+            || base.clone(),
+            |img| alpha_over_rgba(img, black_box(&overlay)),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_alpha_over);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+//! 使用済みパターンの並列登録（Mutex<HashSet> と DashSet の比較）
+//!
+//! 既定の Mutex 版と DashSet 版をそれぞれ計測して比べる:
+//! `cargo bench --bench pattern_store` / `cargo bench --bench pattern_store --features dashset`
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use layered_nft_gen::pattern_store::PatternStore;
+use rayon::prelude::*;
+
+const TOKENS: u32 = 50_000;
+
+fn bench_insert(c: &mut Criterion) {
+    let name = if cfg!(feature = "dashset") { "dashset" } else { "mutex_hashset" };
+    let mut group = c.benchmark_group("pattern_store_50k");
+    group.sample_size(10);
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            let store = PatternStore::open(None).unwrap();
+            (0..TOKENS).into_par_iter().for_each(|i| {
+                let key = format!("layers/Background/{}.png|layers/Eyes/{}.png", i % 97, i);
+                store.insert(&key).unwrap();
+            });
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_insert);
+criterion_main!(benches);
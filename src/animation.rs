@@ -0,0 +1,82 @@
+use crate::config::{AnimationConfig, AnimationFormat};
+use crate::error::{LayeredNftError, Result};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, RgbaImage};
+use std::fs;
+use std::io::BufWriter;
+
+/// 合成済みフレーム列をアニメーション画像として書き出す
+pub fn write_animation(frames: &[RgbaImage], path: &str, anim: &AnimationConfig, quality: f32) -> Result<()> {
+    if frames.is_empty() {
+        return Err(LayeredNftError::Encode("アニメーションのフレームが1枚もありません".to_string()));
+    }
+
+    match anim.format {
+        AnimationFormat::Gif => write_gif(frames, path, anim),
+        AnimationFormat::Webp => write_webp(frames, path, anim, quality),
+    }
+}
+
+fn write_gif(frames: &[RgbaImage], path: &str, anim: &AnimationConfig) -> Result<()> {
+    let file = fs::File::create(path).map_err(LayeredNftError::io(path))?;
+    let mut encoder = GifEncoder::new(BufWriter::new(file));
+
+    let repeat = if anim.loop_count == 0 {
+        Repeat::Infinite
+    } else {
+        Repeat::Finite(anim.loop_count.min(u16::MAX as u32) as u16)
+    };
+    encoder.set_repeat(repeat).map_err(LayeredNftError::image(path))?;
+
+    let delay = Delay::from_numer_denom_ms(anim.frame_delay_ms, 1);
+    encoder.encode_frames(
+        frames
+            .iter()
+            .map(|f| Frame::from_parts(f.clone(), 0, 0, delay)),
+    )
+    .map_err(LayeredNftError::image(path))?;
+    Ok(())
+}
+
+#[cfg(feature = "webp")]
+fn write_webp(frames: &[RgbaImage], path: &str, anim: &AnimationConfig, quality: f32) -> Result<()> {
+    use webp::{AnimEncoder, AnimFrame, WebPConfig};
+
+    let (width, height) = frames[0].dimensions();
+    let mut config = WebPConfig::new()
+        .map_err(|_| LayeredNftError::Encode("WebP エンコーダの初期化に失敗しました".to_string()))?;
+    config.quality = quality.clamp(0.0, 100.0);
+
+    let mut encoder = AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(anim.loop_count as i32);
+
+    for (i, frame) in frames.iter().enumerate() {
+        // タイムスタンプ（ミリ秒）は i32 のため、フレーム数 × frame_delay_ms が溢れる場合はエラーにする
+        let timestamp = u32::try_from(i)
+            .ok()
+            .and_then(|i| i.checked_mul(anim.frame_delay_ms))
+            .and_then(|t| i32::try_from(t).ok())
+            .ok_or_else(|| {
+                LayeredNftError::Encode(format!(
+                    "WebP アニメーションの長さが上限を超えています（{} フレーム目, frame_delay_ms = {}）",
+                    i + 1,
+                    anim.frame_delay_ms
+                ))
+            })?;
+        encoder.add_frame(AnimFrame::from_rgba(frame.as_raw(), width, height, timestamp));
+    }
+
+    let data = encoder
+        .try_encode()
+        .map_err(|e| LayeredNftError::Encode(format!("WebP アニメーションのエンコードに失敗しました: {:?}", e)))?;
+    fs::write(path, &*data).map_err(LayeredNftError::io(path))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "webp"))]
+fn write_webp(_frames: &[RgbaImage], _path: &str, _anim: &AnimationConfig, _quality: f32) -> Result<()> {
+    Err(LayeredNftError::FeatureDisabled {
+        feature: "webp",
+        setting: "animation.format に webp".to_string(),
+    })
+}
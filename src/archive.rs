@@ -0,0 +1,72 @@
+use crate::config::{ZipCompression, ZipOutputConfig};
+use crate::error::{LayeredNftError, Result};
+use crate::output::write_with;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// 画像とメタデータを `images/` `metadata/` 構成の ZIP にまとめる
+/// failed_ids が空でなければ、欠番のトークン ID を ERRORS.txt として同梱する
+/// atomic なら一時ファイルに書き終えてから zip_cfg.path にリネームする
+pub fn write_zip(
+    image_dir: &str,
+    metadata_dir: &str,
+    zip_cfg: &ZipOutputConfig,
+    failed_ids: &[u32],
+    atomic: bool,
+) -> Result<()> {
+    // ZIP 自身が出力ディレクトリ内にある場合に取り込まないよう、作成前に列挙しておく
+    let mut entries = collect_entries(Path::new(image_dir), "images");
+    entries.extend(collect_entries(Path::new(metadata_dir), "metadata"));
+
+    write_with(Path::new(&zip_cfg.path), atomic, |target| write_entries(target, &entries, zip_cfg, failed_ids))
+}
+
+fn write_entries(path: &Path, entries: &[(PathBuf, String)], zip_cfg: &ZipOutputConfig, failed_ids: &[u32]) -> Result<()> {
+    let file = fs::File::create(path).map_err(LayeredNftError::io(path))?;
+    let mut zip = ZipWriter::new(file);
+
+    let method = match zip_cfg.compression {
+        ZipCompression::Stored => CompressionMethod::Stored,
+        ZipCompression::Deflate => CompressionMethod::Deflated,
+    };
+    let options = SimpleFileOptions::default().compression_method(method);
+
+    for (path, name) in entries {
+        zip.start_file(name.as_str(), options)?;
+        let mut f = fs::File::open(path).map_err(LayeredNftError::io(path))?;
+        io::copy(&mut f, &mut zip).map_err(LayeredNftError::io(path))?;
+    }
+
+    if !failed_ids.is_empty() {
+        let mut ids = failed_ids.to_vec();
+        ids.sort_unstable();
+        zip.start_file("ERRORS.txt", options)?;
+        writeln!(zip, "生成に失敗したトークン ID:").map_err(LayeredNftError::io(&zip_cfg.path))?;
+        for id in ids {
+            writeln!(zip, "{}", id).map_err(LayeredNftError::io(&zip_cfg.path))?;
+        }
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+/// ディレクトリ以下のファイルを (実パス, ZIP 内パス) で列挙
+fn collect_entries(dir: &Path, prefix: &str) -> Vec<(PathBuf, String)> {
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+    {
+        let Ok(rel) = entry.path().strip_prefix(dir) else { continue; };
+        let name = format!("{}/{}", prefix, rel.to_string_lossy().replace('\\', "/"));
+        entries.push((entry.into_path(), name));
+    }
+    entries
+}
@@ -0,0 +1,190 @@
+use crate::error::{LayeredNftError, Result};
+use crate::generator::{GeneratedToken, Generator};
+use crate::metadata::NftMetadata;
+use crate::output::encode_image;
+use futures::stream::{self, Stream, StreamExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// Generator を非同期のパイプライン（IPFS へのアップロードなど）から使うためのラッパー
+///
+/// 画像の合成とエンコード（CPU 処理）は `spawn_blocking` で実行し、ファイルの書き出しは `tokio::fs` で行う。
+/// 同時に合成するトークン数は Semaphore で `max_in_flight` 個までに制限する。
+///
+/// 書き出すのはトークンの画像とメタデータだけで、CLI と違ってサムネイル（output.thumbnail）・
+/// アニメーション（output.animation）・output.additional_outputs は書き出さない。
+/// 必要なら `GeneratedToken` の image / frames から呼び出し側で書き出す。
+///
+/// ```no_run
+/// use futures::StreamExt;
+/// use layered_nft_gen::async_generator::AsyncGenerator;
+/// use layered_nft_gen::config::Config;
+/// use layered_nft_gen::generator::Generator;
+///
+/// # async fn run() -> layered_nft_gen::error::Result<()> {
+/// let generator = AsyncGenerator::new(Generator::new(Config::load("config.yaml")?)?);
+/// let mut tokens = generator.stream();
+/// while let Some(token) = tokens.next().await {
+///     let token = token?;
+///     generator.write_image(&token).await?;
+///     // ここで画像をアップロードし、metadata.image を書き換えてから書き出せる
+///     generator.write_metadata(token.token_id, &token.metadata).await?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncGenerator {
+    generator: Arc<Generator>,
+    max_in_flight: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl AsyncGenerator {
+    /// 同時に合成するトークン数は CPU の論理コア数まで
+    pub fn new(generator: Generator) -> Self {
+        let max_in_flight = std::thread::available_parallelism().map_or(4, |n| n.get());
+        Self {
+            generator: Arc::new(generator),
+            max_in_flight,
+            permits: Arc::new(Semaphore::new(max_in_flight)),
+        }
+    }
+
+    /// 同時に合成するトークン数の上限を変える（0 は 1 として扱う）
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self.permits = Arc::new(Semaphore::new(self.max_in_flight));
+        self
+    }
+
+    pub fn generator(&self) -> &Generator {
+        &self.generator
+    }
+
+    /// 1トークン分を生成する（合成はブロッキングスレッドで行う）
+    pub async fn generate_token(&self, token_id: u32) -> Result<GeneratedToken> {
+        let _permit = self.permits.acquire().await.expect("セマフォは閉じられません");
+        let generator = Arc::clone(&self.generator);
+        join(tokio::task::spawn_blocking(move || generator.generate_token(token_id))).await
+    }
+
+    /// 1〜count のトークンを token_id 順に返す Stream（先読みして最大 max_in_flight 個を並行に合成する）
+    pub fn stream(&self) -> impl Stream<Item = Result<GeneratedToken>> + '_ {
+        stream::iter(1..=self.generator.config().count)
+            .map(move |token_id| self.generate_token(token_id))
+            .buffered(self.max_in_flight)
+    }
+
+    /// 画像を output.format でエンコードし、{image_dir}/{id}.{ext}（image_path_template 指定時はその展開先）に書き出す
+    pub async fn write_image(&self, token: &GeneratedToken) -> Result<PathBuf> {
+        let path = self.generator.image_path(token.token_id, &token.metadata);
+        create_parent_dir(&path).await?;
+
+        let generator = Arc::clone(&self.generator);
+        let image = token.image.clone();
+        let data = join(tokio::task::spawn_blocking(move || {
+            let output = &generator.config().output;
+            encode_image(&image, output.format, output)
+        }))
+        .await?;
+
+        self.write_file(path, data).await
+    }
+
+    /// メタデータを出力形式に合わせて {metadata_dir}/{id}.json（metadata_path_template 指定時はその展開先）に書き出す
+    pub async fn write_metadata(&self, token_id: u32, metadata: &NftMetadata) -> Result<PathBuf> {
+        let json = self.generator.metadata_json(token_id, metadata)?;
+        let path = self.generator.metadata_path(token_id, metadata);
+        create_parent_dir(&path).await?;
+        self.write_file(path, json.into_bytes()).await
+    }
+
+    /// output.atomic_writes に従って書き出す（atomic なら同じディレクトリの一時ファイルに書いてから path へリネームする）
+    async fn write_file(&self, path: PathBuf, data: Vec<u8>) -> Result<PathBuf> {
+        if !self.generator.config().output.writes_atomically() {
+            tokio::fs::write(&path, data).await.map_err(LayeredNftError::io(&path))?;
+            return Ok(path);
+        }
+
+        static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let temp_name =
+            format!(".tmp-{}-{}-{}", std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed), file_name);
+        let temp = path.with_file_name(temp_name);
+        let written = match tokio::fs::write(&temp, data).await {
+            Ok(()) => tokio::fs::rename(&temp, &path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            // 書きかけの一時ファイルは残さない（消せなくても元のエラーを返す）
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(LayeredNftError::io(&path)(e));
+        }
+        Ok(path)
+    }
+}
+
+/// パステンプレートでサブディレクトリに出力する場合に、親ディレクトリを作る
+async fn create_parent_dir(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(dir) => tokio::fs::create_dir_all(dir).await.map_err(LayeredNftError::io(dir)),
+        None => Ok(()),
+    }
+}
+
+/// spawn_blocking の結果を待つ（タスク内のパニックは呼び出し側に伝える）
+async fn join<T>(handle: JoinHandle<T>) -> T {
+    match handle.await {
+        Ok(value) => value,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigBuilder, OutputConfig};
+    use std::fs;
+
+    #[test]
+    fn stream_generates_and_writes_tokens() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (image_dir, metadata_dir) = (dir.join("images"), dir.join("metadata"));
+        fs::create_dir_all(&image_dir).unwrap();
+        fs::create_dir_all(&metadata_dir).unwrap();
+
+        let cfg = ConfigBuilder::sample(&["Background", "Eyeball", "Eye color"])
+            .count(4)
+            .output(OutputConfig::new(image_dir.to_str().unwrap(), metadata_dir.to_str().unwrap()))
+            .build()
+            .unwrap();
+        let generator = AsyncGenerator::new(Generator::new(cfg).unwrap()).with_max_in_flight(2);
+
+        let ids = tokio_test::block_on(async {
+            let tokens: Vec<_> = generator.stream().collect().await;
+            let mut ids = Vec::new();
+            for token in tokens {
+                let token = token.unwrap();
+                generator.write_image(&token).await.unwrap();
+                generator.write_metadata(token.token_id, &token.metadata).await.unwrap();
+                ids.push(token.token_id);
+            }
+            ids
+        });
+
+        assert_eq!(ids, [1, 2, 3, 4]);
+        // 一時ファイル経由で書き出した後、一時ファイルは残らない
+        assert_eq!(fs::read_dir(&image_dir).unwrap().count(), 4);
+        assert_eq!(fs::read_dir(&metadata_dir).unwrap().count(), 4);
+        for id in ids {
+            assert!(image::open(image_dir.join(format!("{}.png", id))).is_ok());
+            let json = fs::read_to_string(metadata_dir.join(format!("{}.json", id))).unwrap();
+            let meta: NftMetadata = serde_json::from_str(&json).unwrap();
+            assert_eq!(meta.edition, id);
+        }
+    }
+}
@@ -0,0 +1,895 @@
+use crate::config::{Config, ForbiddenGroup, ForbiddenPair, LayerConfig, MetadataFormat, TraitValue};
+use crate::generator::{grouped_file_weights, layer_options};
+use crate::metadata::{parse_token_metadata, AttributeValue};
+use crate::output::{avif_dimensions, write_file};
+use crate::path_template::PathTemplate;
+use crate::phash::dhash;
+use crate::error::{LayeredNftError, Result};
+use rayon::prelude::*;
+use serde::Serialize;
+use statrs::distribution::{ChiSquared, ContinuousCDF};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 検証時に画像として扱う拡張子
+pub const IMAGE_EXTENSIONS: &[&str] = &["png", "webp", "jpg", "avif"];
+
+/// 違反例として記録する最大件数
+pub const MAX_EXAMPLES: usize = 20;
+
+/// 生成済みメタデータの検証結果
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub total: usize,
+    /// trait_type → value → 出現数
+    pub stats: HashMap<String, HashMap<String, usize>>,
+    /// 画像ファイルが見つからないトークン（ファイル名の stem、ソート済み）
+    pub missing_images: Vec<String>,
+    /// サムネイルが見つからないトークン（output.thumbnail 未設定なら空）
+    pub missing_thumbnails: Vec<String>,
+    /// 追加の出力画像が見つからないトークン (ファイル名の stem, パス)（output.additional_outputs 未設定なら空）
+    pub missing_additional_outputs: Vec<(String, String)>,
+    /// animation_url がローカルのパスを指しているのにファイルがないトークン (ファイル名の stem, パス)
+    pub missing_animations: Vec<(String, String)>,
+    /// 禁則違反のあったトークン数
+    pub violation_count: usize,
+    /// (ファイル名, 違反内容) の例（最大 MAX_EXAMPLES 件）
+    pub violation_examples: Vec<(String, String)>,
+}
+
+/// metadata_dir のトークンメタデータを読み込み、レア度の集計・ファイルの有無・禁則違反を調べる
+pub fn check_output(cfg: &Config) -> Result<CheckReport> {
+    let metadata_dir = Path::new(&cfg.output.metadata_dir);
+    let image_dir = Path::new(&cfg.output.image_dir);
+    let image_template = cfg.output.image_path_template.as_deref().map(PathTemplate::parse).transpose()?;
+    let thumbnail = cfg.output.thumbnail.as_ref();
+
+    let forbidden_pairs: &[ForbiddenPair] = cfg
+        .constraints
+        .as_ref()
+        .and_then(|c| c.forbidden_pairs.as_ref())
+        .map(|v| v.as_slice())
+        .unwrap_or(&[]);
+    let forbidden_groups: &[ForbiddenGroup] = cfg
+        .constraints
+        .as_ref()
+        .and_then(|c| c.forbidden_groups.as_ref())
+        .map(|v| v.as_slice())
+        .unwrap_or(&[]);
+
+    // 禁則はファイル名で指定するので、display_names の表示名や numeric_traits の数値はファイル名に戻して照合する
+    let file_stems: HashMap<(&str, String), &str> = cfg
+        .layers
+        .iter()
+        .flat_map(|l| {
+            let names = l.display_names.iter().flatten().map(|(stem, name)| (name.clone(), stem.as_str()));
+            let numbers =
+                l.numeric_traits.iter().flatten().map(|(stem, n)| (AttributeValue::Number(*n).to_string(), stem.as_str()));
+            names.chain(numbers).map(move |(value, stem)| ((l.name.as_str(), value), stem))
+        })
+        .collect();
+
+    let mut report = CheckReport::default();
+
+    for entry in fs::read_dir(metadata_dir).map_err(LayeredNftError::io(metadata_dir))? {
+        let path = entry.map_err(LayeredNftError::io(metadata_dir))?.path();
+        if path.extension().and_then(|s| s.to_str()) != Some("json") {
+            continue;
+        }
+        // collection.json や _metadata.json などトークン以外の JSON は対象外
+        if path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .is_none_or(|s| s.parse::<u32>().is_err())
+        {
+            continue;
+        }
+
+        let text = fs::read_to_string(&path).map_err(LayeredNftError::io(&path))?;
+        let meta = parse_token_metadata(&text, cfg.metadata.format).map_err(LayeredNftError::json(&path))?;
+
+        report.total += 1;
+
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let has_image = match &image_template {
+            Some(template) => {
+                let token_id = stem.parse().unwrap_or_default();
+                image_dir.join(template.render(token_id, cfg.output.id_width.unwrap_or(0), &meta)).is_file()
+            }
+            None => IMAGE_EXTENSIONS
+                .iter()
+                .any(|ext| image_dir.join(format!("{}.{}", stem, ext)).is_file()),
+        };
+        if !has_image {
+            report.missing_images.push(stem.to_string());
+        }
+        if let Some(t) = thumbnail
+            && !Path::new(&t.dir)
+                .join(format!("{}.{}", stem, t.format.extension()))
+                .is_file()
+        {
+            report.missing_thumbnails.push(stem.to_string());
+        }
+        for extra in cfg.output.additional_outputs.iter().flatten() {
+            let extra_path = Path::new(&extra.dir).join(format!("{}.{}", stem, extra.format.extension()));
+            if !extra_path.is_file() {
+                report.missing_additional_outputs.push((stem.to_string(), extra_path.display().to_string()));
+            }
+        }
+        if let Some(local) = meta.animation_url.as_deref().and_then(local_path)
+            && !local.is_file()
+        {
+            report.missing_animations.push((stem.to_string(), local.display().to_string()));
+        }
+
+        for attr in &meta.attributes {
+            let value_map = report
+                .stats
+                .entry(attr.trait_type.clone())
+                .or_default();
+            *value_map.entry(attr.value.to_string()).or_insert(0) += 1;
+        }
+
+        if forbidden_pairs.is_empty() && forbidden_groups.is_empty() {
+            continue;
+        }
+
+        let present: HashSet<(String, String)> = meta
+            .attributes
+            .iter()
+            .map(|a| {
+                let key = (a.trait_type.as_str(), a.value.to_string());
+                let stem = file_stems.get(&key).map_or(key.1.clone(), |s| s.to_string());
+                (a.trait_type.clone(), stem)
+            })
+            .collect();
+
+        let has = |t: &TraitValue| present.contains(&(t.trait_type.clone(), t.value.clone()));
+        let violated = forbidden_pairs
+            .iter()
+            .find(|p| has(&p.a) && has(&p.b))
+            .map(|p| {
+                format!(
+                    "forbidden pair matched: ({}/{}) + ({}/{})",
+                    p.a.trait_type, p.a.value, p.b.trait_type, p.b.value
+                )
+            })
+            .or_else(|| {
+                let g = forbidden_groups.iter().find(|g| g.members.iter().all(has))?;
+                let members: Vec<String> = g.members.iter().map(|m| format!("({}/{})", m.trait_type, m.value)).collect();
+                Some(format!("forbidden group matched: {}", members.join(" + ")))
+            });
+        if let Some(msg) = violated {
+            report.violation_count += 1;
+            if report.violation_examples.len() < MAX_EXAMPLES {
+                let file = path
+                    .file_name()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or("<unknown>")
+                    .to_string();
+                report.violation_examples.push((file, msg));
+            }
+        }
+    }
+
+    report.missing_images.sort();
+    report.missing_thumbnails.sort();
+    report.missing_additional_outputs.sort();
+    report.missing_animations.sort();
+    Ok(report)
+}
+
+/// URL がローカルのファイルを指していればそのパスを返す（`file://` またはスキームのないパス。相対パスはカレントディレクトリから）
+fn local_path(url: &str) -> Option<PathBuf> {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Some(PathBuf::from(path));
+    }
+    let has_scheme = url
+        .split_once(':')
+        .is_some_and(|(scheme, _)| scheme.len() > 1 && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)));
+    (!has_scheme && !url.is_empty()).then(|| PathBuf::from(url))
+}
+
+/// 画像ファイルの破損チェックの結果
+#[derive(Debug, Default)]
+pub struct ImageVerifyReport {
+    /// デコードを試みた画像の数
+    pub checked: usize,
+    /// 最初に正常にデコードできた画像のサイズ（他の画像はこれと一致するはず）
+    pub expected_dimensions: Option<(u32, u32)>,
+    /// メタデータはあるが画像がない ID
+    pub missing_images: Vec<u32>,
+    /// 画像はあるがメタデータがない ID
+    pub missing_metadata: Vec<u32>,
+    /// デコードに失敗した画像 (ID, エラー内容)
+    pub corrupt: Vec<(u32, String)>,
+    /// サイズが expected_dimensions と異なる画像 (ID, 実際のサイズ)
+    pub dimension_mismatches: Vec<(u32, (u32, u32))>,
+    /// 画像データをデコードせず、ヘッダーのサイズだけを確認した（AVIF）
+    pub header_only: bool,
+}
+
+impl ImageVerifyReport {
+    pub fn has_problems(&self) -> bool {
+        !self.missing_images.is_empty()
+            || !self.missing_metadata.is_empty()
+            || !self.corrupt.is_empty()
+            || !self.dimension_mismatches.is_empty()
+    }
+}
+
+/// 画像とメタデータの ID の突き合わせ結果
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    /// 画像・メタデータを通じた最大のトークン ID
+    pub max_id: u32,
+    /// メタデータはあるが画像がない ID
+    pub metadata_only: Vec<u32>,
+    /// 画像はあるがメタデータがない ID
+    pub image_only: Vec<u32>,
+    /// 1〜max_id のうち、画像もメタデータもない ID
+    pub gaps: Vec<u32>,
+}
+
+impl AuditReport {
+    pub fn has_discrepancies(&self) -> bool {
+        !self.metadata_only.is_empty() || !self.image_only.is_empty() || !self.gaps.is_empty()
+    }
+}
+
+/// metadata_dir と image_dir（output.format の拡張子）のトークン ID を突き合わせる
+pub fn audit_output(cfg: &Config) -> Result<AuditReport> {
+    let image_ids = image_token_ids(cfg)?;
+    let metadata_ids = token_ids(Path::new(&cfg.output.metadata_dir), "json")?;
+    let max_id = image_ids.last().max(metadata_ids.last()).copied().unwrap_or(0);
+    Ok(AuditReport {
+        max_id,
+        metadata_only: metadata_ids.difference(&image_ids).copied().collect(),
+        image_only: image_ids.difference(&metadata_ids).copied().collect(),
+        gaps: (1..=max_id)
+            .filter(|id| !image_ids.contains(id) && !metadata_ids.contains(id))
+            .collect(),
+    })
+}
+
+/// 書き出し済みトークンの画像のパス（Generator::image_path と同じ規則）
+///
+/// image_path_template を指定している場合は `{id}.json` のメタデータで展開するため、メタデータが読めなければ None
+pub fn written_image_path(cfg: &Config, token_id: u32) -> Option<PathBuf> {
+    let image_dir = Path::new(&cfg.output.image_dir);
+    let Some(template) = &cfg.output.image_path_template else {
+        return Some(image_dir.join(format!("{}.{}", token_id, cfg.output.format.extension())));
+    };
+    let template = PathTemplate::parse(template).ok()?;
+    let text = fs::read_to_string(Path::new(&cfg.output.metadata_dir).join(format!("{}.json", token_id))).ok()?;
+    let meta = parse_token_metadata(&text, cfg.metadata.format).ok()?;
+    Some(image_dir.join(template.render(token_id, cfg.output.id_width.unwrap_or(0), &meta)))
+}
+
+/// 画像（output.format の形式）が書き出されているトークン ID
+/// （image_path_template 指定時は、メタデータのあるトークンについて展開先を調べる）
+fn image_token_ids(cfg: &Config) -> Result<BTreeSet<u32>> {
+    if cfg.output.image_path_template.is_none() {
+        return token_ids(Path::new(&cfg.output.image_dir), cfg.output.format.extension());
+    }
+    Ok(token_ids(Path::new(&cfg.output.metadata_dir), "json")?
+        .into_iter()
+        .filter(|&id| written_image_path(cfg, id).is_some_and(|p| p.is_file()))
+        .collect())
+}
+
+/// dir 直下の `{id}.{ext}` の ID を集める
+fn token_ids(dir: &Path, ext: &str) -> Result<BTreeSet<u32>> {
+    let mut ids = BTreeSet::new();
+    for entry in fs::read_dir(dir).map_err(LayeredNftError::io(dir))? {
+        let path = entry.map_err(LayeredNftError::io(dir))?.path();
+        if path.extension().and_then(|s| s.to_str()) != Some(ext) {
+            continue;
+        }
+        if let Some(id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+            ids.insert(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// `5,17,42-50` のようなトークン ID の指定（カンマ区切り、`-` で範囲）を昇順・重複なしの一覧にする
+pub fn parse_token_ids(spec: &str) -> std::result::Result<Vec<u32>, String> {
+    let parse = |s: &str| -> std::result::Result<u32, String> {
+        match s.trim().parse::<u32>() {
+            Ok(0) => Err("token ID は 1 以上で指定してください".to_string()),
+            Ok(id) => Ok(id),
+            Err(_) => Err(format!("token ID として解釈できません: {:?}", s.trim())),
+        }
+    };
+    let mut ids = BTreeSet::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(format!("範囲の始まりが終わりより大きくなっています: {}", part));
+                }
+                ids.extend(start..=end);
+            }
+            None => {
+                ids.insert(parse(part)?);
+            }
+        }
+    }
+    if ids.is_empty() {
+        return Err("token ID が指定されていません".to_string());
+    }
+    Ok(ids.into_iter().collect())
+}
+
+/// image_dir の `{id}.{拡張子}`（output.format の形式、image_path_template 指定時はその展開先）をすべてデコードし、
+/// 破損・サイズ違い・メタデータとの対応漏れを調べる
+///
+/// AVIF はヘッダーのサイズだけを確認するので、画像データの破損は検出できない（report.header_only が true になる）
+pub fn verify_images(cfg: &Config) -> Result<ImageVerifyReport> {
+    let ext = cfg.output.format.extension();
+    let image_ids = image_token_ids(cfg)?;
+    let metadata_ids = token_ids(Path::new(&cfg.output.metadata_dir), "json")?;
+
+    let decoded: Vec<_> = image_ids
+        .par_iter()
+        .map(|&id| {
+            let Some(path) = written_image_path(cfg, id) else {
+                return (id, Err("画像のパスを決められません".to_string()));
+            };
+            // AVIF はデコーダを同梱していないため、ヘッダーからサイズを読んで確認する
+            let dims = if ext == "avif" {
+                fs::read(&path).map_err(|e| e.to_string()).and_then(|data| avif_dimensions(&data))
+            } else {
+                image::open(&path).map(|img| (img.width(), img.height())).map_err(|e| e.to_string())
+            };
+            (id, dims)
+        })
+        .collect();
+
+    let mut report = ImageVerifyReport {
+        checked: decoded.len(),
+        missing_images: metadata_ids.difference(&image_ids).copied().collect(),
+        missing_metadata: image_ids.difference(&metadata_ids).copied().collect(),
+        header_only: ext == "avif",
+        ..Default::default()
+    };
+    for (id, dims) in decoded {
+        match dims {
+            Ok(dims) => match report.expected_dimensions {
+                None => report.expected_dimensions = Some(dims),
+                Some(expected) if expected != dims => report.dimension_mismatches.push((id, dims)),
+                Some(_) => {}
+            },
+            Err(e) => report.corrupt.push((id, e)),
+        }
+    }
+    Ok(report)
+}
+
+/// image_dir の `{id}.{png,webp,jpg}` を読み込み、トークン ID ごとの dHash を計算する（ID 順）
+/// 同じ ID の画像が複数の形式である場合は IMAGE_EXTENSIONS の先頭に近いものを使う。
+/// image_path_template 指定時は、メタデータから展開した画像を使う
+pub fn image_hashes(cfg: &Config) -> Result<Vec<(u32, u64)>> {
+    let image_dir = Path::new(&cfg.output.image_dir);
+    let mut images: BTreeMap<u32, (usize, PathBuf)> = BTreeMap::new();
+    if cfg.output.image_path_template.is_some() {
+        for id in image_token_ids(cfg)? {
+            if let Some(path) = written_image_path(cfg, id) {
+                images.insert(id, (0, path));
+            }
+        }
+    } else {
+        for entry in fs::read_dir(image_dir).map_err(LayeredNftError::io(image_dir))? {
+            let path = entry.map_err(LayeredNftError::io(image_dir))?.path();
+            let rank = path
+                .extension()
+                .and_then(|s| s.to_str())
+                .and_then(|ext| IMAGE_EXTENSIONS.iter().position(|e| *e == ext));
+            let id = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u32>().ok());
+            if let (Some(rank), Some(id)) = (rank, id)
+                && images.get(&id).is_none_or(|(r, _)| rank < *r)
+            {
+                images.insert(id, (rank, path));
+            }
+        }
+    }
+
+    images
+        .into_par_iter()
+        .map(|(id, (_, path))| {
+            let img = image::open(&path).map_err(LayeredNftError::image(&path))?;
+            Ok((id, dhash(&img)))
+        })
+        .collect()
+}
+
+/// レイヤー1つ分のカイ二乗適合度検定の結果
+#[derive(Debug)]
+pub struct ChiSquaredResult {
+    pub trait_type: String,
+    /// そのレイヤーが出現したトークン数
+    pub observed_total: usize,
+    /// value → rarity から求めた期待出現確率（レイヤーが出現したトークンに対する割合）
+    pub expected: HashMap<String, f64>,
+    pub statistic: f64,
+    pub degrees_of_freedom: usize,
+    pub p_value: f64,
+}
+
+impl ChiSquaredResult {
+    /// 有意水準 alpha で「設定どおりの分布」を棄却できないか
+    pub fn passed(&self, alpha: f64) -> bool {
+        self.p_value >= alpha
+    }
+}
+
+/// 各レイヤーの実際の出現数が rarity の重みと統計的に整合するかをカイ二乗検定で調べる
+///
+/// 期待値はレイヤーが出現したトークン数に正規化後の重みを掛けたもの。
+/// 数値トレイトのレイヤーと、選択肢が1つしかないレイヤーは対象外。
+pub fn chi_squared_test(cfg: &Config, report: &CheckReport) -> Vec<ChiSquaredResult> {
+    let animation = cfg.output.animation.is_some();
+    let mut results = Vec::new();
+    for layer in &cfg.layers {
+        if layer.numeric_traits.is_some() {
+            continue;
+        }
+        let expected = expected_distribution(layer, animation);
+        let degrees_of_freedom = expected.len().saturating_sub(1);
+        let observed = report.stats.get(&layer.name);
+        let observed_total: usize = observed.map_or(0, |o| o.values().sum());
+        if degrees_of_freedom == 0 || observed_total == 0 {
+            continue;
+        }
+
+        let statistic = expected
+            .iter()
+            .map(|(value, p)| {
+                let e = p * observed_total as f64;
+                let o = observed.and_then(|o| o.get(value)).copied().unwrap_or(0) as f64;
+                (o - e).powi(2) / e
+            })
+            .sum::<f64>();
+        let dist = ChiSquared::new(degrees_of_freedom as f64).expect("自由度は 1 以上");
+        results.push(ChiSquaredResult {
+            trait_type: layer.name.clone(),
+            observed_total,
+            expected,
+            statistic,
+            degrees_of_freedom,
+            p_value: dist.sf(statistic),
+        });
+    }
+    results
+}
+
+/// CSV の1行（trait_type, value の組み合わせごと）
+#[derive(Debug, Serialize)]
+struct CsvRow<'a> {
+    trait_type: &'a str,
+    value: &'a str,
+    count: usize,
+    percentage: f64,
+    /// --chi-squared 指定時のみ（全トークンに対する期待出現率）
+    expected_percentage: Option<f64>,
+    /// --chi-squared 指定時のみ（(観測 - 期待) / √期待）
+    chi_squared_residual: Option<f64>,
+}
+
+/// レア度統計を CSV に書き出す
+///
+/// 列は `trait_type,value,count,percentage,expected_percentage,chi_squared_residual`。
+/// chi_squared が None の場合、最後の2列は空になる。
+/// 検定対象のレイヤーでは、一度も出現しなかった値も count 0 の行として出力する。
+pub fn export_csv(path: &Path, report: &CheckReport, chi_squared: Option<&[ChiSquaredResult]>) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    let empty = HashMap::new();
+
+    let mut trait_types: Vec<&String> = report.stats.keys().collect();
+    trait_types.sort();
+    for trait_type in trait_types {
+        let observed = &report.stats[trait_type];
+        let result = chi_squared.and_then(|r| r.iter().find(|r| &r.trait_type == trait_type));
+        let expected = result.map_or(&empty, |r| &r.expected);
+
+        let mut values: Vec<&String> = observed.keys().chain(expected.keys()).collect();
+        values.sort();
+        values.dedup();
+        for value in values {
+            let count = observed.get(value).copied().unwrap_or(0);
+            let expected_count = result
+                .and_then(|r| expected.get(value).map(|p| p * r.observed_total as f64));
+            writer.serialize(CsvRow {
+                trait_type,
+                value,
+                count,
+                percentage: count as f64 / report.total as f64 * 100.0,
+                expected_percentage: expected_count.map(|e| e / report.total as f64 * 100.0),
+                chi_squared_residual: expected_count.map(|e| (count as f64 - e) / e.sqrt()),
+            })?;
+        }
+    }
+    writer.flush().map_err(LayeredNftError::io(path))?;
+    Ok(())
+}
+
+/// metadata_dir の全トークン（format 形式）のトレイトを1トークン1行の CSV に書き出し、書き出した行数を返す
+///
+/// 列は `id` と、コレクション全体に現れる trait_type（名前順）。行は token_id 順。
+/// オプションレイヤーなどでそのトレイトを持たないトークンのセルは空になる。
+pub fn export_traits_csv(metadata_dir: &Path, format: MetadataFormat, path: &Path) -> Result<usize> {
+    let mut trait_types = BTreeSet::new();
+    let mut rows = Vec::new();
+    for token_id in token_ids(metadata_dir, "json")? {
+        let metadata_path = metadata_dir.join(format!("{}.json", token_id));
+        let text = fs::read_to_string(&metadata_path).map_err(LayeredNftError::io(&metadata_path))?;
+        let meta = parse_token_metadata(&text, format).map_err(LayeredNftError::json(&metadata_path))?;
+        let values: HashMap<String, String> = meta
+            .attributes
+            .into_iter()
+            .map(|a| (a.trait_type, a.value.to_string()))
+            .collect();
+        trait_types.extend(values.keys().cloned());
+        rows.push((token_id, values));
+    }
+
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(std::iter::once("id").chain(trait_types.iter().map(String::as_str)))?;
+    for (token_id, values) in &rows {
+        let id = token_id.to_string();
+        let cells = trait_types.iter().map(|t| values.get(t).map_or("", String::as_str));
+        writer.write_record(std::iter::once(id.as_str()).chain(cells))?;
+    }
+    writer.flush().map_err(LayeredNftError::io(path))?;
+    Ok(rows.len())
+}
+
+/// rarity-rank の出力ファイル名（metadata_dir 直下）
+pub const RARITY_RANKS_FILE: &str = "rarity_ranks.json";
+
+/// rarity-rank の1トークン分の結果（rarity_ranks.json の要素）
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RarityRank {
+    pub id: u32,
+    pub score: f64,
+    pub rank: u32,
+}
+
+/// metadata_dir の全トークン（format 形式）について OpenSea 方式のレア度スコアを求め、順位の順に返す
+///
+/// スコアは属性ごとの `1 / (その値を持つトークン数 / 総トークン数)` の合計。
+/// スコアの高い順に 1 から順位を付け、同点なら token_id の小さい方を上位にする
+pub fn rarity_ranks(metadata_dir: &Path, format: MetadataFormat) -> Result<Vec<RarityRank>> {
+    let mut tokens = Vec::new();
+    for token_id in token_ids(metadata_dir, "json")? {
+        let metadata_path = metadata_dir.join(format!("{}.json", token_id));
+        let text = fs::read_to_string(&metadata_path).map_err(LayeredNftError::io(&metadata_path))?;
+        let meta = parse_token_metadata(&text, format).map_err(LayeredNftError::json(&metadata_path))?;
+        let traits: Vec<(String, String)> =
+            meta.attributes.into_iter().map(|a| (a.trait_type, a.value.to_string())).collect();
+        tokens.push((token_id, traits));
+    }
+
+    let mut counts: HashMap<&(String, String), usize> = HashMap::new();
+    for (_, traits) in &tokens {
+        for t in traits {
+            *counts.entry(t).or_default() += 1;
+        }
+    }
+    let total = tokens.len() as f64;
+    let mut scored: Vec<(u32, f64)> = tokens
+        .iter()
+        .map(|(token_id, traits)| {
+            let score = traits.iter().map(|t| total / counts[t] as f64).sum();
+            (*token_id, score)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    Ok(scored
+        .into_iter()
+        .zip(1..)
+        .map(|((id, score), rank)| RarityRank { id, score, rank })
+        .collect())
+}
+
+/// 各トークンの `{id}.json` の最上位に rarity_score と rarity_rank を書き込み、書き換えたファイルの数を返す
+pub fn annotate_rarity(cfg: &Config, ranks: &[RarityRank]) -> Result<usize> {
+    for rank in ranks {
+        let path = Path::new(&cfg.output.metadata_dir).join(format!("{}.json", rank.id));
+        let text = fs::read_to_string(&path).map_err(LayeredNftError::io(&path))?;
+        let mut json: serde_json::Value = serde_json::from_str(&text).map_err(LayeredNftError::json(&path))?;
+        if let Some(object) = json.as_object_mut() {
+            object.insert("rarity_score".to_string(), serde_json::json!(rank.score));
+            object.insert("rarity_rank".to_string(), serde_json::json!(rank.rank));
+        }
+        let out = serde_json::to_string_pretty(&json).map_err(LayeredNftError::json(&path))?;
+        write_file(&path, out, cfg.output.writes_atomically())?;
+    }
+    Ok(ranks.len())
+}
+
+/// レイヤーディレクトリのファイルと rarity から、value（display_names の表示名）ごとの期待出現確率を求める
+fn expected_distribution(layer: &LayerConfig, animation: bool) -> HashMap<String, f64> {
+    let mut layer = layer.clone();
+    // 生成時と同じ選択肢（読み込めない場合は期待値なし）
+    let paths = layer_options(Path::new(&layer.directory), animation).unwrap_or_default();
+
+    // rarity_glob は生成時と同じく、一致したファイルごとの rarity に展開してから重みを求める
+    layer.expand_rarity_glob(paths.iter().filter_map(|p| p.file_name().and_then(|s| s.to_str())));
+    let layer = &layer;
+
+    let mut weights: HashMap<String, f64> = HashMap::new();
+    if layer.group_by_directory == Some(true) {
+        // グループを選んでからファイルを選ぶ確率（直下のファイルがあるなど不正な場合は期待値なし）
+        let Ok(probabilities) = grouped_file_weights(layer, &paths) else { return weights; };
+        for (path, p) in paths.iter().zip(probabilities) {
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                *weights.entry(layer.display_name(stem).to_string()).or_insert(0.0) += p;
+            }
+        }
+        weights.retain(|_, p| *p > 0.0);
+        return weights;
+    }
+    // rarity_percent は残りを割り当てた重みに変換する（合計が 100 にならないなど不正な場合は期待値なし）
+    let names = paths.iter().filter_map(|p| p.file_name().and_then(|s| s.to_str()));
+    let Ok(percent) = layer.percent_weights(names) else { return weights; };
+    let rarity = percent.as_ref().or(layer.rarity.as_ref());
+    for path in &paths {
+        let (Some(name), Some(stem)) = (
+            path.file_name().and_then(|s| s.to_str()),
+            path.file_stem().and_then(|s| s.to_str()),
+        ) else {
+            continue;
+        };
+        let weight = rarity.and_then(|r| r.get(name)).copied().unwrap_or(1.0) as f64;
+        *weights.entry(layer.display_name(stem).to_string()).or_insert(0.0) += weight;
+    }
+    let sum: f64 = weights.values().sum();
+    if sum > 0.0 {
+        for w in weights.values_mut() {
+            *w /= sum;
+        }
+    }
+    weights.retain(|_, p| *p > 0.0);
+    weights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigBuilder, ForbiddenPair, LayerConfigBuilder, OutputConfig};
+
+    /// tempdir に images / metadata ディレクトリを作り、そこを出力先にした設定を返す
+    fn output_config(dir: &Path) -> (Config, PathBuf, PathBuf) {
+        let (image_dir, metadata_dir) = (dir.join("images"), dir.join("metadata"));
+        fs::create_dir_all(&image_dir).unwrap();
+        fs::create_dir_all(&metadata_dir).unwrap();
+        let cfg = ConfigBuilder::sample(&["Eyeball"])
+            .output(OutputConfig::new(image_dir.to_string_lossy(), metadata_dir.to_string_lossy()))
+            .build()
+            .unwrap();
+        (cfg, image_dir, metadata_dir)
+    }
+
+    fn report(counts: &[(&str, usize)]) -> CheckReport {
+        let values = counts.iter().map(|(v, c)| (v.to_string(), *c)).collect();
+        CheckReport {
+            total: counts.iter().map(|(_, c)| c).sum(),
+            stats: HashMap::from([("Eyeball".to_string(), values)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn verify_images_reports_corrupt_missing_and_mismatched_images() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (cfg, image_dir, metadata_dir) = output_config(tmp.path());
+        let png = |id: u32, size: u32| {
+            image::RgbaImage::new(size, size).save(image_dir.join(format!("{}.png", id))).unwrap();
+        };
+        png(1, 4);
+        png(2, 4);
+        png(3, 2);
+        fs::write(image_dir.join("4.png"), b"not a png").unwrap();
+        png(6, 4);
+        for id in 1..=5 {
+            fs::write(metadata_dir.join(format!("{}.json", id)), "{}").unwrap();
+        }
+
+        let report = verify_images(&cfg).unwrap();
+        assert_eq!(report.checked, 5);
+        assert_eq!(report.expected_dimensions, Some((4, 4)));
+        assert_eq!(report.dimension_mismatches, [(3, (2, 2))]);
+        assert_eq!(report.corrupt.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [4]);
+        assert_eq!(report.missing_images, [5]);
+        assert_eq!(report.missing_metadata, [6]);
+        assert!(!report.header_only);
+        assert!(report.has_problems());
+    }
+
+    #[test]
+    fn audit_finds_gaps_and_unpaired_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (cfg, image_dir, metadata_dir) = output_config(tmp.path());
+        let report = audit_output(&cfg).unwrap();
+        assert_eq!(report.max_id, 0);
+        assert!(!report.has_discrepancies());
+
+        for id in [1, 2, 5] {
+            fs::write(image_dir.join(format!("{}.png", id)), b"").unwrap();
+        }
+        for id in [1, 3, 5] {
+            fs::write(metadata_dir.join(format!("{}.json", id)), "{}").unwrap();
+        }
+        let report = audit_output(&cfg).unwrap();
+        assert_eq!(report.max_id, 5);
+        assert_eq!(report.image_only, [2]);
+        assert_eq!(report.metadata_only, [3]);
+        assert_eq!(report.gaps, [4]);
+        assert!(report.has_discrepancies());
+    }
+
+    #[test]
+    fn traits_csv_has_one_row_per_token() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let token = |id: u32, attrs: &str| {
+            let json = format!(
+                r#"{{"name":"Token {id}","description":"","image":"","edition":{id},"attributes":[{attrs}]}}"#
+            );
+            fs::write(dir.join(format!("{}.json", id)), json).unwrap();
+        };
+        token(10, r#"{"trait_type":"Eyeball","value":"Red"},{"trait_type":"Goo","value":"Green"}"#);
+        token(2, r#"{"trait_type":"Eyeball","value":"White"},{"trait_type":"Level","value":3}"#);
+        fs::write(dir.join("_metadata.json"), "[]").unwrap();
+
+        let csv_path = dir.join("traits.csv");
+        assert_eq!(export_traits_csv(dir, MetadataFormat::Erc721, &csv_path).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(&csv_path).unwrap(),
+            "id,Eyeball,Goo,Level\n2,White,,3\n10,Red,Green,\n"
+        );
+    }
+
+    #[test]
+    fn numeric_traits_match_forbidden_pairs_by_file_stem() {
+        let tmp = tempfile::tempdir().unwrap();
+        let metadata_dir = tmp.path().join("metadata");
+        fs::create_dir_all(&metadata_dir).unwrap();
+        let attrs = r#"[{"trait_type":"Eyeball","value":2,"display_type":"number"},{"trait_type":"Goo","value":"Green"}]"#;
+        let json = format!(r#"{{"name":"Token 1","description":"","image":"","edition":1,"attributes":{attrs}}}"#);
+        fs::write(metadata_dir.join("1.json"), json).unwrap();
+
+        let cfg = ConfigBuilder::sample(&["Goo"])
+            .output(OutputConfig::new(tmp.path().join("images").to_string_lossy(), metadata_dir.to_string_lossy()))
+            .add_layer(
+                LayerConfigBuilder::new("Eyeball", "layers/Eyeball")
+                    .numeric_trait("Red", 1.0)
+                    .numeric_trait("White", 2.0)
+                    .build(),
+            )
+            .add_forbidden_pair(ForbiddenPair::new(TraitValue::new("Eyeball", "White"), TraitValue::new("Goo", "Green")))
+            .build()
+            .unwrap();
+        let report = check_output(&cfg).unwrap();
+        assert_eq!(report.violation_count, 1);
+        assert_eq!(report.stats["Eyeball"]["2"], 1);
+    }
+
+    #[test]
+    fn check_reads_back_erc1155_collection() {
+        let dir = tempfile::tempdir().unwrap();
+        let (image_dir, metadata_dir) = (dir.path().join("images"), dir.path().join("metadata"));
+        fs::create_dir_all(&image_dir).unwrap();
+        fs::create_dir_all(&metadata_dir).unwrap();
+        let mut cfg = ConfigBuilder::sample(&["Eyeball", "Goo"])
+            .count(3)
+            .seed(42)
+            .output(OutputConfig::new(image_dir.to_string_lossy(), metadata_dir.to_string_lossy()))
+            .build()
+            .unwrap();
+        cfg.metadata.format = MetadataFormat::Erc1155;
+        let generator = crate::generator::Generator::new(cfg).unwrap();
+        for token_id in 1..=3 {
+            let token = generator.generate_token(token_id).unwrap();
+            token.image.save(image_dir.join(format!("{}.png", token_id))).unwrap();
+            let json = generator.metadata_json(token_id, &token.metadata).unwrap();
+            assert!(json.contains("\"properties\"") && !json.contains("\"attributes\""));
+            fs::write(metadata_dir.join(format!("{}.json", token_id)), json).unwrap();
+        }
+
+        let report = check_output(generator.config()).unwrap();
+        assert_eq!(report.total, 3);
+        assert!(report.missing_images.is_empty());
+        assert_eq!(report.stats["Eyeball"].values().sum::<usize>(), 3);
+        let ranks = rarity_ranks(&metadata_dir, MetadataFormat::Erc1155).unwrap();
+        assert_eq!(ranks.len(), 3);
+    }
+
+    #[test]
+    fn image_path_template_is_followed_when_reading() {
+        let dir = tempfile::tempdir().unwrap();
+        let (image_dir, metadata_dir) = (dir.path().join("images"), dir.path().join("metadata"));
+        fs::create_dir_all(&metadata_dir).unwrap();
+        let mut cfg = ConfigBuilder::sample(&["Eyeball"])
+            .count(2)
+            .seed(42)
+            .output(OutputConfig::new(image_dir.to_string_lossy(), metadata_dir.to_string_lossy()))
+            .build()
+            .unwrap();
+        cfg.output.image_path_template = Some("{layer:Eyeball}/{id:03}.png".to_string());
+        let generator = crate::generator::Generator::new(cfg).unwrap();
+        for token_id in 1..=2 {
+            let token = generator.generate_token(token_id).unwrap();
+            let image_path = generator.image_path(token_id, &token.metadata);
+            fs::create_dir_all(image_path.parent().unwrap()).unwrap();
+            token.image.save(&image_path).unwrap();
+            let json = generator.metadata_json(token_id, &token.metadata).unwrap();
+            fs::write(metadata_dir.join(format!("{}.json", token_id)), json).unwrap();
+            assert_eq!(written_image_path(generator.config(), token_id), Some(image_path));
+        }
+
+        let cfg = generator.config();
+        assert!(check_output(cfg).unwrap().missing_images.is_empty());
+        assert!(!audit_output(cfg).unwrap().has_discrepancies());
+        assert_eq!(verify_images(cfg).unwrap().checked, 2);
+        assert_eq!(image_hashes(cfg).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn only_local_animation_urls_are_checked() {
+        assert_eq!(local_path("output/images/1.gif"), Some(PathBuf::from("output/images/1.gif")));
+        assert_eq!(local_path("file:///srv/anim/1.mp4"), Some(PathBuf::from("/srv/anim/1.mp4")));
+        assert_eq!(local_path("C:\\anim\\1.gif"), Some(PathBuf::from("C:\\anim\\1.gif")));
+        assert_eq!(local_path("https://example.com/1.gif"), None);
+        assert_eq!(local_path("ipfs://QmCid/1.gif"), None);
+        assert_eq!(local_path("ar://tx/1.gif"), None);
+    }
+
+    #[test]
+    fn chi_squared_detects_skewed_distribution() {
+        let cfg = ConfigBuilder::sample(&["Eyeball"]).count(2).build().unwrap();
+
+        let even = chi_squared_test(&cfg, &report(&[("Red", 500), ("White", 500)]));
+        assert_eq!(even[0].degrees_of_freedom, 1);
+        assert!(even[0].statistic.abs() < 1e-9);
+        assert!(even[0].passed(0.05));
+
+        let skewed = chi_squared_test(&cfg, &report(&[("Red", 600), ("White", 400)]));
+        assert!((skewed[0].statistic - 40.0).abs() < 1e-9);
+        assert!(!skewed[0].passed(0.05));
+    }
+
+    #[test]
+    fn token_ids_accept_lists_and_ranges() {
+        assert_eq!(parse_token_ids("5,17,42-45").unwrap(), vec![5, 17, 42, 43, 44, 45]);
+        assert_eq!(parse_token_ids(" 3 , 1-3, 2 ").unwrap(), vec![1, 2, 3]);
+        assert!(parse_token_ids("0").is_err());
+        assert!(parse_token_ids("9-3").is_err());
+        assert!(parse_token_ids("a,2").is_err());
+        assert!(parse_token_ids(",").is_err());
+    }
+
+    #[test]
+    fn rarity_ranks_sum_inverse_frequencies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for (id, eyeball, goo) in [(1, "Red", "Green"), (2, "Red", "Green"), (3, "White", "Green"), (4, "Red", "Blue")] {
+            let attrs = format!(
+                r#"{{"trait_type":"Eyeball","value":"{eyeball}"}},{{"trait_type":"Goo","value":"{goo}"}}"#
+            );
+            let json = format!(r#"{{"name":"Token {id}","description":"","image":"","edition":{id},"attributes":[{attrs}]}}"#);
+            fs::write(dir.join(format!("{}.json", id)), json).unwrap();
+        }
+
+        let ranks = rarity_ranks(dir, MetadataFormat::Erc721).unwrap();
+        let order: Vec<(u32, u32)> = ranks.iter().map(|r| (r.id, r.rank)).collect();
+        assert_eq!(order, vec![(3, 1), (4, 2), (1, 3), (2, 4)]);
+        // White: 4/1、Green: 4/3
+        assert!((ranks[0].score - (4.0 + 4.0 / 3.0)).abs() < 1e-9);
+        assert!((ranks[0].score - ranks[1].score).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::config::{ColorReplacement, ColorSwap, LayerConfig};
+use image::RgbaImage;
+use rand::Rng;
+use rand_distr::{Distribution, Normal};
+
+/// swaps に従って画素の色を置き換える（α はそのまま）
+///
+/// 各画素について swaps を上から順に調べ、from との RGB のユークリッド距離が tolerance 以内の最初の swap だけを適用する。
+/// そのため A → B と B → C を並べても、A が C になることはない。
+pub fn remap_palette(img: &mut RgbaImage, swaps: &[ColorSwap]) {
+    if swaps.is_empty() {
+        return;
+    }
+    let thresholds: Vec<u32> = swaps.iter().map(|s| s.tolerance as u32 * s.tolerance as u32).collect();
+    for pixel in img.pixels_mut() {
+        let [r, g, b, _] = pixel.0;
+        let matched = swaps
+            .iter()
+            .zip(&thresholds)
+            .find(|(s, threshold)| distance_sq(&[r, g, b], &s.from) <= **threshold);
+        if let Some((swap, _)) = matched {
+            pixel.0[..3].copy_from_slice(&swap.to);
+        }
+    }
+}
+
+/// レイヤー画像の色を replacements の順に置き換える（layers[].color_replacements）
+///
+/// from_rgba との RGBA のユークリッド距離が tolerance 以内の画素を to_rgba にする。
+/// 置き換えは1つずつ画像全体に適用するため、前の置き換えの結果が後の置き換えの対象になることもある
+pub fn replace_colors(img: &mut RgbaImage, replacements: &[ColorReplacement]) {
+    for replacement in replacements {
+        let threshold = replacement.tolerance as u32 * replacement.tolerance as u32;
+        for pixel in img.pixels_mut() {
+            if distance_sq(&pixel.0, &replacement.from_rgba) <= threshold {
+                pixel.0 = replacement.to_rgba;
+            }
+        }
+    }
+}
+
+/// レイヤー画像の RGB に tint の RGB をリニア空間で掛け合わせる（layers[].tint、α はそのまま）
+///
+/// tint の A は効きの強さで、元の色と乗算後の色を A / 255 で混ぜる。
+/// 出力はチャンネルごとに入力値だけで決まるので、256 段階の変換表を作ってから適用する
+pub fn apply_tint(img: &mut RgbaImage, tint: [u8; 4]) {
+    let strength = tint[3] as f32 / 255.0;
+    let luts: [[u8; 256]; 3] = std::array::from_fn(|c| {
+        let factor = srgb_to_linear(tint[c]);
+        std::array::from_fn(|v| {
+            let tinted = linear_to_srgb(srgb_to_linear(v as u8) * factor);
+            (v as f32 + (tinted - v as f32) * strength).round().clamp(0.0, 255.0) as u8
+        })
+    });
+    for pixel in img.pixels_mut() {
+        for (c, lut) in pixel.0[..3].iter_mut().zip(&luts) {
+            *c = lut[*c as usize];
+        }
+    }
+}
+
+/// 明るさ（RGB に足す値）とコントラストを調整する（layers[].brightness / contrast、α はそのまま）
+///
+/// 明るさを足して 0〜255 に収めてから、`(c - 128) * factor + 128` でコントラストを掛ける。
+/// factor は contrast（-1.0〜1.0）を -255〜255 に直した C から `259 (C + 255) / (255 (259 - C))` で求める
+/// （0.0 で 1 倍、-1.0 で 0 倍）
+pub fn adjust_brightness_contrast(img: &mut RgbaImage, brightness: i16, contrast: f32) {
+    let c = contrast.clamp(-1.0, 1.0) * 255.0;
+    let factor = 259.0 * (c + 255.0) / (255.0 * (259.0 - c));
+    let lut: [u8; 256] = std::array::from_fn(|v| {
+        let brightened = (v as i32 + brightness as i32).clamp(0, 255) as f32;
+        ((brightened - 128.0) * factor + 128.0).round().clamp(0.0, 255.0) as u8
+    });
+    for pixel in img.pixels_mut() {
+        for c in &mut pixel.0[..3] {
+            *c = lut[*c as usize];
+        }
+    }
+}
+
+/// 画素ごとに標準偏差 strength × 255 のガウスノイズを1つ引き、RGB の各チャンネルに同じ値を足す（output.noise）
+///
+/// α はそのまま。完全に透明な画素も乱数は引く（ノイズの模様が画像の内容に左右されないように）が、値は変えない
+pub fn add_noise(img: &mut RgbaImage, strength: f32, rng: &mut impl Rng) {
+    let sigma = strength.clamp(0.0, 1.0) * 255.0;
+    if sigma == 0.0 {
+        return;
+    }
+    let normal = Normal::new(0.0, sigma).expect("標準偏差は 0 より大きい");
+    for pixel in img.pixels_mut() {
+        let noise: f32 = normal.sample(rng);
+        if pixel.0[3] == 0 {
+            continue;
+        }
+        for c in &mut pixel.0[..3] {
+            *c = (*c as f32 + noise).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// sRGB の値（0〜255）をリニアな 0.0〜1.0 にする
+fn srgb_to_linear(v: u8) -> f32 {
+    let v = v as f32 / 255.0;
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+/// リニアな 0.0〜1.0 を sRGB の 0.0〜255.0 に戻す（丸める前の値）
+fn linear_to_srgb(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+    let s = if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 };
+    s * 255.0
+}
+
+/// 輝度（0.2126 R + 0.7152 G + 0.0722 B）でグレースケールにする（RGBA のまま、α はそのまま）
+///
+/// もともと R = G = B でない画素が1つでもあれば true を返す
+pub fn to_grayscale(img: &mut RgbaImage) -> bool {
+    let mut changed = false;
+    for pixel in img.pixels_mut() {
+        let [r, g, b, _] = pixel.0;
+        if r == g && g == b {
+            continue;
+        }
+        let y = (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32).round() as u8;
+        pixel.0[..3].fill(y);
+        changed = true;
+    }
+    changed
+}
+
+/// レイヤーの色相・彩度・明度の調整（LayerConfig の hue_shift / saturation_scale / brightness_scale）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HsvAdjustment {
+    /// 色相の回転量（度）
+    pub hue_shift: f32,
+    /// 彩度の倍率（1.0 = 変化なし）
+    pub saturation_scale: f32,
+    /// 明度の倍率（1.0 = 変化なし）
+    pub brightness_scale: f32,
+}
+
+/// 読み込み時（キャッシュ前）にレイヤー画像へ適用する tint・明るさ・コントラスト
+///
+/// 同じファイルでも値が違えば別の画像としてキャッシュするため、キャッシュのキーにも使う
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadAdjustment {
+    /// RGB へ掛け合わせる色（変化しない値なら None）
+    pub tint: Option<[u8; 4]>,
+    /// RGB に足す値（0 = 変化なし）
+    pub brightness: i16,
+    /// コントラスト（0.0 = 変化なし）
+    pub contrast: f32,
+}
+
+impl LoadAdjustment {
+    /// レイヤーの設定から作る（変化しない値は既定値にそろえ、キャッシュのキーが分かれないようにする）
+    pub fn from_layer(layer: &LayerConfig) -> Self {
+        Self {
+            tint: layer.tint.filter(|t| t[..3] != [255; 3] && t[3] != 0),
+            brightness: layer.brightness.unwrap_or(0),
+            contrast: layer.contrast.filter(|c| *c != 0.0).unwrap_or(0.0),
+        }
+    }
+
+    /// tint・明るさ・コントラストの順に適用する
+    pub fn apply(&self, img: &mut RgbaImage) {
+        if let Some(tint) = self.tint {
+            apply_tint(img, tint);
+        }
+        if self.brightness != 0 || self.contrast != 0.0 {
+            adjust_brightness_contrast(img, self.brightness, self.contrast);
+        }
+    }
+}
+
+impl PartialEq for LoadAdjustment {
+    fn eq(&self, other: &Self) -> bool {
+        self.tint == other.tint && self.brightness == other.brightness && self.contrast.to_bits() == other.contrast.to_bits()
+    }
+}
+
+impl Eq for LoadAdjustment {}
+
+impl std::hash::Hash for LoadAdjustment {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.tint.hash(state);
+        self.brightness.hash(state);
+        self.contrast.to_bits().hash(state);
+    }
+}
+
+impl HsvAdjustment {
+    const IDENTITY: Self = Self { hue_shift: 0.0, saturation_scale: 1.0, brightness_scale: 1.0 };
+
+    /// レイヤーの設定から作る（どれも未指定、または変化しない値なら None）
+    pub fn from_layer(layer: &LayerConfig) -> Option<Self> {
+        let adjustment = Self {
+            hue_shift: layer.hue_shift.unwrap_or(0.0),
+            saturation_scale: layer.saturation_scale.unwrap_or(1.0),
+            brightness_scale: layer.brightness_scale.unwrap_or(1.0),
+        };
+        (adjustment != Self::IDENTITY).then_some(adjustment)
+    }
+}
+
+/// 各画素を HSV に変換して色相・彩度・明度を調整する（α はそのまま、完全に透明な画素は変更しない）
+pub fn adjust_hsv(img: &mut RgbaImage, adjustment: HsvAdjustment) {
+    for pixel in img.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        if a == 0 {
+            continue;
+        }
+        let (h, s, v) = rgb_to_hsv([r, g, b]);
+        let h = (h + adjustment.hue_shift).rem_euclid(360.0);
+        let s = (s * adjustment.saturation_scale).clamp(0.0, 1.0);
+        let v = (v * adjustment.brightness_scale).clamp(0.0, 1.0);
+        let [r, g, b] = hsv_to_rgb(h, s, v);
+        pixel.0 = [r, g, b, a];
+    }
+}
+
+/// RGB → (色相 0〜360, 彩度 0〜1, 明度 0〜1)
+fn rgb_to_hsv(rgb: [u8; 3]) -> (f32, f32, f32) {
+    let [r, g, b] = rgb.map(|c| c as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+    (h, s, max)
+}
+
+fn hsv_to_rgb(h: f32, s: f32, v: f32) -> [u8; 3] {
+    let c = v * s;
+    let x = c * (1.0 - ((h / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let m = v - c;
+    let (r, g, b) = match (h / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    [r, g, b].map(|c| ((c + m) * 255.0).round() as u8)
+}
+
+fn distance_sq(a: &[u8], b: &[u8]) -> u32 {
+    a.iter()
+        .zip(b)
+        .map(|(&x, &y)| {
+            let d = x.abs_diff(y) as u32;
+            d * d
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn first_matching_swap_wins_and_alpha_is_kept() {
+        let mut img = RgbaImage::from_fn(3, 1, |x, _| match x {
+            0 => Rgba([250, 5, 0, 128]),
+            1 => Rgba([0, 0, 255, 255]),
+            _ => Rgba([200, 0, 0, 255]),
+        });
+        let swaps = [
+            ColorSwap { from: [255, 0, 0], to: [0, 0, 255], tolerance: 10 },
+            ColorSwap { from: [0, 0, 255], to: [0, 255, 0], tolerance: 0 },
+        ];
+        remap_palette(&mut img, &swaps);
+        assert_eq!(img.get_pixel(0, 0), &Rgba([0, 0, 255, 128]));
+        assert_eq!(img.get_pixel(1, 0), &Rgba([0, 255, 0, 255]));
+        assert_eq!(img.get_pixel(2, 0), &Rgba([200, 0, 0, 255]));
+    }
+
+    #[test]
+    fn replacements_apply_in_order() {
+        let mut img = RgbaImage::from_fn(3, 1, |x, _| match x {
+            0 => Rgba([255, 0, 0, 255]),
+            1 => Rgba([255, 0, 0, 250]),
+            _ => Rgba([0, 0, 0, 0]),
+        });
+        let replacement = |from_rgba, to_rgba, tolerance| ColorReplacement {
+            filename_glob: "*".to_string(),
+            from_rgba,
+            to_rgba,
+            tolerance,
+        };
+        replace_colors(
+            &mut img,
+            &[replacement([255, 0, 0, 255], [0, 0, 255, 255], 0), replacement([0, 0, 255, 255], [0, 255, 0, 128], 0)],
+        );
+        assert_eq!(img.get_pixel(0, 0), &Rgba([0, 255, 0, 128]));
+        assert_eq!(img.get_pixel(1, 0), &Rgba([255, 0, 0, 250]));
+        assert_eq!(img.get_pixel(2, 0), &Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn grayscale_uses_luminosity_weights() {
+        let mut img = RgbaImage::from_fn(2, 1, |x, _| if x == 0 { Rgba([255, 0, 0, 77]) } else { Rgba([9, 9, 9, 255]) });
+        assert!(to_grayscale(&mut img));
+        assert_eq!(img.get_pixel(0, 0), &Rgba([54, 54, 54, 77]));
+        assert_eq!(img.get_pixel(1, 0), &Rgba([9, 9, 9, 255]));
+        assert!(!to_grayscale(&mut img));
+    }
+
+    #[test]
+    fn hsv_adjustment_shifts_hue_and_scales() {
+        let mut img = RgbaImage::from_fn(3, 1, |x, _| match x {
+            0 => Rgba([255, 0, 0, 200]),
+            1 => Rgba([10, 20, 30, 0]),
+            _ => Rgba([51, 102, 153, 255]),
+        });
+        let adjustment = HsvAdjustment { hue_shift: 120.0, saturation_scale: 1.0, brightness_scale: 0.5 };
+        adjust_hsv(&mut img, adjustment);
+        assert_eq!(img.get_pixel(0, 0), &Rgba([0, 128, 0, 200]));
+        assert_eq!(img.get_pixel(1, 0), &Rgba([10, 20, 30, 0]));
+
+        // 変化しない調整では元の色に戻る
+        for rgb in [[51, 102, 153], [255, 255, 255], [0, 0, 0], [200, 10, 99]] {
+            let (h, s, v) = rgb_to_hsv(rgb);
+            assert_eq!(hsv_to_rgb(h, s, v), rgb);
+        }
+        let mut gray = RgbaImage::from_pixel(1, 1, Rgba([51, 102, 153, 255]));
+        adjust_hsv(&mut gray, HsvAdjustment { saturation_scale: 0.0, ..HsvAdjustment::IDENTITY });
+        assert_eq!(gray.get_pixel(0, 0), &Rgba([153, 153, 153, 255]));
+    }
+
+    #[test]
+    fn tint_multiplies_rgb_and_keeps_alpha() {
+        let original = RgbaImage::from_fn(3, 1, |x, _| match x {
+            0 => Rgba([255, 255, 255, 255]),
+            1 => Rgba([128, 64, 200, 100]),
+            _ => Rgba([0, 0, 0, 0]),
+        });
+        let mut identity = original.clone();
+        apply_tint(&mut identity, [255, 255, 255, 255]);
+        assert_eq!(identity, original);
+
+        let mut reddish = original.clone();
+        apply_tint(&mut reddish, [255, 128, 128, 255]);
+        // リニア空間で 128 は約 0.216 倍なので、白は (255, 128, 128) になる
+        assert_eq!(reddish.get_pixel(0, 0), &Rgba([255, 128, 128, 255]));
+        let [r, g, b, a] = reddish.get_pixel(1, 0).0;
+        assert_eq!((r, a), (128, 100));
+        assert!(g < 64 && b < 200);
+
+        let mut weak = original.clone();
+        apply_tint(&mut weak, [255, 128, 128, 0]);
+        assert_eq!(weak, original);
+    }
+
+    #[test]
+    fn brightness_clamps_and_contrast_spreads_from_middle_gray() {
+        let original = RgbaImage::from_fn(2, 1, |x, _| if x == 0 { Rgba([220, 128, 20, 255]) } else { Rgba([100, 160, 0, 50]) });
+        let mut identity = original.clone();
+        adjust_brightness_contrast(&mut identity, 0, 0.0);
+        assert_eq!(identity, original);
+
+        let mut brighter = original.clone();
+        adjust_brightness_contrast(&mut brighter, 50, 0.0);
+        assert_eq!(brighter.get_pixel(0, 0), &Rgba([255, 178, 70, 255]));
+        assert_eq!(brighter.get_pixel(1, 0), &Rgba([150, 210, 50, 50]));
+
+        let mut flat = original.clone();
+        adjust_brightness_contrast(&mut flat, 0, -1.0);
+        assert_eq!(flat.get_pixel(1, 0), &Rgba([128, 128, 128, 50]));
+
+        let mut punchy = original.clone();
+        adjust_brightness_contrast(&mut punchy, 0, 0.5);
+        let [r, g, b, _] = punchy.get_pixel(0, 0).0;
+        assert!(r > 220 && g == 128 && b < 20);
+    }
+}
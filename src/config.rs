@@ -1,66 +1,2212 @@
-use serde::Deserialize;
+use crate::blend::BlendMode;
+use image::imageops::FilterType;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use anyhow::Result;
+use crate::error::{LayeredNftError, Result};
+use crate::metadata::UrlTemplate;
+use crate::path_template::PathTemplate;
 use std::fs;
+use std::path::Path;
+
+/// rarity_percent の合計を 100 と比べるときの誤差の許容幅（33.3 を3つ並べる場合など）
+const PERCENT_TOLERANCE: f32 = 0.01;
+
+/// required_aspect_ratio の tolerance の既定値（縦横比の相対誤差）
+const DEFAULT_ASPECT_TOLERANCE: f64 = 0.01;
+
+/// 設定値が不正なときに InvalidConfig で早期リターンする
+macro_rules! invalid {
+    ($($arg:tt)*) => {
+        return Err(LayeredNftError::InvalidConfig(format!($($arg)*)))
+    };
+}
 
 impl Config {
+    /// 拡張子から形式を判定して読み込む（.toml → TOML, .json → JSON, それ以外 → YAML）
     pub fn load(path: &str) -> Result<Self> {
-        let text = fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&text)?;
+        match extension(path).as_deref() {
+            Some("toml") => Self::load_toml(path),
+            Some("json") => Self::load_json(path),
+            _ => Self::load_yaml(path),
+        }
+    }
+
+    pub fn load_yaml(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(LayeredNftError::io(path))?;
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&text)?;
+        expand_yaml_strings(&mut value, path)?;
+        let mut config: Config = serde_yaml::from_value(value)?;
+        config.load_external_lists(config_dir(path))?;
+        config.validate_values()?;
         Ok(config)
     }
+
+    pub fn load_toml(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(LayeredNftError::io(path))?;
+        let mut value: toml::Value = toml::from_str(&text).map_err(|e| format_error(path, e))?;
+        expand_toml_strings(&mut value, path)?;
+        let mut config: Config = value.try_into().map_err(|e| format_error(path, e))?;
+        config.load_external_lists(config_dir(path))?;
+        config.validate_values()?;
+        Ok(config)
+    }
+
+    pub fn load_json(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(LayeredNftError::io(path))?;
+        let mut value: serde_json::Value = serde_json::from_str(&text).map_err(|e| format_error(path, e))?;
+        expand_json_strings(&mut value, path)?;
+        let mut config: Config = serde_json::from_value(value).map_err(|e| format_error(path, e))?;
+        config.load_external_lists(config_dir(path))?;
+        config.validate_values()?;
+        Ok(config)
+    }
+
+    /// ベースの設定ファイルに上書き用ファイルを左から順に深くマージして読み込む
+    ///
+    /// オブジェクトはキーごとに再帰的にマージし、スカラー値は後のファイルが優先される。
+    /// 配列は置き換えるが、上書き側のオブジェクトに `_merge_arrays: true` があると、
+    /// そのオブジェクト以下の配列は連結する（レイヤーの追加など）。
+    pub fn load_with_overrides(base: &str, overrides: &[&str]) -> Result<Self> {
+        let mut merged = load_value(base)?;
+        for path in overrides {
+            let value = load_value(path)?;
+            merge_values(&mut merged, value, false);
+        }
+        let mut config: Config = serde_json::from_value(merged)
+            .map_err(|e| format_error(&format!("{} + {:?}", base, overrides), e))?;
+        // 別ファイルのパスは load_value がそれぞれの設定ファイルのディレクトリから解決済み
+        config.load_external_lists(Path::new(""))?;
+        config.validate_values()?;
+        Ok(config)
+    }
+
+    /// 別ファイルに分けたリスト（excluded_patterns_file）を読み込んで本体に統合する
+    /// （相対パスは base_dir = 設定ファイルのディレクトリから解決する）
+    fn load_external_lists(&mut self, base_dir: &Path) -> Result<()> {
+        let Some(c) = &mut self.constraints else { return Ok(()); };
+        let Some(file) = c.excluded_patterns_file.take() else { return Ok(()); };
+
+        let path = base_dir.join(file).to_string_lossy().into_owned();
+        let text = fs::read_to_string(&path).map_err(LayeredNftError::io(&path))?;
+        let patterns: Vec<HashMap<String, String>> =
+            serde_yaml::from_str(&text).map_err(|e| format_error(&path, e))?;
+        c.excluded_patterns.get_or_insert_with(Vec::new).extend(patterns);
+        Ok(())
+    }
+
+    /// 読み込み直後に行う値の形式チェック
+    fn validate_values(&self) -> Result<()> {
+        if let Some(color) = &self.metadata.background_color
+            && !is_hex_color(color)
+        {
+            invalid!(
+                "metadata.background_color は `#` なしの6桁16進数で指定してください: {:?}",
+                color
+            );
+        }
+        if let Some(ratio) = &self.required_aspect_ratio {
+            if ratio.width == 0 || ratio.height == 0 {
+                invalid!("required_aspect_ratio の width / height には 1 以上を指定してください");
+            }
+            if let Some(t) = ratio.tolerance
+                && !(t.is_finite() && t >= 0.0)
+            {
+                invalid!("required_aspect_ratio.tolerance には 0 以上の値を指定してください: {}", t);
+            }
+            if let (Some(w), Some(h)) = (self.output.target_width, self.output.target_height)
+                && !ratio.matches((w, h))
+            {
+                invalid!(
+                    "output.target_width / target_height（{}x{}）が required_aspect_ratio {}:{} と一致しません",
+                    w,
+                    h,
+                    ratio.width,
+                    ratio.height
+                );
+            }
+        }
+        if let Some(hooks) = &self.hooks {
+            for (field, command) in [("post_token", &hooks.post_token), ("post_all", &hooks.post_all)] {
+                if command.as_ref().is_some_and(|c| c.trim().is_empty()) {
+                    invalid!("hooks.{} が空です", field);
+                }
+            }
+        }
+        for layer in &self.layers {
+            for (file, weight) in layer.rarity.iter().flatten() {
+                if !(weight.is_finite() && *weight > 0.0) {
+                    invalid!(
+                        "レイヤー {:?} の rarity の重みには 0 より大きい値を指定してください: {:?} = {}",
+                        layer.name,
+                        file,
+                        weight
+                    );
+                }
+            }
+            for rule in layer.rarity_glob.iter().flatten() {
+                if let Err(e) = glob::Pattern::new(&rule.pattern) {
+                    invalid!("レイヤー {:?} の rarity_glob のパターンが不正です: {:?}: {}", layer.name, rule.pattern, e);
+                }
+                if !(rule.weight.is_finite() && rule.weight > 0.0) {
+                    invalid!(
+                        "レイヤー {:?} の rarity_glob の重みには 0 より大きい値を指定してください: {:?} = {}",
+                        layer.name,
+                        rule.pattern,
+                        rule.weight
+                    );
+                }
+            }
+            let Some(percent) = &layer.rarity_percent else { continue; };
+            if layer.rarity_glob.is_some() {
+                invalid!("レイヤー {:?}: rarity_glob と rarity_percent はどちらか一方だけを指定してください", layer.name);
+            }
+            if layer.rarity.is_some() {
+                invalid!("レイヤー {:?}: rarity と rarity_percent はどちらか一方だけを指定してください", layer.name);
+            }
+            if layer.group_by_directory == Some(true) {
+                invalid!("レイヤー {:?}: group_by_directory のレイヤーでは rarity_percent は使えません（rarity で重みを指定してください）", layer.name);
+            }
+            for (file, p) in percent {
+                if !(p.is_finite() && (0.0..=100.0).contains(p)) {
+                    invalid!("レイヤー {:?} の rarity_percent は 0〜100 で指定してください: {:?} = {}", layer.name, file, p);
+                }
+            }
+            let sum: f32 = percent.values().sum();
+            if sum > 100.0 + PERCENT_TOLERANCE {
+                invalid!("レイヤー {:?} の rarity_percent の合計が 100 を超えています: {}", layer.name, sum);
+            }
+        }
+        if self.output.animation.is_some()
+            && let Some(layer) = self.layers.iter().find(|l| l.group_by_directory == Some(true))
+        {
+            invalid!(
+                "レイヤー {:?}: アニメーション出力ではサブディレクトリをフレーム列として扱うため、group_by_directory は使えません",
+                layer.name
+            );
+        }
+        if self.batch_size == Some(0) {
+            invalid!("batch_size には 1 以上を指定してください");
+        }
+        if let Some(t) = &self.output.thumbnail
+            && (t.width == 0 || t.height == 0)
+        {
+            invalid!("output.thumbnail の width / height には 1 以上を指定してください");
+        }
+        for (i, extra) in self.output.additional_outputs.iter().flatten().enumerate() {
+            if extra.width == Some(0) || extra.height == Some(0) {
+                invalid!("output.additional_outputs[{}] の width / height には 1 以上を指定してください", i);
+            }
+            if let Some(q) = extra.quality
+                && !(1..=100).contains(&q)
+            {
+                invalid!("output.additional_outputs[{}].quality は 1〜100 の範囲で指定してください: {}", i, q);
+            }
+            // 同じディレクトリに同じ拡張子で書き出すと、主の画像や他の追加出力を上書きしてしまう
+            let same_as_primary = Path::new(&extra.dir) == Path::new(&self.output.image_dir)
+                && extra.format.extension() == self.output.format.extension();
+            let same_as_other = self.output.additional_outputs.iter().flatten().take(i).any(|other| {
+                Path::new(&other.dir) == Path::new(&extra.dir) && other.format.extension() == extra.format.extension()
+            });
+            if same_as_primary || same_as_other {
+                invalid!(
+                    "output.additional_outputs[{}] の出力先 {}/*.{} が他の画像の出力先と重なっています",
+                    i,
+                    extra.dir,
+                    extra.format.extension()
+                );
+            }
+        }
+        if let Some(wm) = &self.output.watermark
+            && !(0.0..=1.0).contains(&wm.opacity)
+        {
+            invalid!("output.watermark.opacity は 0.0〜1.0 の範囲で指定してください: {}", wm.opacity);
+        }
+        if let Some(opacity) = self.output.frame.as_ref().and_then(|f| f.opacity)
+            && !(0.0..=1.0).contains(&opacity)
+        {
+            invalid!("output.frame.opacity は 0.0〜1.0 の範囲で指定してください: {}", opacity);
+        }
+        if let Some(noise) = &self.output.noise
+            && !(0.0..=1.0).contains(&noise.strength)
+        {
+            invalid!("output.noise.strength は 0.0〜1.0 の範囲で指定してください: {}", noise.strength);
+        }
+        for layer in &self.layers {
+            if let Some(p) = layer.optional_probability
+                && !(p.is_finite() && (0.0..=1.0).contains(&p))
+            {
+                invalid!("レイヤー {:?} の optional_probability は 0.0〜1.0 の範囲で指定してください: {}", layer.name, p);
+            }
+            if let Some(scale) = layer.transform.and_then(|t| t.scale)
+                && scale <= 0.0
+            {
+                invalid!("レイヤー {:?} の transform.scale は 0 より大きい値にしてください: {}", layer.name, scale);
+            }
+            for replacement in layer.color_replacements.iter().flatten() {
+                if let Err(e) = glob::Pattern::new(&replacement.filename_glob) {
+                    invalid!(
+                        "レイヤー {:?} の color_replacements の filename_glob が不正です: {:?}: {}",
+                        layer.name,
+                        replacement.filename_glob,
+                        e
+                    );
+                }
+            }
+            if let Some(hue) = layer.hue_shift
+                && !(-180.0..=180.0).contains(&hue)
+            {
+                invalid!("レイヤー {:?} の hue_shift は -180〜180 の範囲で指定してください: {}", layer.name, hue);
+            }
+            for (field, scale) in [("saturation_scale", layer.saturation_scale), ("brightness_scale", layer.brightness_scale)] {
+                if let Some(scale) = scale
+                    && !(scale.is_finite() && scale >= 0.0)
+                {
+                    invalid!("レイヤー {:?} の {} には 0 以上の値を指定してください: {}", layer.name, field, scale);
+                }
+            }
+            if let Some(brightness) = layer.brightness
+                && !(-255..=255).contains(&brightness)
+            {
+                invalid!("レイヤー {:?} の brightness は -255〜255 の範囲で指定してください: {}", layer.name, brightness);
+            }
+            if let Some(contrast) = layer.contrast
+                && !(-1.0..=1.0).contains(&contrast)
+            {
+                invalid!("レイヤー {:?} の contrast は -1.0〜1.0 の範囲で指定してください: {}", layer.name, contrast);
+            }
+        }
+        for template in [&self.output.image_path_template, &self.output.metadata_path_template].into_iter().flatten() {
+            PathTemplate::parse(template)?;
+        }
+        for (field, template) in [
+            ("metadata.external_url_template", &self.metadata.external_url_template),
+            ("metadata.youtube_url_template", &self.metadata.youtube_url_template),
+            ("metadata.animation_url_template", &self.metadata.animation_url_template),
+        ] {
+            let Some(template) = template else { continue; };
+            let template = UrlTemplate::parse(template)?;
+            for trait_type in template.traits() {
+                if !self.layers.iter().any(|l| l.name == trait_type) {
+                    invalid!("{} の {{trait:{}}} に一致するレイヤーがありません", field, trait_type);
+                }
+            }
+            if template.uses_file() && (field != "metadata.animation_url_template" || self.output.animation.is_none()) {
+                invalid!("{} の {{file}} は metadata.animation_url_template で output.animation を設定した場合だけ使えます", field);
+            }
+        }
+        if self.output.s3.as_ref().is_some_and(|s3| s3.concurrency == Some(0)) {
+            invalid!("output.s3.concurrency には 1 以上を指定してください");
+        }
+        if let Some(q) = self.output.jpeg_quality
+            && !(1..=100).contains(&q)
+        {
+            invalid!("output.jpeg_quality は 1〜100 の範囲で指定してください: {}", q);
+        }
+        if let Some(q) = self.output.avif_quality
+            && q > 100
+        {
+            invalid!("output.avif_quality は 0〜100 の範囲で指定してください: {}", q);
+        }
+        if self.output.target_width == Some(0) || self.output.target_height == Some(0) {
+            invalid!("output.target_width / target_height には 1 以上を指定してください");
+        }
+        if let Some(loc) = self.metadata.erc1155.as_ref().and_then(|e| e.localization.as_ref())
+            && !loc.uri.contains("{locale}")
+        {
+            invalid!("metadata.erc1155.localization.uri には {{locale}} を含めてください: {}", loc.uri);
+        }
+        if self.metadata.format == MetadataFormat::Metaplex {
+            let Some(mp) = &self.metadata.metaplex else {
+                invalid!("metadata.format が metaplex の場合は metadata.metaplex を設定してください");
+            };
+            let total: u32 = mp.creators.iter().map(|c| c.share as u32).sum();
+            if !mp.creators.is_empty() && total != 100 {
+                invalid!("metadata.metaplex.creators の share の合計は 100 にしてください（現在 {}）", total);
+            }
+        }
+        if let Some(c) = &self.constraints
+            && let (Some(forbidden), Some(required)) = (&c.forbidden_pairs, &c.required_pairs)
+        {
+            for r in required {
+                let conflict = forbidden
+                    .iter()
+                    .any(|f| (f.a == r.a && f.b == r.b) || (f.a == r.b && f.b == r.a));
+                if conflict {
+                    invalid!(
+                        "constraints の forbidden_pairs と required_pairs が矛盾しています: {}={} と {}={}",
+                        r.a.trait_type,
+                        r.a.value,
+                        r.b.trait_type,
+                        r.b.value
+                    );
+                }
+            }
+        }
+        for (i, g) in self.constraints.iter().flat_map(|c| c.forbidden_groups.iter().flatten()).enumerate() {
+            if g.members.len() < 2 {
+                invalid!("constraints.forbidden_groups[{}] の members には2つ以上のトレイトを指定してください", i);
+            }
+            for (j, m) in g.members.iter().enumerate() {
+                if g.members[..j].contains(m) {
+                    invalid!(
+                        "constraints.forbidden_groups[{}] の members に {}={} が重複しています",
+                        i,
+                        m.trait_type,
+                        m.value
+                    );
+                }
+            }
+        }
+        if self
+            .constraints
+            .iter()
+            .flat_map(|c| c.excluded_patterns.iter().flatten())
+            .any(|p| p.is_empty())
+        {
+            invalid!("constraints.excluded_patterns に空のパターンがあります（すべてのトークンが除外されます）");
+        }
+        if let Some(variants) = self.constraints.as_ref().and_then(|c| c.z_order_variants.as_ref()) {
+            let mut layer_names: Vec<&str> = self.layers.iter().map(|l| l.name.as_str()).collect();
+            layer_names.sort_unstable();
+            for (i, variant) in variants.iter().enumerate() {
+                if !(0.0..=1.0).contains(&variant.probability) {
+                    invalid!(
+                        "constraints.z_order_variants[{}].probability は 0.0〜1.0 で指定してください: {}",
+                        i,
+                        variant.probability
+                    );
+                }
+                let mut order: Vec<&str> = variant.order.iter().map(String::as_str).collect();
+                order.sort_unstable();
+                if order != layer_names {
+                    invalid!(
+                        "constraints.z_order_variants[{}].order にはすべてのレイヤー名を1回ずつ並べてください: {:?}",
+                        i,
+                        variant.order
+                    );
+                }
+            }
+            let total: f64 = variants.iter().map(|v| v.probability).sum();
+            if total > 1.0 + 1e-9 {
+                invalid!("constraints.z_order_variants の probability の合計が 1.0 を超えています: {}", total);
+            }
+        }
+        for (i, t) in self.constraints.iter().flat_map(|c| c.required_attributes.iter().flatten()).enumerate() {
+            if !self.layers.iter().any(|l| l.name == t.trait_type) {
+                invalid!(
+                    "constraints.required_attributes[{}] の trait_type {:?} に一致するレイヤーがないため、どのトークンも条件を満たせません",
+                    i,
+                    t.trait_type
+                );
+            }
+        }
+        for (i, g) in self.constraints.iter().flat_map(|c| c.required_group.iter().flatten()).enumerate() {
+            if !g.members.iter().any(|m| self.layers.iter().any(|l| l.name == m.trait_type)) {
+                invalid!(
+                    "constraints.required_group[{}] ({:?}) の members に一致するレイヤーがないため、どのトークンも条件を満たせません",
+                    i,
+                    g.name
+                );
+            }
+        }
+        for cc in self.constraints.iter().flat_map(|c| c.conditional_constraints.iter().flatten()) {
+            if !self.layers.iter().any(|l| l.name == cc.then_layer) {
+                invalid!(
+                    "conditional_constraints の then_layer {:?} に一致するレイヤーがありません",
+                    cc.then_layer
+                );
+            }
+        }
+        if let Some(groups) = self.constraints.as_ref().and_then(|c| c.exclusive_groups.as_ref()) {
+            let mut owner: HashMap<(&str, &str), &str> = HashMap::new();
+            for g in groups {
+                for m in &g.members {
+                    let key = (m.trait_type.as_str(), m.value.as_str());
+                    if let Some(prev) = owner.insert(key, &g.name)
+                        && prev != g.name
+                    {
+                        invalid!(
+                            "{}={} が exclusive_groups の {:?} と {:?} の両方に含まれています",
+                            m.trait_type,
+                            m.value,
+                            prev,
+                            g.name
+                        );
+                    }
+                }
+            }
+
+            // 同じグループ内の2つを required_pairs で必須にすると、a を含むトークンが作れなくなる
+            for r in self.constraints.iter().flat_map(|c| c.required_pairs.iter().flatten()) {
+                let a = owner.get(&(r.a.trait_type.as_str(), r.a.value.as_str()));
+                let b = owner.get(&(r.b.trait_type.as_str(), r.b.value.as_str()));
+                if let (Some(a), Some(b)) = (a, b)
+                    && a == b
+                {
+                    invalid!(
+                        "required_pairs の {}={} と {}={} は exclusive_groups の {:?} に含まれているため両立できません",
+                        r.a.trait_type,
+                        r.a.value,
+                        r.b.trait_type,
+                        r.b.value,
+                        a
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 各レイヤーの rarity の重みを合計 1 の確率に変換する（重みの比率は変わらない）
+    pub fn normalize_rarity(&mut self) {
+        for layer in &mut self.layers {
+            let Some(rarity) = &mut layer.rarity else { continue; };
+            let sum: f32 = rarity.values().sum();
+            if sum > 0.0 {
+                for weight in rarity.values_mut() {
+                    *weight /= sum;
+                }
+            }
+        }
+    }
+
+    /// 読み込んだ設定にコマンドラインからの上書きを適用し、実際に使う設定を組み立てる
+    pub fn resolve(self, overrides: &ConfigOverrides) -> Config {
+        let output = match &overrides.output_dir {
+            Some(dir) => OutputConfig {
+                image_dir: format!("{}/images", dir),
+                metadata_dir: format!("{}/metadata", dir),
+                ..self.output
+            },
+            None => self.output,
+        };
+        Config {
+            count: overrides.count.unwrap_or(self.count),
+            seed: overrides.seed.or(self.seed),
+            output,
+            ..self
+        }
+    }
+
+    /// JSON 形式で書き出す
+    pub fn save_json(&self, path: &str) -> Result<()> {
+        let text = serde_json::to_string_pretty(self).map_err(LayeredNftError::json(path))?;
+        fs::write(path, text).map_err(LayeredNftError::io(path))?;
+        Ok(())
+    }
+
+    /// TOML 形式で書き出す（YAML からの変換用）
+    pub fn save_toml(&self, path: &str) -> Result<()> {
+        let text = toml::to_string_pretty(self).map_err(|e| format_error(path, e))?;
+        fs::write(path, text).map_err(LayeredNftError::io(path))?;
+        Ok(())
+    }
+
+    /// 設定ファイルの JSON Schema（draft-07）。各フィールドの説明はドキュメントコメントから作られる
+    ///
+    /// YAML Language Server などのエディタ拡張に読ませると、config.yaml の補完と検証ができる
+    pub fn json_schema() -> serde_json::Value {
+        schemars::generate::SchemaSettings::draft07()
+            .into_generator()
+            .into_root_schema_for::<Config>()
+            .to_value()
+    }
+}
+
+/// 複数のコレクションをまとめた設定ファイル（トップレベルに `collections` を持つ形式）
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MultiCollectionConfig {
+    /// 生成するコレクション。それぞれ単独の設定ファイルと同じ項目を持つ
+    pub collections: Vec<Config>,
+}
+
+impl MultiCollectionConfig {
+    /// 設定ファイルがトップレベルに collections を持つ形式なら読み込む（持たなければ None）
+    pub fn load(path: &str) -> Result<Option<Self>> {
+        Self::load_with_overrides(path, &[])
+    }
+
+    /// Config::load_with_overrides と同じ要領で上書き用ファイルをマージしてから判定・読み込みする
+    pub fn load_with_overrides(base: &str, overrides: &[&str]) -> Result<Option<Self>> {
+        let mut merged = load_value(base)?;
+        for path in overrides {
+            let value = load_value(path)?;
+            merge_values(&mut merged, value, false);
+        }
+        if merged.get("collections").is_none() {
+            return Ok(None);
+        }
+        let mut multi: Self = serde_json::from_value(merged)
+            .map_err(|e| format_error(&format!("{} + {:?}", base, overrides), e))?;
+        for (i, config) in multi.collections.iter_mut().enumerate() {
+            config
+                .load_external_lists(Path::new(""))
+                .and_then(|()| config.validate_values())
+                .map_err(|e| match e {
+                    LayeredNftError::InvalidConfig(message) => {
+                        LayeredNftError::InvalidConfig(format!("collections[{}]: {}", i, message))
+                    }
+                    e => e,
+                })?;
+        }
+        multi.validate_values()?;
+        Ok(Some(multi))
+    }
+
+    /// コレクションが1つ以上あり、出力先が互いに重ならないか（並列に書き出すため）
+    fn validate_values(&self) -> Result<()> {
+        if self.collections.is_empty() {
+            invalid!("collections に1つ以上のコレクションを指定してください");
+        }
+        let mut dirs = HashMap::new();
+        for (i, config) in self.collections.iter().enumerate() {
+            for dir in [&config.output.image_dir, &config.output.metadata_dir] {
+                if let Some(other) = dirs.insert(Path::new(dir), i)
+                    && other != i
+                {
+                    invalid!("collections[{}] と collections[{}] の出力先 {:?} が重なっています", other, i, dir);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// コマンドラインから設定ファイルの値を上書きする項目（None は設定ファイルの値を使う）
+#[derive(Debug, Default)]
+pub struct ConfigOverrides {
+    /// 指定すると `{dir}/images` と `{dir}/metadata` に出力する
+    pub output_dir: Option<String>,
+    pub count: Option<u32>,
+    pub seed: Option<u64>,
+}
+
+/// 拡張子に応じて設定ファイルを汎用の JSON 値として読み込む（文字列の値の環境変数は展開済み）
+fn load_value(path: &str) -> Result<serde_json::Value> {
+    let text = fs::read_to_string(path).map_err(LayeredNftError::io(path))?;
+    let mut value = match extension(path).as_deref() {
+        Some("toml") => toml::from_str(&text).map_err(|e| format_error(path, e))?,
+        Some("json") => serde_json::from_str(&text).map_err(|e| format_error(path, e))?,
+        _ => serde_yaml::from_str(&text).map_err(|e| format_error(path, e))?,
+    };
+    expand_json_strings(&mut value, path)?;
+    resolve_list_paths(&mut value, path);
+    Ok(value)
+}
+
+/// 設定ファイルのあるディレクトリ（別ファイルに分けたリストの相対パスの基準）
+fn config_dir(path: &str) -> &Path {
+    Path::new(path).parent().unwrap_or(Path::new(""))
+}
+
+/// excluded_patterns_file（collections 内のものも含む）を、書かれている設定ファイルのディレクトリから解決したパスに置き換える
+///
+/// 上書き用ファイルとマージした後では、どのファイルに書かれていたかが分からなくなるため、マージ前に行う
+fn resolve_list_paths(value: &mut serde_json::Value, path: &str) {
+    let resolve = |config: &mut serde_json::Value| {
+        if let Some(serde_json::Value::String(file)) = config.pointer_mut("/constraints/excluded_patterns_file") {
+            *file = config_dir(path).join(&*file).to_string_lossy().into_owned();
+        }
+    };
+    resolve(value);
+    if let Some(serde_json::Value::Array(collections)) = value.get_mut("collections") {
+        collections.iter_mut().for_each(resolve);
+    }
+}
+
+/// 解析済みの YAML の文字列の値すべてに expand_env を適用する（キーとコメントはそのまま）
+fn expand_yaml_strings(value: &mut serde_yaml::Value, source: &str) -> Result<()> {
+    use serde_yaml::Value;
+
+    match value {
+        Value::String(s) => *s = expand_env(s, source, |name| std::env::var(name).ok())?,
+        Value::Sequence(items) => items.iter_mut().try_for_each(|v| expand_yaml_strings(v, source))?,
+        Value::Mapping(map) => map.values_mut().try_for_each(|v| expand_yaml_strings(v, source))?,
+        Value::Tagged(tagged) => expand_yaml_strings(&mut tagged.value, source)?,
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+/// 解析済みの TOML の文字列の値すべてに expand_env を適用する（キーとコメントはそのまま）
+fn expand_toml_strings(value: &mut toml::Value, source: &str) -> Result<()> {
+    use toml::Value;
+
+    match value {
+        Value::String(s) => *s = expand_env(s, source, |name| std::env::var(name).ok())?,
+        Value::Array(items) => items.iter_mut().try_for_each(|v| expand_toml_strings(v, source))?,
+        Value::Table(table) => table.iter_mut().try_for_each(|(_, v)| expand_toml_strings(v, source))?,
+        Value::Integer(_) | Value::Float(_) | Value::Boolean(_) | Value::Datetime(_) => {}
+    }
+    Ok(())
+}
+
+/// 解析済みの JSON の文字列の値すべてに expand_env を適用する（キーはそのまま）
+fn expand_json_strings(value: &mut serde_json::Value, source: &str) -> Result<()> {
+    use serde_json::Value;
+
+    match value {
+        Value::String(s) => *s = expand_env(s, source, |name| std::env::var(name).ok())?,
+        Value::Array(items) => items.iter_mut().try_for_each(|v| expand_json_strings(v, source))?,
+        Value::Object(map) => map.values_mut().try_for_each(|v| expand_json_strings(v, source))?,
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+/// `${VAR}` を環境変数の値に置き換える（未設定ならエラー）。`${VAR:-default}` は未設定か空のときに default を使う
+///
+/// 設定ファイルでは解析後の文字列の値ごとに適用するので、パス・URL・認証情報などどの文字列の値にも使える。
+/// `$${` は展開せずに `${` として残す。source はエラーメッセージに出す参照元（ファイル名）、
+/// lookup は変数名から値を引く関数（通常は環境変数）
+fn expand_env(text: &str, source: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        let after = &rest[start + 1..];
+        if let Some(escaped) = after.strip_prefix("${") {
+            out.push_str(&rest[..start]);
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        if !after.starts_with('{') {
+            out.push_str(&rest[..=start]);
+            rest = after;
+            continue;
+        }
+        let Some(len) = rest[start + 2..].find('}') else { break; };
+        let reference = &rest[start + 2..start + 2 + len];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        let value = match (lookup(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => default.to_string(),
+            (Some(value), _) => value,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => invalid!("{} で参照している環境変数 {} が設定されていません", source, name),
+        };
+        out.push_str(&rest[..start]);
+        out.push_str(&value);
+        rest = &rest[start + 3 + len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// 設定ファイルの形式エラーをパス付きの ConfigFormat にする
+fn format_error(path: &str, e: impl std::fmt::Display) -> LayeredNftError {
+    LayeredNftError::ConfigFormat { path: path.to_string(), message: e.to_string() }
 }
 
+/// overlay を base に深くマージする（merge_arrays が true なら配列は連結）
+fn merge_values(base: &mut serde_json::Value, overlay: serde_json::Value, merge_arrays: bool) {
+    use serde_json::Value;
+
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(mut overlay)) => {
+            let merge_arrays = match overlay.remove("_merge_arrays") {
+                Some(flag) => flag.as_bool().unwrap_or(false),
+                None => merge_arrays,
+            };
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value, merge_arrays),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(overlay)) if merge_arrays => base.extend(overlay),
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// `#` なしの6桁16進数か
+fn is_hex_color(s: &str) -> bool {
+    s.len() == 6 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// "#RRGGBB" / "#RRGGBBAA" をパース（`#` は省略可、アルファ省略時は不透明）
+fn parse_hex_rgba(s: &str) -> Option<[u8; 4]> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    let alpha = if hex.len() == 8 { byte(6)? } else { 255 };
+    Some([byte(0)?, byte(2)?, byte(4)?, alpha])
+}
 
-#[derive(Debug, Deserialize)]
+/// `Option<[u8; 4]>` を "#RRGGBBAA" 形式の文字列として読み書きする
+mod hex_rgba {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(color: &Option<[u8; 4]>, serializer: S) -> Result<S::Ok, S::Error> {
+        match color {
+            Some([r, g, b, a]) => serializer.serialize_str(&format!("#{:02X}{:02X}{:02X}{:02X}", r, g, b, a)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<[u8; 4]>, D::Error> {
+        let Some(s) = Option::<String>::deserialize(deserializer)? else { return Ok(None); };
+        super::parse_hex_rgba(&s)
+            .map(Some)
+            .ok_or_else(|| de::Error::custom(format!("色は \"#RRGGBB\" または \"#RRGGBBAA\" で指定してください: {:?}", s)))
+    }
+}
+
+/// 小文字化した拡張子を取得
+fn extension(path: &str) -> Option<String> {
+    Path::new(path)
+        .extension()
+        .and_then(|s| s.to_str())
+        .map(|s| s.to_ascii_lowercase())
+}
+
+
+/// 生成の設定（config.yaml）
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Config {
+    /// 生成するトークン数
     pub count: u32,
+    /// 画像・メタデータの出力先と画像の形式
     pub output: OutputConfig,
+    /// メタデータの名前・説明・画像 URL と出力形式
     pub metadata: MetadataConfig,
+    /// 下から順に重ねるレイヤー
     pub layers: Vec<LayerConfig>,
+    /// 禁則ルール・必須ペア・レア度ティアなどの制約
     pub constraints: Option<ConstraintsConfig>,
+    /// 乱数シード。指定すると同じ設定から同一の画像・メタデータを再現できる。
+    /// count を変えると重複回避のリトライ結果が変わり、既存トークンのパターンも変わることがある
+    pub seed: Option<u64>,
+    /// 指定すると、この件数ずつトークンを並列生成し、バッチごとにレイヤーキャッシュを解放する。
+    /// 未指定の場合は全トークンをまとめて並列生成する
+    pub batch_size: Option<usize>,
+    /// すべてのレイヤー画像（と output.target_width / target_height）に求める縦横比
+    pub required_aspect_ratio: Option<AspectRatio>,
+    /// 生成後に実行するシェルコマンド
+    pub hooks: Option<HooksConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct OutputConfig {
+    /// 画像の出力ディレクトリ
     pub image_dir: String,
+    /// メタデータ（{id}.json）の出力ディレクトリ
     pub metadata_dir: String,
+    /// PNG を oxipng で圧縮する
     pub png_compression: Option<PngCompressionConfig>,
+    /// 出力画像フォーマット（未指定 = png）
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// WebP の品質（0〜100、未指定 = 80）。静止画・アニメーションとも非可逆圧縮する
+    pub webp_quality: Option<f32>,
+    /// JPEG の品質（1〜100、未指定 = 85）。format = jpeg のときは png_compression を無視する
+    pub jpeg_quality: Option<u8>,
+    /// AVIF の品質（0〜100、未指定 = 80）。avif feature が必要
+    pub avif_quality: Option<u8>,
+    /// 合成後の画像が 256 色以下ならパレット形式の PNG で書き出す（format = png のみ。超える場合は RGBA のまま）。
+    /// png_compression の oxipng はパレット化の後に適用する
+    pub palette_quantize: Option<bool>,
+    /// アニメーション出力設定（指定時は各バリアントをフレーム列として扱う）
+    pub animation: Option<AnimationConfig>,
+    /// 生成後に画像とメタデータを1つの ZIP にまとめる
+    pub zip_output: Option<ZipOutputConfig>,
+    /// 使用済みパターンを保存する SQLite ファイル。--resume 時にメタデータを読み直さずに重複を判定できる
+    pub pattern_db: Option<String>,
+    /// `{id}.json` を置いたディレクトリ。あるトークンは生成したメタデータの代わりにそのファイルをそのまま使う。
+    /// `image` にファイルのパス（このディレクトリからの相対パス）を書くと、画像も合成せずにその画像を使う
+    pub metadata_overrides_dir: Option<String>,
+    /// 出力画像の幅。片方だけ指定した場合は縦横比を保って縮小・拡大する
+    pub target_width: Option<u32>,
+    /// 出力画像の高さ
+    pub target_height: Option<u32>,
+    /// リサイズに使うフィルタ（未指定 = lanczos3）
+    pub resize_filter: Option<ResizeFilter>,
+    /// フロントエンド表示用のサムネイル（メタデータからは参照しない）
+    pub thumbnail: Option<ThumbnailConfig>,
+    /// 同じ合成結果から形式・サイズを変えて追加で書き出す画像（Web 用の WebP など）。メタデータの image は format の画像を指す
+    pub additional_outputs: Option<Vec<AdditionalOutputConfig>>,
+    /// 全トークンに重ねるウォーターマーク（メタデータの attributes には含めない）
+    pub watermark: Option<WatermarkConfig>,
+    /// 全トークンの最前面にキャンバス全体を覆って重ねる枠（装飾用の縁取りなど）
+    pub frame: Option<FrameConfig>,
+    /// キャンバスの背景色（RGBA）。YAML では "#RRGGBB" または "#RRGGBBAA" で指定する
+    #[serde(default, with = "hex_rgba", skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub background_color: Option<[u8; 4]>,
+    /// 合成後の画像の色の置き換え（グレースケールのレイヤーから色違いのコレクションを作る場合など）
+    pub color_palette: Option<ColorPaletteConfig>,
+    /// true なら合成後の画像を輝度でグレースケールにする（合成・ブレンドはカラーのまま行う）
+    pub grayscale: Option<bool>,
+    /// 合成後の画像に重ねるガウスノイズ（フィルムグレインのような質感を出す）
+    pub noise: Option<NoiseConfig>,
+    /// 画像の出力パス（image_dir からの相対パス）。`{id}` `{id:05}` `{name}` `{layer:<名前>}` を展開する（未指定 = `{id}.{ext}`）
+    pub image_path_template: Option<String>,
+    /// メタデータの出力パス（metadata_dir からの相対パス、未指定 = `{id}.json`）
+    pub metadata_path_template: Option<String>,
+    /// パステンプレートの `{id}` をゼロ埋めする桁数（未指定 = ゼロ埋めしない）
+    pub id_width: Option<usize>,
+    /// 出力ファイルを一時ファイルに書いてからリネームする（未指定 = true）。異常終了しても書きかけのファイルが残らない
+    pub atomic_writes: Option<bool>,
+    /// 生成後に画像とメタデータを S3 互換のオブジェクトストレージへアップロードする（s3 feature）
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ColorPaletteConfig {
+    /// 上から順に判定し、画素ごとに最初に一致したものだけを適用する
+    pub swaps: Vec<ColorSwap>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ColorSwap {
+    /// 置き換える色（RGB）
+    pub from: [u8; 3],
+    /// 置き換え後の色（RGB）。α は元の画素のまま
+    pub to: [u8; 3],
+    /// from との RGB のユークリッド距離がこの値以内なら置き換える（未指定 = 0、完全一致のみ）
+    #[serde(default)]
+    pub tolerance: u8,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FrameConfig {
+    /// 出力画像と同じサイズ（リサイズする場合は合成時のサイズでもよい）の PNG
+    pub image_path: String,
+    /// 重ね方（未指定 = normal）
+    #[serde(default)]
+    pub blend_mode: BlendMode,
+    /// 0.0〜1.0（未指定 = 1.0）
+    pub opacity: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct NoiseConfig {
+    /// ノイズの強さ（0.0〜1.0）。ノイズの標準偏差は strength × 255
+    pub strength: f32,
+    /// ノイズの乱数のシード。トークンごとに `seed ^ token_id` を使う（未指定 = 全体の seed、どちらもなければ毎回ランダム）
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WatermarkConfig {
+    /// 重ねる PNG 画像
+    pub image_path: String,
+    /// 配置する位置
+    pub position: WatermarkPosition,
+    /// 0.0〜1.0
+    pub opacity: f32,
+    /// 画像の端からの余白（px、center では無視）
+    #[serde(default)]
+    pub margin: u32,
+}
+
+/// ウォーターマークの配置
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ThumbnailConfig {
+    /// 縦横比を保ったまま width x height に収まるよう縮小する
+    pub width: u32,
+    /// サムネイルの最大の高さ（px）
+    pub height: u32,
+    /// サムネイルの出力ディレクトリ
+    pub dir: String,
+    /// サムネイルの画像フォーマット（未指定 = png）
+    #[serde(default)]
+    pub format: ImageFormat,
+}
+
+/// 追加の出力画像。レイヤーの合成は1回だけ行い、その画像を `{dir}/{id}.{ext}` に書き出す
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AdditionalOutputConfig {
+    /// 画像フォーマット
+    pub format: ImageFormat,
+    /// 出力ディレクトリ
+    pub dir: String,
+    /// 幅（px）。片方だけ指定した場合は縦横比を保ち、両方指定した場合は縦横比を保ったまま width x height に収める（未指定 = 合成画像と同じ）
+    pub width: Option<u32>,
+    /// 高さ（px）
+    pub height: Option<u32>,
+    /// WebP / JPEG / AVIF の品質（1〜100、未指定 = output.webp_quality / jpeg_quality / avif_quality）。
+    /// PNG では使わない
+    pub quality: Option<u8>,
+}
+
+impl AdditionalOutputConfig {
+    /// 合成画像のサイズから、書き出すサイズを求める
+    pub fn size(&self, (src_w, src_h): (u32, u32)) -> (u32, u32) {
+        let scale = match (self.width, self.height) {
+            (Some(w), Some(h)) => (w as f64 / src_w as f64).min(h as f64 / src_h as f64),
+            (Some(w), None) => w as f64 / src_w as f64,
+            (None, Some(h)) => h as f64 / src_h as f64,
+            (None, None) => return (src_w, src_h),
+        };
+        let scaled = |n: u32| ((n as f64 * scale).round() as u32).max(1);
+        (scaled(src_w), scaled(src_h))
+    }
+}
+
+/// リサイズフィルタ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub fn filter_type(self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// S3 へのアップロード設定
+///
+/// 画像は `{prefix}/images/{id}.{ext}`、メタデータは `{prefix}/metadata/{id}.json` に置く。
+/// access_key_id / secret_access_key は `${AWS_SECRET_ACCESS_KEY}` のように環境変数で指定でき、
+/// 省略すると AWS_ACCESS_KEY_ID / AWS_SECRET_ACCESS_KEY を使う
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct S3Config {
+    /// バケット名
+    pub bucket: String,
+    /// リージョン（例: ap-northeast-1）
+    pub region: String,
+    /// キーの先頭に付けるパス（未指定 = バケット直下）
+    pub prefix: Option<String>,
+    /// アクセスキー ID（未指定 = 環境変数 AWS_ACCESS_KEY_ID）
+    pub access_key_id: Option<String>,
+    /// シークレットアクセスキー（未指定 = 環境変数 AWS_SECRET_ACCESS_KEY）
+    pub secret_access_key: Option<String>,
+    /// S3 互換ストレージ（MinIO, Cloudflare R2 など）のエンドポイント URL
+    pub endpoint: Option<String>,
+    /// 同時にアップロードするファイル数（未指定 = 16）
+    pub concurrency: Option<usize>,
+    /// アップロード後、メタデータの画像 URL を S3 の公開 URL に書き換えてからメタデータをアップロードする
+    #[serde(default)]
+    pub rewrite_image_url: bool,
+    /// 書き換えに使う公開 URL（CDN など）。未指定なら bucket と region（または endpoint）から組み立てる
+    pub public_url: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ZipOutputConfig {
+    /// ZIP ファイルの出力先
+    pub path: String,
+    /// 圧縮方式（未指定 = deflate）
+    #[serde(default)]
+    pub compression: ZipCompression,
+}
+
+/// ZIP の圧縮方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ZipCompression {
+    Stored,
+    #[default]
+    Deflate,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AspectRatio {
+    /// 比の横（例: 16:9 なら 16）
+    pub width: u32,
+    /// 比の縦（例: 16:9 なら 9）
+    pub height: u32,
+    /// 許容する縦横比の相対誤差（未指定 = 0.01）。端数の丸めで 1px ずれる程度は許す
+    pub tolerance: Option<f64>,
+}
+
+impl AspectRatio {
+    /// (幅, 高さ) がこの縦横比に tolerance の範囲で一致するか
+    pub fn matches(&self, (w, h): (u32, u32)) -> bool {
+        if h == 0 {
+            return false;
+        }
+        let expected = self.width as f64 / self.height as f64;
+        let actual = w as f64 / h as f64;
+        ((actual - expected) / expected).abs() <= self.tolerance.unwrap_or(DEFAULT_ASPECT_TOLERANCE)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct HooksConfig {
+    /// トークンごとに画像とメタデータを書き出した後に実行するコマンド（`{id}` は token_id に置換）。
+    /// 環境変数 NFT_TOKEN_ID / NFT_IMAGE_PATH / NFT_METADATA_PATH を渡す
+    pub post_token: Option<String>,
+    /// 全トークンの生成が終わった後に1回だけ実行するコマンド。
+    /// 環境変数 NFT_COUNT / NFT_IMAGE_DIR / NFT_METADATA_DIR を渡す
+    pub post_all: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AnimationConfig {
+    /// アニメーションの形式
+    pub format: AnimationFormat,
+    /// 1フレームの表示時間（ミリ秒）
+    pub frame_delay_ms: u32,
+    /// ループ回数（0 = 無限）
+    pub loop_count: u32,
+}
+
+/// アニメーション出力フォーマット
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AnimationFormat {
+    Gif,
+    Webp,
+}
+
+impl AnimationFormat {
+    /// 出力ファイルの拡張子
+    pub fn extension(self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "gif",
+            AnimationFormat::Webp => "webp",
+        }
+    }
+}
+
+/// 出力画像フォーマット
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Webp,
+    /// 透過なし。合成後にアルファチャンネルを捨てて保存する
+    Jpeg,
+    /// avif feature が必要
+    Avif,
+}
+
+impl ImageFormat {
+    /// 出力ファイルの拡張子
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Avif => "avif",
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct PngCompressionConfig {
+    /// true なら PNG を圧縮する
     pub enabled: bool,
+    /// 圧縮レベル（0〜6、大きいほど小さくなるが遅い）
     pub level: u8,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct MetadataConfig {
+    /// 画像 URL のベース（`{base_image_url}/{id}.{ext}`）
     pub base_image_url: String,
+    /// トークン名の接頭辞（`{name} #{id}`）
     pub name: String,
+    /// 全トークン共通の説明
     pub description: String,
+    /// external_url のテンプレート（`{id}` `{name}` `{trait:<type>}` をトークン ID・トークン名・その trait の値に置換）
+    pub external_url_template: Option<String>,
+    /// 背景色（`#` なしの6桁16進数）
+    pub background_color: Option<String>,
+    /// youtube_url のテンプレート（external_url_template と同じ変数を使える）
+    pub youtube_url_template: Option<String>,
+    /// animation_url のテンプレート（external_url_template と同じ変数に加え、アニメーション出力時は `{file}` がファイル名になる）
+    /// 未指定でアニメーションを出力する場合は画像と同じベース URL の `{id}.{ext}` になる
+    pub animation_url_template: Option<String>,
+    /// IPFS の CID 確定前に使うプレースホルダー（例: "ipfs://QmPLACEHOLDER"）
+    /// 指定時は image の URL に base_image_url の代わりに使い、後で patch-cid で置換する
+    pub ipfs_placeholder: Option<String>,
+    /// 出力するメタデータの形式（未指定 = erc721）
+    #[serde(default)]
+    pub format: MetadataFormat,
+    /// format = metaplex のときに使う設定
+    pub metaplex: Option<MetaplexConfig>,
+    /// format = erc1155 のときに使う設定（未指定時は decimals = 0）
+    pub erc1155: Option<Erc1155Config>,
+    /// コレクション単位のメタデータ（contractURI 用の collection.json を出力）
+    pub collection: Option<CollectionConfig>,
+    /// メタデータの最上位に追加する任意のフィールド（既存のキーは上書きしない）。
+    /// 文字列中の `{id}` と `{name}` はトークン ID とトークン名に置換される
+    pub extra_fields: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// contractURI で返すコレクションメタデータ
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CollectionConfig {
+    /// コレクション名
+    pub name: String,
+    /// コレクションの説明
+    pub description: String,
+    /// コレクション画像の URL
+    pub image: String,
+    /// コレクションの Web サイト
+    pub external_link: Option<String>,
+    /// 二次流通のロイヤリティ（1/100 %、500 = 5%）
+    pub seller_fee_basis_points: Option<u16>,
+    /// ロイヤリティの受取アドレス
+    pub fee_recipient: Option<String>,
+}
+
+/// メタデータの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataFormat {
+    /// OpenSea 互換の ERC-721 形式
+    #[default]
+    Erc721,
+    /// Solana / Metaplex 形式
+    Metaplex,
+    /// ERC-1155 形式
+    Erc1155,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Erc1155Config {
+    /// トークンの小数点以下の桁数（未指定 = 0）
+    #[serde(default)]
+    pub decimals: u8,
+    /// ロケール別メタデータの設定
+    pub localization: Option<LocalizationConfig>,
+}
+
+/// EIP-1155 の localization ブロック
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LocalizationConfig {
+    /// ロケール別 JSON の URI（`{locale}` を含む）
+    pub uri: String,
+    /// デフォルトのロケール
+    pub default: String,
+    /// 利用できるロケールの一覧
+    pub locales: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MetaplexConfig {
+    /// トークンのシンボル
+    pub symbol: String,
+    /// 二次流通のロイヤリティ（1/100 %）
+    pub seller_fee_basis_points: u16,
+    /// Metaplex のコレクション情報
+    pub collection: MetaplexCollectionConfig,
+    /// クリエイターと取り分
+    pub creators: Vec<MetaplexCreatorConfig>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MetaplexCollectionConfig {
+    /// コレクション名
+    pub name: String,
+    /// コレクションのファミリー名
+    pub family: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MetaplexCreatorConfig {
+    /// クリエイターのウォレットアドレス
+    pub address: String,
+    /// 取り分（全 creator の合計が 100）
+    pub share: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct LayerConfig {
+    /// トレイト名（メタデータの trait_type）
     pub name: String,
+    /// レイヤー画像のディレクトリ
     pub directory: String,
+    /// ファイル名 → 出現の重み（未指定のファイルは 1）。合計 1 の確率に正規化される
     pub rarity: Option<HashMap<String, f32>>,
+    /// ファイル名 → 出現率（%）。合計は 100 以下で、指定のないファイルには残りを均等に割り当てる（rarity とは併用できない）
+    pub rarity_percent: Option<HashMap<String, f32>>,
+    /// ファイル名の glob パターンで重みを指定するルール。上から順に照合して最初に一致したルールの重みを使う
+    /// （rarity に指定したファイルは rarity を優先し、どれにも一致しないファイルは重み 1）
+    pub rarity_glob: Option<Vec<RarityGlobRule>>,
+    /// レイヤーが出現する確率（0.0 = 出現しない, 1.0 = 必ず出現, 未指定 = 1.0）
+    pub optional_probability: Option<f64>,
+    /// 下のレイヤーへの重ね方（未指定 = normal）
+    pub blend_mode: Option<BlendMode>,
+    /// レイヤー全体の不透明度（0.0〜1.0、未指定 = 1.0）
+    pub opacity: Option<f32>,
+    /// ファイル名（拡張子なし）→ 数値トレイト値。該当ファイルは数値としてメタデータに出力される
+    pub numeric_traits: Option<HashMap<String, f64>>,
+    /// 数値トレイトの display_type（未指定 = "number"）
+    pub display_type: Option<String>,
+    /// ファイル名（拡張子なし）→ メタデータに出力するトレイト値（未指定のファイルはファイル名のまま）
+    pub display_names: Option<HashMap<String, String>>,
+    /// 合成前にレイヤー画像へ適用する拡大縮小・回転・平行移動
+    pub transform: Option<LayerTransform>,
+    /// 色相の回転量（度、-180〜180）
+    pub hue_shift: Option<f32>,
+    /// 彩度の倍率（1.0 = 変化なし）
+    pub saturation_scale: Option<f32>,
+    /// 明度の倍率（1.0 = 変化なし）
+    pub brightness_scale: Option<f32>,
+    /// グレースケールのマスク画像。白（255）の部分はそのまま表示し、黒（0）の部分は隠す（中間値は α に掛け合わせる）
+    pub mask_path: Option<String>,
+    /// ファイル名がグロブに一致するレイヤー画像の色を、読み込み時（キャッシュ前）に置き換える
+    pub color_replacements: Option<Vec<ColorReplacement>>,
+    /// 読み込み時（キャッシュ前）にレイヤー画像の RGB へ掛け合わせる色（RGBA、リニア空間で乗算し、α はそのまま）。
+    /// A は効きの強さ（255 = そのまま乗算、0 = 変化なし）。[255, 255, 255, 255] なら変化しない
+    pub tint: Option<[u8; 4]>,
+    /// 読み込み時（キャッシュ前）に RGB の各チャンネルへ足す値（-255〜255、0 = 変化なし）
+    pub brightness: Option<i16>,
+    /// 読み込み時（キャッシュ前）のコントラスト（-1.0〜1.0、0.0 = 変化なし、-1.0 で全面が中間の灰色）。
+    /// 明るさの調整の後に適用する
+    pub contrast: Option<f32>,
+    /// true なら directory 直下のサブディレクトリをグループとし、グループを選んでからその中のファイルを選ぶ。
+    /// rarity のキーにはサブディレクトリ名（グループの重み）とファイル名（グループ内の重み）を書ける
+    pub group_by_directory: Option<bool>,
+}
+
+/// レイヤー画像の色の置き換え（1つのファイルに複数一致した場合は上から順に適用する）
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+pub struct ColorReplacement {
+    /// 対象のファイル名（拡張子を含む）のグロブ（例: "*.png", "Red*"）。アニメーションではバリアントのディレクトリ名
+    pub filename_glob: String,
+    /// 置き換える色（RGBA）
+    pub from_rgba: [u8; 4],
+    /// 置き換え後の色（RGBA）
+    pub to_rgba: [u8; 4],
+    /// from_rgba との RGBA のユークリッド距離がこの値以内なら置き換える（未指定 = 0、完全一致のみ）
+    #[serde(default)]
+    pub tolerance: u8,
+}
+
+/// レイヤーの変形。キャンバスのサイズは変えず、中心を基準に変形してから同じサイズの土台に重ねる
+///
+/// 変形後の画像をキャッシュするため、キャッシュのキーにも使う
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct LayerTransform {
+    /// 右方向の移動量（px）
+    pub translate_x: Option<i32>,
+    /// 下方向の移動量（px）
+    pub translate_y: Option<i32>,
+    /// 拡大率（1.0 = 等倍）
+    pub scale: Option<f32>,
+    /// 時計回りの回転角（度）。バイリニア補間で回転する
+    pub rotate_degrees: Option<f32>,
 }
 
-#[derive(Debug, Deserialize)]
+impl PartialEq for LayerTransform {
+    fn eq(&self, other: &Self) -> bool {
+        self.translate_x == other.translate_x
+            && self.translate_y == other.translate_y
+            && self.scale.map(f32::to_bits) == other.scale.map(f32::to_bits)
+            && self.rotate_degrees.map(f32::to_bits) == other.rotate_degrees.map(f32::to_bits)
+    }
+}
+
+impl Eq for LayerTransform {}
+
+impl std::hash::Hash for LayerTransform {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.translate_x.hash(state);
+        self.translate_y.hash(state);
+        self.scale.map(f32::to_bits).hash(state);
+        self.rotate_degrees.map(f32::to_bits).hash(state);
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct ConstraintsConfig {
+    /// 同時に出現してはいけないトレイトの組み合わせ
     pub forbidden_pairs: Option<Vec<ForbiddenPair>>,
+    /// members がすべて同時に出現してはいけない3つ以上（2つでも可）のトレイトの組み合わせ
+    pub forbidden_groups: Option<Vec<ForbiddenGroup>>,
+    /// a があるなら b も必須とする組み合わせ（bidirectional = true なら逆方向も必須）。
+    /// 同じペアが forbidden_pairs にもある場合は矛盾として設定読み込み時にエラーにし、生成は開始しない
+    pub required_pairs: Option<Vec<RequiredPair>>,
+    /// すべてのトークンが必ず持つトレイト（条件付きの required_pairs と違い、常に必須）。
+    /// trait_type に一致するレイヤーがない場合は設定読み込み時にエラーにする
+    pub required_attributes: Option<Vec<TraitValue>>,
+    /// members のうち同時に出現できるのは1つまでのグループ。
+    /// 同じトレイトを複数のグループに入れることはできない
+    pub exclusive_groups: Option<Vec<ExclusiveGroup>>,
+    /// members のうち少なくとも1つが出現しなければならないグループ（exclusive_groups の逆）。
+    /// オプションレイヤーが出現しなかった場合、そのレイヤーのトレイトは出現したことにならない
+    pub required_group: Option<Vec<RequiredGroup>>,
+    /// condition のトレイトがある場合、then_layer の値を allowed_values（ファイル名の拡張子なし）に限定する
+    pub conditional_constraints: Option<Vec<ConditionalConstraint>>,
+    /// 生成しないパターン（trait_type → value）。指定したトレイトをすべて含むトークンは、
+    /// 他のレイヤーが何であっても除外される
+    pub excluded_patterns: Option<Vec<HashMap<String, String>>>,
+    /// excluded_patterns を別の YAML ファイルから読み込む（読み込み時に excluded_patterns に統合）。
+    /// 相対パスはこの設定が書かれた設定ファイルのディレクトリから解決する
+    pub excluded_patterns_file: Option<String>,
+    /// レア度ティア。required_traits をすべて持つトークンをコレクション全体で max_count 個までに制限する
+    pub tiers: Option<Vec<RarityTier>>,
+    /// トークンごとに切り替えるレイヤーの重ね順。probability の確率で order の順に重ね、
+    /// どのバリエーションも選ばれなかったトークンは layers の順に重ねる
+    pub z_order_variants: Option<Vec<ZOrderVariant>>,
+}
+
+/// rarity_glob の1ルール
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RarityGlobRule {
+    /// ファイル名（拡張子を含む）の glob パターン（例: `"*_legendary.png"`）
+    pub pattern: String,
+    /// 一致したファイルの重み
+    pub weight: f32,
+}
+
+/// レイヤーの重ね順のバリエーション
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ZOrderVariant {
+    /// このバリエーションが選ばれる確率（0.0〜1.0、全バリエーションの合計は 1.0 以下）
+    pub probability: f64,
+    /// 下から順に並べたレイヤー名（layers[].name をすべて1回ずつ含める）
+    pub order: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RequiredPair {
+    /// 条件側のトレイト
+    pub a: TraitValue,
+    /// a があるときに必須のトレイト
+    pub b: TraitValue,
+    /// true なら b があるときも a を必須にする
+    #[serde(default)]
+    pub bidirectional: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ExclusiveGroup {
+    /// グループ名
+    pub name: String,
+    /// 同時に1つまでしか出現できないトレイト
+    pub members: Vec<TraitValue>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RequiredGroup {
+    /// グループ名
+    pub name: String,
+    /// 少なくとも1つが出現しなければならないトレイト
+    pub members: Vec<TraitValue>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ConditionalConstraint {
+    /// このトレイトがある場合に制約を適用する
+    pub condition: TraitValue,
+    /// 値を限定するレイヤー名
+    pub then_layer: String,
+    /// then_layer に許す値（ファイル名の拡張子なし）
+    pub allowed_values: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RarityTier {
+    /// ティア名
+    pub name: String,
+    /// このティアに該当するトークンの上限数
+    pub max_count: u32,
+    /// ティアに該当する条件（すべてを持つトークンが該当する）
+    pub required_traits: Vec<TraitValue>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct ForbiddenPair {
+    /// 組み合わせの一方
     pub a: TraitValue,
+    /// 組み合わせのもう一方
     pub b: TraitValue,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ForbiddenGroup {
+    /// すべてが同時に出現してはいけないトレイト（2つ以上）
+    pub members: Vec<TraitValue>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct TraitValue {
+    /// レイヤー名
     pub trait_type: String,
+    /// 値（ファイル名の拡張子なし）
     pub value: String,
-}
\ No newline at end of file
+}
+
+impl OutputConfig {
+    /// 出力ディレクトリだけを指定し、その他は既定値で作成
+    pub fn new(image_dir: impl Into<String>, metadata_dir: impl Into<String>) -> Self {
+        Self {
+            image_dir: image_dir.into(),
+            metadata_dir: metadata_dir.into(),
+            ..Default::default()
+        }
+    }
+
+    /// 出力ファイルを一時ファイル経由で書くか（atomic_writes 未指定 = true）
+    pub fn writes_atomically(&self) -> bool {
+        self.atomic_writes.unwrap_or(true)
+    }
+
+    /// メタデータを既定の `{metadata_dir}/{id}.json` に書き出すか
+    /// （check や --resume など既存のメタデータを読む機能は、この場合だけ使える）
+    pub fn has_default_metadata_paths(&self) -> bool {
+        match &self.metadata_path_template {
+            None => true,
+            Some(template) => {
+                self.id_width.unwrap_or(0) == 0
+                    && PathTemplate::parse(template).ok() == PathTemplate::parse("{id}.json").ok()
+            }
+        }
+    }
+}
+
+impl LayerConfig {
+    /// rarity_percent を、実在するファイル名ごとの重み（%）に変換する（rarity_percent 未指定なら None）
+    ///
+    /// 指定のないファイルには 100 から指定分を引いた残りを均等に割り当てる。
+    /// すべてのファイルを指定していて合計が 100 に満たない場合はエラー
+    pub fn percent_weights<'a>(&self, file_names: impl IntoIterator<Item = &'a str>) -> Result<Option<HashMap<String, f32>>> {
+        let Some(percent) = &self.rarity_percent else { return Ok(None); };
+        let mut weights = HashMap::new();
+        let mut unlisted = Vec::new();
+        for name in file_names {
+            match percent.get(name) {
+                Some(p) => {
+                    weights.insert(name.to_string(), *p);
+                }
+                None => unlisted.push(name),
+            }
+        }
+        let remainder = (100.0 - weights.values().sum::<f32>()).max(0.0);
+        if unlisted.is_empty() {
+            if remainder > PERCENT_TOLERANCE {
+                invalid!(
+                    "レイヤー {:?} の rarity_percent の合計が 100 になりません（{}% 足りません）",
+                    self.name,
+                    remainder
+                );
+            }
+        } else {
+            let share = remainder / unlisted.len() as f32;
+            weights.extend(unlisted.into_iter().map(|name| (name.to_string(), share)));
+        }
+        Ok(Some(weights))
+    }
+
+    /// ファイル名に最初に一致した rarity_glob のルールの重み（一致するルールがなければ None）
+    pub fn glob_weight(&self, file_name: &str) -> Option<f32> {
+        self.rarity_glob
+            .iter()
+            .flatten()
+            .find(|rule| glob::Pattern::new(&rule.pattern).is_ok_and(|p| p.matches(file_name)))
+            .map(|rule| rule.weight)
+    }
+
+    /// rarity_glob のルールを、一致したファイルごとの rarity の重みに展開する（rarity に指定済みのファイルはそのまま）
+    pub fn expand_rarity_glob<'a>(&mut self, file_names: impl IntoIterator<Item = &'a str>) {
+        if self.rarity_glob.is_none() {
+            return;
+        }
+        for name in file_names {
+            if let Some(weight) = self.glob_weight(name) {
+                self.rarity.get_or_insert_with(HashMap::new).entry(name.to_string()).or_insert(weight);
+            }
+        }
+        self.rarity_glob = None;
+    }
+
+    /// ファイル名（拡張子なし）に対応する、メタデータに出力するトレイト値
+    pub fn display_name<'a>(&'a self, stem: &'a str) -> &'a str {
+        self.display_names.as_ref().and_then(|names| names.get(stem)).map_or(stem, String::as_str)
+    }
+}
+
+impl MetadataConfig {
+    pub fn new(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        base_image_url: impl Into<String>,
+    ) -> Self {
+        Self {
+            base_image_url: base_image_url.into(),
+            name: name.into(),
+            description: description.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl TraitValue {
+    pub fn new(trait_type: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            trait_type: trait_type.into(),
+            value: value.into(),
+        }
+    }
+}
+
+impl ForbiddenPair {
+    pub fn new(a: TraitValue, b: TraitValue) -> Self {
+        Self { a, b }
+    }
+}
+
+impl ForbiddenGroup {
+    pub fn new(members: Vec<TraitValue>) -> Self {
+        Self { members }
+    }
+}
+
+impl RequiredPair {
+    pub fn new(a: TraitValue, b: TraitValue, bidirectional: bool) -> Self {
+        Self { a, b, bidirectional }
+    }
+}
+
+/// プログラムから `Config` を組み立てるためのビルダー
+///
+/// ```
+/// use layered_nft_gen::config::{
+///     ConfigBuilder, ForbiddenPair, LayerConfigBuilder, MetadataConfig, OutputConfig, TraitValue,
+/// };
+///
+/// let cfg = ConfigBuilder::new()
+///     .count(10)
+///     .output(OutputConfig::new("output/images", "output/metadata"))
+///     .metadata(MetadataConfig::new("My Collection", "desc", "https://example.com/images"))
+///     .add_layer(
+///         LayerConfigBuilder::new("Background", "layers/Background")
+///             .rarity("Black.png", 1.0)
+///             .build(),
+///     )
+///     .add_layer(
+///         LayerConfigBuilder::new("Eyeball", "layers/Eyeball")
+///             .rarity("Red.png", 50.0)
+///             .rarity("White.png", 50.0)
+///             .build(),
+///     )
+///     .add_forbidden_pair(ForbiddenPair::new(
+///         TraitValue::new("Background", "Black"),
+///         TraitValue::new("Eyeball", "Red"),
+///     ))
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(cfg.count, 10);
+/// assert_eq!(cfg.layers.len(), 2);
+/// ```
+#[derive(Debug, Default)]
+pub struct ConfigBuilder {
+    count: Option<u32>,
+    output: Option<OutputConfig>,
+    metadata: Option<MetadataConfig>,
+    layers: Vec<LayerConfig>,
+    forbidden_pairs: Vec<ForbiddenPair>,
+    forbidden_groups: Vec<ForbiddenGroup>,
+    seed: Option<u64>,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(mut self, count: u32) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    pub fn output(mut self, output: OutputConfig) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    pub fn metadata(mut self, metadata: MetadataConfig) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn add_layer(mut self, layer: LayerConfig) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    pub fn add_forbidden_pair(mut self, pair: ForbiddenPair) -> Self {
+        self.forbidden_pairs.push(pair);
+        self
+    }
+
+    pub fn add_forbidden_group(mut self, group: ForbiddenGroup) -> Self {
+        self.forbidden_groups.push(group);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// テスト用: 同梱のサンプルレイヤー（`layers/{name}`）を順に重ねる1件分の設定
+    ///
+    /// レイヤーのパスはクレートのルートからの相対パスなので、cargo test の作業ディレクトリで使う
+    #[cfg(test)]
+    pub(crate) fn sample(layers: &[&str]) -> Self {
+        let builder = Self::new()
+            .count(1)
+            .output(OutputConfig::new("output/images", "output/metadata"))
+            .metadata(MetadataConfig::new("Test", "test", "https://example.com"));
+        layers.iter().fold(builder, |builder, name| {
+            builder.add_layer(LayerConfigBuilder::new(*name, format!("layers/{}", name)).build())
+        })
+    }
+
+    /// 必須項目（count, output, metadata, レイヤー1つ以上）が揃っていなければエラー
+    pub fn build(self) -> Result<Config> {
+        let Some(count) = self.count else { invalid!("count が指定されていません") };
+        let Some(output) = self.output else { invalid!("output が指定されていません") };
+        let Some(metadata) = self.metadata else { invalid!("metadata が指定されていません") };
+        if self.layers.is_empty() {
+            invalid!("レイヤーが1つも追加されていません");
+        }
+
+        let constraints = if self.forbidden_pairs.is_empty() && self.forbidden_groups.is_empty() {
+            None
+        } else {
+            Some(ConstraintsConfig {
+                forbidden_pairs: Some(self.forbidden_pairs).filter(|p| !p.is_empty()),
+                forbidden_groups: Some(self.forbidden_groups).filter(|g| !g.is_empty()),
+                ..Default::default()
+            })
+        };
+
+        Ok(Config {
+            count,
+            output,
+            metadata,
+            layers: self.layers,
+            constraints,
+            seed: self.seed,
+            batch_size: None,
+            required_aspect_ratio: None,
+            hooks: None,
+        })
+    }
+}
+
+/// `LayerConfig` のビルダー
+#[derive(Debug)]
+pub struct LayerConfigBuilder {
+    layer: LayerConfig,
+}
+
+impl LayerConfigBuilder {
+    pub fn new(name: impl Into<String>, directory: impl Into<String>) -> Self {
+        Self {
+            layer: LayerConfig {
+                name: name.into(),
+                directory: directory.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// ファイル名ごとのレア度の重みを追加
+    pub fn rarity(mut self, file_name: impl Into<String>, weight: f32) -> Self {
+        self.layer
+            .rarity
+            .get_or_insert_with(HashMap::new)
+            .insert(file_name.into(), weight);
+        self
+    }
+
+    /// ファイル名ごとの出現率（%）を追加
+    pub fn rarity_percent(mut self, file_name: impl Into<String>, percent: f32) -> Self {
+        self.layer
+            .rarity_percent
+            .get_or_insert_with(HashMap::new)
+            .insert(file_name.into(), percent);
+        self
+    }
+
+    /// glob パターンで重みを指定するルールを追加（先に追加したルールが優先）
+    pub fn rarity_glob(mut self, pattern: impl Into<String>, weight: f32) -> Self {
+        self.layer
+            .rarity_glob
+            .get_or_insert_with(Vec::new)
+            .push(RarityGlobRule { pattern: pattern.into(), weight });
+        self
+    }
+
+    pub fn optional_probability(mut self, probability: f64) -> Self {
+        self.layer.optional_probability = Some(probability);
+        self
+    }
+
+    pub fn blend_mode(mut self, mode: BlendMode) -> Self {
+        self.layer.blend_mode = Some(mode);
+        self
+    }
+
+    pub fn opacity(mut self, opacity: f32) -> Self {
+        self.layer.opacity = Some(opacity);
+        self
+    }
+
+    /// ファイル名（拡張子なし）に対応する数値トレイト値を追加
+    pub fn numeric_trait(mut self, file_stem: impl Into<String>, value: f64) -> Self {
+        self.layer
+            .numeric_traits
+            .get_or_insert_with(HashMap::new)
+            .insert(file_stem.into(), value);
+        self
+    }
+
+    pub fn display_type(mut self, display_type: impl Into<String>) -> Self {
+        self.layer.display_type = Some(display_type.into());
+        self
+    }
+
+    /// ファイル名（拡張子なし）に対応する、メタデータ上の表示名を追加
+    pub fn display_name(mut self, file_stem: impl Into<String>, name: impl Into<String>) -> Self {
+        self.layer
+            .display_names
+            .get_or_insert_with(HashMap::new)
+            .insert(file_stem.into(), name.into());
+        self
+    }
+
+    pub fn transform(mut self, transform: LayerTransform) -> Self {
+        self.layer.transform = Some(transform);
+        self
+    }
+
+    pub fn hue_shift(mut self, degrees: f32) -> Self {
+        self.layer.hue_shift = Some(degrees);
+        self
+    }
+
+    pub fn saturation_scale(mut self, scale: f32) -> Self {
+        self.layer.saturation_scale = Some(scale);
+        self
+    }
+
+    pub fn brightness_scale(mut self, scale: f32) -> Self {
+        self.layer.brightness_scale = Some(scale);
+        self
+    }
+
+    pub fn mask_path(mut self, path: impl Into<String>) -> Self {
+        self.layer.mask_path = Some(path.into());
+        self
+    }
+
+    pub fn tint(mut self, rgba: [u8; 4]) -> Self {
+        self.layer.tint = Some(rgba);
+        self
+    }
+
+    pub fn brightness(mut self, offset: i16) -> Self {
+        self.layer.brightness = Some(offset);
+        self
+    }
+
+    pub fn contrast(mut self, contrast: f32) -> Self {
+        self.layer.contrast = Some(contrast);
+        self
+    }
+
+    pub fn build(self) -> LayerConfig {
+        self.layer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_matches_json_schema() {
+        let schema = Config::json_schema();
+        assert_eq!(schema["$schema"], "http://json-schema.org/draft-07/schema#");
+        let validator = jsonschema::JSONSchema::compile(&schema).unwrap();
+
+        let text = fs::read_to_string("config.yaml").unwrap();
+        let mut example: serde_json::Value = serde_yaml::from_str(&text).unwrap();
+        let errors: Vec<String> = match validator.validate(&example) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.map(|e| format!("{}: {}", e.instance_path, e)).collect(),
+        };
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        example["layers"][0]["blend_mode"] = "dissolve".into();
+        assert!(!validator.is_valid(&example));
+    }
+
+    #[test]
+    fn multi_collection_config_is_detected_by_collections_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let collection = |name: &str, out: &str| {
+            format!(
+                "  - count: 1\n    output: {{ image_dir: \"{out}/images\", metadata_dir: \"{out}/metadata\" }}\n    \
+                 metadata: {{ name: \"{name}\", description: \"\", base_image_url: \"https://example.com\" }}\n    \
+                 layers: [{{ name: \"Eyeball\", directory: \"layers/Eyeball\" }}]\n"
+            )
+        };
+        let multi = dir.join("multi.yaml");
+        fs::write(&multi, format!("collections:\n{}{}", collection("A", "out/a"), collection("B", "out/b"))).unwrap();
+        let loaded = MultiCollectionConfig::load(multi.to_str().unwrap()).unwrap().unwrap();
+        assert_eq!(loaded.collections.len(), 2);
+        assert_eq!(loaded.collections[1].metadata.name, "B");
+
+        fs::write(&multi, format!("collections:\n{}{}", collection("A", "out/a"), collection("B", "out/a"))).unwrap();
+        assert!(MultiCollectionConfig::load(multi.to_str().unwrap()).is_err());
+        assert_eq!(MultiCollectionConfig::load("config.yaml").unwrap(), None);
+    }
+
+    #[test]
+    fn optional_probability_must_be_within_range() {
+        let with_probability = |p| {
+            ConfigBuilder::sample(&["Background"])
+                .add_layer(LayerConfigBuilder::new("Eyeball", "layers/Eyeball").optional_probability(p).build())
+                .build()
+                .unwrap()
+        };
+        assert!(with_probability(0.0).validate_values().is_ok());
+        assert!(with_probability(1.0).validate_values().is_ok());
+        assert!(with_probability(1.5).validate_values().is_err());
+        assert!(with_probability(-0.1).validate_values().is_err());
+        assert!(with_probability(f64::NAN).validate_values().is_err());
+    }
+
+    #[test]
+    fn required_attributes_need_a_matching_layer() {
+        let mut cfg = ConfigBuilder::sample(&["Eyeball"]).build().unwrap();
+        cfg.constraints = Some(ConstraintsConfig {
+            required_attributes: Some(vec![TraitValue::new("Eyeball", "Red"), TraitValue::new("Hat", "Crown")]),
+            ..Default::default()
+        });
+        let err = cfg.validate_values().unwrap_err();
+        assert!(err.to_string().contains("required_attributes[1]") && err.to_string().contains("\"Hat\""), "{}", err);
+
+        cfg.constraints.as_mut().unwrap().required_attributes.as_mut().unwrap().pop();
+        assert!(cfg.validate_values().is_ok());
+    }
+
+    #[test]
+    fn forbidden_groups_reject_short_or_duplicate_members() {
+        let with_group = |members: Vec<TraitValue>| {
+            ConfigBuilder::sample(&["Eyeball"])
+                .add_forbidden_group(ForbiddenGroup::new(members))
+                .build()
+                .unwrap()
+        };
+        let red = || TraitValue::new("Eyeball", "Red");
+        let white = || TraitValue::new("Eyeball", "White");
+        assert!(with_group(vec![red()]).validate_values().is_err());
+        assert!(with_group(vec![red(), white(), red()]).validate_values().is_err());
+        assert!(with_group(vec![red(), white(), TraitValue::new("Eye color", "Red")]).validate_values().is_ok());
+    }
+
+    #[test]
+    fn additional_output_size_keeps_aspect_ratio() {
+        let extra = |width, height| AdditionalOutputConfig {
+            format: ImageFormat::Jpeg,
+            dir: "out".to_string(),
+            width,
+            height,
+            quality: None,
+        };
+        assert_eq!(extra(None, None).size((1000, 500)), (1000, 500));
+        assert_eq!(extra(Some(600), None).size((1000, 500)), (600, 300));
+        assert_eq!(extra(None, Some(100)).size((1000, 500)), (200, 100));
+        assert_eq!(extra(Some(300), Some(300)).size((1000, 500)), (300, 150));
+    }
+
+    #[test]
+    fn rarity_glob_uses_first_match_and_yields_to_rarity() {
+        let mut layer = LayerConfigBuilder::new("Background", "layers/Background")
+            .rarity("gold_legendary.png", 0.5)
+            .rarity_glob("*_legendary.png", 0.1)
+            .rarity_glob("gold_*", 3.0)
+            .build();
+        assert_eq!(layer.glob_weight("gold_common.png"), Some(3.0));
+        assert_eq!(layer.glob_weight("blue.png"), None);
+
+        layer.expand_rarity_glob(["gold_legendary.png", "red_legendary.png", "gold_common.png", "blue.png"]);
+        let rarity = layer.rarity.unwrap();
+        assert_eq!(rarity["gold_legendary.png"], 0.5);
+        assert_eq!(rarity["red_legendary.png"], 0.1);
+        assert_eq!(rarity["gold_common.png"], 3.0);
+        assert!(!rarity.contains_key("blue.png"));
+        assert!(layer.rarity_glob.is_none());
+    }
+
+    #[test]
+    fn rarity_percent_distributes_remainder() {
+        let layer = LayerConfigBuilder::new("Eyeball", "layers/Eyeball").rarity_percent("Red.png", 70.0).build();
+        let weights = layer.percent_weights(["Red.png", "White.png", "Blue.png"]).unwrap().unwrap();
+        assert_eq!(weights["Red.png"], 70.0);
+        assert_eq!(weights["White.png"], 15.0);
+        assert_eq!(weights["Blue.png"], 15.0);
+        // すべてのファイルを指定して 100 に満たない場合はエラー
+        assert!(layer.percent_weights(["Red.png"]).is_err());
+
+        let over = LayerConfigBuilder::new("Eyeball", "layers/Eyeball")
+            .rarity_percent("Red.png", 70.0)
+            .rarity_percent("White.png", 40.0)
+            .build();
+        let both = LayerConfigBuilder::new("Eyeball", "layers/Eyeball")
+            .rarity("Red.png", 1.0)
+            .rarity_percent("Red.png", 100.0)
+            .build();
+        for layer in [over, both] {
+            let cfg = ConfigBuilder::sample(&[]).add_layer(layer).build().unwrap();
+            assert!(cfg.validate_values().is_err());
+        }
+    }
+
+    #[test]
+    fn expands_env_vars_with_defaults() {
+        let vars = HashMap::from([("KEY", "secret"), ("EMPTY", "")]);
+        let expand = |text: &str, source: &str| expand_env(text, source, |name| vars.get(name).map(|v| v.to_string()));
+        assert_eq!(expand("${KEY}", "f").unwrap(), "secret");
+        assert_eq!(expand("pre-${KEY}-post", "f").unwrap(), "pre-secret-post");
+        assert_eq!(expand("${KEY:-x}", "f").unwrap(), "secret");
+        assert_eq!(expand("${UNSET:-out/images}", "f").unwrap(), "out/images");
+        assert_eq!(expand("${EMPTY:-fallback}", "f").unwrap(), "fallback");
+        let err = expand("dir: ${UNSET}", "config.yaml").unwrap_err();
+        assert!(err.to_string().contains("UNSET"));
+        assert_eq!(expand("$${UNSET} costs $5", "f").unwrap(), "${UNSET} costs $5");
+    }
+
+    #[test]
+    fn env_vars_in_comments_are_not_expanded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        let text = fs::read_to_string("config.yaml").unwrap();
+        fs::write(&path, format!("# 例: ${{LAYERED_NFT_GEN_UNSET_VAR}}\n{}", text)).unwrap();
+        let config = Config::load(&path.to_string_lossy()).unwrap();
+        assert_eq!(config, Config::load("config.yaml").unwrap());
+    }
+
+    #[test]
+    fn yaml_json_round_trip() {
+        let yaml = Config::load("config.yaml").unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config.json");
+        let path = path.to_str().unwrap();
+        yaml.save_json(path).unwrap();
+        let json = Config::load(path).unwrap();
+
+        assert_eq!(yaml.count, json.count);
+        assert_eq!(yaml.output, json.output);
+        assert_eq!(yaml.metadata, json.metadata);
+        assert_eq!(yaml.layers.len(), json.layers.len());
+        for (a, b) in yaml.layers.iter().zip(&json.layers) {
+            assert_eq!(a, b);
+        }
+        assert_eq!(yaml.constraints, json.constraints);
+        assert_eq!(yaml, json);
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let cfg = Config::load("config.yaml").unwrap();
+        let seed = cfg.seed;
+        let resolved = cfg.resolve(&ConfigOverrides {
+            output_dir: Some("dist".to_string()),
+            count: Some(3),
+            seed: None,
+        });
+        assert_eq!(resolved.count, 3);
+        assert_eq!(resolved.seed, seed);
+        assert_eq!(resolved.output.image_dir, "dist/images");
+        assert_eq!(resolved.output.metadata_dir, "dist/metadata");
+    }
+
+    #[test]
+    fn excluded_patterns_file_is_relative_to_config_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("project");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("excluded.yaml"), "- { Eyeball: Red }\n").unwrap();
+        let config = r#"
+count: 1
+output: { image_dir: out/images, metadata_dir: out/metadata }
+metadata: { name: Test, description: test, base_image_url: "https://example.com" }
+layers:
+  - { name: Eyeball, directory: layers/Eyeball }
+constraints:
+  excluded_patterns_file: excluded.yaml
+"#;
+        fs::write(dir.join("config.yaml"), config).unwrap();
+        fs::write(dir.join("override.yaml"), "count: 2\n").unwrap();
+
+        let path = dir.join("config.yaml");
+        let excluded = |cfg: Config| cfg.constraints.unwrap().excluded_patterns.unwrap();
+        let expected = vec![HashMap::from([("Eyeball".to_string(), "Red".to_string())])];
+        assert_eq!(excluded(Config::load(path.to_str().unwrap()).unwrap()), expected);
+        let merged = Config::load_with_overrides(path.to_str().unwrap(), &[dir.join("override.yaml").to_str().unwrap()]);
+        assert_eq!(excluded(merged.unwrap()), expected);
+    }
+
+    #[test]
+    fn overrides_merge_into_base() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let base = dir.join("base.yaml");
+        let project = dir.join("project.yaml");
+        fs::write(
+            &base,
+            r#"
+count: 100
+output:
+  image_dir: out/images
+  metadata_dir: out/metadata
+  png_compression: { enabled: false, level: 2 }
+metadata: { name: Base, description: base, base_image_url: "https://example.com" }
+layers:
+  - { name: Background, directory: layers/Background }
+"#,
+        )
+        .unwrap();
+        fs::write(
+            &project,
+            r#"
+_merge_arrays: true
+count: 10
+output:
+  png_compression: { enabled: true }
+layers:
+  - { name: Eyeball, directory: layers/Eyeball }
+"#,
+        )
+        .unwrap();
+
+        let cfg =
+            Config::load_with_overrides(base.to_str().unwrap(), &[project.to_str().unwrap()]).unwrap();
+
+        assert_eq!(cfg.count, 10);
+        assert_eq!(cfg.output.image_dir, "out/images");
+        assert_eq!(
+            cfg.output.png_compression,
+            Some(PngCompressionConfig { enabled: true, level: 2 })
+        );
+        let names: Vec<&str> = cfg.layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["Background", "Eyeball"]);
+    }
+
+    #[test]
+    fn arrays_replaced_without_merge_flag() {
+        let mut base = serde_json::json!({"layers": [1, 2], "output": {"dir": "a"}});
+        merge_values(&mut base, serde_json::json!({"layers": [3]}), false);
+        assert_eq!(base, serde_json::json!({"layers": [3], "output": {"dir": "a"}}));
+    }
+
+    #[test]
+    fn rarity_normalized_to_probabilities() {
+        let mut cfg = ConfigBuilder::sample(&[])
+            .add_layer(
+                LayerConfigBuilder::new("Eyeball", "layers/Eyeball")
+                    .rarity("Red.png", 3.0)
+                    .rarity("White.png", 1.0)
+                    .build(),
+            )
+            .build()
+            .unwrap();
+        cfg.normalize_rarity();
+        let rarity = cfg.layers[0].rarity.as_ref().unwrap();
+        assert_eq!(rarity["Red.png"], 0.75);
+        assert_eq!(rarity["White.png"], 0.25);
+
+        cfg.layers[0].rarity.as_mut().unwrap().insert("Red.png".to_string(), 0.0);
+        assert!(cfg.validate_values().is_err());
+    }
+
+    #[test]
+    fn background_color_format() {
+        assert!(is_hex_color("00ff7F"));
+        assert!(!is_hex_color("#00ff7f"));
+        assert!(!is_hex_color("00ff7"));
+        assert!(!is_hex_color("00ff7g"));
+    }
+
+    #[test]
+    fn output_background_color_hex() {
+        assert_eq!(parse_hex_rgba("#FF000080"), Some([255, 0, 0, 128]));
+        assert_eq!(parse_hex_rgba("00ff00"), Some([0, 255, 0, 255]));
+        assert_eq!(parse_hex_rgba("#FF00"), None);
+
+        let output: OutputConfig = serde_yaml::from_str(
+            "image_dir: out/images\nmetadata_dir: out/metadata\nbackground_color: \"#10203040\"\n",
+        )
+        .unwrap();
+        assert_eq!(output.background_color, Some([0x10, 0x20, 0x30, 0x40]));
+        let json = serde_json::to_string(&output).unwrap();
+        assert!(json.contains("\"#10203040\""));
+    }
+
+    #[test]
+    fn builder_requires_fields() {
+        let err = ConfigBuilder::new()
+            .output(OutputConfig::new("out/images", "out/metadata"))
+            .metadata(MetadataConfig::new("name", "desc", "https://example.com"))
+            .add_layer(LayerConfigBuilder::new("Background", "layers/Background").build())
+            .build();
+        assert!(matches!(err, Err(LayeredNftError::InvalidConfig(_))));
+
+        let err = ConfigBuilder::new()
+            .count(1)
+            .output(OutputConfig::new("out/images", "out/metadata"))
+            .metadata(MetadataConfig::new("name", "desc", "https://example.com"))
+            .build();
+        assert!(matches!(err, Err(LayeredNftError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn conflicting_pairs_rejected() {
+        let mut cfg = ConfigBuilder::new()
+            .count(1)
+            .output(OutputConfig::new("out/images", "out/metadata"))
+            .metadata(MetadataConfig::new("name", "desc", "https://example.com"))
+            .add_layer(LayerConfigBuilder::new("Background", "layers/Background").build())
+            .add_forbidden_pair(ForbiddenPair::new(
+                TraitValue::new("Background", "Black"),
+                TraitValue::new("Eyeball", "Red"),
+            ))
+            .build()
+            .unwrap();
+        assert!(cfg.validate_values().is_ok());
+
+        cfg.constraints.as_mut().unwrap().required_pairs = Some(vec![RequiredPair::new(
+            TraitValue::new("Eyeball", "Red"),
+            TraitValue::new("Background", "Black"),
+            false,
+        )]);
+        assert!(cfg.validate_values().is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::config::{
+    ConditionalConstraint, ConstraintsConfig, ExclusiveGroup, ForbiddenGroup, ForbiddenPair, RequiredGroup,
+    RequiredPair, TraitValue,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// トークンが持つ (trait_type, value) の集合
+pub type TraitSet = HashSet<(String, String)>;
+
+/// constraints に書かれた個々のルール
+#[derive(Debug, Clone, Copy)]
+pub enum Constraint<'a> {
+    Forbidden(usize, &'a ForbiddenPair),
+    ForbiddenGroup(usize, &'a ForbiddenGroup),
+    Required(usize, &'a RequiredPair),
+    RequiredAttribute(usize, &'a TraitValue),
+    Conditional(usize, &'a ConditionalConstraint),
+    Excluded(usize, &'a HashMap<String, String>),
+    Exclusive(usize, &'a ExclusiveGroup),
+    RequiredGroup(usize, &'a RequiredGroup),
+}
+
+/// 設定に書かれた順（forbidden → forbidden_groups → required → required_attributes → conditional → excluded → exclusive
+/// → required_group）でルールを列挙する
+pub fn constraints(c: &ConstraintsConfig) -> Vec<Constraint<'_>> {
+    let mut rules = Vec::new();
+    rules.extend(c.forbidden_pairs.iter().flatten().enumerate().map(|(i, p)| Constraint::Forbidden(i, p)));
+    rules.extend(c.forbidden_groups.iter().flatten().enumerate().map(|(i, g)| Constraint::ForbiddenGroup(i, g)));
+    rules.extend(c.required_pairs.iter().flatten().enumerate().map(|(i, p)| Constraint::Required(i, p)));
+    rules.extend(
+        c.required_attributes
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, t)| Constraint::RequiredAttribute(i, t)),
+    );
+    rules.extend(
+        c.conditional_constraints
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, cc)| Constraint::Conditional(i, cc)),
+    );
+    rules.extend(c.excluded_patterns.iter().flatten().enumerate().map(|(i, p)| Constraint::Excluded(i, p)));
+    rules.extend(c.exclusive_groups.iter().flatten().enumerate().map(|(i, g)| Constraint::Exclusive(i, g)));
+    rules.extend(c.required_group.iter().flatten().enumerate().map(|(i, g)| Constraint::RequiredGroup(i, g)));
+    rules
+}
+
+fn has(present: &TraitSet, t: &TraitValue) -> bool {
+    present.contains(&(t.trait_type.clone(), t.value.clone()))
+}
+
+impl Constraint<'_> {
+    /// このルールに違反しているか
+    pub fn violated(&self, present: &TraitSet) -> bool {
+        match self {
+            Constraint::Forbidden(_, p) => has(present, &p.a) && has(present, &p.b),
+            Constraint::ForbiddenGroup(_, g) => g.members.iter().all(|m| has(present, m)),
+            Constraint::Required(_, p) => {
+                let (a, b) = (has(present, &p.a), has(present, &p.b));
+                (a && !b) || (p.bidirectional && b && !a)
+            }
+            Constraint::RequiredAttribute(_, t) => !has(present, t),
+            Constraint::Conditional(_, cc) => {
+                // then_layer が出現しなかった（オプションレイヤー）場合は制約なし
+                has(present, &cc.condition)
+                    && present
+                        .iter()
+                        .any(|(t, v)| *t == cc.then_layer && !cc.allowed_values.contains(v))
+            }
+            Constraint::Excluded(_, pattern) => pattern
+                .iter()
+                .all(|(t, v)| present.contains(&(t.clone(), v.clone()))),
+            Constraint::Exclusive(_, g) => g.members.iter().filter(|m| has(present, m)).count() > 1,
+            Constraint::RequiredGroup(_, g) => !g.members.iter().any(|m| has(present, m)),
+        }
+    }
+}
+
+impl fmt::Display for Constraint<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constraint::Forbidden(i, p) => write!(
+                f,
+                "forbidden_pairs[{}] ({}/{} + {}/{})",
+                i, p.a.trait_type, p.a.value, p.b.trait_type, p.b.value
+            ),
+            Constraint::ForbiddenGroup(i, g) => {
+                let members: Vec<String> = g.members.iter().map(|m| format!("{}/{}", m.trait_type, m.value)).collect();
+                write!(f, "forbidden_groups[{}] ({})", i, members.join(" + "))
+            }
+            Constraint::Required(i, p) => write!(
+                f,
+                "required_pairs[{}] ({}/{} {} {}/{})",
+                i,
+                p.a.trait_type,
+                p.a.value,
+                if p.bidirectional { "<->" } else { "->" },
+                p.b.trait_type,
+                p.b.value
+            ),
+            Constraint::RequiredAttribute(i, t) => {
+                write!(f, "required_attributes[{}] ({}/{})", i, t.trait_type, t.value)
+            }
+            Constraint::Conditional(i, cc) => write!(
+                f,
+                "conditional_constraints[{}] ({}/{} -> {})",
+                i, cc.condition.trait_type, cc.condition.value, cc.then_layer
+            ),
+            Constraint::Excluded(i, pattern) => {
+                let mut traits: Vec<String> = pattern.iter().map(|(t, v)| format!("{}/{}", t, v)).collect();
+                traits.sort();
+                write!(f, "excluded_patterns[{}] ({})", i, traits.join(" + "))
+            }
+            Constraint::Exclusive(i, g) => write!(f, "exclusive_groups[{}] ({})", i, g.name),
+            Constraint::RequiredGroup(i, g) => write!(f, "required_group[{}] ({})", i, g.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn present(traits: &[(&str, &str)]) -> TraitSet {
+        traits.iter().map(|(t, v)| (t.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn rules_report_violations() {
+        let c = ConstraintsConfig {
+            forbidden_pairs: Some(vec![ForbiddenPair::new(
+                TraitValue::new("Eyeball", "Red"),
+                TraitValue::new("Eye color", "Red"),
+            )]),
+            required_pairs: Some(vec![RequiredPair::new(
+                TraitValue::new("Hat", "Crown"),
+                TraitValue::new("Background", "Gold"),
+                false,
+            )]),
+            required_attributes: Some(vec![TraitValue::new("Collection", "Genesis")]),
+            ..Default::default()
+        };
+        let rules = constraints(&c);
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].to_string(), "forbidden_pairs[0] (Eyeball/Red + Eye color/Red)");
+
+        let red = present(&[("Eyeball", "Red"), ("Eye color", "Red")]);
+        assert!(rules[0].violated(&red));
+        assert!(!rules[1].violated(&red));
+
+        let crown = present(&[("Hat", "Crown"), ("Background", "Black")]);
+        assert!(rules[1].violated(&crown));
+
+        // required_attributes は他のトレイトに関係なく常に必須
+        assert_eq!(rules[2].to_string(), "required_attributes[0] (Collection/Genesis)");
+        assert!(rules[2].violated(&red));
+        assert!(!rules[2].violated(&present(&[("Collection", "Genesis"), ("Eyeball", "Red")])));
+    }
+
+    #[test]
+    fn forbidden_groups_need_every_member() {
+        let group = |members: &[(&str, &str)]| {
+            ForbiddenGroup::new(members.iter().map(|(t, v)| TraitValue::new(*t, *v)).collect())
+        };
+        let c = ConstraintsConfig {
+            forbidden_groups: Some(vec![
+                group(&[("Hat", "Crown"), ("Eyes", "Laser"), ("Background", "Gold")]),
+                group(&[("Hat", "Cap"), ("Eyes", "Sleepy"), ("Mouth", "Pipe"), ("Background", "Night")]),
+            ]),
+            ..Default::default()
+        };
+        let rules = constraints(&c);
+        assert_eq!(rules[0].to_string(), "forbidden_groups[0] (Hat/Crown + Eyes/Laser + Background/Gold)");
+
+        let triplet = present(&[("Hat", "Crown"), ("Eyes", "Laser"), ("Background", "Gold")]);
+        assert!(rules[0].violated(&triplet));
+        let two_of_three = present(&[("Hat", "Crown"), ("Eyes", "Laser"), ("Background", "Night")]);
+        assert!(!rules[0].violated(&two_of_three));
+
+        let four = present(&[("Hat", "Cap"), ("Eyes", "Sleepy"), ("Mouth", "Pipe"), ("Background", "Night")]);
+        assert!(rules[1].violated(&four));
+        let three_of_four = present(&[("Hat", "Cap"), ("Eyes", "Sleepy"), ("Mouth", "Smile"), ("Background", "Night")]);
+        assert!(!rules[1].violated(&three_of_four));
+    }
+}
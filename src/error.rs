@@ -0,0 +1,175 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// ライブラリ関数が返すエラー
+///
+/// 呼び出し側が種類ごとに処理を分けられるよう、原因ごとにバリアントを分けている。
+/// 元になったエラーは Display に含めず `source()` で返すので、バイナリ側では anyhow に
+/// 変換すると「Caused by:」として表示される。
+/// 今後バリアントを追加しても互換性を壊さないよう `#[non_exhaustive]` にしている。
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LayeredNftError {
+    /// 設定ファイルの YAML を解析できない
+    Config(serde_yaml::Error),
+    /// TOML / JSON の設定ファイルや、別ファイルに分けたリストを解析できない
+    ConfigFormat { path: String, message: String },
+    /// 設定値が不正（読み込み時の形式チェック・ConfigBuilder::build）
+    InvalidConfig(String),
+    /// レイヤーディレクトリの走査・読み込みに失敗した
+    LayerIo { layer: String, source: io::Error },
+    /// 重ねるレイヤー画像のサイズが土台と一致しない
+    SizeMismatch { path: PathBuf, expected: (u32, u32), actual: (u32, u32) },
+    /// リトライ上限までに一意なパターンを見つけられなかったトークン ID
+    NoUniquePattern(u32),
+    /// 合成画像が生成済みのトークンと同一になったトークン ID
+    DuplicateImage(u32),
+    /// 制約に違反している
+    ConstraintViolation(String),
+    /// ファイルの読み書きに失敗した
+    Io { path: PathBuf, source: io::Error },
+    /// 画像のデコード・エンコードに失敗した
+    Image { path: PathBuf, source: image::ImageError },
+    /// JSON の読み書きに失敗した
+    Json { path: PathBuf, source: serde_json::Error },
+    /// SVG を解析・描画できない
+    Svg { path: PathBuf, message: String },
+    /// 画像エンコーダが失敗した（WebP など image クレート以外のもの）
+    Encode(String),
+    /// ZIP アーカイブの作成に失敗した
+    Archive(zip::result::ZipError),
+    /// CSV の書き出しに失敗した
+    Csv(csv::Error),
+    /// パターン DB の操作に失敗した
+    #[cfg(feature = "sqlite")]
+    PatternDb(rusqlite::Error),
+    /// オブジェクトストレージへのアップロードに失敗した
+    Upload { key: String, message: String },
+    /// ファイルの監視（--watch）に失敗した
+    #[cfg(feature = "watch")]
+    Watch(notify::Error),
+    /// 設定で使っている機能の feature が無効
+    FeatureDisabled { feature: &'static str, setting: String },
+}
+
+pub type Result<T> = std::result::Result<T, LayeredNftError>;
+
+impl LayeredNftError {
+    /// `map_err(LayeredNftError::io(path))` のように、パス付きの I/O エラーに変換する
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Self {
+        let path = path.into();
+        move |source| LayeredNftError::Io { path, source }
+    }
+
+    /// パス付きの画像エラーに変換する
+    pub fn image(path: impl Into<PathBuf>) -> impl FnOnce(image::ImageError) -> Self {
+        let path = path.into();
+        move |source| LayeredNftError::Image { path, source }
+    }
+
+    /// パス付きの JSON エラーに変換する
+    pub fn json(path: impl Into<PathBuf>) -> impl FnOnce(serde_json::Error) -> Self {
+        let path = path.into();
+        move |source| LayeredNftError::Json { path, source }
+    }
+}
+
+impl fmt::Display for LayeredNftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayeredNftError::Config(_) => f.write_str("設定ファイルの YAML を解析できません"),
+            LayeredNftError::ConfigFormat { path, message } => {
+                write!(f, "設定ファイルを解析できません: {}: {}", path, message)
+            }
+            LayeredNftError::InvalidConfig(message) => f.write_str(message),
+            LayeredNftError::LayerIo { layer, .. } => write!(f, "レイヤー {:?} の読み込みに失敗しました", layer),
+            LayeredNftError::SizeMismatch { path, expected, actual } => write!(
+                f,
+                "レイヤーのサイズが一致しません: {:?} ({}, {}) != base ({}, {})",
+                path, actual.0, actual.1, expected.0, expected.1
+            ),
+            LayeredNftError::NoUniquePattern(token_id) => write!(
+                f,
+                "トークン #{} で一意なパターンを見つけられませんでした（MAX_RETRY超過）。\
+                 count が組み合わせ数ギリギリか、rarity 設定が極端な可能性があります。",
+                token_id
+            ),
+            LayeredNftError::DuplicateImage(token_id) => write!(
+                f,
+                "トークン #{} の合成画像が生成済みのトークンと同一です（SHA-256 一致）",
+                token_id
+            ),
+            LayeredNftError::ConstraintViolation(message) => write!(f, "制約に違反しています: {}", message),
+            LayeredNftError::Io { path, .. } => write!(f, "ファイルの読み書きに失敗しました: {:?}", path),
+            LayeredNftError::Image { path, .. } => write!(f, "画像の読み書きに失敗しました: {:?}", path),
+            LayeredNftError::Json { path, .. } => write!(f, "JSON の読み書きに失敗しました: {:?}", path),
+            LayeredNftError::Svg { path, message } => write!(f, "{}: {:?}", message, path),
+            LayeredNftError::Encode(message) => f.write_str(message),
+            LayeredNftError::Archive(_) => f.write_str("ZIP アーカイブの作成に失敗しました"),
+            LayeredNftError::Csv(_) => f.write_str("CSV の書き出しに失敗しました"),
+            #[cfg(feature = "sqlite")]
+            LayeredNftError::PatternDb(_) => f.write_str("パターン DB の操作に失敗しました"),
+            LayeredNftError::Upload { key, message } => {
+                write!(f, "アップロードに失敗しました: {}: {}", key, message)
+            }
+            #[cfg(feature = "watch")]
+            LayeredNftError::Watch(_) => f.write_str("ファイルの監視に失敗しました"),
+            LayeredNftError::FeatureDisabled { feature, setting } => write!(
+                f,
+                "{} が指定されていますが、{} feature が無効です。`cargo build --features {}` でビルドしてください。",
+                setting, feature, feature
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayeredNftError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LayeredNftError::Config(e) => Some(e),
+            LayeredNftError::LayerIo { source, .. } | LayeredNftError::Io { source, .. } => Some(source),
+            LayeredNftError::Image { source, .. } => Some(source),
+            LayeredNftError::Json { source, .. } => Some(source),
+            LayeredNftError::Archive(e) => Some(e),
+            LayeredNftError::Csv(e) => Some(e),
+            #[cfg(feature = "sqlite")]
+            LayeredNftError::PatternDb(e) => Some(e),
+            #[cfg(feature = "watch")]
+            LayeredNftError::Watch(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for LayeredNftError {
+    fn from(e: serde_yaml::Error) -> Self {
+        LayeredNftError::Config(e)
+    }
+}
+
+impl From<zip::result::ZipError> for LayeredNftError {
+    fn from(e: zip::result::ZipError) -> Self {
+        LayeredNftError::Archive(e)
+    }
+}
+
+impl From<csv::Error> for LayeredNftError {
+    fn from(e: csv::Error) -> Self {
+        LayeredNftError::Csv(e)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for LayeredNftError {
+    fn from(e: rusqlite::Error) -> Self {
+        LayeredNftError::PatternDb(e)
+    }
+}
+
+#[cfg(feature = "watch")]
+impl From<notify::Error> for LayeredNftError {
+    fn from(e: notify::Error) -> Self {
+        LayeredNftError::Watch(e)
+    }
+}
@@ -0,0 +1,1991 @@
+use crate::blend::{alpha_over_rgba, apply_mask, apply_opacity, blend_rgba, BlendMode};
+use crate::cache::LayerCache;
+use crate::color::{add_noise, adjust_hsv, remap_palette, to_grayscale, HsvAdjustment, LoadAdjustment};
+use crate::config::{
+    ColorPaletteConfig, ColorReplacement, Config, FrameConfig, Erc1155Config, LayerConfig, LayerTransform, MetadataConfig, MetadataFormat, RarityTier, WatermarkConfig,
+    WatermarkPosition,
+};
+use crate::constraints::{constraints, TraitSet};
+use crate::error::{LayeredNftError, Result};
+use crate::metadata::{
+    merge_extra_fields, Attribute, AttributeValue, Erc1155Metadata, MetaplexMetadata, NftMetadata,
+    TokenMetadata, UrlTemplate,
+};
+use crate::path_template::PathTemplate;
+use crate::pattern_store::PatternStore;
+use crate::svg::{is_svg, svg_size};
+
+use image::RgbaImage;
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info_span, warn, Span};
+use walkdir::WalkDir;
+
+/// パターンキー上で「レイヤーなし」を表すセンチネル
+pub const ABSENT_LAYER_KEY: &str = "<none>";
+
+/// バリアント（ディレクトリ）→ フレーム列
+pub type FrameMap = HashMap<PathBuf, Vec<PathBuf>>;
+
+/// 1トークン生成時に選ばれたレイヤー1枚分
+#[derive(Debug, Clone)]
+pub struct LayerChoice {
+    pub path: PathBuf,
+    pub trait_type: String,
+    /// ファイル名（拡張子なし）。パターンキーや制約の判定にはこちらを使う
+    pub value: String,
+    /// メタデータに出力する値（display_names で指定されていればその表示名、なければ value と同じ）
+    pub display_value: String,
+    pub blend_mode: BlendMode,
+    pub opacity: f32,
+    /// アニメーション時のフレーム列（静止レイヤーは空）
+    pub frames: Vec<PathBuf>,
+    /// 数値トレイトとして出力する場合の (値, display_type)
+    pub numeric: Option<(f64, String)>,
+    pub transform: Option<LayerTransform>,
+    /// 合成前に適用する色相・彩度・明度の調整
+    pub hsv: Option<HsvAdjustment>,
+    /// α に掛け合わせるマスク画像
+    pub mask: Option<PathBuf>,
+    /// 読み込み時に適用する色の置き換え（ファイル名が filename_glob に一致したもの）
+    pub color_replacements: Option<Arc<[ColorReplacement]>>,
+    /// 読み込み時に適用する tint・明るさ・コントラスト
+    pub adjustment: LoadAdjustment,
+}
+
+/// 各レイヤー種別の候補一覧
+#[derive(Debug, Clone)]
+pub struct LayerCandidate {
+    pub layer: LayerConfig,
+    pub files: Vec<PathBuf>,
+    /// アニメーション時、バリアント（ディレクトリ）ごとのフレーム列
+    pub frames: FrameMap,
+    /// ファイル（バリアント）ごとに適用する色の置き換え
+    pub color_replacements: HashMap<PathBuf, Arc<[ColorReplacement]>>,
+    /// group_by_directory のレイヤーで、files と同じ順に並べた各ファイルの出現確率
+    /// （グループの確率 × グループ内での確率）。None なら rarity の重みで選ぶ
+    pub file_weights: Option<Vec<f64>>,
+}
+
+/// 禁則・ティア上限・重複を通過して選ばれたレイヤーの組み合わせ
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub layers: Vec<LayerChoice>,
+    /// 組み合わせを一意に表すキー（重複判定・パターン DB に使う）
+    pub key: String,
+}
+
+/// ディスクに書き出す前の、生成済みトークン1つ分
+#[derive(Debug, Clone)]
+pub struct GeneratedToken {
+    pub token_id: u32,
+    /// 合成済みの画像（アニメーション時は最初のフレーム）
+    pub image: RgbaImage,
+    /// アニメーション時の全フレーム（先頭は image と同じ）。静止画のときは空
+    pub frames: Vec<RgbaImage>,
+    pub metadata: NftMetadata,
+    pub layers: Vec<LayerChoice>,
+    pub pattern_key: String,
+}
+
+/// output.metadata_overrides_dir から読み込んだ、トークン1つ分の手書きのメタデータ
+#[derive(Debug, Clone)]
+pub struct MetadataOverride {
+    /// そのまま出力するメタデータ（image は出力先の URL に置き換える）
+    pub metadata: NftMetadata,
+    /// 合成の代わりに使う画像（差し替え用 JSON に image があった場合）
+    pub image: Option<PathBuf>,
+}
+
+/// seed 指定時に逐次で決めておく、トークンごとの生成内容
+enum Planned {
+    /// 選んだパターンで合成する（同一画像になった場合に選び直すための乱数の続きと、メタデータを差し替える場合はその内容）
+    Pattern(Pattern, Box<StdRng>, Option<NftMetadata>),
+    /// 合成せずに差し替え用の画像を使う
+    Override(NftMetadata, PathBuf),
+}
+
+/// seed 指定時に並列で合成した結果
+enum Composed {
+    /// 合成したフレーム（同一画像の判定は settle で行う）
+    Pattern(Pattern, Box<StdRng>, Option<NftMetadata>, Vec<RgbaImage>),
+    /// 差し替え用の画像を使ったトークン
+    Override(GeneratedToken),
+}
+
+/// 同一画像になった場合にパターンを選び直す回数の上限
+const MAX_COLLISION_RETRY: u32 = 10;
+
+/// generate_all の結果
+#[derive(Debug)]
+pub struct GenerationReport {
+    /// 生成できたトークン（token_id 順）
+    pub tokens: Vec<GeneratedToken>,
+    /// 生成に失敗したトークン ID とエラー（token_id 順）
+    pub failures: Vec<(u32, LayeredNftError)>,
+    /// 合成画像が生成済みのトークンと同一になった回数
+    pub hash_collisions: usize,
+}
+
+/// 並列生成中にワーカー間で共有する一意性・ティアの状態
+struct GenerationState {
+    /// 確認と登録を不可分に行うので、外側でロックせずに共有する
+    used_patterns: PatternStore,
+    /// ティア名 → そのティアに該当するトークンの生成数
+    tier_counts: Mutex<HashMap<String, u32>>,
+    /// 合成済み画像の SHA-256。異なるファイルの組み合わせでも同一画像になるケースを検出する
+    image_hashes: Mutex<HashSet<[u8; 32]>>,
+    hash_collisions: AtomicUsize,
+    /// output.grayscale 時に、最初の合成画像がすでにグレースケールかを確かめたか
+    grayscale_checked: AtomicBool,
+}
+
+/// 設定から NFT を生成する（ファイルへの書き出しは行わない）
+///
+/// 生成前の準備（レイヤーディレクトリの走査、rarity の正規化、画像サイズや組み合わせ数の検証）は
+/// `new` で済ませておき、`generate_token` / `generate_all` は合成した画像とメタデータをメモリ上で返す。
+/// 使用済みパターンは Generator が保持するので、同じ Generator から生成したトークン同士は重複しない。
+///
+/// ```no_run
+/// use layered_nft_gen::config::Config;
+/// use layered_nft_gen::generator::Generator;
+///
+/// let generator = Generator::new(Config::load("config.yaml")?)?;
+/// let token = generator.generate_token(1)?;
+/// token.image.save("1.png").unwrap();
+/// # Ok::<(), layered_nft_gen::error::LayeredNftError>(())
+/// ```
+pub struct Generator {
+    cfg: Config,
+    candidates: Vec<LayerCandidate>,
+    /// constraints.z_order_variants の (確率, その重ね順に並べ替えた candidates)
+    z_orders: Vec<(f64, Vec<LayerCandidate>)>,
+    frame_count: usize,
+    /// 最下層のレイヤー画像のサイズ
+    source_size: (u32, u32),
+    /// 出力画像のサイズ
+    canvas_size: (u32, u32),
+    /// SVG レイヤーをラスタライズするサイズ
+    svg_canvas: (u32, u32),
+    total_combinations: u128,
+    /// output.image_path_template / metadata_path_template を解析したもの
+    image_path_template: Option<PathTemplate>,
+    metadata_path_template: Option<PathTemplate>,
+    /// metadata の external_url / youtube_url / animation_url のテンプレートを解析したもの
+    url_templates: UrlTemplates,
+    cache: LayerCache,
+    /// output.watermark の画像
+    watermark: Option<RgbaImage>,
+    /// output.frame の画像（出力サイズにリサイズし、opacity を適用済み）
+    frame: Option<RgbaImage>,
+    state: GenerationState,
+}
+
+impl Generator {
+    /// レイヤーディレクトリを走査して生成の準備をする
+    ///
+    /// rarity は実在するファイルに揃えてから正規化する。output.pattern_db があれば開く。
+    pub fn new(mut cfg: Config) -> Result<Self> {
+        let mut layer_files = Vec::with_capacity(cfg.layers.len());
+        for layer in &cfg.layers {
+            let dir_path = Path::new(&layer.directory);
+            let (files, frames) = if cfg.output.animation.is_some() {
+                collect_animation_variants(dir_path)
+            } else {
+                collect_layer_files(dir_path).map(|files| (files, HashMap::new()))
+            }
+            .map_err(|source| LayeredNftError::LayerIo { layer: layer.name.clone(), source })?;
+
+            if files.is_empty() {
+                return Err(LayeredNftError::InvalidConfig(format!(
+                    "レイヤー {:?} ({:?}) に PNG / SVG ファイルがありません",
+                    layer.name, dir_path
+                )));
+            }
+
+            layer_files.push((files, frames));
+        }
+        if layer_files.is_empty() {
+            return Err(LayeredNftError::InvalidConfig("layers が1つも指定されていません".to_string()));
+        }
+
+        // 重みを実在するファイルだけに揃えてから確率に正規化する（未指定のファイルは重み 1）
+        // rarity_percent は指定のないファイルに残りを割り当てた重みとして、
+        // rarity_glob は一致したファイルごとの重みとして rarity に置き換える
+        for (layer, (files, _)) in cfg.layers.iter_mut().zip(&layer_files) {
+            let names = || files.iter().filter_map(|path| path.file_name().and_then(|s| s.to_str()));
+            layer.expand_rarity_glob(names());
+            if let Some(weights) = layer.percent_weights(names())? {
+                layer.rarity = Some(weights);
+                layer.rarity_percent = None;
+            }
+            complete_rarity(layer, files);
+        }
+        cfg.normalize_rarity();
+
+        let candidates = cfg
+            .layers
+            .iter()
+            .zip(layer_files)
+            .map(|(layer, (files, frames))| {
+                let color_replacements = color_replacements_by_file(layer, &files)?;
+                let file_weights = match layer.group_by_directory {
+                    Some(true) => Some(grouped_file_weights(layer, &files)?),
+                    _ => None,
+                };
+                Ok(LayerCandidate { layer: layer.clone(), files, frames, color_replacements, file_weights })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let z_orders = cfg
+            .constraints
+            .iter()
+            .flat_map(|c| c.z_order_variants.iter().flatten())
+            .map(|variant| {
+                let ordered = variant
+                    .order
+                    .iter()
+                    .filter_map(|name| candidates.iter().find(|c| c.layer.name == *name).cloned())
+                    .collect();
+                (variant.probability, ordered)
+            })
+            .collect();
+
+        let frame_count = if cfg.output.animation.is_some() {
+            validate_animation_frames(&candidates)?
+        } else {
+            1
+        };
+
+        // アニメーションのバリアント（ディレクトリ）は最初のフレームでサイズを調べる
+        let candidate = &candidates[0];
+        let first = candidate
+            .frames
+            .get(&candidate.files[0])
+            .and_then(|seq| seq.first())
+            .unwrap_or(&candidate.files[0]);
+        let source_size = layer_dimensions(first)?;
+        let canvas_size = target_size(&cfg, source_size.0, source_size.1);
+
+        // SVG は起動時にすべて解析しておき、壊れたファイルがあれば生成前にエラーにする
+        for candidate in &candidates {
+            for path in candidate.files.iter().chain(candidate.frames.values().flatten()) {
+                if is_svg(path) {
+                    svg_size(path)?;
+                }
+            }
+        }
+        // SVG は合成キャンバスのサイズでラスタライズする。
+        // 最下層が SVG なら出力サイズで直接描画し（拡大にならない）、PNG なら PNG のサイズに合わせる
+        let svg_canvas = if is_svg(first) { canvas_size } else { source_size };
+
+        let watermark = match &cfg.output.watermark {
+            Some(wm) => {
+                let mark = image::open(&wm.image_path).map_err(LayeredNftError::image(&wm.image_path))?.to_rgba8();
+                let (wm_w, wm_h) = mark.dimensions();
+                if wm_w + wm.margin > canvas_size.0 || wm_h + wm.margin > canvas_size.1 {
+                    return Err(LayeredNftError::InvalidConfig(format!(
+                        "ウォーターマーク {}x{}（margin {}）が出力画像 {}x{} に収まりません: {}",
+                        wm_w, wm_h, wm.margin, canvas_size.0, canvas_size.1, wm.image_path
+                    )));
+                }
+                Some(mark)
+            }
+            None => None,
+        };
+
+        let frame = cfg.output.frame.as_ref().map(|f| load_frame(&cfg, f, source_size, canvas_size)).transpose()?;
+
+        let total_combinations = combination_count(&candidates);
+        if cfg.count as u128 > total_combinations {
+            return Err(LayeredNftError::InvalidConfig(format!(
+                "要求された生成数 {} は理論上の最大組み合わせ数 {} を超えています。\
+                 レイヤーのバリエーションを増やすか、count を減らしてください。",
+                cfg.count, total_combinations
+            )));
+        }
+
+        let image_path_template = cfg.output.image_path_template.as_deref().map(PathTemplate::parse).transpose()?;
+        let metadata_path_template =
+            cfg.output.metadata_path_template.as_deref().map(PathTemplate::parse).transpose()?;
+        let url_template = |t: &Option<String>| t.as_deref().map(UrlTemplate::parse).transpose();
+        let url_templates = UrlTemplates {
+            external_url: url_template(&cfg.metadata.external_url_template)?,
+            youtube_url: url_template(&cfg.metadata.youtube_url_template)?,
+            animation_url: url_template(&cfg.metadata.animation_url_template)?,
+        };
+
+        let state = GenerationState {
+            used_patterns: PatternStore::open(cfg.output.pattern_db.as_deref())?,
+            tier_counts: Mutex::new(HashMap::new()),
+            image_hashes: Mutex::new(HashSet::new()),
+            hash_collisions: AtomicUsize::new(0),
+            grayscale_checked: AtomicBool::new(false),
+        };
+
+        Ok(Self {
+            cfg,
+            candidates,
+            z_orders,
+            frame_count,
+            source_size,
+            canvas_size,
+            svg_canvas,
+            total_combinations,
+            image_path_template,
+            metadata_path_template,
+            url_templates,
+            cache: LayerCache::new(true).with_svg_size(svg_canvas.0, svg_canvas.1),
+            watermark,
+            frame,
+            state,
+        })
+    }
+
+    /// レイヤー画像のキャッシュの有無を切り替える（デフォルトは有効。無効にすると毎回ディスクから読み込む）
+    pub fn with_cache(mut self, enabled: bool) -> Self {
+        self.cache = LayerCache::new(enabled).with_svg_size(self.svg_canvas.0, self.svg_canvas.1);
+        self
+    }
+
+    /// rarity を正規化した後の設定
+    pub fn config(&self) -> &Config {
+        &self.cfg
+    }
+
+    /// レイヤーごとの候補ファイル（cfg.layers と同じ順）
+    pub fn candidates(&self) -> &[LayerCandidate] {
+        &self.candidates
+    }
+
+    /// 1トークンあたりのフレーム数（アニメーションでなければ 1）
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// 最下層のレイヤー画像のサイズ
+    pub fn source_size(&self) -> (u32, u32) {
+        self.source_size
+    }
+
+    /// 出力画像のサイズ
+    pub fn canvas_size(&self) -> (u32, u32) {
+        self.canvas_size
+    }
+
+    /// 合成した画像を出力サイズに拡大することになるか（最下層が SVG なら出力サイズで描画するので拡大しない）
+    pub fn upscales(&self) -> bool {
+        self.svg_canvas == self.source_size
+            && (self.canvas_size.0 > self.source_size.0 || self.canvas_size.1 > self.source_size.1)
+    }
+
+    /// 理論上の最大組み合わせ数
+    pub fn total_combinations(&self) -> u128 {
+        self.total_combinations
+    }
+
+    /// パターン DB がファイルに保存されるか
+    pub fn is_persistent(&self) -> bool {
+        self.state.used_patterns.is_persistent()
+    }
+
+    /// 使用済みパターンを（パターン DB も含めて）すべて消す
+    pub fn clear_patterns(&self) -> Result<()> {
+        self.state.used_patterns.clear()
+    }
+
+    /// 生成済みのパターンを使用済みとして登録し、ティアの生成数に加える（--resume 用）
+    /// すでに使用済みだった場合は false を返す
+    pub fn reserve(&self, key: &str, present: &TraitSet) -> Result<bool> {
+        let mut counts = self.state.tier_counts.lock().expect("tier_counts のロックに失敗しました");
+        let is_new = self.state.used_patterns.insert(key)?;
+        for tier in matching_tiers(&self.cfg, present) {
+            *counts.entry(tier.name.clone()).or_insert(0) += 1;
+        }
+        Ok(is_new)
+    }
+
+    /// 合成画像が生成済みのトークンと同一になった回数
+    pub fn hash_collisions(&self) -> usize {
+        self.state.hash_collisions.load(Ordering::Relaxed)
+    }
+
+    /// レイヤー画像のキャッシュを解放する
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// トークンごとの乱数生成器（seed 指定時は token_id から決定的に導出）
+    pub fn token_rng(&self, token_id: u32) -> StdRng {
+        match self.cfg.seed {
+            Some(seed) => {
+                let per_token_seed = seed ^ (token_id as u64).wrapping_mul(6364136223846793005);
+                StdRng::seed_from_u64(per_token_seed)
+            }
+            None => StdRng::from_rng(thread_rng()).expect("乱数生成器の初期化に失敗しました"),
+        }
+    }
+
+    /// output.noise のガウスノイズを frames に順に重ねる（未設定なら何もしない）
+    ///
+    /// `noise.seed ^ token_id`（noise.seed 未指定なら全体の seed）をシードにするので、同じ設定なら同じノイズになり、
+    /// トークンごとに模様は異なる。どちらの seed も未指定なら毎回ランダム
+    pub fn apply_noise(&self, token_id: u32, frames: &mut [RgbaImage]) {
+        let Some(noise) = self.cfg.output.noise else { return; };
+        let mut rng = match noise.seed.or(self.cfg.seed) {
+            Some(seed) => StdRng::seed_from_u64(seed ^ token_id as u64),
+            None => StdRng::from_rng(thread_rng()).expect("乱数生成器の初期化に失敗しました"),
+        };
+        for frame in frames {
+            add_noise(frame, noise.strength, &mut rng);
+        }
+    }
+
+    /// 1トークン分を生成する（画像が既存のトークンと同一になった場合はパターンを選び直す）
+    pub fn generate_token(&self, token_id: u32) -> Result<GeneratedToken> {
+        let overridden = match self.metadata_override(token_id)? {
+            Some(MetadataOverride { metadata, image: Some(image) }) => {
+                return self.render_override(token_id, metadata, &image);
+            }
+            overridden => overridden.map(|o| o.metadata),
+        };
+        let mut rng = self.token_rng(token_id);
+        let pattern = self.choose_pattern(token_id, &mut rng)?;
+        let frames = self.compose_frames(&pattern.layers);
+        let (pattern, frames) = self.settle(token_id, pattern, frames, &mut rng)?;
+        Ok(self.with_metadata(self.finish(token_id, pattern, frames), overridden))
+    }
+
+    /// 合成した frames を登録する。既存のトークンと同一の画像になった場合は、rng（token_rng の続き）で
+    /// パターンを選び直して合成し直す（最大 MAX_COLLISION_RETRY 回）
+    ///
+    /// 使わなかったパターンは使用済みから外し、ティアの枠も返す
+    fn settle(
+        &self,
+        token_id: u32,
+        mut pattern: Pattern,
+        mut frames: Result<Vec<RgbaImage>>,
+        rng: &mut StdRng,
+    ) -> Result<(Pattern, Vec<RgbaImage>)> {
+        let mut collisions = 0;
+        loop {
+            let registered = frames.and_then(|frames| self.register_image(token_id, &frames).map(|()| frames));
+            let err = match registered {
+                Ok(frames) => return Ok((pattern, frames)),
+                Err(err) => err,
+            };
+            self.release_pattern(&pattern)?;
+            match err {
+                LayeredNftError::DuplicateImage(_) if collisions < MAX_COLLISION_RETRY => {
+                    collisions += 1;
+                    debug!("画像ハッシュが衝突したため、パターンを選び直します（{} 回目）", collisions);
+                    pattern = self.choose_pattern(token_id, rng)?;
+                    frames = self.compose_frames(&pattern.layers);
+                }
+                err => return Err(err),
+            }
+        }
+    }
+
+    /// choose_pattern で登録したパターンを使用済みから外し、ティアの生成数を戻す（トークンを書き出さなかった場合）
+    pub fn release_pattern(&self, pattern: &Pattern) -> Result<()> {
+        let tiers = matching_tiers(&self.cfg, &present_traits(&pattern.layers));
+        if !tiers.is_empty() {
+            let mut counts = self.state.tier_counts.lock().expect("tier_counts のロックに失敗しました");
+            for t in &tiers {
+                if let Some(count) = counts.get_mut(&t.name) {
+                    *count = count.saturating_sub(1);
+                }
+            }
+        }
+        self.state.used_patterns.remove(&pattern.key)
+    }
+
+    /// 1〜count のトークンをすべて生成してメモリ上に集める
+    pub fn generate_all(&self) -> Result<GenerationReport> {
+        let token_ids: Vec<u32> = (1..=self.cfg.count).collect();
+        let results = Mutex::new(Vec::with_capacity(token_ids.len()));
+        self.for_each_token(&token_ids, |token_id, result| {
+            results.lock().expect("results のロックに失敗しました").push((token_id, result));
+        });
+
+        let mut results = results.into_inner().expect("results のロックに失敗しました");
+        results.sort_unstable_by_key(|(token_id, _)| *token_id);
+        let mut report = GenerationReport {
+            tokens: Vec::with_capacity(results.len()),
+            failures: Vec::new(),
+            hash_collisions: self.hash_collisions(),
+        };
+        for (token_id, result) in results {
+            match result {
+                Ok(token) => report.tokens.push(token),
+                Err(err) => report.failures.push((token_id, err)),
+            }
+        }
+        Ok(report)
+    }
+
+    /// 1〜count のトークンを1つずつ生成するイテレータ
+    ///
+    /// 次の要素を要求されたときに初めて合成するので、生成したトークンをすぐにアップロードするなど、
+    /// コレクション全体の完成を待たずに流し込める。`rayon::iter::ParallelBridge` で並列にもできる
+    /// （その場合、seed を指定してもトークン ID とパターンの対応は実行ごとに変わりうる）。
+    pub fn iter(&self) -> GeneratorIter<'_> {
+        GeneratorIter { generator: self, next_id: 1, end: self.cfg.count }
+    }
+
+    /// token_ids を並列に生成し、トークンごとの結果を f に渡す
+    ///
+    /// seed 指定時はパターン選択をトークン ID 順に逐次で行い、
+    /// 並列実行の順序によって結果が変わらないようにする（合成だけを並列に行い、同一画像になった場合の選び直しも逐次で行う）。
+    pub fn for_each_token<F>(&self, token_ids: &[u32], f: F)
+    where
+        F: Fn(u32, Result<GeneratedToken>) + Sync,
+    {
+        // rayon のワーカースレッドでもトークンのスパンを呼び出し側（コレクションなど）のスパンの下に置く
+        let parent = Span::current();
+        if self.cfg.seed.is_some() {
+            let mut selections = Vec::with_capacity(token_ids.len());
+            for &token_id in token_ids {
+                let planned = self.metadata_override(token_id).and_then(|overridden| match overridden {
+                    Some(MetadataOverride { metadata, image: Some(image) }) => Ok(Planned::Override(metadata, image)),
+                    overridden => {
+                        let mut rng = self.token_rng(token_id);
+                        let pattern = self.choose_pattern(token_id, &mut rng)?;
+                        Ok(Planned::Pattern(pattern, Box::new(rng), overridden.map(|o| o.metadata)))
+                    }
+                });
+                match planned {
+                    Ok(planned) => selections.push((token_id, planned)),
+                    Err(err) => {
+                        let _span = info_span!(parent: &parent, "token", token_id).entered();
+                        f(token_id, Err(err));
+                    }
+                }
+            }
+
+            // 合成は並列に行い、同一画像の判定と選び直しはトークン ID 順に逐次で行う（どのトークンが選び直すかを
+            // 実行順に左右されないようにする）。画像をすべてメモリに持たないよう、一定数ずつ処理する
+            let batch_size = rayon::current_num_threads().max(1) * 4;
+            let mut selections = selections.into_iter().peekable();
+            while selections.peek().is_some() {
+                let batch: Vec<_> = selections.by_ref().take(batch_size).collect();
+                let composed: Vec<(u32, Result<Composed>)> = batch
+                    .into_par_iter()
+                    .map(|(token_id, planned)| {
+                        let _span = info_span!(parent: &parent, "token", token_id).entered();
+                        let composed = match planned {
+                            Planned::Pattern(pattern, rng, overridden) => match self.compose_frames(&pattern.layers) {
+                                Ok(frames) => Ok(Composed::Pattern(pattern, rng, overridden, frames)),
+                                Err(err) => self.release_pattern(&pattern).and(Err(err)),
+                            },
+                            Planned::Override(metadata, image) => {
+                                self.render_override(token_id, metadata, &image).map(Composed::Override)
+                            }
+                        };
+                        (token_id, composed)
+                    })
+                    .collect();
+                let settled: Vec<(u32, Result<Composed>)> = composed
+                    .into_iter()
+                    .map(|(token_id, composed)| {
+                        let _span = info_span!(parent: &parent, "token", token_id).entered();
+                        let settled = composed.and_then(|composed| match composed {
+                            Composed::Pattern(pattern, mut rng, overridden, frames) => self
+                                .settle(token_id, pattern, Ok(frames), &mut rng)
+                                .map(|(pattern, frames)| Composed::Pattern(pattern, rng, overridden, frames)),
+                            Composed::Override(token) => Ok(Composed::Override(token)),
+                        });
+                        (token_id, settled)
+                    })
+                    .collect();
+                settled.into_par_iter().for_each(|(token_id, settled)| {
+                    let _span = info_span!(parent: &parent, "token", token_id).entered();
+                    let result = settled.map(|settled| match settled {
+                        Composed::Pattern(pattern, _, overridden, frames) => {
+                            self.with_metadata(self.finish(token_id, pattern, frames), overridden)
+                        }
+                        Composed::Override(token) => token,
+                    });
+                    f(token_id, result);
+                });
+            }
+        } else {
+            token_ids.par_iter().copied().for_each(|token_id| {
+                // 結果を受け取った側のログもトークンごとのスパンに入れる
+                let _span = info_span!(parent: &parent, "token", token_id).entered();
+                f(token_id, self.generate_token(token_id));
+            });
+        }
+    }
+
+    /// 禁則ルール・ティア上限・重複を避けてレイヤーの組み合わせを選び、使用済みとして登録する
+    pub fn choose_pattern(&self, token_id: u32, rng: &mut StdRng) -> Result<Pattern> {
+        const MAX_RETRY: u32 = 1000;
+
+        for _attempt in 0..MAX_RETRY {
+            let chosen_layers = roll_layers(self.roll_z_order(rng), rng);
+
+            if chosen_layers.is_empty() || violates_constraints(&self.cfg, &chosen_layers) {
+                continue;
+            }
+
+            let key = build_pattern_key(&self.candidates, &chosen_layers);
+            let present = present_traits(&chosen_layers);
+            let tiers = matching_tiers(&self.cfg, &present);
+
+            let store = &self.state.used_patterns;
+            if store.contains_cached(&key) {
+                continue;
+            }
+
+            // ティア上限の確認と加算は、パターン登録と同じロック区間内で行う。
+            // ティアに該当しない組み合わせは tier_counts のロックを取らずに登録する
+            let mut counts = if tiers.is_empty() {
+                None
+            } else {
+                let counts = self.state.tier_counts.lock().expect("tier_counts のロックに失敗しました");
+                let over_cap = tiers
+                    .iter()
+                    .any(|t| counts.get(&t.name).copied().unwrap_or(0) >= t.max_count);
+                if over_cap {
+                    continue;
+                }
+                Some(counts)
+            };
+
+            // DB がある場合は以前の実行で使われたパターンもここで弾かれる
+            if !store.insert(&key)? {
+                continue;
+            }
+            if let Some(counts) = counts.as_mut() {
+                for t in &tiers {
+                    *counts.entry(t.name.clone()).or_insert(0) += 1;
+                }
+            }
+            return Ok(Pattern { layers: chosen_layers, key });
+        }
+
+        Err(LayeredNftError::NoUniquePattern(token_id))
+    }
+
+    /// z_order_variants から今回の重ね順を選び、その順に並べた候補を返す（どれも選ばれなければ layers の順）
+    fn roll_z_order(&self, rng: &mut StdRng) -> &[LayerCandidate] {
+        if self.z_orders.is_empty() {
+            return &self.candidates;
+        }
+        let mut roll: f64 = rng.gen_range(0.0..1.0);
+        for (probability, candidates) in &self.z_orders {
+            if roll < *probability {
+                return candidates;
+            }
+            roll -= probability;
+        }
+        &self.candidates
+    }
+
+    /// 選ばれたパターンの全フレームを合成し、メタデータを組み立てる
+    /// 合成画像が生成済みのトークンと同一なら DuplicateImage を返す（失敗した場合はパターンを使用済みから外す）
+    pub fn render(&self, token_id: u32, pattern: Pattern) -> Result<GeneratedToken> {
+        let registered = self
+            .compose_frames(&pattern.layers)
+            .and_then(|frames| self.register_image(token_id, &frames).map(|()| frames));
+        match registered {
+            Ok(frames) => Ok(self.finish(token_id, pattern, frames)),
+            Err(err) => {
+                self.release_pattern(&pattern)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// 全フレームを合成する
+    fn compose_frames(&self, layers: &[LayerChoice]) -> Result<Vec<RgbaImage>> {
+        (0..self.frame_count).map(|frame| self.compose(layers, frame)).collect()
+    }
+
+    /// 合成済みの画像を登録する（既存のトークンと同一の画像なら DuplicateImage）
+    fn register_image(&self, token_id: u32, frames: &[RgbaImage]) -> Result<()> {
+        let is_new = self
+            .state
+            .image_hashes
+            .lock()
+            .expect("image_hashes のロックに失敗しました")
+            .insert(image_hash(frames));
+        if !is_new {
+            self.state.hash_collisions.fetch_add(1, Ordering::Relaxed);
+            return Err(LayeredNftError::DuplicateImage(token_id));
+        }
+        Ok(())
+    }
+
+    /// 登録済みの frames にノイズ・ウォーターマーク・枠を重ね、メタデータを付けてトークンにする
+    fn finish(&self, token_id: u32, pattern: Pattern, mut frames: Vec<RgbaImage>) -> GeneratedToken {
+        // 重複の判定はノイズを重ねる前の画像で行う（ノイズがあると同じ組み合わせでも別の画像になるため）
+        self.decorate(token_id, &mut frames);
+
+        let image = if self.cfg.output.animation.is_some() { frames[0].clone() } else { frames.remove(0) };
+        let metadata = self.metadata(token_id, &pattern.layers);
+        GeneratedToken { token_id, image, frames, metadata, layers: pattern.layers, pattern_key: pattern.key }
+    }
+
+    /// output.metadata_overrides_dir に `{token_id}.json` があるか
+    pub fn has_metadata_override(&self, token_id: u32) -> bool {
+        self.cfg
+            .output
+            .metadata_overrides_dir
+            .as_ref()
+            .is_some_and(|dir| Path::new(dir).join(format!("{}.json", token_id)).is_file())
+    }
+
+    /// output.metadata_overrides_dir に `{token_id}.json` があれば読み込む（なければ None）
+    ///
+    /// NftMetadata として読めなければエラーにする。image は省略でき、書かれていれば
+    /// 差し替え用ディレクトリからの相対パスとして扱う
+    pub fn metadata_override(&self, token_id: u32) -> Result<Option<MetadataOverride>> {
+        let Some(dir) = &self.cfg.output.metadata_overrides_dir else { return Ok(None); };
+        if !self.has_metadata_override(token_id) {
+            return Ok(None);
+        }
+        let path = Path::new(dir).join(format!("{}.json", token_id));
+        let text = fs::read_to_string(&path).map_err(LayeredNftError::io(&path))?;
+        let mut json: serde_json::Value = serde_json::from_str(&text).map_err(LayeredNftError::json(&path))?;
+        let image = match json.get("image") {
+            Some(serde_json::Value::String(image)) if !image.is_empty() => Some(Path::new(dir).join(image)),
+            _ => None,
+        };
+        // image を省略した場合は合成した画像の URL を入れるので、ここでは空文字で埋めておく
+        if let Some(object) = json.as_object_mut() {
+            object.entry("image").or_insert_with(|| serde_json::Value::String(String::new()));
+        }
+        let metadata = serde_json::from_value(json).map_err(LayeredNftError::json(&path))?;
+        Ok(Some(MetadataOverride { metadata, image }))
+    }
+
+    /// 合成したトークンのメタデータを差し替え用のものに置き換える（image は合成した画像の URL のまま）
+    fn with_metadata(&self, mut token: GeneratedToken, overridden: Option<NftMetadata>) -> GeneratedToken {
+        if let Some(mut metadata) = overridden {
+            metadata.image = std::mem::take(&mut token.metadata.image);
+            token.metadata = metadata;
+        }
+        token
+    }
+
+    /// 合成の代わりに差し替え用の画像を読み込んでトークンにする（出力サイズと一致しなければエラー）
+    ///
+    /// output.noise は合成した画像と同じく重ねるが、ウォーターマークと枠は差し替え用の画像に含めておく
+    fn render_override(&self, token_id: u32, mut metadata: NftMetadata, image_path: &Path) -> Result<GeneratedToken> {
+        let mut image = image::open(image_path).map_err(LayeredNftError::image(image_path))?.to_rgba8();
+        if image.dimensions() != self.canvas_size {
+            return Err(LayeredNftError::SizeMismatch {
+                path: image_path.to_path_buf(),
+                expected: self.canvas_size,
+                actual: image.dimensions(),
+            });
+        }
+        self.apply_noise(token_id, std::slice::from_mut(&mut image));
+        metadata.image = self.metadata(token_id, &[]).image;
+        let frames = if self.cfg.output.animation.is_some() { vec![image.clone()] } else { Vec::new() };
+        Ok(GeneratedToken { token_id, image, frames, metadata, layers: Vec::new(), pattern_key: String::new() })
+    }
+
+    /// 1フレーム分を合成し、グレースケール化・出力サイズへのリサイズまで適用する
+    /// （ノイズ・ウォーターマーク・枠は decorate で重ねる）
+    pub fn compose(&self, layers: &[LayerChoice], frame: usize) -> Result<RgbaImage> {
+        let output = &self.cfg.output;
+        let mut composed =
+            compose_layers(layers, frame, output.background_color, output.color_palette.as_ref(), &self.cache)?;
+        if output.grayscale == Some(true) {
+            let changed = to_grayscale(&mut composed);
+            // 判定するのは最初に合成した画像だけ（変換した場合も確認済みにする）
+            let first = !self.state.grayscale_checked.swap(true, Ordering::Relaxed);
+            if first && !changed {
+                warn!("⚠ 合成画像がすでにグレースケール（R=G=B）です。レイヤーがグレースケールなら output.grayscale は不要です");
+            }
+        }
+        Ok(resize_output(&self.cfg, composed))
+    }
+
+    /// compose した frames にノイズ・ウォーターマーク・枠の順に重ねる
+    ///
+    /// ノイズはウォーターマークと枠より下に入るので、どちらもノイズで汚れない
+    pub fn decorate(&self, token_id: u32, frames: &mut [RgbaImage]) {
+        self.apply_noise(token_id, frames);
+        let output = &self.cfg.output;
+        for composed in frames {
+            if let (Some(mark), Some(wm)) = (&self.watermark, &output.watermark) {
+                apply_watermark(composed, mark, wm);
+            }
+            if let (Some(frame), Some(f)) = (&self.frame, &output.frame) {
+                blend_rgba(composed, frame, f.blend_mode);
+            }
+        }
+    }
+
+    /// 選ばれたレイヤーから NFT メタデータを組み立てる
+    pub fn metadata(&self, token_id: u32, layers: &[LayerChoice]) -> NftMetadata {
+        let animation_ext = self.cfg.output.animation.as_ref().map(|anim| anim.format.extension());
+        let mut metadata =
+            build_metadata(token_id, &self.cfg.metadata, &self.url_templates, layers, self.cfg.output.format.extension(), animation_ext);
+        if !self.z_orders.is_empty() {
+            // 重ね順を入れ替えたトークンでも、attributes は layers の順に並べる
+            metadata.attributes.sort_by_key(|a| self.candidates.iter().position(|c| c.layer.name == a.trait_type));
+        }
+        if let Some(template) = &self.image_path_template {
+            // 画像 URL もテンプレートで決まる image_dir からの相対パスに合わせる
+            let relative = template.render(token_id, self.id_width(), &metadata);
+            let base = self.cfg.metadata.ipfs_placeholder.as_deref().unwrap_or(&self.cfg.metadata.base_image_url);
+            metadata.image = format!("{}/{}", base, relative.to_string_lossy().replace('\\', "/"));
+        }
+        metadata
+    }
+
+    /// 画像の出力パス（image_path_template があれば展開し、なければ {image_dir}/{id}.{ext}）
+    pub fn image_path(&self, token_id: u32, metadata: &NftMetadata) -> PathBuf {
+        let relative = match &self.image_path_template {
+            Some(template) => template.render(token_id, self.id_width(), metadata),
+            None => PathBuf::from(format!("{}.{}", token_id, self.cfg.output.format.extension())),
+        };
+        Path::new(&self.cfg.output.image_dir).join(relative)
+    }
+
+    /// メタデータの出力パス（metadata_path_template があれば展開し、なければ {metadata_dir}/{id}.json）
+    pub fn metadata_path(&self, token_id: u32, metadata: &NftMetadata) -> PathBuf {
+        let relative = match &self.metadata_path_template {
+            Some(template) => template.render(token_id, self.id_width(), metadata),
+            None => PathBuf::from(format!("{}.json", token_id)),
+        };
+        Path::new(&self.cfg.output.metadata_dir).join(relative)
+    }
+
+    fn id_width(&self) -> usize {
+        self.cfg.output.id_width.unwrap_or(0)
+    }
+
+    /// トークンのメタデータを出力形式（ERC-721 / ERC-1155 / Metaplex）に合わせて JSON 文字列にする
+    pub fn metadata_json(&self, token_id: u32, metadata: &NftMetadata) -> Result<String> {
+        let metadata = format_metadata(metadata.clone(), &self.cfg.metadata);
+        let path = format!("{}/{}.json", self.cfg.output.metadata_dir, token_id);
+        let mut metadata = serde_json::to_value(&metadata).map_err(LayeredNftError::json(&path))?;
+        if let Some(extra) = &self.cfg.metadata.extra_fields {
+            merge_extra_fields(&mut metadata, extra, token_id);
+        }
+        serde_json::to_string_pretty(&metadata).map_err(LayeredNftError::json(path))
+    }
+
+    /// パターンキーに含まれる (trait_type, ファイル名（拡張子なし）) の一覧（制約やティアの判定用）
+    pub fn key_traits(&self, key: &str) -> TraitSet {
+        self.candidates
+            .iter()
+            .zip(key.split('|'))
+            .filter(|(_, part)| *part != ABSENT_LAYER_KEY)
+            .filter_map(|(candidate, part)| Some((candidate.layer.name.clone(), file_stem(Path::new(part))?)))
+            .collect()
+    }
+
+    /// 既存メタデータの attributes からパターンキーを復元する（現在のレイヤー構成と一致しなければ None）
+    /// （display_names で表示名を付けた値は、対応するファイル名に戻して照合する）
+    pub fn pattern_key(&self, meta: &NftMetadata) -> Option<String> {
+        let mut parts = Vec::with_capacity(self.candidates.len());
+        for candidate in &self.candidates {
+            let Some(attr) = meta
+                .attributes
+                .iter()
+                .find(|a| a.trait_type == candidate.layer.name)
+            else {
+                parts.push(ABSENT_LAYER_KEY.to_string());
+                continue;
+            };
+
+            let path = candidate.files.iter().find(|path| {
+                let Some(stem) = file_stem(path) else { return false; };
+                match &attr.value {
+                    AttributeValue::Text(v) => v == candidate.layer.display_name(&stem),
+                    AttributeValue::Number(n) => {
+                        numeric_trait(&candidate.layer, &stem).is_some_and(|(v, _)| v == *n)
+                    }
+                }
+            })?;
+            parts.push(path.to_string_lossy().to_string());
+        }
+        Some(parts.join("|"))
+    }
+}
+
+/// Generator::iter が返す、トークンを1つずつ生成するイテレータ
+///
+/// 使用済みパターンは Generator 側で共有しているため、`par_bridge` で複数スレッドから進めても重複しない。
+pub struct GeneratorIter<'a> {
+    generator: &'a Generator,
+    next_id: u32,
+    /// 最後に生成するトークン ID（この値を含む）
+    end: u32,
+}
+
+impl Iterator for GeneratorIter<'_> {
+    type Item = Result<GeneratedToken>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_id > self.end {
+            return None;
+        }
+        let token_id = self.next_id;
+        self.next_id += 1;
+        Some(self.generator.generate_token(token_id))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.end + 1).saturating_sub(self.next_id) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for GeneratorIter<'_> {}
+
+/// 各レイヤーの出現判定とレア度に従って、制約を考慮せずに1パターン分のレイヤーを選ぶ
+pub fn roll_layers<R: Rng + ?Sized>(layer_candidates: &[LayerCandidate], rng: &mut R) -> Vec<LayerChoice> {
+    let mut chosen_layers = Vec::with_capacity(layer_candidates.len());
+    for candidate in layer_candidates {
+        if !roll_layer_presence(&candidate.layer, rng) {
+            continue;
+        }
+
+        let chosen_path = match &candidate.file_weights {
+            Some(weights) => choose_weighted_file(&candidate.files, weights, rng),
+            None => choose_layer_file_with_rng(&candidate.files, &candidate.layer.rarity, rng),
+        };
+        let value = file_stem(&chosen_path).unwrap_or_else(|| "Unknown".to_string());
+        let frames = candidate.frames.get(&chosen_path).cloned().unwrap_or_default();
+        let numeric = numeric_trait(&candidate.layer, &value);
+        let color_replacements = candidate.color_replacements.get(&chosen_path).cloned();
+
+        chosen_layers.push(LayerChoice {
+            path: chosen_path,
+            trait_type: candidate.layer.name.clone(),
+            display_value: candidate.layer.display_name(&value).to_string(),
+            value,
+            blend_mode: candidate.layer.blend_mode.unwrap_or_default(),
+            opacity: candidate.layer.opacity.unwrap_or(1.0).clamp(0.0, 1.0),
+            frames,
+            numeric,
+            transform: candidate.layer.transform,
+            hsv: HsvAdjustment::from_layer(&candidate.layer),
+            mask: candidate.layer.mask_path.as_ref().map(PathBuf::from),
+            color_replacements,
+            adjustment: LoadAdjustment::from_layer(&candidate.layer),
+        });
+    }
+    chosen_layers
+}
+
+/// 選ばれたレイヤーの (trait_type, value) 一覧
+pub fn present_traits(layers: &[LayerChoice]) -> TraitSet {
+    layers
+        .iter()
+        .map(|l| (l.trait_type.clone(), l.value.clone()))
+        .collect()
+}
+
+/// required_traits をすべて満たすティアを返す
+pub fn matching_tiers<'a>(cfg: &'a Config, present: &TraitSet) -> Vec<&'a RarityTier> {
+    let Some(tiers) = cfg.constraints.as_ref().and_then(|c| c.tiers.as_ref()) else {
+        return Vec::new();
+    };
+
+    tiers
+        .iter()
+        .filter(|t| {
+            t.required_traits
+                .iter()
+                .all(|tv| present.contains(&(tv.trait_type.clone(), tv.value.clone())))
+        })
+        .collect()
+}
+
+/// レイヤー1つあたりの選択肢の数（オプションレイヤーは「なし」も1パターンとして数える）
+pub fn layer_option_count(c: &LayerCandidate) -> u128 {
+    match c.layer.optional_probability {
+        Some(p) if p <= 0.0 => 1,
+        Some(p) if p < 1.0 => c.files.len() as u128 + 1,
+        _ => c.files.len() as u128,
+    }
+}
+
+/// 候補レイヤー全体で作れる組み合わせの数
+/// （全レイヤーがオプションの場合、すべてスキップした空のパターンは数えない）
+pub fn combination_count(candidates: &[LayerCandidate]) -> u128 {
+    let total: u128 = candidates.iter().map(layer_option_count).product();
+    let can_be_empty = candidates.iter().all(|c| c.layer.optional_probability.is_some_and(|p| p < 1.0));
+    total - u128::from(can_be_empty)
+}
+
+/// target_width / target_height から出力サイズを求める（片方のみ指定時は縦横比を維持）
+pub fn target_size(cfg: &Config, src_w: u32, src_h: u32) -> (u32, u32) {
+    let scale = |n: u32, num: u32, den: u32| {
+        ((n as u64 * num as u64 + den as u64 / 2) / den as u64).max(1) as u32
+    };
+    match (cfg.output.target_width, cfg.output.target_height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, scale(src_h, w, src_w)),
+        (None, Some(h)) => (scale(src_w, h, src_h), h),
+        (None, None) => (src_w, src_h),
+    }
+}
+
+/// ウォーターマークを指定位置に重ねる（メタデータには含めない）
+fn apply_watermark(base: &mut RgbaImage, mark: &RgbaImage, wm: &WatermarkConfig) {
+    let (w, h) = base.dimensions();
+    let (mw, mh) = mark.dimensions();
+    let right = w.saturating_sub(mw + wm.margin);
+    let bottom = h.saturating_sub(mh + wm.margin);
+    let (x, y) = match wm.position {
+        WatermarkPosition::TopLeft => (wm.margin, wm.margin),
+        WatermarkPosition::TopRight => (right, wm.margin),
+        WatermarkPosition::BottomLeft => (wm.margin, bottom),
+        WatermarkPosition::BottomRight => (right, bottom),
+        WatermarkPosition::Center => (w.saturating_sub(mw) / 2, h.saturating_sub(mh) / 2),
+    };
+
+    let mut layer = RgbaImage::new(w, h);
+    image::imageops::replace(&mut layer, mark, x as i64, y as i64);
+    apply_opacity(&mut layer, wm.opacity);
+    alpha_over_rgba(base, &layer);
+}
+
+/// 枠の画像を読み込む。出力サイズと同じならそのまま、合成時のサイズ（リサイズ前）なら出力サイズにリサイズする
+fn load_frame(cfg: &Config, frame: &FrameConfig, source_size: (u32, u32), canvas_size: (u32, u32)) -> Result<RgbaImage> {
+    let path = Path::new(&frame.image_path);
+    let img = image::open(path).map_err(LayeredNftError::image(path))?.into_rgba8();
+    let mut img = if img.dimensions() == canvas_size {
+        img
+    } else if img.dimensions() == source_size {
+        resize_output(cfg, img)
+    } else {
+        return Err(LayeredNftError::SizeMismatch {
+            path: path.to_path_buf(),
+            expected: canvas_size,
+            actual: img.dimensions(),
+        });
+    };
+    apply_opacity(&mut img, frame.opacity.unwrap_or(1.0));
+    Ok(img)
+}
+
+/// 設定されていれば合成画像を出力サイズにリサイズする
+fn resize_output(cfg: &Config, img: RgbaImage) -> RgbaImage {
+    let (w, h) = target_size(cfg, img.width(), img.height());
+    if (w, h) == img.dimensions() {
+        return img;
+    }
+    let filter = cfg.output.resize_filter.unwrap_or_default().filter_type();
+    image::imageops::resize(&img, w, h, filter)
+}
+
+/// 合成済みフレーム列の SHA-256（サイズと RGBA の生データから計算）
+fn image_hash(frames: &[RgbaImage]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for frame in frames {
+        hasher.update(frame.width().to_le_bytes());
+        hasher.update(frame.height().to_le_bytes());
+        hasher.update(frame.as_raw());
+    }
+    hasher.finalize().into()
+}
+
+/// 禁則ルール判定
+fn violates_constraints(cfg: &Config, layers: &[LayerChoice]) -> bool {
+    let Some(c) = &cfg.constraints else { return false; };
+
+    let present = present_traits(layers);
+    constraints(c).iter().any(|rule| rule.violated(&present))
+}
+
+/// ディレクトリ以下のレイヤー画像（PNG / SVG）をパス順に列挙
+///
+/// ファイルシステムが返す順番に左右されないよう並べ替える（seed 指定時に同じファイルを選ぶため）
+pub(crate) fn collect_layer_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() && is_layer_file(entry.path()) {
+            files.push(entry.into_path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// レイヤー画像のサイズ（SVG は元のサイズ）
+pub fn layer_dimensions(path: &Path) -> Result<(u32, u32)> {
+    if is_svg(path) {
+        return svg_size(path);
+    }
+    image::image_dimensions(path).map_err(LayeredNftError::image(path))
+}
+
+/// レイヤー画像として扱う拡張子か
+pub(crate) fn is_layer_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png") || ext.eq_ignore_ascii_case("svg"))
+}
+
+/// 生成時にレイヤーの選択肢になるパスを列挙する（check / validate も同じ選択肢を使う）
+///
+/// アニメーション時は直下のファイルとフレームを含むサブディレクトリ、それ以外は配下の全ファイル
+pub(crate) fn layer_options(dir: &Path, animation: bool) -> io::Result<Vec<PathBuf>> {
+    if animation {
+        collect_animation_variants(dir).map(|(files, _)| files)
+    } else {
+        collect_layer_files(dir)
+    }
+}
+
+/// アニメーション用にレイヤーディレクトリ直下のバリアントを列挙
+/// 画像ファイルは静止バリアント、サブディレクトリはファイル名順のフレーム列として扱う
+fn collect_animation_variants(dir: &Path) -> io::Result<(Vec<PathBuf>, FrameMap)> {
+    let mut files = Vec::new();
+    let mut frames = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let seq = collect_layer_files(&path)?;
+            if seq.is_empty() {
+                continue;
+            }
+            frames.insert(path.clone(), seq);
+            files.push(path);
+        } else if is_layer_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok((files, frames))
+}
+
+/// 全フレーム列の枚数・サイズが揃っているか検証し、フレーム数を返す
+fn validate_animation_frames(layer_candidates: &[LayerCandidate]) -> Result<usize> {
+    let mut frame_count: Option<(usize, &Path)> = None;
+    let mut dimensions: Option<((u32, u32), &Path)> = None;
+
+    for candidate in layer_candidates {
+        for (variant, seq) in &candidate.frames {
+            match frame_count {
+                None => frame_count = Some((seq.len(), variant)),
+                Some((n, first)) if n != seq.len() => {
+                    return Err(LayeredNftError::InvalidConfig(format!(
+                        "フレーム数が一致しません: {:?} ({} 枚) != {:?} ({} 枚)",
+                        variant,
+                        seq.len(),
+                        first,
+                        n
+                    )));
+                }
+                _ => {}
+            }
+
+            for frame in seq {
+                let dim = layer_dimensions(frame)?;
+                match dimensions {
+                    None => dimensions = Some((dim, frame)),
+                    Some((d, first)) if d != dim => {
+                        return Err(LayeredNftError::InvalidConfig(format!(
+                            "フレームのサイズが一致しません: {:?} {:?} != {:?} {:?}",
+                            frame, dim, first, d
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(frame_count.map(|(n, _)| n).unwrap_or(1))
+}
+
+/// パスから拡張子抜きのファイル名を取得
+pub fn file_stem(path: &Path) -> Option<String> {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .map(|s| s.to_string())
+}
+
+/// レイヤー組み合わせを一意に表すキーを作成
+/// ここではフルパス文字列を "|" で連結している
+/// スキップされたオプションレイヤーは "<none>" として埋める
+/// 重ね順（z_order_variants）が違っても同じキーになるよう、layers の順に並べる
+fn build_pattern_key(layer_candidates: &[LayerCandidate], layers: &[LayerChoice]) -> String {
+    let mut parts = Vec::with_capacity(layer_candidates.len());
+    for candidate in layer_candidates {
+        match layers.iter().find(|l| l.trait_type == candidate.layer.name) {
+            Some(l) => parts.push(l.path.to_string_lossy().to_string()),
+            None => parts.push(ABSENT_LAYER_KEY.to_string()),
+        }
+    }
+    parts.join("|")
+}
+
+/// ファイル名（拡張子なし）が数値トレイトとして設定されていれば (値, display_type) を返す
+fn numeric_trait(layer: &LayerConfig, stem: &str) -> Option<(f64, String)> {
+    let value = *layer.numeric_traits.as_ref()?.get(stem)?;
+    let display_type = layer
+        .display_type
+        .clone()
+        .unwrap_or_else(|| "number".to_string());
+    Some((value, display_type))
+}
+
+/// オプションレイヤーが今回のトークンに出現するかを判定
+fn roll_layer_presence<R: Rng + ?Sized>(layer: &LayerConfig, rng: &mut R) -> bool {
+    match layer.optional_probability {
+        Some(p) => rng.gen_bool(p.clamp(0.0, 1.0)),
+        None => true,
+    }
+}
+
+/// rarity が指定されたレイヤーの重みを、実在するファイルごとの重みに置き換える
+/// （指定のないファイルは重み 1、存在しないファイルのキーは取り除く）
+/// group_by_directory のレイヤーはグループ（サブディレクトリ）名の重みも同様に揃える
+fn complete_rarity(layer: &mut LayerConfig, files: &[PathBuf]) {
+    let Some(rarity) = &layer.rarity else { return; };
+    let mut names: Vec<&str> = files
+        .iter()
+        .filter_map(|path| path.file_name().and_then(|s| s.to_str()))
+        .collect();
+    if layer.group_by_directory == Some(true) {
+        names.extend(
+            files
+                .iter()
+                .filter_map(|path| path.strip_prefix(&layer.directory).ok()?.parent()?.components().next())
+                .filter_map(|group| group.as_os_str().to_str()),
+        );
+    }
+    let completed = names
+        .into_iter()
+        .map(|name| (name.to_string(), *rarity.get(name).unwrap_or(&1.0)))
+        .collect();
+    layer.rarity = Some(completed);
+}
+
+/// レア度テーブル付きの重み付きランダム選択
+fn choose_layer_file_with_rng<R: Rng + ?Sized>(
+    files: &[PathBuf],
+    rarity: &Option<HashMap<String, f32>>,
+    rng: &mut R,
+) -> PathBuf {
+    if let Some(rarity_map) = rarity {
+        let weights: Vec<f32> = files
+            .iter()
+            .map(|path| {
+                let file_name = path
+                    .file_name()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or("");
+                *rarity_map.get(file_name).unwrap_or(&1.0)
+            })
+            .collect();
+
+        if let Ok(dist) = WeightedIndex::new(weights.iter().cloned()) {
+            let idx = dist.sample(rng);
+            return files[idx].clone();
+        } else {
+            warn!("⚠ レア度設定が不正です。均等ランダムにフォールバックします。");
+        }
+    }
+
+    files
+        .choose(rng)
+        .expect("レイヤーファイルが空です")
+        .clone()
+}
+
+/// group_by_directory のレイヤーで、各ファイルの出現確率（files と同じ順）を求める
+///
+/// 直下のサブディレクトリ（グループ）を rarity のサブディレクトリ名の重みで選び、
+/// その中のファイルを rarity のファイル名の重みで選ぶのと同じ確率になる（どちらも未指定なら重み 1）。
+/// directory 直下に置かれたファイルはどのグループにも属さないためエラーにする。
+/// トレイト値にはグループ名を含めないため、別のグループに同じファイル名（拡張子なし）があるのもエラーにする
+pub fn grouped_file_weights(layer: &LayerConfig, files: &[PathBuf]) -> Result<Vec<f64>> {
+    let weight = |key: &str| layer.rarity.as_ref().and_then(|r| r.get(key)).copied().unwrap_or(1.0) as f64;
+
+    let mut groups: Vec<(String, f64)> = Vec::with_capacity(files.len());
+    let mut stems: HashMap<&str, &Path> = HashMap::new();
+    for file in files {
+        let stem = file.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+        if let Some(other) = stems.insert(stem, file) {
+            return Err(LayeredNftError::InvalidConfig(format!(
+                "レイヤー {:?} は group_by_directory のため、グループをまたいで同じファイル名は使えません（トレイト値が区別できません）: {:?} と {:?}",
+                layer.name, other, file
+            )));
+        }
+        let relative = file.strip_prefix(&layer.directory).unwrap_or(file);
+        let mut components = relative.components();
+        let group = components.next().and_then(|c| c.as_os_str().to_str()).unwrap_or_default();
+        if components.next().is_none() {
+            return Err(LayeredNftError::InvalidConfig(format!(
+                "レイヤー {:?} は group_by_directory のため、画像はサブディレクトリに置いてください: {:?}",
+                layer.name, file
+            )));
+        }
+        let name = file.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+        groups.push((group.to_string(), weight(name)));
+    }
+
+    let mut group_totals: HashMap<&str, f64> = HashMap::new();
+    for (group, file_weight) in &groups {
+        *group_totals.entry(group.as_str()).or_insert(0.0) += file_weight;
+    }
+    let group_weight_sum: f64 = group_totals.keys().map(|g| weight(g)).sum();
+    Ok(groups
+        .iter()
+        .map(|(group, file_weight)| {
+            let within = group_totals[group.as_str()];
+            if within <= 0.0 || group_weight_sum <= 0.0 {
+                0.0
+            } else {
+                weight(group) / group_weight_sum * file_weight / within
+            }
+        })
+        .collect())
+}
+
+/// 確率（files と同じ順）に従ってファイルを選ぶ
+fn choose_weighted_file<R: Rng + ?Sized>(files: &[PathBuf], weights: &[f64], rng: &mut R) -> PathBuf {
+    match WeightedIndex::new(weights) {
+        Ok(dist) => files[dist.sample(rng)].clone(),
+        Err(_) => {
+            warn!("⚠ レア度設定が不正です。均等ランダムにフォールバックします。");
+            files.choose(rng).expect("レイヤーファイルが空です").clone()
+        }
+    }
+}
+
+/// PNG レイヤーを順に重ねて1枚にする（frame はアニメーション時のフレーム番号）
+/// palette があれば、重ね終えた画像の色を置き換える
+fn compose_layers(
+    layers: &[LayerChoice],
+    frame: usize,
+    background: Option<[u8; 4]>,
+    palette: Option<&ColorPaletteConfig>,
+    cache: &LayerCache,
+) -> Result<RgbaImage> {
+    if layers.is_empty() {
+        return Err(LayeredNftError::InvalidConfig("レイヤーが1枚も指定されていません".to_string()));
+    }
+
+    let first = load_layer_image(&layers[0], frame, cache)?;
+    let (width, height) = first.dimensions();
+
+    // 背景色があれば単色のキャンバスに最初のレイヤーから重ね、なければ最初のレイヤーをそのまま土台にする。
+    // キャッシュに共有されていない画像（--no-cache や色相などの調整・マスク適用後）はコピーせずに土台として使い回す
+    let (mut base, rest): (RgbaImage, &[LayerChoice]) = match background {
+        Some(color) => {
+            drop(first);
+            (RgbaImage::from_pixel(width, height, image::Rgba(color)), layers)
+        }
+        None => {
+            let mut base = Arc::unwrap_or_clone(first);
+            apply_opacity(&mut base, layers[0].opacity);
+            (base, &layers[1..])
+        }
+    };
+
+    for layer in rest {
+        let path = layer_frame_path(layer, frame);
+        let img = load_layer_image(layer, frame, cache)?;
+
+        if img.width() != width || img.height() != height {
+            return Err(LayeredNftError::SizeMismatch {
+                path: path.to_path_buf(),
+                expected: (width, height),
+                actual: img.dimensions(),
+            });
+        }
+
+        if layer.opacity < 1.0 {
+            let mut img = (*img).clone();
+            apply_opacity(&mut img, layer.opacity);
+            blend_rgba(&mut base, &img, layer.blend_mode);
+        } else {
+            blend_rgba(&mut base, &img, layer.blend_mode);
+        }
+    }
+
+    if let Some(palette) = palette {
+        remap_palette(&mut base, &palette.swaps);
+    }
+    Ok(base)
+}
+
+/// レイヤー画像を読み込み、transform・色相・彩度・明度の調整・マスクがあれば適用する
+/// （キャッシュには色の置き換えと tint・明るさ・コントラストだけを適用した画像が残る）
+fn load_layer_image(layer: &LayerChoice, frame: usize, cache: &LayerCache) -> Result<Arc<RgbaImage>> {
+    let path = layer_frame_path(layer, frame);
+    let replacements = layer.color_replacements.as_deref().unwrap_or_default();
+    let img = cache.get_transformed(path, replacements, layer.adjustment, layer.transform.as_ref())?;
+    if layer.hsv.is_none() && layer.mask.is_none() {
+        return Ok(img);
+    }
+
+    let mut img = Arc::unwrap_or_clone(img);
+    if let Some(adjustment) = layer.hsv {
+        adjust_hsv(&mut img, adjustment);
+    }
+    if let Some(path) = &layer.mask {
+        let mask = cache.get_mask(path)?;
+        if mask.dimensions() != img.dimensions() {
+            return Err(LayeredNftError::SizeMismatch {
+                path: path.clone(),
+                expected: img.dimensions(),
+                actual: mask.dimensions(),
+            });
+        }
+        apply_mask(&mut img, &mask);
+    }
+    Ok(Arc::new(img))
+}
+
+/// color_replacements の filename_glob に一致するファイルごとに、適用する置き換えをまとめる
+fn color_replacements_by_file(
+    layer: &LayerConfig,
+    files: &[PathBuf],
+) -> Result<HashMap<PathBuf, Arc<[ColorReplacement]>>> {
+    let Some(replacements) = &layer.color_replacements else { return Ok(HashMap::new()); };
+    let patterns = replacements
+        .iter()
+        .map(|r| {
+            glob::Pattern::new(&r.filename_glob).map_err(|e| {
+                LayeredNftError::InvalidConfig(format!(
+                    "レイヤー {:?} の filename_glob が不正です: {:?}: {}",
+                    layer.name, r.filename_glob, e
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut by_file = HashMap::new();
+    for file in files {
+        let Some(name) = file.file_name().and_then(|n| n.to_str()) else { continue; };
+        let matched: Arc<[ColorReplacement]> = replacements
+            .iter()
+            .zip(&patterns)
+            .filter(|(_, pattern)| pattern.matches(name))
+            .map(|(r, _)| r.clone())
+            .collect();
+        if !matched.is_empty() {
+            by_file.insert(file.clone(), matched);
+        }
+    }
+    Ok(by_file)
+}
+
+/// 指定フレームで使うレイヤー画像のパス（静止レイヤーは常に同じ画像）
+fn layer_frame_path(layer: &LayerChoice, frame: usize) -> &Path {
+    match layer.frames.get(frame) {
+        Some(p) => p,
+        None => &layer.path,
+    }
+}
+
+/// 解析済みの metadata の URL テンプレート
+struct UrlTemplates {
+    external_url: Option<UrlTemplate>,
+    youtube_url: Option<UrlTemplate>,
+    animation_url: Option<UrlTemplate>,
+}
+
+/// NFT メタデータを構築
+fn build_metadata(
+    token_id: u32,
+    metadata_config: &MetadataConfig,
+    url_templates: &UrlTemplates,
+    layers: &[LayerChoice],
+    image_ext: &str,
+    animation_ext: Option<&str>,
+) -> NftMetadata {
+    let name = if metadata_config.name.is_empty() {
+        format!("#{}", token_id)
+    } else {
+        format!("{} #{}", metadata_config.name, token_id)
+    };
+    let description = metadata_config.description.clone();
+    let image_base = metadata_config
+        .ipfs_placeholder
+        .as_deref()
+        .unwrap_or(&metadata_config.base_image_url);
+    let image = format!("{}/{}.{}", image_base, token_id, image_ext);
+    let attributes = layers
+        .iter()
+        .map(|l| match &l.numeric {
+            Some((n, display_type)) => Attribute {
+                trait_type: l.trait_type.clone(),
+                value: AttributeValue::Number(*n),
+                display_type: Some(display_type.clone()),
+            },
+            None => Attribute {
+                trait_type: l.trait_type.clone(),
+                value: AttributeValue::Text(l.display_value.clone()),
+                display_type: None,
+            },
+        })
+        .collect::<Vec<_>>();
+    let external_url = url_templates.external_url.as_ref().map(|t| t.render(token_id, &name, &attributes, None));
+    let youtube_url = url_templates.youtube_url.as_ref().map(|t| t.render(token_id, &name, &attributes, None));
+    // アニメーションを出力する場合、テンプレートの {file} は書き出すファイル名（{id}.{ext}）になる
+    let animation_file = animation_ext.map(|ext| format!("{}.{}", token_id, ext));
+    let animation_url = match (&url_templates.animation_url, &animation_file) {
+        (Some(t), file) => Some(t.render(token_id, &name, &attributes, file.as_deref())),
+        (None, Some(file)) => Some(format!("{}/{}", image_base, file)),
+        (None, None) => None,
+    };
+
+    NftMetadata {
+        name,
+        description,
+        image,
+        edition: token_id,
+        attributes,
+        external_url,
+        background_color: metadata_config.background_color.clone(),
+        youtube_url,
+        animation_url,
+    }
+}
+
+/// 設定された形式のメタデータに変換
+fn format_metadata(nft: NftMetadata, metadata_config: &MetadataConfig) -> TokenMetadata {
+    match (metadata_config.format, &metadata_config.metaplex) {
+        (MetadataFormat::Metaplex, Some(mp)) => {
+            TokenMetadata::Metaplex(MetaplexMetadata::from_nft(nft, mp))
+        }
+        (MetadataFormat::Erc1155, _) => {
+            let default_cfg = Erc1155Config::default();
+            let erc1155 = metadata_config.erc1155.as_ref().unwrap_or(&default_cfg);
+            TokenMetadata::Erc1155(Erc1155Metadata::from_nft(nft, erc1155))
+        }
+        _ => TokenMetadata::Erc721(nft),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        ConditionalConstraint, ConfigBuilder, ConstraintsConfig, ExclusiveGroup, LayerConfigBuilder, NoiseConfig, OutputConfig, RequiredGroup, TraitValue,
+        WatermarkConfig, WatermarkPosition, ZOrderVariant,
+    };
+    use rayon::iter::ParallelBridge;
+
+    fn generator(count: u32) -> Generator {
+        let cfg = ConfigBuilder::sample(&["Background", "Eyeball", "Eye color"])
+            .count(count)
+            .seed(42)
+            .build()
+            .unwrap();
+        Generator::new(cfg).unwrap()
+    }
+
+    fn constrained(layers: &[&str], count: u32, constraints: ConstraintsConfig) -> Generator {
+        let mut cfg = ConfigBuilder::sample(layers).count(count).seed(42).build().unwrap();
+        cfg.constraints = Some(constraints);
+        Generator::new(cfg).unwrap()
+    }
+
+    fn has_trait(layers: &[LayerChoice], trait_type: &str, value: &str) -> bool {
+        present_traits(layers).contains(&(trait_type.to_string(), value.to_string()))
+    }
+
+    #[test]
+    fn iter_generates_unique_tokens_in_parallel() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<GeneratorIter<'_>>();
+
+        let generator = generator(8);
+        let iter = generator.iter();
+        assert_eq!(iter.len(), 8);
+        let tokens: Vec<GeneratedToken> = iter.par_bridge().map(|t| t.unwrap()).collect();
+
+        let ids: HashSet<u32> = tokens.iter().map(|t| t.token_id).collect();
+        let keys: HashSet<&str> = tokens.iter().map(|t| t.pattern_key.as_str()).collect();
+        assert_eq!(ids, (1..=8).collect());
+        assert_eq!(keys.len(), 8);
+        assert_eq!(tokens[0].metadata.attributes.len(), 3);
+    }
+
+    #[test]
+    fn seeded_noise_is_reproducible_and_differs_per_token() {
+        let noisy = || {
+            // 全体の seed がなくても noise.seed だけで再現できる
+            let mut cfg = ConfigBuilder::sample(&["Eyeball"]).count(2).build().unwrap();
+            cfg.output.noise = Some(NoiseConfig { strength: 0.1, seed: Some(9) });
+            Generator::new(cfg).unwrap()
+        };
+        let (first, second) = (noisy(), noisy());
+        let gray = RgbaImage::from_pixel(8, 8, image::Rgba([128, 128, 128, 255]));
+        let noised = |generator: &Generator, token_id| {
+            let mut frames = [gray.clone()];
+            generator.apply_noise(token_id, &mut frames);
+            frames
+        };
+
+        let token1 = noised(&first, 1);
+        assert_ne!(token1[0], gray);
+        assert!(token1[0].pixels().all(|p| p[0] == p[1] && p[1] == p[2] && p[3] == 255));
+        assert_eq!(token1, noised(&second, 1));
+        assert_ne!(token1, noised(&first, 2));
+    }
+
+    #[test]
+    fn noise_is_drawn_under_the_watermark_and_on_overrides() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let red = image::Rgba([255, 0, 0, 255]);
+        let mark_path = dir.join("mark.png");
+        RgbaImage::from_pixel(2, 2, red).save(&mark_path).unwrap();
+        let mut output = OutputConfig::new("output/images", "output/metadata");
+        output.metadata_overrides_dir = Some(dir.to_string_lossy().to_string());
+        output.noise = Some(NoiseConfig { strength: 0.5, seed: Some(9) });
+        output.watermark = Some(WatermarkConfig {
+            image_path: mark_path.to_string_lossy().to_string(),
+            position: WatermarkPosition::TopLeft,
+            opacity: 1.0,
+            margin: 0,
+        });
+        let cfg = ConfigBuilder::sample(&["Eyeball"]).count(2).seed(42).output(output).build().unwrap();
+        let generator = Generator::new(cfg).unwrap();
+
+        let token = generator.generate_token(1).unwrap();
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(*token.image.get_pixel(x, y), red);
+        }
+        assert_ne!(token.image, generator.compose(&token.layers, 0).unwrap());
+
+        let (w, h) = generator.canvas_size();
+        let gray = RgbaImage::from_pixel(w, h, image::Rgba([128, 128, 128, 255]));
+        gray.save(dir.join("custom.png")).unwrap();
+        let attrs = r#""attributes":[{"trait_type":"Role","value":"Founder"}]"#;
+        let metadata = format!(r#"{{"name":"1/1","description":"","image":"custom.png","edition":2,{attrs}}}"#);
+        fs::write(dir.join("2.json"), metadata).unwrap();
+        let overridden = generator.generate_token(2).unwrap();
+        assert_ne!(overridden.image, gray);
+    }
+
+    #[test]
+    fn display_names_change_metadata_but_not_pattern_key() {
+        let cfg = ConfigBuilder::sample(&[])
+            .count(2)
+            .seed(42)
+            .add_layer(
+                LayerConfigBuilder::new("Eyeball", "layers/Eyeball")
+                    .display_name("Red", "Blood Red")
+                    .display_name("White", "Snow White")
+                    .build(),
+            )
+            .build()
+            .unwrap();
+        let generator = Generator::new(cfg).unwrap();
+        let pattern = generator.choose_pattern(1, &mut generator.token_rng(1)).unwrap();
+        let meta = generator.metadata(1, &pattern.layers);
+
+        let value = meta.attributes[0].value.to_string();
+        assert!(value == "Blood Red" || value == "Snow White");
+        assert!(!pattern.key.contains("Blood Red") && !pattern.key.contains("Snow White"));
+        assert_eq!(generator.pattern_key(&meta), Some(pattern.key.clone()));
+        let stem = file_stem(&pattern.layers[0].path).unwrap();
+        assert_eq!(generator.key_traits(&pattern.key), TraitSet::from([("Eyeball".to_string(), stem)]));
+    }
+
+    #[test]
+    fn z_order_variant_changes_only_compositing_order() {
+        let mut cfg = ConfigBuilder::sample(&["Background", "Eyeball"]).count(2).seed(42).build().unwrap();
+        cfg.constraints = Some(ConstraintsConfig {
+            z_order_variants: Some(vec![ZOrderVariant {
+                probability: 1.0,
+                order: vec!["Eyeball".to_string(), "Background".to_string()],
+            }]),
+            ..Default::default()
+        });
+        let generator = Generator::new(cfg).unwrap();
+        let pattern = generator.choose_pattern(1, &mut generator.token_rng(1)).unwrap();
+
+        let composed: Vec<&str> = pattern.layers.iter().map(|l| l.trait_type.as_str()).collect();
+        assert_eq!(composed, ["Eyeball", "Background"]);
+        assert!(pattern.key.starts_with("layers/Background"));
+        let meta = generator.metadata(1, &pattern.layers);
+        assert_eq!(meta.attributes[0].trait_type, "Background");
+    }
+
+    #[test]
+    fn required_group_ignores_skipped_optional_layers() {
+        let mut cfg = ConfigBuilder::sample(&["Background"])
+            .count(8)
+            .seed(42)
+            .add_layer(LayerConfigBuilder::new("Eyeball", "layers/Eyeball").optional_probability(0.2).build())
+            .add_layer(LayerConfigBuilder::new("Eye color", "layers/Eye color").optional_probability(0.2).build())
+            .build()
+            .unwrap();
+        cfg.constraints = Some(ConstraintsConfig {
+            required_group: Some(vec![RequiredGroup {
+                name: "eyes".to_string(),
+                members: vec![TraitValue::new("Eyeball", "Red"), TraitValue::new("Eye color", "Cyan")],
+            }]),
+            ..Default::default()
+        });
+        let generator = Generator::new(cfg).unwrap();
+
+        let mut skipped_eyeball = false;
+        // 条件を満たす組み合わせは Eyeball/Red の7通りと、Eyeball/Red 以外 + Eye color/Cyan の2通り
+        for token_id in 1..=8 {
+            let pattern = generator.choose_pattern(token_id, &mut generator.token_rng(token_id)).unwrap();
+            let present = present_traits(&pattern.layers);
+            let has = |t: &str, v: &str| present.contains(&(t.to_string(), v.to_string()));
+            assert!(has("Eyeball", "Red") || has("Eye color", "Cyan"), "{}", pattern.key);
+            skipped_eyeball |= !present.iter().any(|(t, _)| t == "Eyeball");
+        }
+        // Eyeball が出現しなかったトークンも Eye color/Cyan で条件を満たしている
+        assert!(skipped_eyeball);
+    }
+
+    #[test]
+    fn optional_layers_are_absent_but_never_all_at_once() {
+        let optional_eyeball = |count| {
+            ConfigBuilder::sample(&[])
+                .count(count)
+                .seed(1)
+                .add_layer(LayerConfigBuilder::new("Eyeball", "layers/Eyeball").optional_probability(0.5).build())
+                .build()
+                .unwrap()
+        };
+        // Red / White / なし の3通りのうち、レイヤーが1枚もない組み合わせは数えない
+        let err = Generator::new(optional_eyeball(3)).err().unwrap();
+        assert!(err.to_string().contains("最大組み合わせ数 2"), "{}", err);
+
+        let generator = Generator::new(optional_eyeball(2)).unwrap();
+        assert_eq!(generator.total_combinations(), 2);
+        let report = generator.generate_all().unwrap();
+        assert!(report.failures.is_empty());
+        assert!(report.tokens.iter().all(|t| t.layers.len() == 1));
+
+        let cfg = ConfigBuilder::sample(&["Background"])
+            .count(3)
+            .seed(1)
+            .add_layer(LayerConfigBuilder::new("Eyeball", "layers/Eyeball").optional_probability(0.5).build())
+            .build()
+            .unwrap();
+        let generator = Generator::new(cfg).unwrap();
+        assert_eq!(generator.total_combinations(), 3);
+        let report = generator.generate_all().unwrap();
+        let absent = report.tokens.iter().filter(|t| t.layers.len() == 1).count();
+        assert_eq!(absent, 1);
+        assert!(report.tokens.iter().all(|t| t.metadata.attributes.len() == t.layers.len()));
+    }
+
+    #[test]
+    fn required_attributes_reroll_patterns_without_them() {
+        let required = ConstraintsConfig {
+            required_attributes: Some(vec![TraitValue::new("Eyeball", "Red")]),
+            ..Default::default()
+        };
+        // Eyeball/White を引いたパターンは選び直すので、Eyeball/Red の6通りだけで6トークン作れる
+        let report = constrained(&["Eyeball", "Eye color"], 6, required).generate_all().unwrap();
+        assert!(report.failures.is_empty());
+        assert_eq!(report.tokens.len(), 6);
+        assert!(report.tokens.iter().all(|t| has_trait(&t.layers, "Eyeball", "Red")));
+
+        let unsatisfiable = ConstraintsConfig {
+            required_attributes: Some(vec![TraitValue::new("Eyeball", "Blue")]),
+            ..Default::default()
+        };
+        let generator = constrained(&["Eyeball"], 1, unsatisfiable);
+        let result = generator.choose_pattern(1, &mut generator.token_rng(1));
+        assert!(matches!(result, Err(LayeredNftError::NoUniquePattern(1))), "{:?}", result);
+    }
+
+    #[test]
+    fn exclusive_groups_never_pick_two_members_together() {
+        let exclusive = ConstraintsConfig {
+            exclusive_groups: Some(vec![ExclusiveGroup {
+                name: "red".to_string(),
+                members: vec![
+                    TraitValue::new("Eyeball", "Red"),
+                    TraitValue::new("Eye color", "Red"),
+                    TraitValue::new("Eye color", "Pink"),
+                ],
+            }]),
+            ..Default::default()
+        };
+        // 12通りから Eyeball/Red + Eye color/Red と Eyeball/Red + Eye color/Pink を除いた10通りをすべて使う
+        let report = constrained(&["Eyeball", "Eye color"], 10, exclusive).generate_all().unwrap();
+        assert!(report.failures.is_empty());
+        assert_eq!(report.tokens.len(), 10);
+        for token in &report.tokens {
+            let red_eyeball = has_trait(&token.layers, "Eyeball", "Red");
+            let red_color = has_trait(&token.layers, "Eye color", "Red") || has_trait(&token.layers, "Eye color", "Pink");
+            assert!(!(red_eyeball && red_color), "{}", token.pattern_key);
+        }
+    }
+
+    #[test]
+    fn conditional_constraints_apply_only_when_the_condition_matches() {
+        let conditional = ConstraintsConfig {
+            conditional_constraints: Some(vec![ConditionalConstraint {
+                condition: TraitValue::new("Eyeball", "Red"),
+                then_layer: "Eye color".to_string(),
+                allowed_values: vec!["Cyan".to_string(), "Green".to_string()],
+            }]),
+            ..Default::default()
+        };
+        // Eyeball/Red は Cyan と Green の2通り、条件に一致しない Eyeball/White は6色すべてを使える
+        let report = constrained(&["Eyeball", "Eye color"], 8, conditional).generate_all().unwrap();
+        assert!(report.failures.is_empty());
+        let (red, white): (Vec<_>, Vec<_>) =
+            report.tokens.iter().partition(|t| has_trait(&t.layers, "Eyeball", "Red"));
+        assert_eq!((red.len(), white.len()), (2, 6));
+        for token in red {
+            let allowed = has_trait(&token.layers, "Eye color", "Cyan") || has_trait(&token.layers, "Eye color", "Green");
+            assert!(allowed, "{}", token.pattern_key);
+        }
+    }
+
+    #[test]
+    fn tiers_cap_tokens_and_release_slots_of_failed_tokens() {
+        let tier = |name: &str, max_count, required: TraitValue| ConstraintsConfig {
+            tiers: Some(vec![RarityTier { name: name.to_string(), max_count, required_traits: vec![required] }]),
+            ..Default::default()
+        };
+        // Eyeball/Red の6通りのうち2つまでしか使えないので、White の6通りと合わせて8トークン
+        let generator = constrained(&["Eyeball", "Eye color"], 8, tier("red", 2, TraitValue::new("Eyeball", "Red")));
+        let report = generator.generate_all().unwrap();
+        assert!(report.failures.is_empty());
+        assert_eq!(report.tokens.iter().filter(|t| has_trait(&t.layers, "Eyeball", "Red")).count(), 2);
+        assert_eq!(generator.state.tier_counts.lock().unwrap()["red"], 2);
+
+        // 合成に失敗したトークンのティアの枠とパターンは返される
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::copy("layers/Background/Black.png", dir.join("Good.png")).unwrap();
+        fs::write(dir.join("Broken.png"), b"not a png").unwrap();
+        let mut cfg = ConfigBuilder::sample(&["Background"])
+            .count(2)
+            .seed(42)
+            .add_layer(LayerConfigBuilder::new("Hat", dir.to_string_lossy()).build())
+            .build()
+            .unwrap();
+        cfg.constraints = Some(tier("broken", 1, TraitValue::new("Hat", "Broken")));
+        let generator = Generator::new(cfg).unwrap();
+        let report = generator.generate_all().unwrap();
+        assert_eq!((report.tokens.len(), report.failures.len()), (1, 1));
+        assert!(has_trait(&report.tokens[0].layers, "Hat", "Good"));
+        assert_eq!(generator.state.tier_counts.lock().unwrap().get("broken").copied().unwrap_or(0), 0);
+        let broken_key = format!("layers/Background/Black.png|{}", dir.join("Broken.png").display());
+        assert!(!generator.state.used_patterns.contains_cached(&broken_key));
+    }
+
+    #[test]
+    fn duplicate_images_fail_deterministically_and_release_the_tier() {
+        // 不透明な Black を同じ Black で覆っても画像は変わらないため、2つのパターンは同一画像になる
+        let run = || {
+            let mut cfg = ConfigBuilder::sample(&["Background"])
+                .count(2)
+                .seed(42)
+                .add_layer(LayerConfigBuilder::new("Cover", "layers/Background").optional_probability(0.5).build())
+                .build()
+                .unwrap();
+            cfg.constraints = Some(ConstraintsConfig {
+                tiers: Some(vec![RarityTier {
+                    name: "covered".to_string(),
+                    max_count: 1,
+                    required_traits: vec![TraitValue::new("Cover", "Black")],
+                }]),
+                ..Default::default()
+            });
+            let generator = Generator::new(cfg).unwrap();
+            let results = Mutex::new(Vec::new());
+            generator.for_each_token(&[1, 2], |token_id, result| {
+                results.lock().unwrap().push((token_id, result.map(|t| t.pattern_key)));
+            });
+            let mut results = results.into_inner().unwrap();
+            results.sort_by_key(|(token_id, _)| *token_id);
+            (generator, results)
+        };
+
+        let (generator, results) = run();
+        let written: Vec<&String> = results.iter().filter_map(|(_, r)| r.as_ref().ok()).collect();
+        assert_eq!(written.len(), 1);
+        // 書き出さなかったトークンのパターンとティアの枠は返されている
+        let covered = !written[0].ends_with(ABSENT_LAYER_KEY);
+        let counts = generator.state.tier_counts.lock().unwrap();
+        assert_eq!(counts.get("covered").copied().unwrap_or(0), u32::from(covered));
+        drop(counts);
+        let black = "layers/Background/Black.png";
+        let released = [format!("{}|{}", black, ABSENT_LAYER_KEY), format!("{}|{}", black, black)]
+            .iter()
+            .filter(|key| !generator.state.used_patterns.contains_cached(key))
+            .count();
+        assert_eq!(released, 1);
+
+        // どのトークンが失敗するかは実行順に左右されない
+        for _ in 0..4 {
+            let (_, again) = run();
+            let ok = |r: &[(u32, Result<String>)]| r.iter().map(|(id, r)| (*id, r.is_ok())).collect::<Vec<_>>();
+            assert_eq!(ok(&again), ok(&results));
+        }
+    }
+
+    #[test]
+    fn grouped_weights_pick_group_then_file() {
+        let layer = LayerConfigBuilder::new("Background", "layers/Background")
+            .rarity("blue", 1.0)
+            .rarity("red", 3.0)
+            .rarity("b.png", 3.0)
+            .build();
+        let files: Vec<PathBuf> = ["blue/a.png", "blue/b.png", "red/c.png"]
+            .iter()
+            .map(|f| Path::new("layers/Background").join(f))
+            .collect();
+        let weights = grouped_file_weights(&layer, &files).unwrap();
+        assert_eq!(weights, [0.0625, 0.1875, 0.75]);
+
+        let flat = [Path::new("layers/Background").join("a.png")];
+        assert!(grouped_file_weights(&layer, &flat).is_err());
+        // blue/a.png と red/a.png はどちらもトレイト値 "a" になる
+        let ambiguous = [Path::new("layers/Background").join("blue/a.png"), Path::new("layers/Background").join("red/a.png")];
+        assert!(grouped_file_weights(&layer, &ambiguous).is_err());
+    }
+
+    #[test]
+    fn metadata_overrides_replace_metadata_and_optionally_image() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut output = OutputConfig::new("output/images", "output/metadata");
+        output.metadata_overrides_dir = Some(dir.to_string_lossy().to_string());
+        let cfg = ConfigBuilder::sample(&["Eyeball", "Eye color"]).count(4).seed(42).output(output).build().unwrap();
+        let generator = Generator::new(cfg).unwrap();
+        let (w, h) = generator.canvas_size();
+        RgbaImage::from_pixel(w, h, image::Rgba([1, 2, 3, 255])).save(dir.join("founder.png")).unwrap();
+        let attrs = r#""attributes":[{"trait_type":"Role","value":"Founder"}]"#;
+        fs::write(dir.join("1.json"), format!(r#"{{"name":"Founder #1","description":"","edition":1,{attrs}}}"#)).unwrap();
+        let with_image = format!(r#"{{"name":"1/1","description":"","image":"founder.png","edition":2,{attrs}}}"#);
+        fs::write(dir.join("2.json"), with_image).unwrap();
+        fs::write(dir.join("3.json"), r#"{"name":"missing fields"}"#).unwrap();
+
+        let report = generator.generate_all().unwrap();
+        let token = |id: u32| report.tokens.iter().find(|t| t.token_id == id).unwrap();
+        assert_eq!(token(1).metadata.name, "Founder #1");
+        assert_eq!(token(1).metadata.image, "https://example.com/1.png");
+        assert_eq!(token(1).layers.len(), 2);
+        assert_eq!(token(2).metadata.image, "https://example.com/2.png");
+        assert!(token(2).layers.is_empty());
+        assert_eq!(token(2).image.get_pixel(0, 0).0, [1, 2, 3, 255]);
+        assert_eq!(token(4).metadata.attributes.len(), 2);
+        assert_eq!(report.failures.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![3]);
+    }
+}
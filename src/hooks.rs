@@ -0,0 +1,103 @@
+use crate::config::{Config, HooksConfig};
+use std::io;
+use std::process::{Command, Output};
+use tracing::{debug, info, warn};
+
+/// post_token の `{id}` を token_id に置き換えたコマンド
+pub fn post_token_command(template: &str, token_id: u32) -> String {
+    template.replace("{id}", &token_id.to_string())
+}
+
+/// トークン1件の画像とメタデータを書き出した後に post_token を実行する
+///
+/// 環境変数 NFT_TOKEN_ID / NFT_IMAGE_PATH / NFT_METADATA_PATH を渡す。
+/// 終了コードが 0 以外でも生成は失敗にせず、警告として記録するだけにする
+pub fn run_post_token(hooks: &HooksConfig, token_id: u32, image_path: &str, metadata_path: &str) {
+    let Some(template) = &hooks.post_token else { return; };
+    let command = post_token_command(template, token_id);
+    let id = token_id.to_string();
+    let envs = [
+        ("NFT_TOKEN_ID", id.as_str()),
+        ("NFT_IMAGE_PATH", image_path),
+        ("NFT_METADATA_PATH", metadata_path),
+    ];
+    log_result(&format!("post_token（token #{}）", token_id), &command, run_shell(&command, &envs));
+}
+
+/// 全トークンの生成が終わった後に post_all を実行する
+///
+/// 環境変数 NFT_COUNT / NFT_IMAGE_DIR / NFT_METADATA_DIR を渡す
+pub fn run_post_all(hooks: &HooksConfig, cfg: &Config) {
+    let Some(command) = &hooks.post_all else { return; };
+    let count = cfg.count.to_string();
+    let envs = [
+        ("NFT_COUNT", count.as_str()),
+        ("NFT_IMAGE_DIR", cfg.output.image_dir.as_str()),
+        ("NFT_METADATA_DIR", cfg.output.metadata_dir.as_str()),
+    ];
+    log_result("post_all", command, run_shell(command, &envs));
+}
+
+/// シェル経由でコマンドを実行し、終了するまで待つ（標準出力と標準エラー出力は取り込む）
+pub fn run_shell(command: &str, envs: &[(&str, &str)]) -> io::Result<Output> {
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    cmd.envs(envs.iter().copied()).output()
+}
+
+fn log_result(label: &str, command: &str, result: io::Result<Output>) {
+    let output = match result {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("⚠ {} フックを起動できませんでした: {} ({})", label, command, e);
+            return;
+        }
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stdout.trim().is_empty() {
+        debug!("🪝 {} フックの標準出力: {}", label, stdout.trim_end());
+    }
+    let code = output.status.code().map_or_else(|| "シグナルで終了".to_string(), |c| c.to_string());
+    if output.status.success() {
+        info!("🪝 {} フックが終了しました (exit {})", label, code);
+        if !stderr.trim().is_empty() {
+            info!("🪝 {} フックの標準エラー出力: {}", label, stderr.trim_end());
+        }
+    } else {
+        warn!(
+            "⚠ {} フックが失敗しました (exit {}): {}{}",
+            label,
+            code,
+            command,
+            if stderr.trim().is_empty() { String::new() } else { format!("\n{}", stderr.trim_end()) }
+        );
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_token_substitutes_id_and_passes_env() {
+        let command = post_token_command(r#"printf '%s %s %s' {id} "$NFT_TOKEN_ID" "$NFT_IMAGE_PATH""#, 7);
+        let output = run_shell(&command, &[("NFT_TOKEN_ID", "7"), ("NFT_IMAGE_PATH", "out/images/7.png")]).unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "7 7 out/images/7.png");
+
+        let failed = run_shell("echo oops >&2; exit 3", &[]).unwrap();
+        assert_eq!(failed.status.code(), Some(3));
+        assert_eq!(String::from_utf8_lossy(&failed.stderr).trim(), "oops");
+    }
+}
@@ -0,0 +1,146 @@
+use crate::error::{LayeredNftError, Result};
+use crate::output::write_file;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// patch-cid の実行結果
+#[derive(Debug, Default)]
+pub struct PatchReport {
+    /// プレースホルダーを置換したファイル
+    pub patched: Vec<PathBuf>,
+    /// すでに同じ CID に置換済みだったファイル
+    pub unchanged: Vec<PathBuf>,
+    /// プレースホルダーを含まず、別の URL / CID が入っていたファイル
+    pub foreign: Vec<(PathBuf, String)>,
+}
+
+/// プレースホルダーを置き換える文字列（プレースホルダーが ipfs:// で始まる場合はスキームを維持）
+pub fn cid_replacement(placeholder: &str, cid: &str) -> String {
+    let cid = cid.trim_start_matches("ipfs://");
+    if placeholder.starts_with("ipfs://") {
+        format!("ipfs://{}", cid)
+    } else {
+        cid.to_string()
+    }
+}
+
+/// metadata_dir 以下の JSON の `image`（と `animation_url`）に含まれるプレースホルダーを実際の CID に置換する
+/// 置換済みのファイルは変更しないため、同じ CID で何度実行しても結果は変わらない
+pub fn patch_cid(metadata_dir: &Path, placeholder: &str, cid: &str) -> Result<PatchReport> {
+    let replacement = cid_replacement(placeholder, cid);
+    let mut report = PatchReport::default();
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(metadata_dir)
+        .map_err(LayeredNftError::io(metadata_dir))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().and_then(|s| s.to_str()) == Some("json"))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let text = fs::read_to_string(&path).map_err(LayeredNftError::io(&path))?;
+        let mut json: Value = serde_json::from_str(&text).map_err(LayeredNftError::json(&path))?;
+
+        // _metadata.json のような配列ファイルは各要素の image を置換する
+        let outcome = match &mut json {
+            Value::Array(items) => items
+                .iter_mut()
+                .map(|item| patch_image(item, placeholder, &replacement))
+                .fold(Outcome::Skipped, Outcome::merge),
+            item => patch_image(item, placeholder, &replacement),
+        };
+
+        match outcome {
+            Outcome::Patched => {
+                let out = serde_json::to_string_pretty(&json).map_err(LayeredNftError::json(&path))?;
+                // 既存のメタデータを書き換えるので、途中で止まっても元のファイルが壊れないよう常に一時ファイル経由で書く
+                write_file(&path, out, true)?;
+                report.patched.push(path);
+            }
+            Outcome::Unchanged => report.unchanged.push(path),
+            Outcome::Foreign(image) => report.foreign.push((path, image)),
+            Outcome::Skipped => {}
+        }
+    }
+
+    Ok(report)
+}
+
+/// 1つのメタデータオブジェクトに対する置換結果
+enum Outcome {
+    Patched,
+    Unchanged,
+    Foreign(String),
+    Skipped,
+}
+
+impl Outcome {
+    /// 配列の各要素の結果をまとめる（置換が1件でもあれば書き戻す）
+    fn merge(self, other: Outcome) -> Outcome {
+        match (self, other) {
+            (Outcome::Patched, _) | (_, Outcome::Patched) => Outcome::Patched,
+            (Outcome::Foreign(s), _) | (_, Outcome::Foreign(s)) => Outcome::Foreign(s),
+            (Outcome::Unchanged, _) | (_, Outcome::Unchanged) => Outcome::Unchanged,
+            _ => Outcome::Skipped,
+        }
+    }
+}
+
+fn patch_image(json: &mut Value, placeholder: &str, replacement: &str) -> Outcome {
+    let Some(image) = json.get("image").and_then(|v| v.as_str()).map(str::to_string) else {
+        return Outcome::Skipped;
+    };
+
+    // animation_url もプレースホルダーを指していれば一緒に置換する
+    let animation_patched = match json.get("animation_url").and_then(|v| v.as_str()) {
+        Some(url) if url.contains(placeholder) => {
+            json["animation_url"] = Value::String(url.replace(placeholder, replacement));
+            true
+        }
+        _ => false,
+    };
+
+    if image.contains(placeholder) {
+        json["image"] = Value::String(image.replace(placeholder, replacement));
+        Outcome::Patched
+    } else if animation_patched {
+        Outcome::Patched
+    } else if image.starts_with(replacement) {
+        Outcome::Unchanged
+    } else {
+        Outcome::Foreign(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replacement_keeps_scheme() {
+        assert_eq!(cid_replacement("ipfs://QmPLACEHOLDER", "QmReal"), "ipfs://QmReal");
+        assert_eq!(cid_replacement("ipfs://QmPLACEHOLDER", "ipfs://QmReal"), "ipfs://QmReal");
+        assert_eq!(cid_replacement("QmPLACEHOLDER", "QmReal"), "QmReal");
+    }
+
+    #[test]
+    fn patch_is_idempotent() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let file = dir.join("1.json");
+        fs::write(&file, r##"{"name":"#1","image":"ipfs://QmPLACEHOLDER/1.png","animation_url":"ipfs://QmPLACEHOLDER/1.gif"}"##).unwrap();
+
+        let first = patch_cid(dir, "ipfs://QmPLACEHOLDER", "QmReal").unwrap();
+        let after_first = fs::read_to_string(&file).unwrap();
+        let second = patch_cid(dir, "ipfs://QmPLACEHOLDER", "QmReal").unwrap();
+        let after_second = fs::read_to_string(&file).unwrap();
+
+        assert_eq!(first.patched.len(), 1);
+        assert_eq!(second.unchanged.len(), 1);
+        assert!(second.foreign.is_empty());
+        assert_eq!(after_first, after_second);
+        assert!(after_first.contains("ipfs://QmReal/1.png"));
+        assert!(after_first.contains("ipfs://QmReal/1.gif"));
+    }
+}
@@ -1,2 +1,26 @@
+pub mod animation;
+pub mod archive;
+#[cfg(feature = "async")]
+pub mod async_generator;
+pub mod blend;
+pub mod cache;
+pub mod check;
+pub mod color;
 pub mod config;
+pub mod constraints;
+pub mod error;
+pub mod generator;
+pub mod hooks;
+pub mod ipfs;
+pub mod merkle;
 pub mod metadata;
+pub mod output;
+pub mod path_template;
+pub mod pattern_store;
+pub mod phash;
+pub mod report;
+pub mod s3;
+pub mod svg;
+pub mod transform;
+pub mod validate;
+pub mod watch;
@@ -1,386 +1,2037 @@
-use layered_nft_gen::config::{Config, LayerConfig, MetadataConfig};
-use layered_nft_gen::metadata::{Attribute, NftMetadata};
+use layered_nft_gen::animation::write_animation;
+use layered_nft_gen::archive::write_zip;
+use layered_nft_gen::constraints::{constraints, TraitSet};
+use layered_nft_gen::check::{
+    self, audit_output, AuditReport, ImageVerifyReport,
+    check_output, chi_squared_test, export_csv, export_traits_csv, image_hashes, ChiSquaredResult, CheckReport, IMAGE_EXTENSIONS,
+    annotate_rarity, parse_token_ids, rarity_ranks, written_image_path, MAX_EXAMPLES, RARITY_RANKS_FILE,
+};
+use layered_nft_gen::generator::{
+    combination_count, file_stem, layer_option_count, present_traits, roll_layers, GeneratedToken, Generator, LayerCandidate, Pattern,
+};
+use layered_nft_gen::hooks;
+use layered_nft_gen::ipfs::patch_cid;
+use layered_nft_gen::output::{
+    compress_png, encode_image, save_additional_output, save_image, save_png_compressed, save_png_palette, write_file,
+    write_with, PaletteReport, MAX_PALETTE_COLORS,
+};
+use layered_nft_gen::merkle::{self, MerkleProofs, MerkleTree};
+use layered_nft_gen::phash::similar_pairs;
+use layered_nft_gen::report::write_html_report;
+use layered_nft_gen::s3::upload_collection;
+use layered_nft_gen::config::{Config, ConfigOverrides, ImageFormat, MultiCollectionConfig};
+use layered_nft_gen::metadata::{parse_token_metadata, CollectionMetadata, NftMetadata};
+use layered_nft_gen::watch::{self, ConfigChange, LayerChanges, Snapshot};
 
 use anyhow::{bail, Context, Result};
-use image::{ImageBuffer, RgbaImage};
-use rand::distributions::WeightedIndex;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use rand::prelude::*;
 use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use walkdir::WalkDir;
-use oxipng::{InFile, OutFile, Options};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tracing::{error, info, info_span, warn};
 
-/// 1トークン生成時に選ばれたレイヤー1枚分
-struct LayerChoice {
-    path: PathBuf,
-    trait_type: String,
-    value: String,
+/// merkle サブコマンドの出力ファイル名（metadata_dir 直下）
+const MERKLE_PROOFS_FILE: &str = "merkle_proofs.json";
+
+/// --preview の一覧画像で、1枚あたりの最大の幅・高さ（これより大きい画像は縮小して並べる）
+const PREVIEW_TILE_SIZE: u32 = 256;
+
+/// コマンドライン引数
+#[derive(Parser)]
+#[command(version, about = "レイヤー画像を組み合わせて NFT の画像とメタデータを生成する")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// 設定ファイル（.toml → TOML, .json → JSON, それ以外 → YAML）
+    #[arg(long = "config", default_value = "config.yaml", global = true)]
+    config_path: String,
+    /// --config の上に深くマージする上書き用の設定ファイル（複数指定可、後のファイルが優先）
+    #[arg(long = "override", value_name = "FILE", global = true)]
+    overrides: Vec<String>,
+    /// 出力先ディレクトリ（{dir}/images と {dir}/metadata で output.image_dir / metadata_dir を上書き）
+    #[arg(long, global = true)]
+    output_dir: Option<String>,
+    /// 生成枚数（count を上書き）
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    count: Option<u32>,
+    /// 乱数シード（seed を上書き）
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+    /// ログの最大レベル（error / warn / info / debug / trace）
+    #[arg(long, global = true, default_value = "info")]
+    log_level: tracing::Level,
+    /// ログの出力形式
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    #[command(flatten)]
+    generate: GenerateArgs,
+}
+
+/// generate 用のフラグ（サブコマンドを省略した場合も使えるようにトップレベルに置く）
+#[derive(Args)]
+struct GenerateArgs {
+    /// 既存の画像とメタデータが揃っているトークンをスキップする
+    #[arg(long, global = true)]
+    resume: bool,
+    /// 既存ファイルを無視してすべて再生成する（--resume より優先）。output.pattern_db の内容も消去する
+    #[arg(long, global = true)]
+    force: bool,
+    /// レイヤー画像のキャッシュを無効化する（低メモリ環境向け）
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// トークンごとのログの代わりにプログレスバーを表示する
+    #[arg(long, global = true)]
+    progress: bool,
+    /// ファイルを書き出さずに設定の検証とパターン選択のシミュレーションだけを行う
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// 各レイヤーの正規化後の出現確率を表示して終了する
+    #[arg(long, global = true)]
+    print_rarity: bool,
+    /// この ID から生成を始める（それより前の既存トークンのパターンは使用済みとして読み込む）
+    #[arg(long, global = true, value_name = "ID", conflicts_with = "resume", value_parser = clap::value_parser!(u32).range(1..))]
+    start_id: Option<u32>,
+    /// 生成を行わず、書き出し済みの画像とメタデータを output.s3 へアップロードだけする
+    #[arg(long, global = true)]
+    upload_only: bool,
+    /// PNG 圧縮をトークンごとに行わず、生成がすべて終わってから並列にまとめて行う
+    #[arg(long, global = true)]
+    compress_post: bool,
+    /// 生成後も設定ファイルとレイヤーディレクトリを監視し、変更の影響を受けるトークンだけを作り直す
+    #[arg(long, global = true, conflicts_with_all = ["dry_run", "print_rarity", "upload_only"])]
+    watch: bool,
+    /// N 個（省略時は 5 個）だけ生成し、画像と一覧画像（sheet.png）を image_dir と同じ階層の preview/ に書き出す（メタデータは書き出さない）
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "5",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["dry_run", "print_rarity", "upload_only", "watch", "resume", "start_id"]
+    )]
+    preview: Option<u32>,
+}
+
+/// check サブコマンドのオプション
+#[derive(Args)]
+struct CheckArgs {
+    /// レイヤーごとに出現数が rarity の重みと整合するかカイ二乗検定する
+    #[arg(long)]
+    chi_squared: bool,
+    /// カイ二乗検定の有意水準
+    #[arg(long, default_value_t = 0.05, requires = "chi_squared")]
+    alpha: f64,
+    /// レア度統計を CSV ファイルに書き出す
+    #[arg(long, value_name = "PATH")]
+    export_csv: Option<PathBuf>,
+    /// 画像の知覚ハッシュ（dHash）を比べ、見た目がほぼ同じトークンの組を報告する
+    #[arg(long)]
+    phash_check: bool,
+    /// この値未満のハミング距離を「ほぼ同じ」とみなす
+    #[arg(long, default_value_t = 8, requires = "phash_check")]
+    phash_threshold: u32,
+    /// すべての画像をデコードして破損・サイズ違い・メタデータとの対応漏れを調べる（問題があれば exit code 1）
+    #[arg(long)]
+    verify_images: bool,
+    /// 画像とメタデータの ID を突き合わせ、片方だけのトークンと欠番を報告する（あれば exit code 1）
+    #[arg(long)]
+    audit: bool,
+    /// --audit で見つかった欠けているトークンを --resume と同じ要領で再生成する
+    #[arg(long, requires = "audit")]
+    fix: bool,
+}
+
+/// サブコマンド
+#[derive(Subcommand)]
+enum Command {
+    /// コレクションを生成する（デフォルト）
+    Generate,
+    /// 生成済みメタデータのレア度統計・ファイルの有無・禁則違反を検証する（違反があれば exit code 1）
+    Check(CheckArgs),
+    /// 生成済みメタデータのトレイトごとの出現率を表示する
+    Stats {
+        /// 出現率の表と棒グラフを1ファイルの HTML にも書き出す（インライン CSS のみ、オフラインで閲覧可）
+        #[arg(long, value_name = "PATH")]
+        report_html: Option<PathBuf>,
+    },
+    /// メタデータ中の IPFS プレースホルダーを実際の CID に置換する
+    PatchCid {
+        /// 置換後の CID
+        #[arg(long)]
+        cid: String,
+    },
+    /// 全トークンのトレイトを1トークン1行の CSV に書き出す（Pandas / Excel などでの分析用）
+    ExportCsv {
+        /// 書き出す CSV ファイル
+        #[arg(long, value_name = "PATH")]
+        output: PathBuf,
+    },
+    /// 設定ファイルの JSON Schema（draft-07）を標準出力に出力する（エディタの補完・検証用）
+    Schema,
+    /// メタデータの Merkle ルートと各トークンの証明を出力する
+    Merkle {
+        /// 指定したトークンの証明を merkle_proofs.json の root と照合する
+        #[arg(long, value_name = "ID")]
+        verify: Option<u32>,
+    },
+    /// OpenSea 方式のレア度スコアと順位を計算し、rarity_ranks.json に書き出す
+    RarityRank {
+        /// 各トークンのメタデータにも rarity_score と rarity_rank を書き込む
+        #[arg(long)]
+        annotate: bool,
+    },
+    /// 指定したトークンだけを、残りのトークンと重複しないパターンで作り直す
+    Regenerate {
+        /// 作り直すトークン ID（カンマ区切り、`42-50` のような範囲指定も可）
+        #[arg(long, value_name = "IDS")]
+        ids: String,
+    },
 }
 
-/// 各レイヤー種別の候補一覧
-struct LayerCandidate<'a> {
-    layer: &'a LayerConfig,
-    files: Vec<PathBuf>,
+/// ログの出力形式（--log-format）
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// 人が読むためのテキスト（デフォルト）
+    Text,
+    /// 1行1イベントの JSON（CI やログ収集向け）
+    Json,
+}
+
+/// 生成の進捗表示（--progress 時はプログレスバー、それ以外はトークンごとのログ）
+struct Progress {
+    bar: Option<ProgressBar>,
+    errors: AtomicUsize,
+    /// プログレスバー表示中に発生したエラー（完了後にまとめて表示）
+    error_logs: Mutex<Vec<String>>,
+    /// 生成に失敗したトークン ID
+    failed_ids: Mutex<Vec<u32>>,
+}
+
+impl Progress {
+    /// collection を渡すと、コレクション名を付けたバーを複数コレクションの表示に加える
+    fn new(total: usize, enabled: bool, collection: Option<(&str, &MultiProgress)>) -> Self {
+        let bar = enabled.then(|| {
+            let bar = ProgressBar::new(total as u64);
+            let template = match collection {
+                Some(_) => "{prefix} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} ({per_sec}, ETA {eta}) {msg}",
+                None => "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} ({per_sec}, ETA {eta}) {msg}",
+            };
+            bar.set_style(
+                ProgressStyle::with_template(template)
+                    .expect("プログレスバーのテンプレートが不正です")
+                    .progress_chars("=> "),
+            );
+            match collection {
+                Some((name, bars)) => {
+                    bar.set_prefix(name.to_string());
+                    bars.add(bar)
+                }
+                None => bar,
+            }
+        });
+        Self {
+            bar,
+            errors: AtomicUsize::new(0),
+            error_logs: Mutex::new(Vec::new()),
+            failed_ids: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// プログレスバーを崩さずに1行表示する
+    fn log(&self, line: String) {
+        match &self.bar {
+            Some(bar) => bar.suspend(|| info!("{}", line)),
+            None => info!("{}", line),
+        }
+    }
+
+    /// 1トークンの生成成功を記録
+    fn success(&self, line: String) {
+        match &self.bar {
+            Some(bar) => bar.inc(1),
+            None => info!("{}", line),
+        }
+    }
+
+    /// 1トークンの生成失敗を記録（プログレスバーは中断せず、エラー数を末尾に表示）
+    fn failure(&self, token_id: u32, err: anyhow::Error) {
+        let errors = self.errors.fetch_add(1, Ordering::Relaxed) + 1;
+        self.failed_ids
+            .lock()
+            .expect("failed_ids のロックに失敗しました")
+            .push(token_id);
+        let line = format!("❌ Error in token #{}: {:?}", token_id, err);
+        match &self.bar {
+            Some(bar) => {
+                self.error_logs
+                    .lock()
+                    .expect("error_logs のロックに失敗しました")
+                    .push(line);
+                bar.set_message(format!("errors: {}", errors));
+                bar.inc(1);
+            }
+            None => error!("{}", line),
+        }
+    }
+
+    fn failed_ids(&self) -> Vec<u32> {
+        self.failed_ids
+            .lock()
+            .expect("failed_ids のロックに失敗しました")
+            .clone()
+    }
+
+    /// バーを閉じて、溜めておいたエラーとサマリーを表示
+    fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish();
+            for line in self.error_logs.lock().expect("error_logs のロックに失敗しました").iter() {
+                error!("{}", line);
+            }
+        }
+
+        let errors = self.errors.load(Ordering::Relaxed);
+        if errors == 0 {
+            info!("✅ All tokens generated without duplication!");
+        } else {
+            warn!("⚠ 生成完了（{} 件のトークンでエラーが発生しました）", errors);
+        }
+    }
+}
+
+/// output.palette_quantize の効果の集計（パレット化するかどうかの判断材料）
+#[derive(Default)]
+struct PaletteStats {
+    /// 全画像を RGBA の PNG にした場合の合計バイト数
+    rgba_bytes: AtomicU64,
+    /// 実際に書き出した PNG の合計バイト数（oxipng で圧縮する前）
+    written_bytes: AtomicU64,
+    indexed: AtomicUsize,
+    fallbacks: AtomicUsize,
+}
+
+impl PaletteStats {
+    fn record(&self, report: PaletteReport) {
+        self.rgba_bytes.fetch_add(report.rgba_bytes, Ordering::Relaxed);
+        self.written_bytes
+            .fetch_add(report.indexed_bytes.unwrap_or(report.rgba_bytes), Ordering::Relaxed);
+        match report.indexed_bytes {
+            Some(_) => self.indexed.fetch_add(1, Ordering::Relaxed),
+            None => self.fallbacks.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    fn log(&self) {
+        let (indexed, fallbacks) = (self.indexed.load(Ordering::Relaxed), self.fallbacks.load(Ordering::Relaxed));
+        if indexed + fallbacks == 0 {
+            return;
+        }
+        let before = self.rgba_bytes.load(Ordering::Relaxed);
+        let after = self.written_bytes.load(Ordering::Relaxed);
+        info!(
+            "🎨 パレット化: {} 枚（{} 色を超えて RGBA のまま: {} 枚）、RGBA {} → {} バイト（{:.1}% 削減）",
+            indexed,
+            MAX_PALETTE_COLORS,
+            fallbacks,
+            before,
+            after,
+            if before > 0 { before.saturating_sub(after) as f64 / before as f64 * 100.0 } else { 0.0 }
+        );
+    }
+}
+
+/// 設定ファイルの読み込み方（--watch で変更のたびに読み直す）
+struct ConfigSource {
+    path: String,
+    overrides: Vec<String>,
+    cli: ConfigOverrides,
+}
+
+impl ConfigSource {
+    /// 設定ファイルを読み込み、コマンドラインの指定を設定ファイルより優先して適用する
+    fn load(&self) -> Result<Config> {
+        let loaded = if self.overrides.is_empty() {
+            Config::load(&self.path)
+        } else {
+            let overrides: Vec<&str> = self.overrides.iter().map(String::as_str).collect();
+            Config::load_with_overrides(&self.path, &overrides)
+        }
+        .with_context(|| format!("{} の読み込みに失敗しました", self.path))?;
+        Ok(loaded.resolve(&self.cli))
+    }
+
+    /// トップレベルに collections を持つ設定ファイルなら読み込み、コマンドラインの指定を各コレクションに適用する
+    /// （collections がなければ None）
+    fn load_collections(&self) -> Result<Option<MultiCollectionConfig>> {
+        let overrides: Vec<&str> = self.overrides.iter().map(String::as_str).collect();
+        let Some(multi) = MultiCollectionConfig::load_with_overrides(&self.path, &overrides)
+            .with_context(|| format!("{} の読み込みに失敗しました", self.path))?
+        else {
+            return Ok(None);
+        };
+        if self.cli.output_dir.is_some() {
+            bail!("--output-dir は複数コレクションの設定ファイルでは使えません（コレクションごとに output を指定してください）");
+        }
+        let collections = multi.collections.into_iter().map(|c| c.resolve(&self.cli)).collect();
+        Ok(Some(MultiCollectionConfig { collections }))
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        std::iter::once(&self.path).chain(&self.overrides).map(PathBuf::from).collect()
+    }
 }
 
 fn main() -> Result<()> {
-    let cfg = Config::load("config.yaml")
-        .context("config.yaml の読み込みに失敗しました")?;
+    let cli = Cli::parse();
+    init_logging(cli.log_level, cli.log_format);
+    // スキーマの出力には設定ファイルを読み込まない
+    if let Some(Command::Schema) = &cli.command {
+        println!("{}", serde_json::to_string_pretty(&Config::json_schema())?);
+        return Ok(());
+    }
+    let source = ConfigSource {
+        path: cli.config_path,
+        overrides: cli.overrides,
+        cli: ConfigOverrides {
+            output_dir: cli.output_dir,
+            count: cli.count,
+            seed: cli.seed,
+        },
+    };
+    let mut args = cli.generate;
+    // トップレベルに collections を持つ設定ファイルは、複数のコレクションをまとめて生成する
+    if let Some(multi) = source.load_collections()? {
+        if !matches!(cli.command, Some(Command::Generate) | None) {
+            bail!("複数コレクションの設定ファイルでは生成だけを実行できます。サブコマンドにはコレクションごとの設定ファイルを指定してください");
+        }
+        if args.watch {
+            bail!("--watch は複数コレクションの設定ファイルでは使えません");
+        }
+        return run_collections(multi, &args);
+    }
+    let cfg = source.load()?;
+    if !matches!(cli.command, Some(Command::Generate) | None) {
+        ensure_default_metadata_paths(&cfg, "既存のメタデータを読むサブコマンドは")?;
+    }
 
-    fs::create_dir_all(&cfg.output.image_dir)
-        .with_context(|| format!("画像出力ディレクトリの作成に失敗しました: {}", cfg.output.image_dir))?;
-    fs::create_dir_all(&cfg.output.metadata_dir)
-        .with_context(|| format!("メタデータ出力ディレクトリの作成に失敗しました: {}", cfg.output.metadata_dir))?;
+    let mut fixing = false;
+    match &cli.command {
+        Some(Command::Check(check)) => {
+            fixing = run_check(&cfg, check)?;
+            if !fixing {
+                return Ok(());
+            }
+            // 欠けているトークン（画像かメタデータのどちらかがない ID と欠番）は --resume で再生成される
+            info!("🔧 --fix: 欠けているトークンを再生成します");
+            args.resume = true;
+            args.force = false;
+        }
+        Some(Command::Stats { report_html }) => return run_stats(&cfg, report_html.as_deref()),
+        Some(Command::PatchCid { cid }) => return run_patch_cid(&cfg, cid),
+        Some(Command::ExportCsv { output }) => return run_export_csv(&cfg, output),
+        Some(Command::Merkle { verify: None }) => return run_merkle(&cfg),
+        Some(Command::Merkle { verify: Some(id) }) => return run_merkle_verify(&cfg, *id),
+        Some(Command::RarityRank { annotate }) => return run_rarity_rank(&cfg, *annotate),
+        Some(Command::Regenerate { ids }) => return run_regenerate(cfg, ids, args.no_cache),
+        Some(Command::Schema) => unreachable!("schema は設定の読み込み前に処理済み"),
+        Some(Command::Generate) | None => {}
+    }
+
+    if let Some(snapshot) = run_generate(cfg, &args, None)? {
+        return run_watch(source, snapshot, args.no_cache);
+    }
+    if fixing {
+        // 再生成できても、不整合があったことは終了コードで知らせる
+        warn!("⚠ --fix: 欠けていたトークンを再生成しました（不整合があったため exit code 1 で終了します）");
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-    let mut layer_candidates: Vec<LayerCandidate> = Vec::new();
+/// 1コレクション分を生成する（--watch なら、監視の起点になる生成前の状態を返す）
+///
+/// collection に (コレクション名, 表示先) を渡すと、プログレスバーを複数コレクションの表示に加える
+fn run_generate(mut cfg: Config, args: &GenerateArgs, collection: Option<(&str, &MultiProgress)>) -> Result<Option<Snapshot>> {
+    validate_config(&cfg)?;
+    if args.resume || args.start_id.is_some_and(|id| id > 1) || args.watch || args.upload_only {
+        ensure_default_metadata_paths(&cfg, "--resume / --start-id / --watch / --upload-only は")?;
+    }
 
-    for layer in &cfg.layers {
-        let dir_path = Path::new(&layer.directory);
-        let files = collect_png_files(dir_path)
-            .with_context(|| format!("レイヤーディレクトリの走査に失敗しました: {:?}", dir_path))?;
+    if args.upload_only {
+        run_upload(&cfg)?;
+        return Ok(None);
+    }
 
-        if files.is_empty() {
+    // AVIF の出力がひとつでもあれば、ディレクトリを作る前に avif feature の有無を確認する
+    let uses_avif = cfg.output.format == ImageFormat::Avif
+        || cfg.output.thumbnail.as_ref().is_some_and(|t| t.format == ImageFormat::Avif)
+        || cfg.output.additional_outputs.iter().flatten().any(|e| e.format == ImageFormat::Avif);
+    if uses_avif && !cfg!(feature = "avif") {
+        bail!(
+            "出力形式に avif が指定されていますが、avif feature が無効です。\
+             `cargo build --features avif` でビルドしてください。"
+        );
+    }
+
+    // --dry-run と --preview では出力先のディレクトリを作らない
+    let creates_dirs = !args.dry_run && args.preview.is_none();
+    if creates_dirs {
+        fs::create_dir_all(&cfg.output.image_dir)
+            .with_context(|| format!("画像出力ディレクトリの作成に失敗しました: {}", cfg.output.image_dir))?;
+        fs::create_dir_all(&cfg.output.metadata_dir)
+            .with_context(|| format!("メタデータ出力ディレクトリの作成に失敗しました: {}", cfg.output.metadata_dir))?;
+    }
+
+    if let Some(thumb) = &cfg.output.thumbnail {
+        if thumb.format == ImageFormat::Webp && !cfg!(feature = "webp") {
             bail!(
-                "レイヤー {:?} ({:?}) に PNG ファイルがありません",
-                layer.name,
-                dir_path
+                "output.thumbnail.format に webp が指定されていますが、webp feature が無効です。\
+                 `cargo build --features webp` でビルドしてください。"
             );
         }
+        if creates_dirs {
+            fs::create_dir_all(&thumb.dir)
+                .with_context(|| format!("サムネイル出力ディレクトリの作成に失敗しました: {}", thumb.dir))?;
+        }
+    }
 
-        layer_candidates.push(LayerCandidate { layer, files });
+    for extra in cfg.output.additional_outputs.iter().flatten() {
+        if extra.format == ImageFormat::Webp && !cfg!(feature = "webp") {
+            bail!(
+                "output.additional_outputs に webp が指定されていますが、webp feature が無効です。\
+                 `cargo build --features webp` でビルドしてください。"
+            );
+        }
+        if creates_dirs {
+            fs::create_dir_all(&extra.dir)
+                .with_context(|| format!("追加の画像の出力ディレクトリの作成に失敗しました: {}", extra.dir))?;
+        }
     }
 
-    let total_combinations: u128 = layer_candidates
-        .iter()
-        .map(|c| c.files.len() as u128)
-        .product();
+    if cfg.output.palette_quantize == Some(true) && cfg.output.format != ImageFormat::Png {
+        warn!("⚠ output.format が png ではないため、output.palette_quantize は無視されます");
+    }
 
-    if cfg.count as u128 > total_combinations {
+    if cfg.output.format == ImageFormat::Webp {
+        if !cfg!(feature = "webp") {
+            bail!(
+                "output.format に webp が指定されていますが、webp feature が無効です。\
+                 `cargo build --features webp` でビルドしてください。"
+            );
+        }
+        if let Some(q) = cfg.output.webp_quality
+            && !(0.0..=100.0).contains(&q)
+        {
+            bail!("output.webp_quality は 0〜100 の範囲で指定してください: {}", q);
+        }
+    }
+
+    if let Some(anim) = &cfg.output.animation
+        && anim.format.extension() == cfg.output.format.extension()
+    {
         bail!(
-            "要求された生成数 {} は理論上の最大組み合わせ数 {} を超えています。\
-             レイヤーのバリエーションを増やすか、count を減らしてください。",
-            cfg.count,
-            total_combinations
+            "output.format と animation.format が同じ拡張子 ({}) のため、静止画とアニメーションの出力先が衝突します",
+            anim.format.extension()
         );
     }
 
-    println!(
+    if args.dry_run || args.print_rarity || args.preview.is_some() {
+        // パターン DB は開かず、メモリ上だけで重複を確認する
+        cfg.output.pattern_db = None;
+    }
+    // --watch では、ここで記録した状態と変更後の状態を比べて作り直すトークンを決める
+    let snapshot = args.watch.then(|| Snapshot::capture(&cfg)).transpose()?;
+    let generator = Generator::new(cfg)?.with_cache(!args.no_cache);
+    let cfg = generator.config();
+
+    if args.print_rarity {
+        print_rarity_table(generator.candidates());
+        return Ok(None);
+    }
+
+    if cfg.output.format == ImageFormat::Jpeg {
+        warn_jpeg_transparency(cfg, generator.candidates());
+    }
+
+    if generator.upscales() {
+        let ((src_w, src_h), (canvas_w, canvas_h)) = (generator.source_size(), generator.canvas_size());
+        warn!(
+            "⚠ 出力サイズ {}x{} がレイヤー画像 {}x{} より大きいため、拡大されます",
+            canvas_w, canvas_h, src_w, src_h
+        );
+    }
+
+    warn_infeasible_tiers(&generator);
+    feasibility_analysis(&generator);
+
+    if args.dry_run {
+        run_dry_run(&generator)?;
+        return Ok(None);
+    }
+    if let Some(count) = args.preview {
+        run_preview(&generator, count)?;
+        return Ok(None);
+    }
+
+    info!(
         "Generating {} NFTs in parallel (max unique patterns: {})...",
-        cfg.count, total_combinations
+        cfg.count,
+        generator.total_combinations()
     );
 
-    let used_patterns: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+    let resuming = args.resume && !args.force;
+    if args.force {
+        // 以前の実行で記録したパターンも使えるように、DB を空にしてから作り直す
+        generator.clear_patterns()?;
+    } else if !resuming && generator.is_persistent() {
+        info!("🗃 パターン DB に記録済みのパターンは使わずに生成します（DB を消去するには --force を指定してください）");
+    }
+    let start_id = args.start_id.unwrap_or(1);
+    if start_id > cfg.count {
+        bail!("--start-id ({}) が count ({}) を超えています", start_id, cfg.count);
+    }
+    if start_id > 1 {
+        prepare_extension(&generator, start_id)?;
+    }
+
+    let has_tiers = cfg.constraints.as_ref().is_some_and(|c| c.tiers.is_some());
+    let skipped: HashSet<u32> = if resuming && generator.is_persistent() && !has_tiers {
+        // 使用済みパターンは DB に残っているため、既存メタデータの読み込みは省略する
+        let ids = existing_token_ids(cfg);
+        for token_id in &ids {
+            info!("⏭ token #{} は既存のためスキップしました", token_id);
+        }
+        ids.into_iter().collect()
+    } else if resuming {
+        let ids = existing_token_ids(cfg);
+        for (token_id, key, present) in load_existing_tokens(&generator, &ids)? {
+            if !generator.reserve(&key, &present)? {
+                warn!("⚠ 既存トークン #{} のパターンが他の既存トークンと重複しています", token_id);
+            }
+        }
+        for token_id in &ids {
+            info!("⏭ token #{} は既存のためスキップしました", token_id);
+        }
+        ids.into_iter().collect()
+    } else {
+        HashSet::new()
+    };
+    let token_ids: Vec<u32> = (start_id..=cfg.count).filter(|id| !skipped.contains(id)).collect();
+    let progress = Progress::new(token_ids.len(), args.progress, collection);
 
-    (1..=cfg.count)
-        .into_par_iter()
-        .for_each(|token_id| {
-            if let Err(err) =
-                generate_one(token_id, &cfg, &layer_candidates, &used_patterns)
-            {
-                eprintln!("❌ Error in token #{}: {:?}", token_id, err);
+    // --compress-post で後から圧縮する画像
+    let written_images = Mutex::new(Vec::new());
+    let palette_stats = PaletteStats::default();
+
+    let batch_size = cfg.batch_size.unwrap_or(token_ids.len()).max(1);
+    let batch_count = token_ids.len().div_ceil(batch_size);
+    for (batch_index, batch) in token_ids.chunks(batch_size).enumerate() {
+        if cfg.batch_size.is_some() {
+            progress.log(format!(
+                "📦 バッチ {}/{}: token #{}〜#{}",
+                batch_index + 1,
+                batch_count,
+                batch[0],
+                batch[batch.len() - 1]
+            ));
+        }
+
+        generator.for_each_token(batch, |token_id, result| {
+            let written = result.map_err(anyhow::Error::from).and_then(|token| {
+                let line = write_token(&generator, &token, !args.compress_post, &palette_stats)?;
+                if args.compress_post {
+                    let path = generator.image_path(token_id, &token.metadata);
+                    written_images.lock().expect("written_images のロックに失敗しました").push(path);
+                }
+                Ok(line)
+            });
+            match written {
+                Ok(line) => progress.success(line),
+                Err(err) => progress.failure(token_id, err),
             }
         });
 
-    println!("✅ All tokens generated without duplication!");
+        // キャッシュがコレクション全体分まで膨らまないよう、バッチごとに解放する
+        if cfg.batch_size.is_some() {
+            generator.clear_cache();
+        }
+    }
 
-    Ok(())
+    progress.finish();
+    palette_stats.log();
+    info!("🔁 画像ハッシュの衝突: {} 件", generator.hash_collisions());
+
+    if args.compress_post {
+        compress_post(cfg, &written_images.into_inner().expect("written_images のロックに失敗しました"))?;
+    }
+
+    let (collection, all_metadata) = rayon::join(
+        || write_collection_json(cfg),
+        || write_all_metadata_json(cfg),
+    );
+    collection?;
+    all_metadata?;
+
+    if let Some(zip_cfg) = &cfg.output.zip_output {
+        write_zip(
+            &cfg.output.image_dir,
+            &cfg.output.metadata_dir,
+            zip_cfg,
+            &progress.failed_ids(),
+            cfg.output.writes_atomically(),
+        )
+        .with_context(|| format!("ZIP アーカイブの作成に失敗しました: {}", zip_cfg.path))?;
+        info!("📦 ZIP アーカイブを作成しました: {}", zip_cfg.path);
+    }
+
+    if cfg.output.s3.is_some() {
+        run_upload(cfg)?;
+    }
+
+    if let Some(hooks) = &cfg.hooks {
+        hooks::run_post_all(hooks, cfg);
+    }
+
+    Ok(snapshot)
 }
 
-/// 1トークン分を生成する処理（並列で呼ばれる）
-fn generate_one(
-    token_id: u32,
-    cfg: &Config,
-    layer_candidates: &[LayerCandidate],
-    used_patterns: &Arc<Mutex<HashSet<String>>>,
-) -> Result<()> {
-    const MAX_RETRY: u32 = 1000;
-
-    let mut rng = thread_rng();
-    let mut chosen_layers: Vec<LayerChoice> = Vec::new();
-    let pattern_key: String;
-
-    'retry_loop: {
-        for _attempt in 0..MAX_RETRY {
-            chosen_layers.clear();
-
-            for candidate in layer_candidates {
-                let chosen_path =
-                    choose_layer_file_with_rng(&candidate.files, &candidate.layer.rarity, &mut rng);
-                let value =
-                    file_stem(&chosen_path).unwrap_or_else(|| "Unknown".to_string());
-
-                chosen_layers.push(LayerChoice {
-                    path: chosen_path,
-                    trait_type: candidate.layer.name.clone(),
-                    value,
-                });
+/// 複数コレクションの設定ファイル: コレクションごとに専用のスレッドプールを作り、並列に生成する
+///
+/// CPU のコア数はコレクション数で分け合う。1つのコレクションが失敗してもほかのコレクションは最後まで生成し、
+/// 失敗したコレクションは最後にまとめて報告する
+fn run_collections(multi: MultiCollectionConfig, args: &GenerateArgs) -> Result<()> {
+    let cores = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let threads = cores.div_ceil(multi.collections.len()).max(1);
+    let labels: Vec<String> = multi
+        .collections
+        .iter()
+        .enumerate()
+        .map(|(i, c)| match c.metadata.name.as_str() {
+            "" => format!("collections[{}]", i),
+            name => name.to_string(),
+        })
+        .collect();
+    info!("🗂 {} 件のコレクションを並列に生成します（コレクションごとに {} スレッド）", labels.len(), threads);
+
+    let bars = MultiProgress::new();
+    let results: Vec<Result<()>> = multi
+        .collections
+        .into_par_iter()
+        .zip(labels.par_iter())
+        .map(|(cfg, label)| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .context("スレッドプールの作成に失敗しました")?;
+            pool.install(|| {
+                let _span = info_span!("collection", name = %label).entered();
+                run_generate(cfg, args, Some((label, &bars))).map(|_| ())
+            })
+        })
+        .collect();
+
+    let mut failed = 0;
+    for (label, result) in labels.iter().zip(results) {
+        match result {
+            Ok(()) => info!("✅ コレクション {} の生成が完了しました", label),
+            Err(err) => {
+                failed += 1;
+                error!("❌ コレクション {} の生成に失敗しました: {:?}", label, err);
             }
+        }
+    }
+    if failed > 0 {
+        bail!("{} / {} 件のコレクションでエラーが発生しました", failed, labels.len());
+    }
+    Ok(())
+}
 
-            if violates_constraints(cfg, &chosen_layers) {
-                continue;
+/// 設定の整合性を検証し、警告を表示する（エラーがあれば中断する）
+///
+/// 続けてレイヤーファイルのヘッダーを読み、壊れたファイルやサイズの揃っていないファイルをまとめて表示する
+fn validate_config(cfg: &Config) -> Result<()> {
+    match Config::validate(cfg) {
+        Ok(warnings) => {
+            for w in &warnings {
+                warn!("⚠ {}", w);
+            }
+        }
+        Err(errors) => {
+            for e in &errors {
+                error!("❌ {}", e);
             }
+            bail!("設定に {} 件のエラーがあります。修正してから再実行してください", errors.len());
+        }
+    }
 
-            let key = build_pattern_key(&chosen_layers);
+    let issues = cfg.validate_layer_files().context("レイヤーファイルの検証に失敗しました")?;
+    if !issues.is_empty() {
+        for issue in &issues {
+            warn!("⚠ {}", issue);
+        }
+        warn!("⚠ レイヤーファイルに {} 件の問題があります。合成前に修正してください", issues.len());
+    }
+    Ok(())
+}
 
-            {
-                let mut set = used_patterns
-                    .lock()
-                    .expect("used_patterns のロックに失敗しました");
-                if !set.contains(&key) {
-                    set.insert(key.clone());
-                    pattern_key = key;
-                    break 'retry_loop;
-                }
-            }
+/// --watch: 設定ファイルとレイヤーディレクトリの変更を待ち、変更のたびに影響を受けるトークンを作り直す
+fn run_watch(source: ConfigSource, snapshot: Snapshot, no_cache: bool) -> Result<()> {
+    info!("👀 設定ファイルとレイヤーディレクトリの変更を監視しています（Ctrl+C で終了）");
+    let config_files = source.files();
+    let layer_dirs = snapshot.layer_dirs();
+    let mut previous = snapshot;
+    watch::watch(&config_files, layer_dirs, move |changed| {
+        info!("🔄 変更を検知しました: {} 件のファイル", changed.len());
+        // 失敗しても監視は続け、次の変更は最後に成功した状態と比べる
+        if let Err(err) = regenerate_changed(&source, &mut previous, no_cache) {
+            error!("❌ 再生成に失敗しました: {:?}", err);
+        }
+        previous.layer_dirs()
+    })
+    .context("ファイルの監視に失敗しました")
+}
+
+/// 設定を読み直して前回との差分を表示し、影響を受けるトークンを作り直す
+fn regenerate_changed(source: &ConfigSource, previous: &mut Snapshot, no_cache: bool) -> Result<()> {
+    let cfg = source.load()?;
+    validate_config(&cfg)?;
+    let current = Snapshot::capture(&cfg)?;
+    let config_changes = previous.config_changes(&current);
+    let layer_changes = previous.layer_changes(&current);
+    print_changes(&config_changes, &layer_changes);
+    if config_changes.is_empty() && layer_changes.is_empty() {
+        *previous = current;
+        return Ok(());
+    }
+
+    fs::create_dir_all(&cfg.output.image_dir)
+        .with_context(|| format!("画像出力ディレクトリの作成に失敗しました: {}", cfg.output.image_dir))?;
+    fs::create_dir_all(&cfg.output.metadata_dir)
+        .with_context(|| format!("メタデータ出力ディレクトリの作成に失敗しました: {}", cfg.output.metadata_dir))?;
+    let generator = Generator::new(cfg)?.with_cache(!no_cache);
+    let cfg = generator.config();
+
+    // 設定が変わった場合は、どのトークンの画像やメタデータが変わるか分からないのですべて作り直す
+    let regenerate = if config_changes.is_empty() {
+        affected_tokens(&generator, &layer_changes)?
+    } else {
+        generator.clear_patterns()?;
+        Regenerate::Ids((1..=cfg.count).collect())
+    };
+    let count = match &regenerate {
+        Regenerate::Ids(ids) => ids.len(),
+        Regenerate::Patterns(patterns) => patterns.len(),
+    };
+    if count == 0 {
+        info!("✨ 影響を受けるトークンはありません");
+        *previous = current;
+        return Ok(());
+    }
+    info!("🎨 {} 件のトークンを作り直します", count);
+
+    let progress = Progress::new(count, false, None);
+    let palette_stats = PaletteStats::default();
+    let write = |token_id: u32, result: layered_nft_gen::error::Result<GeneratedToken>| {
+        let written = result
+            .map_err(anyhow::Error::from)
+            .and_then(|token| write_token(&generator, &token, true, &palette_stats));
+        match written {
+            Ok(line) => progress.success(line),
+            Err(err) => progress.failure(token_id, err),
         }
+    };
+    match regenerate {
+        Regenerate::Ids(ids) => generator.for_each_token(&ids, write),
+        Regenerate::Patterns(patterns) => patterns.into_par_iter().for_each(|(token_id, pattern)| {
+            let _span = tracing::info_span!("token", token_id).entered();
+            write(token_id, generator.render(token_id, pattern));
+        }),
+    }
+    progress.finish();
+    palette_stats.log();
+
+    let (collection, all_metadata) = rayon::join(|| write_collection_json(cfg), || write_all_metadata_json(cfg));
+    collection?;
+    all_metadata?;
+    *previous = current;
+    Ok(())
+}
 
+/// regenerate: 指定したトークンのパターンを使用済みから外してそのトークンだけを生成し直し、
+/// 書き出した画像を check --verify-images と同じ要領で検証する
+///
+/// 残りのトークンのパターンは既存メタデータから使用済みとして登録し直すため、作り直したトークンとは重複しない
+fn run_regenerate(cfg: Config, ids: &str, no_cache: bool) -> Result<()> {
+    let ids = parse_token_ids(ids).map_err(|e| anyhow::anyhow!("--ids が不正です: {}", e))?;
+    validate_config(&cfg)?;
+    if let Some(id) = ids.iter().find(|id| **id > cfg.count) {
+        bail!("--ids の token #{} が count ({}) を超えています", id, cfg.count);
+    }
+    let missing: Vec<String> = ids
+        .iter()
+        .filter(|id| !Path::new(&format!("{}/{}.json", cfg.output.metadata_dir, id)).is_file())
+        .map(u32::to_string)
+        .collect();
+    if !missing.is_empty() {
         bail!(
-            "トークン #{} で一意なパターンを見つけられませんでした（MAX_RETRY超過）。\
-             count が組み合わせ数ギリギリか、rarity 設定が極端な可能性があります。",
-            token_id
+            "メタデータファイルがないトークンは作り直せません ({}): {}",
+            cfg.output.metadata_dir,
+            missing.join(", ")
         );
     }
 
-    let composed = compose_layers(&chosen_layers)
-        .with_context(|| format!("トークン #{} の画像合成に失敗しました", token_id))?;
+    let generator = Generator::new(cfg)?.with_cache(!no_cache);
+    let cfg = generator.config();
+    generator.clear_patterns()?;
+    let targets: HashSet<u32> = ids.iter().copied().collect();
+    let survivors: Vec<u32> = existing_token_ids(cfg).into_iter().filter(|id| !targets.contains(id)).collect();
+    for (token_id, key, present) in load_existing_tokens(&generator, &survivors)? {
+        if !generator.reserve(&key, &present)? {
+            warn!("⚠ 既存トークン #{} のパターンが他の既存トークンと重複しています", token_id);
+        }
+    }
+    info!("🎨 {} 件のトークンを作り直します（残りの {} 件はそのまま）", ids.len(), survivors.len());
 
-    let image_path = format!("{}/{}.png", cfg.output.image_dir, token_id);
-    composed
-        .save(&image_path)
-        .with_context(|| format!("画像の保存に失敗しました: {}", image_path))?;
+    let progress = Progress::new(ids.len(), false, None);
+    let palette_stats = PaletteStats::default();
+    generator.for_each_token(&ids, |token_id, result| {
+        let written = result
+            .map_err(anyhow::Error::from)
+            .and_then(|token| write_token(&generator, &token, true, &palette_stats));
+        match written {
+            Ok(line) => progress.success(line),
+            Err(err) => progress.failure(token_id, err),
+        }
+    });
+    progress.finish();
+    palette_stats.log();
 
-    if let Some(c) = &cfg.output.png_compression {
-        if c.enabled {
-            compress_png(&image_path, c.level)
-                .with_context(|| format!("PNG 圧縮に失敗しました: {}", image_path))?;
+    let (collection, all_metadata) = rayon::join(|| write_collection_json(cfg), || write_all_metadata_json(cfg));
+    collection?;
+    all_metadata?;
+
+    let report = check::verify_images(cfg)?;
+    print_image_verification(&report);
+    let failed = progress.failed_ids();
+    if !failed.is_empty() {
+        let failed: Vec<String> = failed.iter().map(u32::to_string).collect();
+        bail!("{} 件のトークンを作り直せませんでした: {}", failed.len(), failed.join(", "));
+    }
+    if report.has_problems() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// --watch で作り直すトークン
+enum Regenerate {
+    /// パターンを選び直して生成するトークン ID
+    Ids(Vec<u32>),
+    /// 選び直したパターンで合成するトークン（seed 指定時）
+    Patterns(Vec<(u32, Pattern)>),
+}
+
+/// レイヤーファイルの変更の影響を受けるトークンを調べる（影響を受けないトークンのパターンは使用済みとして登録する）
+///
+/// 削除・更新されたファイルを使っているトークンと、画像かメタデータがないトークンは作り直す。
+/// seed 指定時はさらに全トークンのパターンを選び直し、ファイルの追加や削除で選ばれるパターンが変わったトークンも作り直す
+/// （通常の生成をやり直した場合と同じ結果になる）。
+fn affected_tokens(generator: &Generator, changes: &[LayerChanges]) -> Result<Regenerate> {
+    let cfg = generator.config();
+    let touched: Vec<&Path> = changes.iter().flat_map(LayerChanges::touched).collect();
+    let uses_touched = |key: &str| key.split('|').any(|part| touched.iter().any(|path| path.starts_with(part)));
+    let existing: HashMap<u32, NftMetadata> = read_existing_metadata(cfg, &existing_token_ids(cfg))?.into_iter().collect();
+    generator.clear_patterns()?;
+
+    if cfg.seed.is_some() {
+        let mut patterns = Vec::new();
+        for token_id in 1..=cfg.count {
+            let mut rng = generator.token_rng(token_id);
+            let pattern = generator.choose_pattern(token_id, &mut rng)?;
+            let unchanged = existing
+                .get(&token_id)
+                .and_then(|meta| generator.pattern_key(meta))
+                .is_some_and(|key| key == pattern.key && !uses_touched(&key));
+            if !unchanged {
+                patterns.push((token_id, pattern));
+            }
         }
+        return Ok(Regenerate::Patterns(patterns));
     }
 
-    let metadata =
-        build_metadata(token_id, &cfg.metadata, &chosen_layers);
-    let metadata_path = format!("{}/{}.json", cfg.output.metadata_dir, token_id);
-    let json = serde_json::to_string_pretty(&metadata)
-        .context("メタデータのJSONシリアライズに失敗しました")?;
-    fs::write(&metadata_path, json)
-        .with_context(|| format!("メタデータの書き込みに失敗しました: {}", metadata_path))?;
+    let mut ids = Vec::new();
+    for token_id in 1..=cfg.count {
+        let kept = match existing.get(&token_id) {
+            Some(meta) => match generator.pattern_key(meta) {
+                Some(key) if !uses_touched(&key) => generator.reserve(&key, &generator.key_traits(&key))?,
+                _ => false,
+            },
+            None => false,
+        };
+        if !kept {
+            ids.push(token_id);
+        }
+    }
+    Ok(Regenerate::Ids(ids))
+}
 
-    println!(
-        "✅ token #{} -> {}, {} (pattern: {})",
-        token_id, image_path, metadata_path, pattern_key
+/// 前回からの設定の差分とレイヤーファイルの変更を表示する
+fn print_changes(config_changes: &[ConfigChange], layer_changes: &[LayerChanges]) {
+    if config_changes.is_empty() && layer_changes.is_empty() {
+        println!("変更はありません");
+        return;
+    }
+    if !config_changes.is_empty() {
+        println!("▶ 設定の変更");
+        for change in config_changes {
+            println!("  {}", change);
+        }
+    }
+    for changes in layer_changes {
+        println!("▶ Layer: {}", changes.layer);
+        for (mark, paths) in [("+", &changes.added), ("-", &changes.removed), ("~", &changes.modified)] {
+            for path in paths {
+                println!("  {} {}", mark, path.display());
+            }
+        }
+    }
+}
+
+/// 書き出し済みの画像とメタデータを output.s3 のバケットへアップロードする
+fn run_upload(cfg: &Config) -> Result<()> {
+    let Some(s3) = &cfg.output.s3 else {
+        bail!("--upload-only を使うには output.s3 を設定してください");
+    };
+    info!("☁ s3://{} へアップロードしています...", s3.bucket);
+    let report = upload_collection(cfg).context("S3 へのアップロードに失敗しました")?;
+    if report.rewritten > 0 {
+        info!("🔗 {} 件のメタデータの画像 URL を {} に書き換えました", report.rewritten, report.image_base_url);
+        write_all_metadata_json(cfg)?;
+    }
+    info!(
+        "☁ アップロード完了: 画像 {} 件, メタデータ {} 件 ({} バイト)",
+        report.images, report.metadata, report.bytes
     );
+    Ok(())
+}
+
+/// コレクションメタデータ（contractURI 用）を {metadata_dir}/collection.json に書き出す
+fn write_collection_json(cfg: &Config) -> Result<()> {
+    let Some(collection) = &cfg.metadata.collection else { return Ok(()); };
 
+    let path = format!("{}/collection.json", cfg.output.metadata_dir);
+    let json = serde_json::to_string_pretty(&CollectionMetadata::from(collection))
+        .context("コレクションメタデータのJSONシリアライズに失敗しました")?;
+    write_file(&path, json, cfg.output.writes_atomically())
+        .with_context(|| format!("コレクションメタデータの書き込みに失敗しました: {}", path))?;
+    info!("📄 {}", path);
     Ok(())
 }
 
+/// 全トークンのメタデータを1つの配列にまとめて {metadata_dir}/_metadata.json に書き出す
+fn write_all_metadata_json(cfg: &Config) -> Result<()> {
+    let mut all = Vec::new();
+    for token_id in 1..=cfg.count {
+        let path = format!("{}/{}.json", cfg.output.metadata_dir, token_id);
+        let Ok(text) = fs::read_to_string(&path) else { continue; };
+        let value: serde_json::Value = serde_json::from_str(&text)
+            .with_context(|| format!("メタデータのパースに失敗しました: {}", path))?;
+        all.push(value);
+    }
 
-/// 禁則ルール判定
-fn violates_constraints(cfg: &Config, layers: &[LayerChoice]) -> bool {
-    let Some(c) = &cfg.constraints else { return false; };
-    let Some(pairs) = &c.forbidden_pairs else { return false; };
+    let path = format!("{}/_metadata.json", cfg.output.metadata_dir);
+    let json = serde_json::to_string_pretty(&all)
+        .context("メタデータのJSONシリアライズに失敗しました")?;
+    write_file(&path, json, cfg.output.writes_atomically())
+        .with_context(|| format!("メタデータの書き込みに失敗しました: {}", path))?;
+    info!("📄 {} ({} tokens)", path, all.len());
+    Ok(())
+}
 
-    let present: HashSet<(String, String)> = layers
-        .iter()
-        .map(|l| (l.trait_type.clone(), l.value.clone()))
-        .collect();
+/// tracing のサブスクライバを設定する（text は時刻とターゲットを省いた簡潔な表示）
+fn init_logging(level: tracing::Level, format: LogFormat) {
+    let builder = tracing_subscriber::fmt().with_max_level(level);
+    match format {
+        LogFormat::Text => builder.without_time().with_target(false).init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
 
-    for p in pairs {
-        let a = (p.a.trait_type.clone(), p.a.value.clone());
-        let b = (p.b.trait_type.clone(), p.b.value.clone());
+/// check サブコマンド: 生成済みメタデータを検証し、禁則違反があれば exit code 1 で終了する
+/// --verify-images / --audit で問題が見つかった場合も exit code 1 で終了する。
+/// --audit --fix で欠けているトークンがあり、ほかに問題がなければ true（再生成が必要）を返す
+fn run_check(cfg: &Config, args: &CheckArgs) -> Result<bool> {
+    if args.chi_squared && !(args.alpha > 0.0 && args.alpha < 1.0) {
+        bail!("--alpha は 0 より大きく 1 未満で指定してください: {}", args.alpha);
+    }
+    let report = check_output(cfg)?;
+    print_rarity_stats(&report);
+    let chi_squared = args.chi_squared.then(|| {
+        let results = chi_squared_test(cfg, &report);
+        print_chi_squared(&results, args.alpha);
+        results
+    });
+    if let Some(path) = &args.export_csv {
+        export_csv(path, &report, chi_squared.as_deref())?;
+        println!("📄 レア度統計を CSV に書き出しました: {}\n", path.display());
+    }
+    if args.phash_check {
+        print_similar_images(cfg, args.phash_threshold)?;
+    }
+    let mut images_ok = true;
+    if args.verify_images {
+        let report = check::verify_images(cfg)?;
+        print_image_verification(&report);
+        images_ok = !report.has_problems();
+    }
+    let mut audit_ok = true;
+    if args.audit {
+        let audit = audit_output(cfg)?;
+        print_audit(cfg, &audit);
+        audit_ok = !audit.has_discrepancies();
+    }
 
-        if present.contains(&a) && present.contains(&b) {
-            return true;
+    if !report.missing_images.is_empty() {
+        println!(
+            "⚠ 画像ファイル ({}) が見つからないトークン: {} 件",
+            IMAGE_EXTENSIONS.join("/"),
+            report.missing_images.len()
+        );
+        for stem in report.missing_images.iter().take(MAX_EXAMPLES) {
+            println!("  - {}", stem);
         }
+        println!();
     }
 
-    false
-}
+    if !report.missing_animations.is_empty() {
+        println!("⚠ animation_url のファイルが見つからないトークン: {} 件", report.missing_animations.len());
+        for (stem, path) in report.missing_animations.iter().take(MAX_EXAMPLES) {
+            println!("  - {}: {}", stem, path);
+        }
+        println!();
+    }
 
-/// ディレクトリ以下の PNG ファイルを列挙
-fn collect_png_files(dir: &Path) -> Result<Vec<PathBuf>> {
-    let mut files = Vec::new();
-    for entry in WalkDir::new(dir)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-    {
-        let path = entry.into_path();
-        if let Some(ext) = path.extension() {
-            if ext.eq_ignore_ascii_case("png") {
-                files.push(path);
+    if let Some(t) = &cfg.output.thumbnail {
+        if report.missing_thumbnails.is_empty() {
+            println!("✅ すべてのトークンのサムネイルがあります ({})\n", t.dir);
+        } else {
+            println!(
+                "⚠ サムネイル ({}) が見つからないトークン: {} 件",
+                t.dir,
+                report.missing_thumbnails.len()
+            );
+            for stem in report.missing_thumbnails.iter().take(MAX_EXAMPLES) {
+                println!("  - {}", stem);
+            }
+            println!();
+        }
+    }
+
+    if cfg.output.additional_outputs.as_ref().is_some_and(|v| !v.is_empty()) {
+        if report.missing_additional_outputs.is_empty() {
+            println!("✅ すべてのトークンの追加の出力画像があります\n");
+        } else {
+            println!("⚠ 追加の出力画像が見つからないトークン: {} 件", report.missing_additional_outputs.len());
+            for (stem, path) in report.missing_additional_outputs.iter().take(MAX_EXAMPLES) {
+                println!("  - {}: {}", stem, path);
             }
+            println!();
         }
     }
-    Ok(files)
+
+    let has_forbidden = cfg.constraints.as_ref().is_some_and(|c| {
+        c.forbidden_pairs.as_ref().is_some_and(|p| !p.is_empty())
+            || c.forbidden_groups.as_ref().is_some_and(|g| !g.is_empty())
+    });
+    if !has_forbidden {
+        println!("(constraints.forbidden_pairs / forbidden_groups が未設定のため、禁則チェックはスキップしました)");
+    } else {
+        println!("==============================");
+        println!(" Forbidden-pairs Check");
+        println!(" Violations(tokens): {}", report.violation_count);
+        println!("==============================");
+
+        if report.violation_count == 0 {
+            println!("✅ 禁則違反は見つかりませんでした");
+        } else {
+            println!("❌ 禁則違反が見つかりました（最大 {} 件表示）:", MAX_EXAMPLES);
+            for (file, msg) in &report.violation_examples {
+                println!("  - {} : {}", file, msg);
+            }
+        }
+    }
+
+    if report.violation_count > 0 || !images_ok || (!audit_ok && !args.fix) {
+        std::process::exit(1);
+    }
+    Ok(!audit_ok)
 }
 
-/// パスから拡張子抜きのファイル名を取得
-fn file_stem(path: &Path) -> Option<String> {
-    path.file_stem()
-        .and_then(|s| s.to_str())
-        .map(|s| s.to_string())
+/// カイ二乗検定の結果をレイヤーごとに表示する
+fn print_chi_squared(results: &[ChiSquaredResult], alpha: f64) {
+    println!("==============================");
+    println!(" Chi-squared Goodness-of-fit");
+    println!(" alpha: {}", alpha);
+    println!("==============================");
+    for r in results {
+        println!(
+            "{} {:20} χ²={:10.3} df={:3} p={:.4} (n={})",
+            if r.passed(alpha) { "✅" } else { "❌" },
+            r.trait_type,
+            r.statistic,
+            r.degrees_of_freedom,
+            r.p_value,
+            r.observed_total
+        );
+    }
+    if results.iter().any(|r| !r.passed(alpha)) {
+        println!("❌ の付いたレイヤーは設定した rarity から有意にずれています（禁則や重複回避の影響も含みます）");
+    }
+    println!();
 }
 
-/// レイヤー組み合わせを一意に表すキーを作成
-/// ここではフルパス文字列を "|" で連結している
-fn build_pattern_key(layers: &[LayerChoice]) -> String {
-    let mut parts = Vec::with_capacity(layers.len());
-    for l in layers {
-        let p = l.path.to_string_lossy();
-        parts.push(p.to_string());
+/// 知覚ハッシュのハミング距離が threshold 未満の画像の組を `token_A,token_B,hamming_distance` 形式で表示する
+fn print_similar_images(cfg: &Config, threshold: u32) -> Result<()> {
+    let hashes = image_hashes(cfg)?;
+    let pairs = similar_pairs(&hashes, threshold);
+    println!("==============================");
+    println!(" Perceptual-hash Check");
+    println!(" Images: {}, threshold: {}", hashes.len(), threshold);
+    println!("==============================");
+    if pairs.is_empty() {
+        println!("✅ 見た目がほぼ同じ画像の組は見つかりませんでした\n");
+        return Ok(());
+    }
+    println!("⚠ 見た目がほぼ同じ画像の組: {} 件", pairs.len());
+    println!("token_A,token_B,hamming_distance");
+    for (a, b, d) in &pairs {
+        println!("{},{},{}", a, b, d);
     }
-    parts.join("|")
+    println!();
+    Ok(())
 }
 
-/// レア度テーブル付きの重み付きランダム選択
-fn choose_layer_file_with_rng(
-    files: &[PathBuf],
-    rarity: &Option<HashMap<String, f32>>,
-    rng: &mut ThreadRng,
-) -> PathBuf {
-    if let Some(rarity_map) = rarity {
-        let weights: Vec<f32> = files
-            .iter()
-            .map(|path| {
-                let file_name = path
-                    .file_name()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or("");
-                *rarity_map.get(file_name).unwrap_or(&1.0)
-            })
-            .collect();
+/// 該当するトークン ID の件数と先頭 MAX_EXAMPLES 件を表示する（空なら何も表示しない）
+fn print_ids(label: &str, ids: &[u32]) {
+    if ids.is_empty() {
+        return;
+    }
+    println!("❌ {}: {} 件", label, ids.len());
+    for id in ids.iter().take(MAX_EXAMPLES) {
+        println!("  - {}", id);
+    }
+}
 
-        if let Ok(dist) = WeightedIndex::new(weights.iter().cloned()) {
-            let idx = dist.sample(rng);
-            return files[idx].clone();
+/// --audit の結果を表示する
+fn print_audit(cfg: &Config, audit: &AuditReport) {
+    println!("==============================");
+    println!(" Output Audit");
+    println!(" Max token ID: {}", audit.max_id);
+    println!("==============================");
+    if !audit.has_discrepancies() {
+        println!("✅ 画像とメタデータの ID はすべて揃っています\n");
+        return;
+    }
+    print_ids("メタデータはあるが画像がないトークン", &audit.metadata_only);
+    print_ids("画像はあるがメタデータがないトークン", &audit.image_only);
+    print_ids("1〜最大 ID の間の欠番", &audit.gaps);
+    if audit.max_id > cfg.count {
+        println!("⚠ count ({}) より大きい ID があります（--fix では再生成されません）", cfg.count);
+    }
+    println!();
+}
+
+/// --verify-images の結果を表示する
+fn print_image_verification(report: &ImageVerifyReport) {
+    println!("==============================");
+    println!(" Image Integrity Check");
+    println!(" Images: {}", report.checked);
+    if let Some((w, h)) = report.expected_dimensions {
+        println!(" Expected size: {}x{}", w, h);
+    }
+    println!("==============================");
+    if report.header_only {
+        println!("⚠ AVIF はデコーダを同梱していないため、ヘッダーのサイズだけを確認しています（AV1 データの破損は検出できません）");
+    }
+    if !report.has_problems() {
+        if report.header_only {
+            println!("✅ すべての画像のヘッダーを読み取れました\n");
         } else {
-            eprintln!("⚠ レア度設定が不正です。均等ランダムにフォールバックします。");
+            println!("✅ すべての画像を正常にデコードできました\n");
+        }
+        return;
+    }
+
+    print_ids("メタデータに対応する画像がないトークン", &report.missing_images);
+    print_ids("画像に対応するメタデータがないトークン", &report.missing_metadata);
+    if !report.corrupt.is_empty() {
+        println!("❌ デコードできない画像: {} 件", report.corrupt.len());
+        for (id, e) in report.corrupt.iter().take(MAX_EXAMPLES) {
+            println!("  - {} : {}", id, e);
+        }
+    }
+    if !report.dimension_mismatches.is_empty() {
+        println!("❌ サイズが異なる画像: {} 件", report.dimension_mismatches.len());
+        for (id, (w, h)) in report.dimension_mismatches.iter().take(MAX_EXAMPLES) {
+            println!("  - {} : {}x{}", id, w, h);
+        }
+    }
+    println!();
+}
+
+/// stats サブコマンド: トレイトごとの出現率だけを表示する
+fn run_stats(cfg: &Config, report_html: Option<&Path>) -> Result<()> {
+    let report = check_output(cfg)?;
+    print_rarity_stats(&report);
+    if let Some(path) = report_html {
+        write_html_report(cfg, &report, path)
+            .with_context(|| format!("HTML レポートの書き出しに失敗しました: {}", path.display()))?;
+        println!("📄 HTML レポートを書き出しました: {}", path.display());
+    }
+    Ok(())
+}
+
+/// export-csv サブコマンド: 全トークンのトレイトを CSV に書き出す
+fn run_export_csv(cfg: &Config, output: &Path) -> Result<()> {
+    let rows = export_traits_csv(Path::new(&cfg.output.metadata_dir), cfg.metadata.format, output)
+        .with_context(|| format!("CSV の書き出しに失敗しました: {}", output.display()))?;
+    println!("📄 {} トークン分のトレイトを CSV に書き出しました: {}", rows, output.display());
+    Ok(())
+}
+
+/// check / stats の結果はログではなくレポートとして標準出力にそのまま表示する
+fn print_rarity_stats(report: &CheckReport) {
+    println!("==============================");
+    println!(" NFT Rarity Check");
+    println!(" Total tokens: {}", report.total);
+    println!("==============================\n");
+
+    for (trait_type, values) in &report.stats {
+        println!("▶ Trait: {}", trait_type);
+
+        let mut sorted: Vec<_> = values.iter().collect();
+        sorted.sort_by_key(|b| std::cmp::Reverse(*b.1));
+
+        for (value, count) in sorted {
+            let ratio = *count as f64 / report.total as f64 * 100.0;
+            println!("  {:30} {:5} ({:.2}%)", value, count, ratio);
+        }
+        println!();
+    }
+}
+
+/// patch-cid サブコマンド: メタデータの image に含まれるプレースホルダーを CID に置換
+fn run_patch_cid(cfg: &Config, cid: &str) -> Result<()> {
+    let placeholder = cfg
+        .metadata
+        .ipfs_placeholder
+        .as_deref()
+        .context("metadata.ipfs_placeholder が設定されていません")?;
+
+    let report = patch_cid(Path::new(&cfg.output.metadata_dir), placeholder, cid)?;
+
+    for (path, image) in &report.foreign {
+        warn!(
+            "⚠ {:?} にはプレースホルダー以外の URL / CID が含まれています（変更しません）: {}",
+            path, image
+        );
+    }
+    info!(
+        "✅ CID を置換しました: {} 件（置換済み {} 件, 対象外 {} 件）",
+        report.patched.len(),
+        report.unchanged.len(),
+        report.foreign.len()
+    );
+    Ok(())
+}
+
+/// merkle サブコマンド: トークンメタデータから Merkle ツリーを作り、ルートと証明を書き出す
+fn run_merkle(cfg: &Config) -> Result<()> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(&cfg.output.metadata_dir)
+        .with_context(|| format!("metadata ディレクトリが読めません: {}", cfg.output.metadata_dir))?
+    {
+        let path = entry?.path();
+        if path.extension().and_then(|s| s.to_str()) != Some("json") {
+            continue;
         }
+        // collection.json や merkle_proofs.json などトークン以外の JSON は対象外
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            ids.push(id);
+        }
+    }
+    if ids.is_empty() {
+        bail!("{} にトークンのメタデータがありません", cfg.output.metadata_dir);
     }
+    ids.sort_unstable();
+
+    let leaves = ids
+        .iter()
+        .map(|id| token_leaf_hash(cfg, *id))
+        .collect::<Result<Vec<_>>>()?;
+    let tree = MerkleTree::new(leaves);
+    let root = tree.root().expect("葉が1つ以上あればルートがある");
 
-    files
-        .choose(rng)
-        .expect("レイヤーファイルが空です")
-        .clone()
+    let proofs = MerkleProofs {
+        root: merkle::to_hex(&root),
+        proofs: ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (*id, tree.proof(i)))
+            .collect(),
+    };
+    let path = format!("{}/{}", cfg.output.metadata_dir, MERKLE_PROOFS_FILE);
+    let json = serde_json::to_string_pretty(&proofs).context("Merkle 証明のJSONシリアライズに失敗しました")?;
+    write_file(&path, json, cfg.output.writes_atomically())
+        .with_context(|| format!("Merkle 証明の書き込みに失敗しました: {}", path))?;
+
+    info!("🌳 Merkle root: {}", proofs.root);
+    info!("📄 {} ({} tokens)", path, ids.len());
+    Ok(())
 }
 
-/// PNG レイヤーを順に重ねて1枚にする
-fn compose_layers(layers: &[LayerChoice]) -> Result<RgbaImage> {
-    if layers.is_empty() {
-        bail!("レイヤーが1枚も指定されていません");
+/// rarity-rank サブコマンド: 全トークンのレア度スコアと順位を rarity_ranks.json に書き出す
+fn run_rarity_rank(cfg: &Config, annotate: bool) -> Result<()> {
+    let ranks = rarity_ranks(Path::new(&cfg.output.metadata_dir), cfg.metadata.format)
+        .with_context(|| format!("メタデータの読み込みに失敗しました: {}", cfg.output.metadata_dir))?;
+    if ranks.is_empty() {
+        bail!("{} にトークンのメタデータがありません", cfg.output.metadata_dir);
     }
+    let path = format!("{}/{}", cfg.output.metadata_dir, RARITY_RANKS_FILE);
+    let json = serde_json::to_string_pretty(&ranks).context("レア度順位のJSONシリアライズに失敗しました")?;
+    write_file(&path, json, cfg.output.writes_atomically())
+        .with_context(|| format!("レア度順位の書き込みに失敗しました: {}", path))?;
+
+    println!("==============================");
+    println!(" Rarity Rank");
+    println!(" Total tokens: {}", ranks.len());
+    println!("==============================");
+    for rank in ranks.iter().take(MAX_EXAMPLES) {
+        println!("  #{:<5} token {:>6}  score {:.2}", rank.rank, rank.id, rank.score);
+    }
+    println!("\n📄 {}", path);
+
+    if annotate {
+        let annotated = annotate_rarity(cfg, &ranks).context("メタデータへのレア度の書き込みに失敗しました")?;
+        println!("✏ {} 件のメタデータに rarity_score と rarity_rank を書き込みました", annotated);
+    }
+    Ok(())
+}
+
+/// merkle --verify: 現在のメタデータと merkle_proofs.json の証明からルートを再計算して照合する
+fn run_merkle_verify(cfg: &Config, token_id: u32) -> Result<()> {
+    let path = format!("{}/{}", cfg.output.metadata_dir, MERKLE_PROOFS_FILE);
+    let text = fs::read_to_string(&path).with_context(|| {
+        format!("{} が読めません。先に merkle サブコマンドを実行してください", path)
+    })?;
+    let proofs: MerkleProofs =
+        serde_json::from_str(&text).with_context(|| format!("JSON パース失敗: {}", path))?;
+    let root = merkle::from_hex(&proofs.root)
+        .with_context(|| format!("{} の root が不正です: {}", path, proofs.root))?;
+    let proof = proofs
+        .proofs
+        .get(&token_id)
+        .with_context(|| format!("トークン #{} の証明が {} にありません", token_id, path))?;
+
+    let leaf = token_leaf_hash(cfg, token_id)?;
+    if merkle::verify(&leaf, proof, &root) {
+        info!("✅ トークン #{} の証明は root {} と一致しました", token_id, proofs.root);
+        Ok(())
+    } else {
+        bail!(
+            "トークン #{} の証明が root {} と一致しません（メタデータが変更された可能性があります）",
+            token_id,
+            proofs.root
+        );
+    }
+}
+
+/// {id}.json のバイト列から Merkle の葉を計算する
+fn token_leaf_hash(cfg: &Config, token_id: u32) -> Result<merkle::Hash> {
+    let path = format!("{}/{}.json", cfg.output.metadata_dir, token_id);
+    let data = fs::read(&path).with_context(|| format!("メタデータの読み込みに失敗しました: {}", path))?;
+    Ok(merkle::leaf_hash(&data))
+}
+
+/// 既存のメタデータは `{metadata_dir}/{id}.json` から読むため、metadata_path_template で別の場所に
+/// 書き出している場合は、それを読む機能を使えないようにする
+fn ensure_default_metadata_paths(cfg: &Config, feature: &str) -> Result<()> {
+    if !cfg.output.has_default_metadata_paths() {
+        bail!(
+            "output.metadata_path_template（{}）を指定している場合、{}使えません",
+            cfg.output.metadata_path_template.as_deref().unwrap_or_default(),
+            feature
+        );
+    }
+    Ok(())
+}
+
+/// 画像とメタデータが両方揃っている既存トークンの ID（画像は image_path_template の展開先を見る）
+fn existing_token_ids(cfg: &Config) -> Vec<u32> {
+    (1..=cfg.count)
+        .filter(|&token_id| {
+            let metadata_path = format!("{}/{}.json", cfg.output.metadata_dir, token_id);
+            Path::new(&metadata_path).is_file() && written_image_path(cfg, token_id).is_some_and(|p| p.is_file())
+        })
+        .collect()
+}
+
+/// 画像とメタデータが両方揃っている既存トークンを読み込み、(token_id, パターンキー, トレイト一覧) を返す
+///
+/// output.metadata_overrides_dir でメタデータを差し替えたトークンは、トレイトからパターンを復元できないので除く
+fn load_existing_tokens(generator: &Generator, token_ids: &[u32]) -> Result<Vec<(u32, String, TraitSet)>> {
+    let token_ids: Vec<u32> = token_ids.iter().copied().filter(|id| !generator.has_metadata_override(*id)).collect();
+    read_existing_metadata(generator.config(), &token_ids)?
+        .into_iter()
+        .map(|(token_id, meta)| {
+            let key = existing_pattern_key(generator, token_id, &meta)?;
+            let present = generator.key_traits(&key);
+            Ok((token_id, key, present))
+        })
+        .collect()
+}
+
+/// 指定したトークンの既存メタデータを読み込む
+fn read_existing_metadata(cfg: &Config, token_ids: &[u32]) -> Result<Vec<(u32, NftMetadata)>> {
+    token_ids
+        .iter()
+        .map(|&token_id| {
+            let metadata_path = format!("{}/{}.json", cfg.output.metadata_dir, token_id);
+            let text = fs::read_to_string(&metadata_path)
+                .with_context(|| format!("メタデータの読み込みに失敗しました: {}", metadata_path))?;
+            let meta = parse_token_metadata(&text, cfg.metadata.format)
+                .with_context(|| format!("メタデータのパースに失敗しました: {}", metadata_path))?;
+            Ok((token_id, meta))
+        })
+        .collect()
+}
 
-    let first = image::open(&layers[0].path)
-        .with_context(|| format!("画像の読み込みに失敗しました: {:?}", layers[0].path))?
-        .into_rgba8();
-    let (width, height) = first.dimensions();
+/// 既存メタデータのパターンキー（現在のレイヤー構成で表せなければエラー）
+fn existing_pattern_key(generator: &Generator, token_id: u32, meta: &NftMetadata) -> Result<String> {
+    generator.pattern_key(meta).with_context(|| {
+        format!(
+            "既存メタデータのトレイトが現在のレイヤー構成と一致しません: {}/{}.json",
+            generator.config().output.metadata_dir,
+            token_id
+        )
+    })
+}
 
-    let mut base: RgbaImage =
-        ImageBuffer::from_fn(width, height, |x, y| *first.get_pixel(x, y));
+/// --start-id: start_id より前の既存トークンのパターンを使用済みとして登録し、
+/// 既存コレクションに続けて生成しても問題ないかを確かめる
+fn prepare_extension(generator: &Generator, start_id: u32) -> Result<()> {
+    let cfg = generator.config();
+    let ids: Vec<u32> = existing_token_ids(cfg).into_iter().filter(|id| *id < start_id).collect();
+    if ids.is_empty() {
+        warn!("⚠ token #{} より前の既存トークンが見つかりません", start_id);
+        return Ok(());
+    }
+    if ids.len() < (start_id - 1) as usize {
+        warn!(
+            "⚠ token #1〜#{} のうち {} 件は画像かメタデータがないため、パターンの重複確認に使えません",
+            start_id - 1,
+            (start_id - 1) as usize - ids.len()
+        );
+    }
 
-    for layer in &layers[1..] {
-        let img = image::open(&layer.path)
-            .with_context(|| format!("画像の読み込みに失敗しました: {:?}", layer.path))?
-            .into_rgba8();
+    let existing = read_existing_metadata(cfg, &ids)?;
 
-        if img.width() != width || img.height() != height {
+    // 新しいトークンの画像 URL が既存コレクションと同じ場所を指すか確かめる
+    let image_base = cfg
+        .metadata
+        .ipfs_placeholder
+        .as_deref()
+        .unwrap_or(&cfg.metadata.base_image_url);
+    for (token_id, meta) in &existing {
+        let existing_base = meta.image.rsplit_once('/').map_or("", |(base, _)| base);
+        if existing_base != image_base {
             bail!(
-                "レイヤーのサイズが一致しません: {:?} ({}, {}) != base ({}, {})",
-                layer.path,
-                img.width(),
-                img.height(),
-                width,
-                height
+                "既存トークン #{} の画像 URL ({}) が現在の base_image_url ({}) と一致しません。\
+                 既存コレクションと同じ設定で実行してください",
+                token_id,
+                meta.image,
+                image_base
             );
         }
+    }
 
-        overlay_rgba(&mut base, &img);
+    let mut used_files: Vec<HashSet<String>> = vec![HashSet::new(); generator.candidates().len()];
+    for (token_id, meta) in existing.iter().filter(|(id, _)| !generator.has_metadata_override(*id)) {
+        let key = existing_pattern_key(generator, *token_id, meta)?;
+        for (files, part) in used_files.iter_mut().zip(key.split('|')) {
+            files.insert(part.to_string());
+        }
+        if !generator.reserve(&key, &generator.key_traits(&key))? {
+            warn!("⚠ 既存トークン #{} のパターンが他の既存トークンと重複しています", token_id);
+        }
+    }
+
+    // 既存コレクションで一度も使われていないファイルは、元の生成後に追加された可能性がある
+    for (candidate, used) in generator.candidates().iter().zip(&used_files) {
+        let unused: Vec<String> = candidate
+            .files
+            .iter()
+            .filter(|path| !used.contains(path.to_string_lossy().as_ref()))
+            .filter_map(|path| file_stem(path))
+            .collect();
+        if !unused.is_empty() {
+            warn!(
+                "⚠ レイヤー '{}' に既存トークンで一度も使われていないファイルがあります: {}（元の生成後に追加した場合、トレイトの分布が既存分と変わります）",
+                candidate.layer.name,
+                unused.join(", ")
+            );
+        }
     }
 
-    Ok(base)
+    info!(
+        "📚 既存トークン {} 件のパターンを読み込みました。token #{} から生成します",
+        existing.len(),
+        start_id
+    );
+    Ok(())
 }
 
-/// base の上に overlay をαブレンドで重ねる
-fn overlay_rgba(base: &mut RgbaImage, overlay: &RgbaImage) {
-    for (x, y, pixel) in overlay.enumerate_pixels() {
-        let [or, og, ob, oa] = pixel.0;
-        let alpha = oa as f32 / 255.0;
-        if alpha == 0.0 {
-            continue;
+/// 生成したトークンの画像・サムネイル・アニメーション・メタデータを書き出す。成功時はログ用の1行を返す
+/// compress_png_inline が false なら PNG 圧縮は行わない（--compress-post で後からまとめて圧縮する）
+fn write_token(
+    generator: &Generator,
+    token: &GeneratedToken,
+    compress_png_inline: bool,
+    palette_stats: &PaletteStats,
+) -> Result<String> {
+    let cfg = generator.config();
+    let token_id = token.token_id;
+    let image_path = generator.image_path(token_id, &token.metadata).to_string_lossy().to_string();
+    create_parent_dir(&image_path)?;
+    // 圧縮してから最終的なパスに置くので、圧縮前の PNG が一時的にでも見えることはない
+    let inline_level = cfg
+        .output
+        .png_compression
+        .as_ref()
+        .filter(|c| c.enabled && compress_png_inline && cfg.output.format == ImageFormat::Png)
+        .map(|c| c.level);
+    if cfg.output.format == ImageFormat::Png && cfg.output.palette_quantize == Some(true) {
+        let report = save_png_palette(&token.image, &image_path, inline_level, &cfg.output)
+            .with_context(|| format!("画像の保存に失敗しました: {}", image_path))?;
+        if report.indexed_bytes.is_none() {
+            warn!("⚠ token #{} は {} 色を超えるため、RGBA の PNG で保存しました", token_id, MAX_PALETTE_COLORS);
+        }
+        palette_stats.record(report);
+    } else {
+        match inline_level {
+            Some(level) => save_png_compressed(&token.image, &image_path, level, &cfg.output),
+            None => save_image(&token.image, &image_path, cfg.output.format, &cfg.output),
         }
+        .with_context(|| format!("画像の保存に失敗しました: {}", image_path))?;
+    }
 
-        let base_pixel = base.get_pixel_mut(x, y);
-        let [br, bg, bb, ba] = base_pixel.0;
+    if let Some(thumb) = &cfg.output.thumbnail {
+        let thumb_path = format!("{}/{}.{}", thumb.dir, token_id, thumb.format.extension());
+        let (w, h) = fit_within(token.image.dimensions(), (thumb.width, thumb.height));
+        save_image(&image::imageops::thumbnail(&token.image, w, h), &thumb_path, thumb.format, &cfg.output)
+            .with_context(|| format!("サムネイルの保存に失敗しました: {}", thumb_path))?;
+    }
 
-        let ba_f = ba as f32 / 255.0;
-        let out_a = alpha + ba_f * (1.0 - alpha);
+    // 合成は1回だけで、同じ画像を形式・サイズを変えて書き出す
+    for extra in cfg.output.additional_outputs.iter().flatten() {
+        let extra_path = format!("{}/{}.{}", extra.dir, token_id, extra.format.extension());
+        save_additional_output(&token.image, &extra_path, extra, &cfg.output)
+            .with_context(|| format!("追加の画像の保存に失敗しました: {}", extra_path))?;
+    }
 
-        let blend = |oc: u8, bc: u8| -> u8 {
-            let oc_f = oc as f32 / 255.0;
-            let bc_f = bc as f32 / 255.0;
-            let out = if out_a == 0.0 {
-                0.0
-            } else {
-                (oc_f * alpha + bc_f * ba_f * (1.0 - alpha)) / out_a
-            };
-            (out * 255.0).round().clamp(0.0, 255.0) as u8
-        };
+    if let Some(anim) = &cfg.output.animation {
+        let anim_path = format!("{}/{}.{}", cfg.output.image_dir, token_id, anim.format.extension());
+        let quality = cfg.output.webp_quality.unwrap_or(80.0);
+        write_with(Path::new(&anim_path), cfg.output.writes_atomically(), |target| {
+            write_animation(&token.frames, &target.to_string_lossy(), anim, quality)
+        })
+        .with_context(|| format!("アニメーションの保存に失敗しました: {}", anim_path))?;
+    }
 
-        let out_r = blend(or, br);
-        let out_g = blend(og, bg);
-        let out_b = blend(ob, bb);
-        let out_a_u8 = (out_a * 255.0).round().clamp(0.0, 255.0) as u8;
+    let json = generator.metadata_json(token_id, &token.metadata)?;
+    let metadata_path = generator.metadata_path(token_id, &token.metadata).to_string_lossy().to_string();
+    create_parent_dir(&metadata_path)?;
+    write_file(&metadata_path, json, cfg.output.writes_atomically())
+        .with_context(|| format!("メタデータの書き込みに失敗しました: {}", metadata_path))?;
 
-        *base_pixel = image::Rgba([out_r, out_g, out_b, out_a_u8]);
+    // フックはこのトークンを処理している rayon のタスク内で同期的に実行する
+    if let Some(hooks) = &cfg.hooks {
+        hooks::run_post_token(hooks, token_id, &image_path, &metadata_path);
     }
-}
 
-/// NFT メタデータを構築
-fn build_metadata(
-    token_id: u32,
-    metadata_config: &MetadataConfig,
-    layers: &[LayerChoice],
-) -> NftMetadata {
-    let name = if metadata_config.name.is_empty() {
-        format!("#{}", token_id)
+    let origin = if token.layers.is_empty() {
+        "override image".to_string()
     } else {
-        format!("{} #{}", metadata_config.name, token_id)
+        format!("pattern: {}", token.pattern_key)
     };
-    let description = metadata_config.description.clone();
-    let image = format!("{}/{}.png", metadata_config.base_image_url, token_id);
-    let attributes = layers
-        .iter()
-        .map(|l| Attribute {
-            trait_type: l.trait_type.clone(),
-            value: l.value.clone(),
+    Ok(format!("✅ token #{} -> {}, {} ({})", token_id, image_path, metadata_path, origin))
+}
+
+/// パステンプレートでサブディレクトリに出力する場合に、親ディレクトリを作る
+fn create_parent_dir(path: &str) -> Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir).with_context(|| format!("出力ディレクトリの作成に失敗しました: {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// --compress-post: 書き出し済みの PNG を rayon で並列に圧縮し、圧縮前後の合計サイズを表示する
+fn compress_post(cfg: &Config, image_paths: &[PathBuf]) -> Result<()> {
+    let Some(c) = cfg.output.png_compression.as_ref().filter(|c| c.enabled) else {
+        warn!("⚠ output.png_compression が有効でないため、--compress-post は無視されます");
+        return Ok(());
+    };
+    if cfg.output.format != ImageFormat::Png {
+        warn!("⚠ output.format が png ではないため、--compress-post は無視されます");
+        return Ok(());
+    }
+
+    info!("🗜 {} 枚の PNG を圧縮しています (level {})...", image_paths.len(), c.level.min(6));
+    let sizes = image_paths
+        .par_iter()
+        .map(|path| {
+            let path = path.to_string_lossy();
+            let file_size = |path: &str| {
+                fs::metadata(path)
+                    .map(|m| m.len())
+                    .with_context(|| format!("ファイルサイズを取得できません: {}", path))
+            };
+            let before = file_size(&path)?;
+            compress_png(&path, c.level, cfg.output.writes_atomically()).with_context(|| format!("PNG 圧縮に失敗しました: {}", path))?;
+            Ok((before, file_size(&path)?))
         })
-        .collect();
+        .collect::<Result<Vec<(u64, u64)>>>()?;
 
-    NftMetadata {
-        name,
-        description,
-        image,
-        edition: token_id,
-        attributes,
+    let before: u64 = sizes.iter().map(|(b, _)| b).sum();
+    let after: u64 = sizes.iter().map(|(_, a)| a).sum();
+    info!(
+        "🗜 PNG 圧縮: {} → {} バイト（{} バイト削減, {:.1}%）",
+        before,
+        after,
+        before.saturating_sub(after),
+        if before > 0 { (before.saturating_sub(after)) as f64 / before as f64 * 100.0 } else { 0.0 }
+    );
+    Ok(())
+}
+
+/// --preview: count 個のトークンを本番と同じ制約・レア度で生成し、{image_dir の親}/preview に画像と
+/// 一覧画像（sheet.png）を書き出す。前回のプレビューのファイルは削除してから書き出し、メタデータは書き出さない
+fn run_preview(generator: &Generator, count: u32) -> Result<()> {
+    let cfg = generator.config();
+    let dir = Path::new(&cfg.output.image_dir)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .join("preview");
+    fs::create_dir_all(&dir).with_context(|| format!("プレビューの出力先を作成できませんでした: {}", dir.display()))?;
+    remove_previous_preview(&dir)?;
+
+    let token_ids: Vec<u32> = (1..=count).collect();
+    let images = Mutex::new(Vec::with_capacity(token_ids.len()));
+    generator.for_each_token(&token_ids, |token_id, result| {
+        let saved = result.map_err(anyhow::Error::from).and_then(|token| {
+            let path = dir.join(format!("{}.{}", token_id, cfg.output.format.extension()));
+            save_image(&token.image, &path.to_string_lossy(), cfg.output.format, &cfg.output)?;
+            images.lock().expect("images のロックに失敗しました").push((token_id, token.image));
+            Ok(path)
+        });
+        match saved {
+            Ok(path) => info!("🖼 preview #{} -> {}", token_id, path.display()),
+            Err(err) => error!("❌ Error in preview #{}: {:?}", token_id, err),
+        }
+    });
+
+    let mut images = images.into_inner().expect("images のロックに失敗しました");
+    if images.is_empty() {
+        bail!("プレビューを1つも生成できませんでした");
+    }
+    images.sort_by_key(|(token_id, _)| *token_id);
+
+    // 大きい画像は縮小し、正方形に近いグリッドに並べる
+    let (w, h) = images[0].1.dimensions();
+    let (tile_w, tile_h) = if w > PREVIEW_TILE_SIZE || h > PREVIEW_TILE_SIZE {
+        fit_within((w, h), (PREVIEW_TILE_SIZE, PREVIEW_TILE_SIZE))
+    } else {
+        (w, h)
+    };
+    let columns = (images.len() as f64).sqrt().ceil() as u32;
+    let rows = (images.len() as u32).div_ceil(columns);
+    let mut sheet = image::RgbaImage::new(tile_w * columns, tile_h * rows);
+    for (i, (_, img)) in images.iter().enumerate() {
+        let tile = image::imageops::thumbnail(img, tile_w, tile_h);
+        let (x, y) = (i as u32 % columns * tile_w, i as u32 / columns * tile_h);
+        image::imageops::overlay(&mut sheet, &tile, x as i64, y as i64);
+    }
+    let sheet_path = dir.join("sheet.png");
+    sheet.save(&sheet_path).with_context(|| format!("一覧画像の保存に失敗しました: {}", sheet_path.display()))?;
+    info!("🖼 プレビュー {} 件の一覧画像を書き出しました: {}", images.len(), sheet_path.display());
+    Ok(())
+}
+
+/// 前回の --preview が書き出したファイル（`{id}.{拡張子}` と sheet.png）だけを削除する
+/// （preview/ に置かれた他のファイルやサブディレクトリには触れない）
+fn remove_previous_preview(dir: &Path) -> Result<()> {
+    let extensions = [ImageFormat::Png, ImageFormat::Webp, ImageFormat::Jpeg, ImageFormat::Avif].map(ImageFormat::extension);
+    let entries = fs::read_dir(dir).with_context(|| format!("プレビューの出力先を読み込めませんでした: {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let written_by_preview = path.file_name().is_some_and(|name| name == "sheet.png")
+            || (path.file_stem().and_then(|s| s.to_str()).is_some_and(|s| s.parse::<u32>().is_ok())
+                && path.extension().and_then(|e| e.to_str()).is_some_and(|e| extensions.contains(&e)));
+        if written_by_preview && path.is_file() {
+            fs::remove_file(&path).with_context(|| format!("前回のプレビューを削除できませんでした: {}", path.display()))?;
+        }
     }
+    Ok(())
 }
 
+/// --dry-run: ファイルを書き出さずに全トークンのパターン選択をシミュレーションし、
+/// 組み合わせ数・推定出力サイズ・パターンの例を表示する
+fn run_dry_run(generator: &Generator) -> Result<()> {
+    const SAMPLE_COUNT: usize = 5;
+
+    let cfg = generator.config();
+    let mut selections = Vec::with_capacity(cfg.count as usize);
+    let mut failures = 0usize;
+    for token_id in 1..=cfg.count {
+        let mut rng = generator.token_rng(token_id);
+        match generator.choose_pattern(token_id, &mut rng) {
+            Ok(pattern) => selections.push((token_id, pattern)),
+            Err(err) => {
+                failures += 1;
+                error!("❌ token #{} のパターンを選べません: {:?}", token_id, anyhow::Error::from(err));
+            }
+        }
+    }
 
-fn compress_png(path: &str, level: u8) -> anyhow::Result<()> {
-    let level = level.min(6);
-    let mut options = Options::from_preset(level);
-    options.fix_errors = true;
+    let samples: Vec<_> = selections
+        .choose_multiple(&mut thread_rng(), SAMPLE_COUNT)
+        .collect();
 
-    let p = PathBuf::from(path);
-    let in_file = InFile::Path(p.clone());
-    let out_file = OutFile::Path {
-        path: Some(p),
-        preserve_attrs: true,
+    // サンプルをメモリ上で合成・エンコードし、1トークンあたりの平均サイズを求める
+    let mut sample_bytes = 0usize;
+    for (token_id, pattern) in &samples {
+        let mut composed = generator.compose(&pattern.layers, 0)?;
+        // ノイズは PNG の圧縮率を大きく下げるので、見積もりにも含める
+        generator.decorate(*token_id, std::slice::from_mut(&mut composed));
+        sample_bytes += encode_image(&composed, cfg.output.format, &cfg.output)?.len();
+        let metadata = generator.metadata(*token_id, &pattern.layers);
+        sample_bytes += generator.metadata_json(*token_id, &metadata)?.len();
+    }
+    let estimated_mb = if samples.is_empty() {
+        0.0
+    } else {
+        sample_bytes as f64 / samples.len() as f64 * selections.len() as f64 / (1024.0 * 1024.0)
     };
 
-    oxipng::optimize(&in_file, &out_file, &options)?;
+    info!("🧪 Dry run（ファイルは書き出していません）");
+    info!("🧮 理論上の組み合わせ数: {}", generator.total_combinations());
+    info!("🎲 パターン選択: {} / {} 件成功（重複なし）", selections.len(), cfg.count);
+    if failures > 0 {
+        warn!("⚠ パターンを選べなかったトークン: {} 件", failures);
+    }
+    info!(
+        "💾 推定出力サイズ: {:.1} MB（サンプル {} 件の画像とメタデータの平均から推定。アニメーション・サムネイルは含まない）",
+        estimated_mb,
+        samples.len()
+    );
+    info!("🔍 パターンの例:");
+    for (token_id, pattern) in &samples {
+        info!("  #{}: {}", token_id, pattern.key);
+    }
     Ok(())
 }
 
+/// 制約を考慮した実効的な組み合わせ数をサンプリングで見積もり、count に対して余裕がなければ警告する
+///
+/// レア度どおりに SAMPLES 個のパターンを選び、すべての制約を通過した割合を組み合わせ数に掛ける。
+/// 重みの偏りがあると実際の組み合わせの割合とはずれるため、あくまで目安として扱う。
+/// seed を指定した場合は見積もりも毎回同じになる。
+fn feasibility_analysis(generator: &Generator) {
+    let (cfg, layer_candidates) = (generator.config(), generator.candidates());
+    const SAMPLES: usize = 10_000;
+    const WARN_RATIO: f64 = 0.8;
+
+    let Some(c) = &cfg.constraints else { return; };
+    let rules = constraints(c);
+    if rules.is_empty() {
+        return;
+    }
+
+    let mut rng = match cfg.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_rng(thread_rng()).expect("乱数生成器の初期化に失敗しました"),
+    };
+    let mut rejected = vec![0usize; rules.len()];
+    let mut passed = 0usize;
+    for _ in 0..SAMPLES {
+        let present = present_traits(&roll_layers(layer_candidates, &mut rng));
+        let mut ok = true;
+        for (count, rule) in rejected.iter_mut().zip(&rules) {
+            if rule.violated(&present) {
+                *count += 1;
+                ok = false;
+            }
+        }
+        if ok {
+            passed += 1;
+        }
+    }
+
+    let pass_rate = passed as f64 / SAMPLES as f64;
+    let effective = generator.total_combinations() as f64 * pass_rate;
+    info!(
+        "🧮 制約を考慮した実効的な組み合わせ数: 約 {:.0} 通り（サンプル {} 件中 {:.1}% が制約を通過）",
+        effective,
+        SAMPLES,
+        pass_rate * 100.0
+    );
+
+    let mut by_rate: Vec<_> = rules.iter().zip(&rejected).filter(|(_, n)| **n > 0).collect();
+    by_rate.sort_by_key(|(_, n)| std::cmp::Reverse(**n));
+    for (rule, n) in by_rate.iter().take(10) {
+        info!("  - {}: {:.1}% を除外", rule, **n as f64 / SAMPLES as f64 * 100.0);
+    }
+
+    if effective <= 0.0 || cfg.count as f64 / effective > WARN_RATIO {
+        warn!(
+            "⚠ count ({}) が実効的な組み合わせ数（約 {:.0}）の {:.0}% を超えるため、パターン選択のリトライが上限に達する可能性があります。\
+             除外率の高いルールを見直すか、count を減らしてください",
+            cfg.count,
+            effective,
+            WARN_RATIO * 100.0
+        );
+    }
+}
+
+/// ティア上限によって count 個の一意な組み合わせを作れなくなる場合に警告する
+/// （ティア同士が重なる場合は概算）
+fn warn_infeasible_tiers(generator: &Generator) {
+    let (cfg, layer_candidates) = (generator.config(), generator.candidates());
+    let Some(tiers) = cfg.constraints.as_ref().and_then(|c| c.tiers.as_ref()) else { return; };
+
+    let mut excluded: u128 = 0;
+    for tier in tiers {
+        let tier_combinations: u128 = if tier.required_traits.is_empty() {
+            combination_count(layer_candidates)
+        } else {
+            layer_candidates
+                .iter()
+                .map(|c| {
+                    let values: HashSet<&str> = tier
+                        .required_traits
+                        .iter()
+                        .filter(|tv| tv.trait_type == c.layer.name)
+                        .map(|tv| tv.value.as_str())
+                        .collect();
+                    match values.len() {
+                        0 => layer_option_count(c),
+                        1 => c
+                            .files
+                            .iter()
+                            .filter(|p| file_stem(p).is_some_and(|s| values.contains(s.as_str())))
+                            .count() as u128,
+                        _ => 0,
+                    }
+                })
+                .product()
+        };
+
+        if tier_combinations == 0 {
+            warn!("⚠ ティア {:?} の required_traits を満たす組み合わせがありません", tier.name);
+        }
+        excluded += tier_combinations.saturating_sub(tier.max_count as u128);
+    }
+
+    let available = generator.total_combinations().saturating_sub(excluded);
+    if (cfg.count as u128) > available {
+        warn!(
+            "⚠ ティアの max_count により利用できる組み合わせは約 {} 通りで、count ({}) に届かない可能性があります",
+            available, cfg.count
+        );
+    }
+}
+
+/// JPEG 出力で透過が失われる可能性がある場合に警告する
+/// 合成結果の透過は一番下のレイヤーで決まるため、不透明な背景色がなければ最下層のファイルを調べる
+fn warn_jpeg_transparency(cfg: &Config, layer_candidates: &[LayerCandidate]) {
+    if cfg.output.background_color.is_some_and(|c| c[3] == 255) {
+        return;
+    }
+    let Some(bottom) = layer_candidates.first() else { return; };
+
+    if bottom.layer.optional_probability.is_some_and(|p| p < 1.0) {
+        warn!(
+            "⚠ 最下層のレイヤー {:?} がオプションのため、JPEG 出力では透過部分が失われる可能性があります",
+            bottom.layer.name
+        );
+    }
+
+    let transparent: Vec<&PathBuf> = bottom
+        .files
+        .iter()
+        .filter(|path| {
+            let path = bottom.frames.get(*path).and_then(|seq| seq.first()).unwrap_or(path);
+            image::open(path).is_ok_and(|img| img.to_rgba8().pixels().any(|p| p.0[3] < 255))
+        })
+        .collect();
+    if !transparent.is_empty() {
+        warn!(
+            "⚠ 最下層のレイヤー {:?} に透過を含むファイルがあります。JPEG では透過部分が失われます: {:?}",
+            bottom.layer.name, transparent
+        );
+    }
+}
+
+/// 縦横比を保ったまま (max_w, max_h) に収まるサイズ
+fn fit_within((w, h): (u32, u32), (max_w, max_h): (u32, u32)) -> (u32, u32) {
+    let scale = f64::min(max_w as f64 / w as f64, max_h as f64 / h as f64);
+    (
+        ((w as f64 * scale).round() as u32).clamp(1, max_w),
+        ((h as f64 * scale).round() as u32).clamp(1, max_h),
+    )
+}
+
+/// --print-rarity: 正規化済みの出現確率をレイヤーごとに表示する
+/// オプションレイヤーは出現確率を掛けた値と「なし」の確率を表示する
+fn print_rarity_table(layer_candidates: &[LayerCandidate]) {
+    for LayerCandidate { layer, files, file_weights, .. } in layer_candidates {
+        let presence = layer.optional_probability.unwrap_or(1.0).clamp(0.0, 1.0);
+        println!("▶ Layer: {}", layer.name);
+        // group_by_directory のレイヤーは「グループ/ファイル名」ごと、rarity 未指定のレイヤーは全ファイル均等
+        let mut sorted: Vec<(String, f64)> = match (file_weights, &layer.rarity) {
+            (Some(weights), _) => files
+                .iter()
+                .zip(weights)
+                .map(|(path, p)| {
+                    let relative = path.strip_prefix(&layer.directory).unwrap_or(path);
+                    (relative.to_string_lossy().replace('\\', "/"), *p)
+                })
+                .collect(),
+            (None, Some(rarity)) => rarity.iter().map(|(file, p)| (file.clone(), *p as f64)).collect(),
+            (None, None) => files
+                .iter()
+                .filter_map(|path| path.file_name().and_then(|s| s.to_str()))
+                .map(|name| (name.to_string(), 1.0 / files.len() as f64))
+                .collect(),
+        };
+        sorted.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        for (file, p) in sorted {
+            println!("  {:30} {:6.2}%", file, p * presence * 100.0);
+        }
+        if presence < 1.0 {
+            println!("  {:30} {:6.2}%", "(なし)", (1.0 - presence) * 100.0);
+        }
+        println!();
+    }
+}
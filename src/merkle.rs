@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub type Hash = [u8; 32];
+
+/// 証明の各ステップで、兄弟ノードが左右どちらにあるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    Left,
+    Right,
+}
+
+/// Merkle 証明の1ステップ（hash は 0x 付きの16進文字列）
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub hash: String,
+    pub position: Position,
+}
+
+/// merkle_proofs.json の内容（トークン ID → 証明）
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MerkleProofs {
+    pub root: String,
+    pub proofs: BTreeMap<u32, Vec<ProofStep>>,
+}
+
+/// 2分木の Merkle ツリー
+///
+/// 葉はデータの double-SHA-256、内部ノードは子2つをソートして連結した SHA-256。
+/// ペアをソートするので position を使わずに検証できる（ただしハッシュは keccak256 ではなく SHA-256 のため、
+/// OpenZeppelin の `MerkleProof.verify` にはそのまま渡せない）。奇数個の段では余ったノードをそのまま上の段に送る。
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Hash>) -> Self {
+        let mut levels = vec![leaves];
+        while levels.last().is_some_and(|l| l.len() > 1) {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => hash_pair(a, b),
+                    [a] => *a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    /// ルートハッシュ（葉が無い場合は None）
+    pub fn root(&self) -> Option<Hash> {
+        self.levels.last().and_then(|l| l.first()).copied()
+    }
+
+    /// index 番目の葉の証明（葉側から順）
+    pub fn proof(&self, index: usize) -> Vec<ProofStep> {
+        let mut steps = Vec::new();
+        let mut i = index;
+        for level in &self.levels[..self.levels.len().saturating_sub(1)] {
+            let sibling = i ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                steps.push(ProofStep {
+                    hash: to_hex(hash),
+                    position: if sibling < i { Position::Left } else { Position::Right },
+                });
+            }
+            i /= 2;
+        }
+        steps
+    }
+}
+
+/// メタデータ JSON のバイト列から葉のハッシュを計算する（double-SHA-256）
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+/// 子2つをソートして連結し SHA-256 を取る
+fn hash_pair(a: &Hash, b: &Hash) -> Hash {
+    let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+    let mut hasher = Sha256::new();
+    hasher.update(lo);
+    hasher.update(hi);
+    hasher.finalize().into()
+}
+
+/// 葉と証明からルートを計算し、期待するルートと一致するか確認する
+pub fn verify(leaf: &Hash, proof: &[ProofStep], root: &Hash) -> bool {
+    let mut current = *leaf;
+    for step in proof {
+        let Some(sibling) = from_hex(&step.hash) else {
+            return false;
+        };
+        current = hash_pair(&current, &sibling);
+    }
+    &current == root
+}
+
+/// 0x 付きの16進文字列にする
+pub fn to_hex(hash: &Hash) -> String {
+    let mut s = String::with_capacity(2 + hash.len() * 2);
+    s.push_str("0x");
+    for b in hash {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+/// 0x 付き（省略可）の16進文字列をハッシュに戻す
+pub fn from_hex(s: &str) -> Option<Hash> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if s.len() != 64 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_verify_against_root() {
+        let leaves: Vec<Hash> = (0..5u8).map(|i| leaf_hash(&[i])).collect();
+        let tree = MerkleTree::new(leaves.clone());
+        let root = tree.root().unwrap();
+
+        for (i, leaf) in leaves.iter().enumerate() {
+            assert!(verify(leaf, &tree.proof(i), &root));
+        }
+        assert!(!verify(&leaf_hash(b"other"), &tree.proof(0), &root));
+        assert_eq!(from_hex(&to_hex(&root)), Some(root));
+    }
+
+    #[test]
+    fn root_matches_known_answer() {
+        // Python の hashlib で別に計算した値:
+        // leaf = sha256(sha256(x))、node = sha256(min(l, r) + max(l, r))、root = node(node(a, b), c)
+        let leaves: Vec<Hash> = [b"a", b"b", b"c"].iter().map(|d| leaf_hash(*d)).collect();
+        assert_eq!(to_hex(&leaves[0]), "0xbf5d3affb73efd2ec6c36ad3112dd933efed63c4e1cbffcfa88e2759c144f2d8");
+        let root = MerkleTree::new(leaves).root().unwrap();
+        assert_eq!(to_hex(&root), "0x21b915ede27345c56bd5649399a4059c786daadd51c4fc135709be6e896cfde3");
+    }
+}
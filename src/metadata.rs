@@ -1,16 +1,517 @@
+use crate::config::{CollectionConfig, Erc1155Config, LocalizationConfig, MetadataFormat, MetaplexConfig};
+use crate::error::{LayeredNftError, Result};
+use crate::path_template::{parse_parts, trait_value, Part};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NftMetadata {
     pub name: String,
     pub description: String,
     pub image: String,
     pub edition: u32,
     pub attributes: Vec<Attribute>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub youtube_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animation_url: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Attribute {
     pub trait_type: String,
-    pub value: String,
+    pub value: AttributeValue,
+    /// OpenSea の display_type（"number", "boost_percentage", "boost_number" など）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_type: Option<String>,
+}
+
+/// トレイト値（JSON 上では文字列または数値）
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AttributeValue {
+    Text(String),
+    Number(f64),
+}
+
+impl fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttributeValue::Text(s) => f.write_str(s),
+            AttributeValue::Number(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// 出力するトークンメタデータ（形式ごと）
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum TokenMetadata {
+    Erc721(NftMetadata),
+    Metaplex(MetaplexMetadata),
+    Erc1155(Erc1155Metadata),
+}
+
+/// ERC-1155 形式のメタデータ（EIP-1155 Metadata JSON Schema 準拠）
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Erc1155Metadata {
+    pub name: String,
+    pub description: String,
+    pub image: String,
+    pub decimals: u8,
+    pub properties: Erc1155Properties,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub localization: Option<LocalizationConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Erc1155Properties {
+    pub edition: u32,
+    pub traits: Vec<Attribute>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animation_url: Option<String>,
+}
+
+impl Erc1155Metadata {
+    /// ERC-721 形式のメタデータと ERC-1155 設定から組み立てる
+    pub fn from_nft(nft: NftMetadata, cfg: &Erc1155Config) -> Self {
+        Self {
+            name: nft.name,
+            description: nft.description,
+            image: nft.image,
+            decimals: cfg.decimals,
+            properties: Erc1155Properties {
+                edition: nft.edition,
+                traits: nft.attributes,
+                external_url: nft.external_url,
+                animation_url: nft.animation_url,
+            },
+            localization: cfg.localization.clone(),
+        }
+    }
+
+    /// ERC-721 形式のメタデータに戻す（properties の edition・traits を最上位に戻す）
+    pub fn into_nft(self) -> NftMetadata {
+        NftMetadata {
+            name: self.name,
+            description: self.description,
+            image: self.image,
+            edition: self.properties.edition,
+            attributes: self.properties.traits,
+            external_url: self.properties.external_url,
+            background_color: None,
+            youtube_url: None,
+            animation_url: self.properties.animation_url,
+        }
+    }
+}
+
+/// 書き出したトークンメタデータ（metadata.format の形式）を ERC-721 形式として読み戻す
+///
+/// Metaplex 形式は edition と attributes を最上位に持つので、そのまま読める
+pub fn parse_token_metadata(text: &str, format: MetadataFormat) -> serde_json::Result<NftMetadata> {
+    match format {
+        MetadataFormat::Erc1155 => serde_json::from_str::<Erc1155Metadata>(text).map(Erc1155Metadata::into_nft),
+        MetadataFormat::Erc721 | MetadataFormat::Metaplex => serde_json::from_str(text),
+    }
+}
+
+/// Solana / Metaplex 形式のメタデータ
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetaplexMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub description: String,
+    pub seller_fee_basis_points: u16,
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animation_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_url: Option<String>,
+    pub edition: u32,
+    pub attributes: Vec<Attribute>,
+    pub collection: MetaplexCollection,
+    pub properties: MetaplexProperties,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetaplexCollection {
+    pub name: String,
+    pub family: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetaplexProperties {
+    pub files: Vec<MetaplexFile>,
+    pub category: String,
+    pub creators: Vec<MetaplexCreator>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetaplexFile {
+    pub uri: String,
+    #[serde(rename = "type")]
+    pub mime_type: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetaplexCreator {
+    pub address: String,
+    pub share: u8,
+}
+
+impl MetaplexMetadata {
+    /// ERC-721 形式のメタデータと Metaplex 設定から組み立てる
+    pub fn from_nft(nft: NftMetadata, cfg: &MetaplexConfig) -> Self {
+        let mut files = vec![MetaplexFile {
+            mime_type: mime_type(&nft.image),
+            uri: nft.image.clone(),
+        }];
+        if let Some(anim) = &nft.animation_url {
+            files.push(MetaplexFile {
+                mime_type: mime_type(anim),
+                uri: anim.clone(),
+            });
+        }
+
+        Self {
+            name: nft.name,
+            symbol: cfg.symbol.clone(),
+            description: nft.description,
+            seller_fee_basis_points: cfg.seller_fee_basis_points,
+            image: nft.image,
+            animation_url: nft.animation_url,
+            external_url: nft.external_url,
+            edition: nft.edition,
+            attributes: nft.attributes,
+            collection: MetaplexCollection {
+                name: cfg.collection.name.clone(),
+                family: cfg.collection.family.clone(),
+            },
+            properties: MetaplexProperties {
+                files,
+                category: "image".to_string(),
+                creators: cfg
+                    .creators
+                    .iter()
+                    .map(|c| MetaplexCreator {
+                        address: c.address.clone(),
+                        share: c.share,
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// collection.json（contractURI）の内容
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CollectionMetadata {
+    pub name: String,
+    pub description: String,
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_link: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seller_fee_basis_points: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee_recipient: Option<String>,
+}
+
+impl From<&CollectionConfig> for CollectionMetadata {
+    fn from(cfg: &CollectionConfig) -> Self {
+        Self {
+            name: cfg.name.clone(),
+            description: cfg.description.clone(),
+            image: cfg.image.clone(),
+            external_link: cfg.external_link.clone(),
+            seller_fee_basis_points: cfg.seller_fee_basis_points,
+            fee_recipient: cfg.fee_recipient.clone(),
+        }
+    }
+}
+
+/// シリアライズ済みメタデータに extra_fields を追加する
+/// 既存のキーは上書きせず、文字列中の `{id}` `{name}` はトークン ID とトークン名に置換する
+pub fn merge_extra_fields(metadata: &mut Value, extra: &Map<String, Value>, token_id: u32) {
+    let name = metadata
+        .get("name")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let Some(obj) = metadata.as_object_mut() else {
+        return;
+    };
+
+    for (key, value) in extra {
+        if obj.contains_key(key) {
+            continue;
+        }
+        obj.insert(key.clone(), substitute(value, token_id, &name));
+    }
+}
+
+fn substitute(value: &Value, token_id: u32, name: &str) -> Value {
+    match value {
+        Value::String(s) => {
+            Value::String(s.replace("{id}", &token_id.to_string()).replace("{name}", name))
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|v| substitute(v, token_id, name)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), substitute(v, token_id, name)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// URL テンプレート（metadata.external_url_template など）
+///
+/// 変数はパステンプレート（PathTemplate）と共通で、`{id}` `{id:05}` はトークン ID、`{name}` はトークン名、
+/// `{trait:<type>}`（`{layer:<type>}` とも書ける）はその trait の値（レイヤーが選ばれなかった場合は `none`）、
+/// `{file}` はアニメーションのファイル名に置換する。置換した値は URL の1要素としてパーセントエンコードする
+#[derive(Debug, Clone, PartialEq)]
+pub struct UrlTemplate {
+    parts: Vec<Part>,
+}
+
+/// パーセントエンコードしない文字（RFC 3986 の unreserved）
+const URL_VALUE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+impl UrlTemplate {
+    /// テンプレートを解析する。未知の変数・閉じていない `{` はエラー
+    pub fn parse(template: &str) -> Result<Self> {
+        let parts = parse_parts(template)
+            .map_err(|message| LayeredNftError::InvalidConfig(format!("URL テンプレート {:?}: {}", template, message)))?;
+        Ok(Self { parts })
+    }
+
+    /// `{trait:<type>}` で参照している trait_type
+    pub fn traits(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|p| match p {
+            Part::Trait(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// `{file}` を使っているか
+    pub fn uses_file(&self) -> bool {
+        self.parts.contains(&Part::File)
+    }
+
+    /// トークンの値で展開する（file はアニメーションのファイル名。None なら `{file}` は空になる）
+    pub fn render(&self, token_id: u32, name: &str, attributes: &[Attribute], file: Option<&str>) -> String {
+        let encode = |value: &str| utf8_percent_encode(value, URL_VALUE).to_string();
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => out.push_str(s),
+                Part::Id { width } => out.push_str(&format!("{:0width$}", token_id, width = width.unwrap_or(0))),
+                Part::Name => out.push_str(&encode(name)),
+                Part::Trait(trait_type) => out.push_str(&encode(&trait_value(attributes, trait_type))),
+                Part::File => out.push_str(&encode(file.unwrap_or_default())),
+            }
+        }
+        out
+    }
+}
+
+/// テンプレートの `{trait:<type>}` / `{layer:<type>}` で、その trait を持たないトークンに使う値
+pub const ABSENT_TRAIT_VALUE: &str = "none";
+
+/// URL の拡張子から MIME タイプを推定
+fn mime_type(uri: &str) -> String {
+    let ext = uri.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    match ext.as_str() {
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "gif" => "image/gif",
+        "jpg" | "jpeg" => "image/jpeg",
+        _ => "image/png",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metaplex_required_keys() {
+        use crate::config::{MetaplexCollectionConfig, MetaplexCreatorConfig};
+
+        let nft = NftMetadata {
+            name: "Eyes #1".to_string(),
+            description: "desc".to_string(),
+            image: "https://example.com/images/1.png".to_string(),
+            edition: 1,
+            attributes: vec![Attribute {
+                trait_type: "Eyeball".to_string(),
+                value: AttributeValue::Text("Red".to_string()),
+                display_type: None,
+            }],
+            external_url: None,
+            background_color: None,
+            youtube_url: None,
+            animation_url: None,
+        };
+        let cfg = MetaplexConfig {
+            symbol: "EYES".to_string(),
+            seller_fee_basis_points: 500,
+            collection: MetaplexCollectionConfig {
+                name: "Eyes".to_string(),
+                family: "Eyes Family".to_string(),
+            },
+            creators: vec![MetaplexCreatorConfig {
+                address: "CreatorAddress111".to_string(),
+                share: 100,
+            }],
+        };
+
+        let json = serde_json::to_value(MetaplexMetadata::from_nft(nft, &cfg)).unwrap();
+        for key in [
+            "name",
+            "symbol",
+            "description",
+            "seller_fee_basis_points",
+            "image",
+            "attributes",
+            "collection",
+            "properties",
+        ] {
+            assert!(json.get(key).is_some(), "missing key: {}", key);
+        }
+        assert_eq!(json["collection"]["family"], "Eyes Family");
+        assert_eq!(json["properties"]["files"][0]["type"], "image/png");
+        assert_eq!(json["properties"]["files"][0]["uri"], "https://example.com/images/1.png");
+        assert_eq!(json["properties"]["creators"][0]["share"], 100);
+        assert_eq!(json["attributes"][0]["value"], "Red");
+    }
+
+    #[test]
+    fn expands_url_template_variables() {
+        let attributes = vec![
+            Attribute {
+                trait_type: "Eyeball".to_string(),
+                value: AttributeValue::Text("Red".to_string()),
+                display_type: None,
+            },
+            Attribute {
+                trait_type: "Level".to_string(),
+                value: AttributeValue::Number(3.0),
+                display_type: Some("number".to_string()),
+            },
+        ];
+        let template = UrlTemplate::parse("https://example.com/{trait:Eyeball}/{layer:Goo}/{trait:Level}/{id:03}?n={name}").unwrap();
+        assert_eq!(
+            template.render(12, "Eyes #12", &attributes, None),
+            "https://example.com/Red/none/3/012?n=Eyes%20%2312"
+        );
+        assert_eq!(template.traits().collect::<Vec<_>>(), ["Eyeball", "Goo", "Level"]);
+        let file = UrlTemplate::parse("https://cdn.example.com/{file}").unwrap();
+        assert!(file.uses_file());
+        assert_eq!(file.render(1, "", &[], Some("1.gif")), "https://cdn.example.com/1.gif");
+        assert!(UrlTemplate::parse("https://example.com/{id").is_err());
+        assert!(UrlTemplate::parse("https://example.com/{unknown}").is_err());
+    }
+
+    #[test]
+    fn erc1155_schema_round_trip() {
+        let nft = NftMetadata {
+            name: "Eyes #1".to_string(),
+            description: "desc".to_string(),
+            image: "https://example.com/images/1.png".to_string(),
+            edition: 1,
+            attributes: vec![Attribute {
+                trait_type: "Eyeball".to_string(),
+                value: AttributeValue::Text("Red".to_string()),
+                display_type: None,
+            }],
+            external_url: None,
+            background_color: None,
+            youtube_url: None,
+            animation_url: None,
+        };
+        let cfg = Erc1155Config {
+            decimals: 0,
+            localization: Some(LocalizationConfig {
+                uri: "ipfs://QmLocales/{locale}.json".to_string(),
+                default: "en".to_string(),
+                locales: vec!["en".to_string(), "ja".to_string()],
+            }),
+        };
+
+        let text = serde_json::to_string(&Erc1155Metadata::from_nft(nft, &cfg)).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+
+        // EIP-1155 Metadata JSON Schema の型に一致すること
+        assert!(json["name"].is_string());
+        assert!(json["description"].is_string());
+        assert!(json["image"].is_string());
+        assert!(json["decimals"].is_u64());
+        assert!(json["properties"].is_object());
+        assert!(json["localization"]["uri"].as_str().unwrap().contains("{locale}"));
+        assert!(json["localization"]["default"].is_string());
+        assert!(json["localization"]["locales"].is_array());
+        assert!(json.get("attributes").is_none());
+        assert_eq!(json["properties"]["traits"][0]["trait_type"], "Eyeball");
+
+        let back: Erc1155Metadata = serde_json::from_str(&text).unwrap();
+        assert_eq!(back.name, "Eyes #1");
+        assert_eq!(back.decimals, 0);
+        assert_eq!(back.properties.traits.len(), 1);
+        assert_eq!(back.localization.unwrap().locales, vec!["en", "ja"]);
+
+        let nft = parse_token_metadata(&text, MetadataFormat::Erc1155).unwrap();
+        assert_eq!((nft.edition, nft.attributes[0].trait_type.as_str()), (1, "Eyeball"));
+        assert!(parse_token_metadata(&text, MetadataFormat::Erc721).is_err());
+    }
+
+    #[test]
+    fn attribute_value_serialization() {
+        let text = Attribute {
+            trait_type: "Eyeball".to_string(),
+            value: AttributeValue::Text("Red".to_string()),
+            display_type: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&text).unwrap(),
+            r#"{"trait_type":"Eyeball","value":"Red"}"#
+        );
+
+        let number = Attribute {
+            trait_type: "Level".to_string(),
+            value: AttributeValue::Number(3.0),
+            display_type: Some("number".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_string(&number).unwrap(),
+            r#"{"trait_type":"Level","value":3.0,"display_type":"number"}"#
+        );
+    }
+
+    #[test]
+    fn extra_fields_merge() {
+        let mut metadata = serde_json::json!({"name": "Eye #3", "image": "https://example.com/3.png"});
+        let extra: Map<String, Value> = serde_json::from_str(
+            r#"{"image_url": "https://example.com/{id}.png", "compiler": "{name} gen", "image": "ignored"}"#,
+        )
+        .unwrap();
+        merge_extra_fields(&mut metadata, &extra, 3);
+
+        assert_eq!(metadata["image"], "https://example.com/3.png");
+        assert_eq!(metadata["image_url"], "https://example.com/3.png");
+        assert_eq!(metadata["compiler"], "Eye #3 gen");
+        let keys: Vec<&String> = metadata.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["name", "image", "image_url", "compiler"]);
+    }
 }
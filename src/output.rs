@@ -0,0 +1,453 @@
+use crate::config::{AdditionalOutputConfig, ImageFormat, OutputConfig};
+use crate::error::{LayeredNftError, Result};
+use image::buffer::ConvertBuffer;
+use image::codecs::jpeg::JpegEncoder;
+use image::{imageops, ImageResult, RgbImage, RgbaImage};
+use oxipng::{InFile, OutFile, Options};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufWriter, Cursor, Seek, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "avif")]
+use std::sync::OnceLock;
+
+/// JPEG の品質の既定値
+const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+/// AVIF の品質の既定値
+const DEFAULT_AVIF_QUALITY: u8 = 80;
+
+/// 静止画 WebP の品質の既定値
+const DEFAULT_WEBP_QUALITY: u8 = 80;
+
+/// AVIF のエンコード速度（1〜10、大きいほど速いがファイルが大きくなる）
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 6;
+
+/// パレット形式の PNG に入る最大の色数
+pub const MAX_PALETTE_COLORS: usize = 256;
+
+/// palette_quantize で書き出した PNG のサイズ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaletteReport {
+    /// RGBA の PNG にした場合のバイト数
+    pub rgba_bytes: u64,
+    /// パレット形式にできた場合のバイト数（256 色を超えて RGBA で書き出した場合は None）
+    pub indexed_bytes: Option<u64>,
+}
+
+/// 指定フォーマットで画像を書き出す（output.atomic_writes に従って一時ファイル経由で書く）
+///
+/// エンコード結果はメモリに溜めずにファイルへ直接書く
+pub fn save_image(img: &RgbaImage, path: &str, format: ImageFormat, output: &OutputConfig) -> Result<()> {
+    let quality = quality(format, None, output);
+    write_with(Path::new(path), output.writes_atomically(), |target| {
+        let file = fs::File::create(target).map_err(LayeredNftError::io(path))?;
+        let mut writer = BufWriter::new(file);
+        write_image(img, &mut writer, format, quality).map_err(LayeredNftError::image(path))?;
+        writer.flush().map_err(LayeredNftError::io(path))
+    })
+}
+
+/// 合成済みの画像を additional_outputs の1件分のサイズ・形式・品質で書き出す
+pub fn save_additional_output(img: &RgbaImage, path: &str, extra: &AdditionalOutputConfig, output: &OutputConfig) -> Result<()> {
+    let (w, h) = extra.size(img.dimensions());
+    let img = if (w, h) == img.dimensions() {
+        Cow::Borrowed(img)
+    } else {
+        Cow::Owned(imageops::resize(img, w, h, output.resize_filter.unwrap_or_default().filter_type()))
+    };
+    let quality = quality(extra.format, extra.quality, output);
+    write_with(Path::new(path), output.writes_atomically(), |target| {
+        let file = fs::File::create(target).map_err(LayeredNftError::io(path))?;
+        let mut writer = BufWriter::new(file);
+        write_image(&img, &mut writer, extra.format, quality).map_err(LayeredNftError::image(path))?;
+        writer.flush().map_err(LayeredNftError::io(path))
+    })
+}
+
+/// PNG を書き出して oxipng で圧縮する。atomic_writes が有効なら一時ファイルを圧縮してからリネームするので、
+/// 最終的なパスには圧縮済みのファイルしか現れない
+pub fn save_png_compressed(img: &RgbaImage, path: &str, level: u8, output: &OutputConfig) -> Result<()> {
+    write_with(Path::new(path), output.writes_atomically(), |target| {
+        let file = fs::File::create(target).map_err(LayeredNftError::io(path))?;
+        let mut writer = BufWriter::new(file);
+        write_png(img, &mut writer).map_err(LayeredNftError::image(path))?;
+        writer.flush().map_err(LayeredNftError::io(path))?;
+        drop(writer);
+        optimize_png(target, target, level)
+    })
+}
+
+/// palette_quantize: 256 色以下ならパレット形式、超えていれば RGBA の PNG で書き出す
+///
+/// 比較のために RGBA でもエンコードし、両方のサイズを返す。compression_level を渡すと、
+/// パレット化した後の PNG を oxipng で圧縮する（サイズは圧縮前の値）
+pub fn save_png_palette(
+    img: &RgbaImage,
+    path: &str,
+    compression_level: Option<u8>,
+    output: &OutputConfig,
+) -> Result<PaletteReport> {
+    let mut rgba = Cursor::new(Vec::new());
+    write_png(img, &mut rgba).map_err(LayeredNftError::image(path))?;
+    let rgba = rgba.into_inner();
+    let indexed = encode_indexed_png(img).map_err(|e| LayeredNftError::Encode(e.to_string()))?;
+    let report = PaletteReport {
+        rgba_bytes: rgba.len() as u64,
+        indexed_bytes: indexed.as_ref().map(|d| d.len() as u64),
+    };
+
+    let data = indexed.unwrap_or(rgba);
+    write_with(Path::new(path), output.writes_atomically(), |target| {
+        fs::write(target, &data).map_err(LayeredNftError::io(path))?;
+        match compression_level {
+            Some(level) => optimize_png(target, target, level),
+            None => Ok(()),
+        }
+    })?;
+    Ok(report)
+}
+
+/// 画像の色（RGBA）を出現順に並べたパレットと、各ピクセルのパレット番号を返す（256 色を超えれば None）
+pub fn palette_indices(img: &RgbaImage) -> Option<(Vec<[u8; 4]>, Vec<u8>)> {
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut indices = Vec::with_capacity(img.len() / 4);
+    for pixel in img.pixels() {
+        let index = match lookup.get(&pixel.0) {
+            Some(&index) => index,
+            None => {
+                if palette.len() == MAX_PALETTE_COLORS {
+                    return None;
+                }
+                let index = palette.len() as u8;
+                palette.push(pixel.0);
+                lookup.insert(pixel.0, index);
+                index
+            }
+        };
+        indices.push(index);
+    }
+    Some((palette, indices))
+}
+
+/// パレット形式（8bit インデックス + tRNS）の PNG にエンコードする（256 色を超えれば None）
+fn encode_indexed_png(img: &RgbaImage) -> std::result::Result<Option<Vec<u8>>, png::EncodingError> {
+    let Some((palette, indices)) = palette_indices(img) else { return Ok(None); };
+    let rgb: Vec<u8> = palette.iter().flat_map(|c| [c[0], c[1], c[2]]).collect();
+    // 末尾の不透明な色のアルファは省略できる
+    let opaque = palette.iter().rev().take_while(|c| c[3] == u8::MAX).count();
+    let alpha: Vec<u8> = palette[..palette.len() - opaque].iter().map(|c| c[3]).collect();
+
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(rgb);
+    if !alpha.is_empty() {
+        encoder.set_trns(alpha);
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&indices)?;
+    writer.finish()?;
+    Ok(Some(data))
+}
+
+/// データをファイルに書き出す（atomic なら一時ファイル経由）
+pub fn write_file(path: impl AsRef<Path>, data: impl AsRef<[u8]>, atomic: bool) -> Result<()> {
+    let path = path.as_ref();
+    write_with(path, atomic, |target| fs::write(target, data).map_err(LayeredNftError::io(path)))
+}
+
+/// write に渡したパスへ書き出させる。atomic なら同じディレクトリ（同じファイルシステム）の一時ファイルに書いてから
+/// path へリネームするので、途中で異常終了しても書きかけのファイルが path に残らない。atomic でなければ path に直接書く
+pub fn write_with(path: &Path, atomic: bool, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    if !atomic {
+        return write(path);
+    }
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".tmp-");
+    // 一時ファイルは既定で 0600 になるため、通常のファイルと同じ権限にしておく
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o644));
+    let temp = builder.tempfile_in(dir).map_err(LayeredNftError::io(path))?;
+    write(temp.path())?;
+    temp.persist(path).map_err(|e| LayeredNftError::io(path)(e.error))?;
+    Ok(())
+}
+
+/// 画像をメモリ上でエンコードする（PNG 圧縮が有効なら oxipng も適用する）
+pub fn encode_image(img: &RgbaImage, format: ImageFormat, output: &OutputConfig) -> Result<Vec<u8>> {
+    let indexed = if format == ImageFormat::Png && output.palette_quantize == Some(true) {
+        encode_indexed_png(img).map_err(|e| LayeredNftError::Encode(e.to_string()))?
+    } else {
+        None
+    };
+    let data = match indexed {
+        Some(data) => data,
+        None => {
+            let mut buf = Cursor::new(Vec::new());
+            write_image(img, &mut buf, format, quality(format, None, output)).map_err(|e| LayeredNftError::Encode(e.to_string()))?;
+            buf.into_inner()
+        }
+    };
+    match &output.png_compression {
+        Some(c) if format == ImageFormat::Png && c.enabled => {
+            let mut options = Options::from_preset(c.level.min(6));
+            options.fix_errors = true;
+            oxipng::optimize_from_memory(&data, &options)
+                .map_err(|e| LayeredNftError::Encode(e.to_string()))
+        }
+        _ => Ok(data),
+    }
+}
+
+/// 形式ごとの品質（override > output の形式別の設定 > 既定値）。PNG では使わない
+fn quality(format: ImageFormat, override_quality: Option<u8>, output: &OutputConfig) -> u8 {
+    match format {
+        ImageFormat::Avif => override_quality.or(output.avif_quality).unwrap_or(DEFAULT_AVIF_QUALITY),
+        ImageFormat::Webp => override_quality
+            .or(output.webp_quality.map(|q| q.clamp(0.0, 100.0).round() as u8))
+            .unwrap_or(DEFAULT_WEBP_QUALITY),
+        _ => override_quality.or(output.jpeg_quality).unwrap_or(DEFAULT_JPEG_QUALITY),
+    }
+}
+
+/// quality は format = webp / jpeg / avif のときだけ使う
+fn write_image<W: Write + Seek>(
+    img: &RgbaImage,
+    writer: &mut W,
+    format: ImageFormat,
+    quality: u8,
+) -> ImageResult<()> {
+    match format {
+        ImageFormat::Png => write_png(img, writer)?,
+        ImageFormat::Webp => write_webp(img, writer, quality)?,
+        ImageFormat::Jpeg => {
+            // 合成が済んだ段階でアルファチャンネルを捨てる（RGBA のコピーは作らずに直接 RGB へ変換する）
+            let rgb: RgbImage = img.convert();
+            JpegEncoder::new_with_quality(writer, quality).encode_image(&rgb)?;
+        }
+        ImageFormat::Avif => write_avif(img, writer, quality)?,
+    }
+    Ok(())
+}
+
+/// PNG で書き出す（ロスレスなので品質の指定はない）
+fn write_png<W: Write + Seek>(img: &RgbaImage, writer: &mut W) -> ImageResult<()> {
+    img.write_to(writer, image::ImageFormat::Png)
+}
+
+/// 静止画 WebP を quality で非可逆圧縮する（image クレートの WebP エンコーダはロスレスのみのため libwebp を使う）
+#[cfg(feature = "webp")]
+fn write_webp<W: Write>(img: &RgbaImage, writer: &mut W, quality: u8) -> ImageResult<()> {
+    let encoded = webp::Encoder::from_rgba(img.as_raw(), img.width(), img.height()).encode(f32::from(quality));
+    writer.write_all(&encoded)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "webp"))]
+fn write_webp<W: Write + Seek>(img: &RgbaImage, writer: &mut W, _quality: u8) -> ImageResult<()> {
+    img.write_to(writer, image::ImageFormat::WebP)
+}
+
+/// AVIF は1枚のエンコードに時間がかかり、エンコーダ自身も rayon で並列化するため、
+/// トークンの並列生成と重なって CPU を奪い合わないよう、専用のスレッドプールでエンコードする
+/// （プールのスレッド数が同時にエンコードする枚数の上限になる）。
+/// 呼び出し元の rayon ワーカーはエンコードの完了を待つ間も、自分のプールの他のトークンを処理し続ける
+#[cfg(feature = "avif")]
+fn write_avif<W: Write>(img: &RgbaImage, writer: &mut W, quality: u8) -> ImageResult<()> {
+    use image::codecs::avif::AvifEncoder;
+    use image::{ExtendedColorType, ImageEncoder};
+
+    let encoded = avif_pool().install(|| {
+        let mut encoded = Vec::new();
+        AvifEncoder::new_with_speed_quality(&mut encoded, AVIF_SPEED, quality.max(1))
+            .write_image(img.as_raw(), img.width(), img.height(), ExtendedColorType::Rgba8)
+            .map(|()| encoded)
+    })?;
+    writer.write_all(&encoded)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "avif"))]
+fn write_avif<W: Write>(_img: &RgbaImage, _writer: &mut W, _quality: u8) -> ImageResult<()> {
+    Err(image::ImageError::Unsupported(image::error::UnsupportedError::from_format_and_kind(
+        image::error::ImageFormatHint::Name("avif".to_string()),
+        image::error::UnsupportedErrorKind::Format(image::error::ImageFormatHint::Name("avif".to_string())),
+    )))
+}
+
+/// AVIF のエンコード用スレッドプール（論理コア数の半分のスレッド）
+#[cfg(feature = "avif")]
+fn avif_pool() -> &'static rayon::ThreadPool {
+    static POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let threads = (std::thread::available_parallelism().map_or(2, |n| n.get()) / 2).max(1);
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("avif-encode-{}", i))
+            .build()
+            .expect("AVIF エンコード用のスレッドプールを作成できません")
+    })
+}
+
+/// AVIF（ISOBMFF）のヘッダーから画像のサイズを読む
+///
+/// image クレートの AVIF デコーダはシステムの dav1d を必要とするため使わず、
+/// ftyp のブランドと meta/iprp/ipco/ispe のサイズ、画像データ（mdat）の有無を確認する
+pub fn avif_dimensions(data: &[u8]) -> std::result::Result<(u32, u32), String> {
+    let top = isobmff_boxes(data)?;
+    let ftyp = top.iter().find(|(kind, _)| kind == b"ftyp").ok_or("ftyp ボックスがありません")?.1;
+    let brands: Vec<&[u8]> = ftyp.chunks(4).enumerate().filter(|(i, _)| *i != 1).map(|(_, b)| b).collect();
+    if !brands.iter().any(|b| *b == b"avif" || *b == b"avis") {
+        return Err("AVIF のブランドがありません".to_string());
+    }
+    if !top.iter().any(|(kind, body)| kind == b"mdat" && !body.is_empty()) {
+        return Err("画像データ（mdat）がありません".to_string());
+    }
+
+    let meta = top.iter().find(|(kind, _)| kind == b"meta").ok_or("meta ボックスがありません")?.1;
+    // meta は FullBox なので、先頭の version / flags を飛ばす
+    let child = |body: &'_ [u8], kind: &[u8; 4]| -> std::result::Result<Vec<u8>, String> {
+        isobmff_boxes(body)?
+            .into_iter()
+            .find(|(k, _)| k == kind)
+            .map(|(_, b)| b.to_vec())
+            .ok_or_else(|| format!("{} ボックスがありません", String::from_utf8_lossy(kind)))
+    };
+    let iprp = child(meta.get(4..).ok_or("meta ボックスが壊れています")?, b"iprp")?;
+    let ipco = child(&iprp, b"ipco")?;
+    let ispe = child(&ipco, b"ispe")?;
+    let field = |at: usize| ispe.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    match (field(4), field(8)) {
+        (Some(w), Some(h)) if w > 0 && h > 0 => Ok((w, h)),
+        _ => Err("ispe ボックスのサイズが不正です".to_string()),
+    }
+}
+
+/// ISOBMFF のボックス（種類, 中身）
+type IsoBox<'a> = ([u8; 4], &'a [u8]);
+
+/// ISOBMFF のボックス列を (種類, 中身) に分ける
+fn isobmff_boxes(mut data: &[u8]) -> std::result::Result<Vec<IsoBox<'_>>, String> {
+    let mut boxes = Vec::new();
+    while !data.is_empty() {
+        let header = data.get(..8).ok_or("ボックスのヘッダーが途中で切れています")?;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let (size, offset) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            0 => (data.len(), 8),
+            1 => {
+                let large = data.get(8..16).ok_or("ボックスのヘッダーが途中で切れています")?;
+                let size = u64::from_be_bytes(large.try_into().expect("8 バイト"));
+                (usize::try_from(size).map_err(|_| "ボックスが大きすぎます")?, 16)
+            }
+            size => (size as usize, 8),
+        };
+        if size < offset || size > data.len() {
+            return Err(format!("{} ボックスのサイズが不正です", String::from_utf8_lossy(&kind)));
+        }
+        boxes.push((kind, &data[offset..size]));
+        data = &data[size..];
+    }
+    Ok(boxes)
+}
+
+/// 書き出し済みの PNG を oxipng で上書き圧縮する（atomic なら一時ファイルに圧縮してから置き換える）
+pub fn compress_png(path: &str, level: u8, atomic: bool) -> Result<()> {
+    let source = Path::new(path);
+    write_with(source, atomic, |target| optimize_png(source, target, level))
+}
+
+fn optimize_png(input: &Path, output: &Path, level: u8) -> Result<()> {
+    let mut options = Options::from_preset(level.min(6));
+    options.fix_errors = true;
+
+    let in_file = InFile::Path(PathBuf::from(input));
+    let out_file = OutFile::Path {
+        path: Some(PathBuf::from(output)),
+        preserve_attrs: true,
+    };
+
+    oxipng::optimize(&in_file, &out_file, &options)
+        .map_err(|e| LayeredNftError::Encode(e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_png_round_trips_and_falls_back_over_256_colors() {
+        let mut img = RgbaImage::from_pixel(16, 16, image::Rgba([0, 0, 0, 0]));
+        img.put_pixel(1, 1, image::Rgba([255, 0, 0, 255]));
+        img.put_pixel(2, 2, image::Rgba([0, 255, 0, 128]));
+        let data = encode_indexed_png(&img).unwrap().unwrap();
+        let decoded = image::load_from_memory(&data).unwrap().into_rgba8();
+        assert_eq!(decoded, img);
+
+        let many = RgbaImage::from_fn(32, 32, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+        assert!(palette_indices(&many).is_none());
+        assert!(encode_indexed_png(&many).unwrap().is_none());
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn webp_quality_controls_lossy_encoding() {
+        let img = RgbaImage::from_fn(64, 64, |x, y| image::Rgba([(x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8, 255]));
+        let encode = |quality| {
+            let mut buf = Cursor::new(Vec::new());
+            write_image(&img, &mut buf, ImageFormat::Webp, quality).unwrap();
+            buf.into_inner()
+        };
+        let (low, high) = (encode(10), encode(95));
+        assert!(low.len() < high.len());
+        let decoded = image::load_from_memory(&low).unwrap().into_rgba8();
+        assert_eq!(decoded.dimensions(), (64, 64));
+        assert_ne!(decoded, img);
+    }
+
+    #[test]
+    fn avif_dimensions_reads_header_and_rejects_other_files() {
+        let png = encode_image(&RgbaImage::new(4, 4), ImageFormat::Png, &OutputConfig::default()).unwrap();
+        assert!(avif_dimensions(&png).is_err());
+        assert!(avif_dimensions(b"").is_err());
+
+        #[cfg(feature = "avif")]
+        {
+            let img = RgbaImage::from_pixel(24, 16, image::Rgba([10, 20, 30, 255]));
+            let avif = encode_image(&img, ImageFormat::Avif, &OutputConfig::default()).unwrap();
+            assert_eq!(avif_dimensions(&avif), Ok((24, 16)));
+            assert!(avif_dimensions(&avif[..avif.len() / 2]).is_err());
+        }
+    }
+
+    #[test]
+    fn failed_atomic_write_keeps_previous_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("1.json");
+
+        write_file(&path, "old", true).unwrap();
+        let failed = write_with(&path, true, |target| {
+            fs::write(target, "partial").unwrap();
+            Err(LayeredNftError::Encode("中断".to_string()))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        // 一時ファイルは後始末されている
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+
+        write_file(&path, "new", true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o644);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::error::{LayeredNftError, Result};
+use crate::metadata::{Attribute, NftMetadata, ABSENT_TRAIT_VALUE};
+use std::path::{Component, Path, PathBuf};
+
+/// 出力パスのテンプレート（output.image_path_template / metadata_path_template）
+///
+/// 使える変数:
+/// - `{id}`: トークン ID（output.id_width 桁までゼロ埋め）
+/// - `{id:05}`: 桁数を指定してゼロ埋めしたトークン ID
+/// - `{name}`: メタデータの name
+/// - `{layer:<name>}`: そのレイヤーで選ばれた値（レイヤーがなければ `none`）。URL テンプレートと同じく `{trait:<name>}` とも書ける
+///
+/// 展開結果は image_dir / metadata_dir からの相対パスとして扱う。
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathTemplate {
+    parts: Vec<Part>,
+}
+
+/// テンプレートの1要素（パステンプレートと URL テンプレートで共通）
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Part {
+    Literal(String),
+    Id { width: Option<usize> },
+    Name,
+    /// `{layer:<name>}` / `{trait:<name>}`
+    Trait(String),
+    /// `{file}`（animation_url_template のみ）
+    File,
+}
+
+/// テンプレートを変数ごとに分解する。未知の変数・閉じていない `{` はエラー（メッセージを返す）
+pub(crate) fn parse_parts(template: &str) -> std::result::Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Literal(rest[..start].to_string()));
+        }
+        let Some(len) = rest[start..].find('}') else {
+            return Err("`{` が閉じられていません".to_string());
+        };
+        let var = &rest[start + 1..start + len];
+        parts.push(match var.split_once(':') {
+            None if var == "id" => Part::Id { width: None },
+            None if var == "name" => Part::Name,
+            None if var == "file" => Part::File,
+            Some(("id", width)) => {
+                let width = width
+                    .parse::<usize>()
+                    .map_err(|_| format!("{{id:{}}} の桁数を数値として解釈できません", width))?;
+                Part::Id { width: Some(width) }
+            }
+            Some(("layer" | "trait", name)) if !name.is_empty() => Part::Trait(name.to_string()),
+            _ => return Err(format!("未知の変数 {{{}}} です", var)),
+        });
+        rest = &rest[start + len + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+/// `{layer:<name>}` / `{trait:<name>}` に入る値（そのトレイトがなければ ABSENT_TRAIT_VALUE）
+pub(crate) fn trait_value(attributes: &[Attribute], name: &str) -> String {
+    attributes
+        .iter()
+        .find(|a| a.trait_type == name)
+        .map_or(ABSENT_TRAIT_VALUE.to_string(), |a| a.value.to_string())
+}
+
+impl PathTemplate {
+    /// テンプレートを解析する。未知の変数・閉じていない `{`・絶対パス・`..` を含む場合はエラー
+    pub fn parse(template: &str) -> Result<Self> {
+        let invalid = |message: String| LayeredNftError::InvalidConfig(format!("パステンプレート {:?}: {}", template, message));
+
+        let path = Path::new(template);
+        if template.trim().is_empty() {
+            return Err(invalid("空のテンプレートは指定できません".to_string()));
+        }
+        if path.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Err(invalid("絶対パスや `..` は指定できません（出力ディレクトリからの相対パスにしてください）".to_string()));
+        }
+
+        let parts = parse_parts(template).map_err(invalid)?;
+        if parts.contains(&Part::File) {
+            return Err(invalid("{file} はパステンプレートでは使えません".to_string()));
+        }
+        Ok(Self { parts })
+    }
+
+    /// `{layer:<name>}` で参照しているレイヤー名
+    pub fn layer_names(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|p| match p {
+            Part::Trait(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// トークンのメタデータで展開した相対パス（`{id}` は id_width 桁までゼロ埋め）
+    pub fn render(&self, token_id: u32, id_width: usize, metadata: &NftMetadata) -> PathBuf {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => out.push_str(s),
+                Part::Id { width } => out.push_str(&format!("{:0width$}", token_id, width = width.unwrap_or(id_width))),
+                Part::Name => out.push_str(&path_segment(&metadata.name)),
+                Part::Trait(name) => out.push_str(&path_segment(&trait_value(&metadata.attributes, name))),
+                Part::File => {}
+            }
+        }
+        PathBuf::from(out)
+    }
+}
+
+/// 値をパスの1要素として使えるようにする（区切り文字と `.` / `..` だけの値は `_` に置き換える）
+fn path_segment(value: &str) -> String {
+    let value = value.replace(['/', '\\'], "_");
+    if value.is_empty() || value.chars().all(|c| c == '.') { "_".to_string() } else { value }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{Attribute, AttributeValue};
+
+    fn metadata() -> NftMetadata {
+        NftMetadata {
+            name: "Eyes #7".to_string(),
+            description: String::new(),
+            image: String::new(),
+            edition: 7,
+            attributes: vec![Attribute {
+                trait_type: "Background".to_string(),
+                value: AttributeValue::Text("../Black".to_string()),
+                display_type: None,
+            }],
+            external_url: None,
+            background_color: None,
+            youtube_url: None,
+            animation_url: None,
+        }
+    }
+
+    #[test]
+    fn renders_variables() {
+        let meta = metadata();
+        let t = PathTemplate::parse("{layer:Background}/{id:04}/{id}-{name}.png").unwrap();
+        assert_eq!(t.render(7, 2, &meta), PathBuf::from(".._Black/0007/07-Eyes #7.png"));
+        let t = PathTemplate::parse("{trait:Goo}/{id}.json").unwrap();
+        assert_eq!(t.render(7, 0, &meta), PathBuf::from("none/7.json"));
+    }
+
+    #[test]
+    fn rejects_traversal_and_unknown_variables() {
+        assert!(PathTemplate::parse("../{id}.png").is_err());
+        assert!(PathTemplate::parse("/tmp/{id}.png").is_err());
+        assert!(PathTemplate::parse("{id}/../../x.png").is_err());
+        assert!(PathTemplate::parse("{token}.png").is_err());
+        assert!(PathTemplate::parse("{id.png").is_err());
+        assert!(PathTemplate::parse("{id:x}.png").is_err());
+        assert!(PathTemplate::parse("{file}").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::error::Result;
+#[cfg(not(feature = "dashset"))]
+use std::collections::HashSet;
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+
+/// メモリ上の使用済みキー。dashset feature ではシャードごとにロックする DashSet を使い、
+/// 並列生成中のワーカー同士が1つのロックを取り合わないようにする
+#[cfg(feature = "dashset")]
+type SeenSet = dashmap::DashSet<String>;
+#[cfg(not(feature = "dashset"))]
+type SeenSet = std::sync::Mutex<HashSet<String>>;
+
+/// 使用済みパターンキーの集合
+///
+/// pattern_db を指定した場合は SQLite の `patterns` テーブルを正とし、
+/// メモリ上の集合はその書き込みスルーキャッシュとして使う。
+/// 内部でロックするので、`&self` のまま rayon ワーカー間で共有できる。
+pub struct PatternStore {
+    seen: SeenSet,
+    #[cfg(feature = "sqlite")]
+    db: Option<Mutex<rusqlite::Connection>>,
+}
+
+impl PatternStore {
+    /// db_path が None ならメモリ上のみで管理する
+    pub fn open(db_path: Option<&str>) -> Result<Self> {
+        match db_path {
+            None => Ok(Self {
+                seen: SeenSet::default(),
+                #[cfg(feature = "sqlite")]
+                db: None,
+            }),
+            Some(path) => Self::open_db(path),
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn open_db(path: &str) -> Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS patterns (key TEXT PRIMARY KEY)",
+            [],
+        )?;
+        Ok(Self {
+            seen: SeenSet::default(),
+            db: Some(Mutex::new(conn)),
+        })
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn open_db(_path: &str) -> Result<Self> {
+        Err(crate::error::LayeredNftError::FeatureDisabled {
+            feature: "sqlite",
+            setting: "output.pattern_db".to_string(),
+        })
+    }
+
+    /// DB が設定されているか
+    pub fn is_persistent(&self) -> bool {
+        #[cfg(feature = "sqlite")]
+        {
+            self.db.is_some()
+        }
+        #[cfg(not(feature = "sqlite"))]
+        {
+            false
+        }
+    }
+
+    /// メモリ上のキャッシュに含まれているか（DB は見ない）
+    pub fn contains_cached(&self, key: &str) -> bool {
+        #[cfg(feature = "dashset")]
+        {
+            self.seen.contains(key)
+        }
+        #[cfg(not(feature = "dashset"))]
+        {
+            self.seen.lock().expect("seen のロックに失敗しました").contains(key)
+        }
+    }
+
+    /// 未使用なら登録して true を返す（確認と登録は不可分に行う）。DB がある場合は `INSERT OR IGNORE` で判定する
+    pub fn insert(&self, key: &str) -> Result<bool> {
+        if !self.insert_cached(key) {
+            return Ok(false);
+        }
+
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            let changed = db
+                .lock()
+                .expect("pattern_db のロックに失敗しました")
+                .execute("INSERT OR IGNORE INTO patterns (key) VALUES (?1)", [key])?;
+            return Ok(changed > 0);
+        }
+
+        Ok(true)
+    }
+
+    fn insert_cached(&self, key: &str) -> bool {
+        #[cfg(feature = "dashset")]
+        {
+            self.seen.insert(key.to_string())
+        }
+        #[cfg(not(feature = "dashset"))]
+        {
+            self.seen.lock().expect("seen のロックに失敗しました").insert(key.to_string())
+        }
+    }
+
+    /// 登録したキーを取り消す（トークンを書き出さなかった場合）
+    pub fn remove(&self, key: &str) -> Result<()> {
+        #[cfg(feature = "dashset")]
+        self.seen.remove(key);
+        #[cfg(not(feature = "dashset"))]
+        self.seen.lock().expect("seen のロックに失敗しました").remove(key);
+
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            db.lock()
+                .expect("pattern_db のロックに失敗しました")
+                .execute("DELETE FROM patterns WHERE key = ?1", [key])?;
+        }
+
+        Ok(())
+    }
+
+    /// 登録済みのパターンをすべて消去する（DB のテーブルも空にする）
+    pub fn clear(&self) -> Result<()> {
+        #[cfg(feature = "dashset")]
+        self.seen.clear();
+        #[cfg(not(feature = "dashset"))]
+        self.seen.lock().expect("seen のロックに失敗しました").clear();
+
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            db.lock()
+                .expect("pattern_db のロックに失敗しました")
+                .execute("DELETE FROM patterns", [])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+
+    #[test]
+    fn parallel_inserts_accept_each_key_once() {
+        // 10k トークン分のキーを、各キー4回ずつ複数スレッドから登録する
+        let store = PatternStore::open(None).unwrap();
+        let accepted = (0..40_000u32)
+            .into_par_iter()
+            .filter(|i| store.insert(&format!("layer/{}.png|<none>", i % 10_000)).unwrap())
+            .count();
+        assert_eq!(accepted, 10_000);
+        assert!(store.contains_cached("layer/9999.png|<none>"));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn db_persists_across_runs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("patterns.db");
+        let path = path.to_str().unwrap();
+
+        {
+            let store = PatternStore::open(Some(path)).unwrap();
+            assert!(store.insert("a|b").unwrap());
+            assert!(!store.insert("a|b").unwrap());
+        }
+
+        let store = PatternStore::open(Some(path)).unwrap();
+        assert!(!store.contains_cached("a|b"));
+        assert!(!store.insert("a|b").unwrap());
+        assert!(store.insert("a|c").unwrap());
+
+        store.clear().unwrap();
+        assert!(store.insert("a|b").unwrap());
+    }
+}
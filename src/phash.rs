@@ -0,0 +1,132 @@
+use image::DynamicImage;
+use image::imageops::FilterType;
+
+/// 画像の 64bit difference hash（dHash）
+///
+/// 9x8 のグレースケールに縮小し、横に隣り合う画素の明暗を1ビットずつ並べる。
+/// 見た目が近い画像ほどハミング距離が小さくなる。
+/// image_hasher と同じ手順だが、使うのはこの1種類だけで、BK 木には u64 のまま載せたいので
+/// 依存を増やさずに既存の image クレートの縮小処理で計算している。
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// 2つのハッシュのハミング距離
+pub fn hamming(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// ハミング距離による BK 木（距離 threshold 未満のハッシュを総当たりせずに探す）
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    id: u32,
+    hash: u64,
+    /// (親との距離, 子ノードの添字)
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    fn new() -> Self {
+        Self { nodes: Vec::new() }
+    }
+
+    fn insert(&mut self, id: u32, hash: u64) {
+        let new = self.nodes.len();
+        self.nodes.push(BkNode { id, hash, children: Vec::new() });
+        if new == 0 {
+            return;
+        }
+        let mut current = 0;
+        loop {
+            let d = hamming(self.nodes[current].hash, hash);
+            match self.nodes[current].children.iter().find(|(cd, _)| *cd == d) {
+                Some(&(_, child)) => current = child,
+                None => {
+                    self.nodes[current].children.push((d, new));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// hash との距離が threshold 未満のノードの (id, 距離)
+    fn find(&self, hash: u64, threshold: u32) -> Vec<(u32, u32)> {
+        let mut found = Vec::new();
+        if self.nodes.is_empty() {
+            return found;
+        }
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            let d = hamming(node.hash, hash);
+            if d < threshold {
+                found.push((node.id, d));
+            }
+            // 三角不等式により |d - cd| < threshold の子だけを辿ればよい
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(cd, _)| cd.abs_diff(d) < threshold)
+                    .map(|&(_, child)| child),
+            );
+        }
+        found
+    }
+}
+
+/// ハミング距離が threshold 未満のトークンの組 (token_a, token_b, 距離) を返す（token_a < token_b 順にソート）
+pub fn similar_pairs(hashes: &[(u32, u64)], threshold: u32) -> Vec<(u32, u32, u32)> {
+    let mut tree = BkTree::new();
+    let mut pairs = Vec::new();
+    for &(id, hash) in hashes {
+        for (other, d) in tree.find(hash, threshold) {
+            pairs.push((other.min(id), other.max(id), d));
+        }
+        tree.insert(id, hash);
+    }
+    pairs.sort_unstable();
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similar_pairs_matches_brute_force() {
+        // 適当な疑似乱数で近いハッシュと遠いハッシュを混ぜる
+        let mut x = 0x9E37_79B9_7F4A_7C15u64;
+        let mut hashes = Vec::new();
+        for id in 0..300u32 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let hash = if id % 3 == 0 { hashes.last().map_or(x, |&(_, h): &(u32, u64)| h ^ (x & 0x0101)) } else { x };
+            hashes.push((id, hash));
+        }
+
+        let mut expected = Vec::new();
+        for (i, &(a, ha)) in hashes.iter().enumerate() {
+            for &(b, hb) in &hashes[i + 1..] {
+                if hamming(ha, hb) < 8 {
+                    expected.push((a, b, hamming(ha, hb)));
+                }
+            }
+        }
+        assert!(!expected.is_empty());
+        assert_eq!(similar_pairs(&hashes, 8), expected);
+    }
+}
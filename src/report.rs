@@ -0,0 +1,143 @@
+use crate::check::{CheckReport, RARITY_RANKS_FILE};
+use crate::config::Config;
+use crate::error::Result;
+use crate::output::write_file;
+use std::fmt::Write as _;
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// stats --report-html の HTML テンプレート（`{{名前}}` を置き換える）
+const TEMPLATE: &str = include_str!("report.html");
+
+/// レア度統計を1ファイルで完結する HTML（インライン CSS のみ、JavaScript なし）にして path に書き出す
+///
+/// 生成日時は `_metadata.json` の更新日時（なければ現在時刻）。
+/// metadata_dir に rarity_ranks.json があれば、HTML からの相対パスでリンクする
+pub fn write_html_report(cfg: &Config, report: &CheckReport, path: &Path) -> Result<()> {
+    let metadata_dir = Path::new(&cfg.output.metadata_dir);
+    let generated_at = std::fs::metadata(metadata_dir.join("_metadata.json"))
+        .and_then(|m| m.modified())
+        .unwrap_or_else(|_| SystemTime::now());
+    let ranks = metadata_dir.join(RARITY_RANKS_FILE);
+    let ranks_href = ranks.is_file().then(|| relative_href(path, &ranks));
+    let title = cfg.metadata.collection.as_ref().map_or(&cfg.metadata.name, |c| &c.name);
+    let html = render_html_report(title, report, generated_at, ranks_href.as_deref());
+    write_file(path, html, cfg.output.writes_atomically())
+}
+
+/// レア度統計の HTML を組み立てる（trait_type は名前順、値は出現数の多い順）
+pub fn render_html_report(title: &str, report: &CheckReport, generated_at: SystemTime, ranks_href: Option<&str>) -> String {
+    let mut trait_types: Vec<_> = report.stats.iter().collect();
+    trait_types.sort_by_key(|(trait_type, _)| *trait_type);
+
+    let mut tables = String::new();
+    for (trait_type, values) in trait_types {
+        let mut sorted: Vec<_> = values.iter().collect();
+        sorted.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        let _ = writeln!(tables, "<section>\n  <h2>{}</h2>", escape(trait_type));
+        tables.push_str("  <table>\n    <tr><th>Value</th><th>Count</th><th>%</th><th></th></tr>\n");
+        for (value, count) in sorted {
+            let ratio = if report.total == 0 { 0.0 } else { *count as f64 / report.total as f64 * 100.0 };
+            let _ = writeln!(
+                tables,
+                "    <tr><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{:.2}%</td>\
+                 <td class=\"bar\"><div class=\"bar-track\"><div class=\"bar-fill\" style=\"width: {:.2}%\"></div></div></td></tr>",
+                escape(value),
+                count,
+                ratio,
+                ratio
+            );
+        }
+        tables.push_str("  </table>\n</section>\n");
+    }
+
+    let ranks_link = ranks_href.map_or_else(String::new, |href| {
+        format!("<span><a href=\"{}\">{}</a></span>", escape(href), RARITY_RANKS_FILE)
+    });
+    TEMPLATE
+        .replace("{{title}}", &escape(title))
+        .replace("{{total}}", &report.total.to_string())
+        .replace("{{generated_at}}", &format_utc(generated_at))
+        .replace("{{ranks_link}}", &ranks_link)
+        .replace("{{tables}}", &tables)
+}
+
+/// HTML の本文・属性値に埋め込めるようにエスケープする
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// "YYYY-MM-DD HH:MM UTC" 形式にする
+fn format_utc(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+    // 1970-01-01 からの日数を年月日に変換する（proleptic グレゴリオ暦）
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, rest / 3_600, rest % 3_600 / 60)
+}
+
+/// HTML ファイル report から target へのリンク（同じディレクトリ基準の相対パス。求められなければ絶対パス）
+fn relative_href(report: &Path, target: &Path) -> String {
+    let (Ok(report), Ok(target)) = (std::path::absolute(report), std::path::absolute(target)) else {
+        return target.to_string_lossy().replace('\\', "/");
+    };
+    let from: Vec<Component> = report.parent().map_or_else(Vec::new, |p| p.components().collect());
+    let to: Vec<Component> = target.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return target.to_string_lossy().replace('\\', "/");
+    }
+    let mut parts: Vec<String> = vec!["..".to_string(); from.len() - common];
+    parts.extend(to[common..].iter().map(|c| c.as_os_str().to_string_lossy().to_string()));
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn html_report_has_escaped_rows_and_bars() {
+        let report = CheckReport {
+            total: 4,
+            stats: HashMap::from([(
+                "Eyeball".to_string(),
+                HashMap::from([("Red".to_string(), 3), ("<White>".to_string(), 1)]),
+            )]),
+            ..Default::default()
+        };
+        let generated_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let html = render_html_report("My & Co", &report, generated_at, Some("metadata/rarity_ranks.json"));
+
+        assert!(html.contains("<title>My &amp; Co - Rarity Report</title>"));
+        assert!(html.contains("2023-11-14 22:13 UTC"));
+        assert!(html.contains("<a href=\"metadata/rarity_ranks.json\">"));
+        assert!(html.contains("<td>&lt;White&gt;</td>"));
+        assert!(html.contains("style=\"width: 75.00%\""));
+        assert!(html.find("<td>Red</td>").unwrap() < html.find("<td>&lt;White&gt;</td>").unwrap());
+        assert!(!html.contains("{{"));
+        assert_eq!(relative_href(Path::new("out/report.html"), Path::new("out/metadata/rarity_ranks.json")), "metadata/rarity_ranks.json");
+    }
+}
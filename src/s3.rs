@@ -0,0 +1,313 @@
+use crate::check::written_image_path;
+use crate::config::{Config, S3Config};
+use crate::error::{LayeredNftError, Result};
+use crate::output::write_file;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 同時アップロード数の既定値
+pub const DEFAULT_CONCURRENCY: usize = 16;
+
+/// upload_collection の実行結果
+#[derive(Debug, Default)]
+pub struct UploadReport {
+    /// アップロードした画像（アニメーションを含む）の数
+    pub images: usize,
+    /// アップロードしたメタデータの数
+    pub metadata: usize,
+    /// アップロードした合計バイト数
+    pub bytes: u64,
+    /// 画像 URL を書き換えたメタデータの数
+    pub rewritten: usize,
+    /// 画像の公開 URL のベース（`{base}/{id}.{ext}` で参照できる）
+    pub image_base_url: String,
+}
+
+/// アップロードする1ファイル
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+struct UploadFile {
+    path: PathBuf,
+    key: String,
+    content_type: &'static str,
+}
+
+/// `{prefix}/{kind}/{file_name}` 形式のオブジェクトキー（prefix 前後の `/` は無視する）
+pub fn object_key(prefix: Option<&str>, kind: &str, file_name: &str) -> String {
+    match prefix.map(|p| p.trim_matches('/')).filter(|p| !p.is_empty()) {
+        Some(prefix) => format!("{}/{}/{}", prefix, kind, file_name),
+        None => format!("{}/{}", kind, file_name),
+    }
+}
+
+/// 画像の公開 URL のベース
+/// public_url > endpoint（パス形式）> AWS の仮想ホスト形式 の順に決める
+pub fn image_base_url(s3: &S3Config) -> String {
+    let root = match (&s3.public_url, &s3.endpoint) {
+        (Some(url), _) => url.trim_end_matches('/').to_string(),
+        (None, Some(endpoint)) => format!("{}/{}", endpoint.trim_end_matches('/'), s3.bucket),
+        (None, None) => format!("https://{}.s3.{}.amazonaws.com", s3.bucket, s3.region),
+    };
+    format!("{}/{}", root, object_key(s3.prefix.as_deref(), "images", "")).trim_end_matches('/').to_string()
+}
+
+/// 設定値（省略時は環境変数 fallback）から認証情報を取り出す（設定ファイルの `${VAR}` は読み込み時に展開済み）
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+fn credential(value: Option<&str>, field: &str, fallback: &str) -> Result<String> {
+    match value {
+        Some(v) => Ok(v.to_string()),
+        None => std::env::var(fallback).map_err(|_| {
+            LayeredNftError::InvalidConfig(format!(
+                "{} が未指定で、環境変数 {} も設定されていません",
+                field, fallback
+            ))
+        }),
+    }
+}
+
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+fn content_type(ext: &str) -> &'static str {
+    match ext {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "gif" => "image/gif",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// 書き出し済みの画像（とアニメーション）を token_id 順に列挙する
+///
+/// 画像は image_path_template の展開先を使い、キーも image_dir からの相対パスにする
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+fn image_files(cfg: &Config, s3: &S3Config) -> Vec<UploadFile> {
+    let image_dir = Path::new(&cfg.output.image_dir);
+    let mut files = Vec::new();
+    for token_id in 1..=cfg.count {
+        let image = written_image_path(cfg, token_id).map(|path| (path, cfg.output.format.extension()));
+        let animation = cfg.output.animation.as_ref().map(|anim| {
+            (image_dir.join(format!("{}.{}", token_id, anim.format.extension())), anim.format.extension())
+        });
+        for (path, ext) in image.into_iter().chain(animation) {
+            let relative = path.strip_prefix(image_dir).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+            files.push(UploadFile {
+                key: object_key(s3.prefix.as_deref(), "images", &relative),
+                content_type: content_type(ext),
+                path,
+            });
+        }
+    }
+    files.retain(|f| f.path.is_file());
+    files
+}
+
+/// 書き出し済みのメタデータ（{id}.json）を token_id 順に列挙する
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+fn metadata_files(cfg: &Config, s3: &S3Config) -> Vec<UploadFile> {
+    (1..=cfg.count)
+        .map(|token_id| {
+            let file_name = format!("{}.json", token_id);
+            UploadFile {
+                path: PathBuf::from(&cfg.output.metadata_dir).join(&file_name),
+                key: object_key(s3.prefix.as_deref(), "metadata", &file_name),
+                content_type: content_type("json"),
+            }
+        })
+        .filter(|f| f.path.is_file())
+        .collect()
+}
+
+/// メタデータの画像 URL（`image`・`animation_url`・`properties.files[].uri`）のうち
+/// `{base_image_url}/...`（ipfs_placeholder 指定時はそちら）で始まるものを new_base に付け替える
+/// 書き換えたファイルの数を返す
+pub fn rewrite_image_urls(cfg: &Config, new_base: &str) -> Result<usize> {
+    let mut old_bases = vec![format!("{}/", cfg.metadata.base_image_url.trim_end_matches('/'))];
+    if let Some(placeholder) = &cfg.metadata.ipfs_placeholder {
+        old_bases.push(format!("{}/", placeholder.trim_end_matches('/')));
+    }
+    let new_base = format!("{}/", new_base.trim_end_matches('/'));
+
+    let mut rewritten = 0;
+    for token_id in 1..=cfg.count {
+        let path = PathBuf::from(&cfg.output.metadata_dir).join(format!("{}.json", token_id));
+        let Ok(text) = fs::read_to_string(&path) else { continue; };
+        let mut json: Value = serde_json::from_str(&text).map_err(LayeredNftError::json(&path))?;
+        if replace_prefix(&mut json, &old_bases, &new_base) {
+            let out = serde_json::to_string_pretty(&json).map_err(LayeredNftError::json(&path))?;
+            write_file(&path, out, cfg.output.writes_atomically())?;
+            rewritten += 1;
+        }
+    }
+    Ok(rewritten)
+}
+
+/// 画像 URL のフィールドだけ接頭辞を置き換える（変更があれば true）
+///
+/// external_url など、同じベース URL を共有する他のフィールドは書き換えない
+fn replace_prefix(json: &mut Value, old_bases: &[String], new_base: &str) -> bool {
+    let mut changed = false;
+    for key in ["image", "animation_url"] {
+        if let Some(value) = json.get_mut(key) {
+            changed |= replace_url_prefix(value, old_bases, new_base);
+        }
+    }
+    if let Some(Value::Array(files)) = json.pointer_mut("/properties/files") {
+        for file in files {
+            if let Some(uri) = file.get_mut("uri") {
+                changed |= replace_url_prefix(uri, old_bases, new_base);
+            }
+        }
+    }
+    changed
+}
+
+/// 文字列が old_bases のいずれかで始まっていれば new_base に付け替える（変更があれば true）
+fn replace_url_prefix(value: &mut Value, old_bases: &[String], new_base: &str) -> bool {
+    let Value::String(s) = value else { return false; };
+    let Some(rest) = old_bases.iter().find_map(|old| s.strip_prefix(old.as_str())) else {
+        return false;
+    };
+    *s = format!("{}{}", new_base, rest);
+    true
+}
+
+/// 書き出し済みの画像とメタデータを output.s3 のバケットへアップロードする
+///
+/// 画像を先にすべてアップロードし、rewrite_image_url が有効ならメタデータの画像 URL を書き換えてから
+/// メタデータをアップロードする。同時アップロード数は concurrency（未指定 = 16）まで
+#[cfg(feature = "s3")]
+pub fn upload_collection(cfg: &Config) -> Result<UploadReport> {
+    use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+    use futures::stream::{self, StreamExt, TryStreamExt};
+
+    let Some(s3) = &cfg.output.s3 else {
+        return Err(LayeredNftError::InvalidConfig("output.s3 が設定されていません".to_string()));
+    };
+    let access_key_id = credential(s3.access_key_id.as_deref(), "output.s3.access_key_id", "AWS_ACCESS_KEY_ID")?;
+    let secret_access_key =
+        credential(s3.secret_access_key.as_deref(), "output.s3.secret_access_key", "AWS_SECRET_ACCESS_KEY")?;
+
+    let mut builder = aws_sdk_s3::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new(s3.region.clone()))
+        .credentials_provider(Credentials::new(access_key_id, secret_access_key, None, None, "layered-nft-gen"));
+    if let Some(endpoint) = &s3.endpoint {
+        // S3 互換ストレージの多くは仮想ホスト形式に対応していないため、パス形式にする
+        builder = builder.endpoint_url(endpoint).force_path_style(true);
+    }
+    let client = aws_sdk_s3::Client::from_conf(builder.build());
+    let concurrency = s3.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| LayeredNftError::Upload { key: s3.bucket.clone(), message: e.to_string() })?;
+
+    runtime.block_on(async {
+        let upload_all = |files: Vec<UploadFile>| {
+            let client = &client;
+            stream::iter(files)
+                .map(move |file| async move {
+                    let bytes = fs::metadata(&file.path).map_err(LayeredNftError::io(&file.path))?.len();
+                    let body = aws_sdk_s3::primitives::ByteStream::from_path(&file.path)
+                        .await
+                        .map_err(|e| LayeredNftError::Upload { key: file.key.clone(), message: e.to_string() })?;
+                    client
+                        .put_object()
+                        .bucket(&s3.bucket)
+                        .key(&file.key)
+                        .content_type(file.content_type)
+                        .body(body)
+                        .send()
+                        .await
+                        .map_err(|e| LayeredNftError::Upload {
+                            key: file.key.clone(),
+                            message: aws_sdk_s3::error::DisplayErrorContext(e).to_string(),
+                        })?;
+                    Ok::<u64, LayeredNftError>(bytes)
+                })
+                .buffer_unordered(concurrency)
+                .try_collect::<Vec<u64>>()
+        };
+
+        let mut report = UploadReport { image_base_url: image_base_url(s3), ..UploadReport::default() };
+        let images = upload_all(image_files(cfg, s3)).await?;
+        report.images = images.len();
+        report.bytes += images.iter().sum::<u64>();
+
+        if s3.rewrite_image_url {
+            report.rewritten = rewrite_image_urls(cfg, &report.image_base_url)?;
+        }
+
+        let metadata = upload_all(metadata_files(cfg, s3)).await?;
+        report.metadata = metadata.len();
+        report.bytes += metadata.iter().sum::<u64>();
+        Ok(report)
+    })
+}
+
+#[cfg(not(feature = "s3"))]
+pub fn upload_collection(_cfg: &Config) -> Result<UploadReport> {
+    Err(LayeredNftError::FeatureDisabled {
+        feature: "s3",
+        setting: "output.s3".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s3_config() -> S3Config {
+        S3Config {
+            bucket: "nft".to_string(),
+            region: "ap-northeast-1".to_string(),
+            prefix: Some("/drops/1/".to_string()),
+            access_key_id: None,
+            secret_access_key: None,
+            endpoint: None,
+            concurrency: None,
+            rewrite_image_url: true,
+            public_url: None,
+        }
+    }
+
+    #[test]
+    fn keys_and_public_url_follow_prefix_and_endpoint() {
+        let mut s3 = s3_config();
+        assert_eq!(object_key(s3.prefix.as_deref(), "images", "1.png"), "drops/1/images/1.png");
+        assert_eq!(object_key(None, "metadata", "1.json"), "metadata/1.json");
+        assert_eq!(image_base_url(&s3), "https://nft.s3.ap-northeast-1.amazonaws.com/drops/1/images");
+
+        s3.endpoint = Some("http://localhost:9000/".to_string());
+        s3.prefix = None;
+        assert_eq!(image_base_url(&s3), "http://localhost:9000/nft/images");
+
+        s3.public_url = Some("https://cdn.example.com".to_string());
+        assert_eq!(image_base_url(&s3), "https://cdn.example.com/images");
+    }
+
+    #[test]
+    fn replaces_only_urls_under_old_base() {
+        let mut json: Value = serde_json::json!({
+            "image": "https://example.com/images/1.png",
+            "animation_url": "https://example.com/images/1.gif",
+            "external_url": "https://example.com/images/1.html",
+            "description": "https://example.com/images/",
+            "properties": { "files": [{ "uri": "https://example.com/images/1.png" }] }
+        });
+        let old = vec!["https://example.com/images/".to_string()];
+        assert!(replace_prefix(&mut json, &old, "https://cdn.example.com/images/"));
+        assert_eq!(json["image"], "https://cdn.example.com/images/1.png");
+        assert_eq!(json["animation_url"], "https://cdn.example.com/images/1.gif");
+        assert_eq!(json["properties"]["files"][0]["uri"], "https://cdn.example.com/images/1.png");
+        // 画像 URL 以外のフィールドは同じベース URL でも書き換えない
+        assert_eq!(json["external_url"], "https://example.com/images/1.html");
+        assert_eq!(json["description"], "https://example.com/images/");
+
+        let mut other = serde_json::json!({ "image": "ipfs://cid/1.png" });
+        assert!(!replace_prefix(&mut other, &old, "https://cdn.example.com/images/"));
+    }
+}
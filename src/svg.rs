@@ -0,0 +1,67 @@
+use crate::error::{LayeredNftError, Result};
+use image::RgbaImage;
+use std::path::Path;
+
+/// SVG ファイルか（拡張子で判定）
+pub fn is_svg(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+}
+
+/// SVG をパースして元のサイズ（px、切り上げ）を返す。起動時の検証にも使う
+#[cfg(feature = "svg")]
+pub fn svg_size(path: &Path) -> Result<(u32, u32)> {
+    let tree = parse(path)?;
+    let size = tree.size();
+    Ok((size.width().ceil() as u32, size.height().ceil() as u32))
+}
+
+/// SVG を width x height にラスタライズする（縦横比が異なる場合は引き伸ばす）
+#[cfg(feature = "svg")]
+pub fn rasterize(path: &Path, width: u32, height: u32) -> Result<RgbaImage> {
+    use resvg::tiny_skia::{Pixmap, Transform};
+
+    let tree = parse(path)?;
+    let size = tree.size();
+    let mut pixmap = Pixmap::new(width, height)
+        .ok_or_else(|| LayeredNftError::Svg {
+            path: path.to_path_buf(),
+            message: format!("SVG の描画先を確保できません: {}x{}", width, height),
+        })?;
+    let transform = Transform::from_scale(width as f32 / size.width(), height as f32 / size.height());
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia は乗算済みアルファなので、通常の RGBA に戻す
+    let mut img = RgbaImage::new(width, height);
+    for (dst, src) in img.pixels_mut().zip(pixmap.pixels()) {
+        let c = src.demultiply();
+        dst.0 = [c.red(), c.green(), c.blue(), c.alpha()];
+    }
+    Ok(img)
+}
+
+#[cfg(feature = "svg")]
+fn parse(path: &Path) -> Result<resvg::usvg::Tree> {
+    let data = std::fs::read(path).map_err(LayeredNftError::io(path))?;
+    resvg::usvg::Tree::from_data(&data, &resvg::usvg::Options::default()).map_err(|e| LayeredNftError::Svg {
+        path: path.to_path_buf(),
+        message: format!("SVG を解析できません（usvg: {}）。ファイルが壊れていないか確認してください", e),
+    })
+}
+
+#[cfg(not(feature = "svg"))]
+pub fn svg_size(path: &Path) -> Result<(u32, u32)> {
+    disabled(path)
+}
+
+#[cfg(not(feature = "svg"))]
+pub fn rasterize(path: &Path, _width: u32, _height: u32) -> Result<RgbaImage> {
+    disabled(path)
+}
+
+#[cfg(not(feature = "svg"))]
+fn disabled<T>(path: &Path) -> Result<T> {
+    Err(LayeredNftError::FeatureDisabled {
+        feature: "svg",
+        setting: format!("SVG レイヤー {:?}", path),
+    })
+}
@@ -0,0 +1,143 @@
+use crate::config::LayerTransform;
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+
+/// レイヤー画像に拡大縮小・回転・平行移動を適用し、元と同じサイズのキャンバスに配置し直す
+///
+/// 拡大縮小後のサイズや中央寄せの位置など、端数が出るピクセル位置は四捨五入する。
+/// キャンバスからはみ出した部分は切り捨て、空いた部分は透明になる。
+pub fn apply_transform(img: &RgbaImage, t: &LayerTransform) -> RgbaImage {
+    let (width, height) = img.dimensions();
+
+    let scale = t.scale.unwrap_or(1.0);
+    let scaled = if scale > 0.0 && scale != 1.0 {
+        let w = ((width as f32 * scale).round() as u32).max(1);
+        let h = ((height as f32 * scale).round() as u32).max(1);
+        imageops::resize(img, w, h, FilterType::Triangle)
+    } else {
+        img.clone()
+    };
+
+    let rotated = match t.rotate_degrees {
+        Some(deg) if deg.rem_euclid(360.0) != 0.0 => rotate(&scaled, deg),
+        _ => scaled,
+    };
+
+    // 変形後の画像の中心をキャンバス中心 + 平行移動量に合わせる
+    let x = ((width as f32 - rotated.width() as f32) / 2.0).round() as i64 + t.translate_x.unwrap_or(0) as i64;
+    let y = ((height as f32 - rotated.height() as f32) / 2.0).round() as i64 + t.translate_y.unwrap_or(0) as i64;
+
+    let mut canvas = RgbaImage::new(width, height);
+    imageops::replace(&mut canvas, &rotated, x, y);
+    canvas
+}
+
+/// 時計回りに回転する。90 度単位はそのまま、それ以外はバイリニア補間で
+/// 回転後の外接矩形まで透明ピクセルでキャンバスを広げる
+fn rotate(img: &RgbaImage, degrees: f32) -> RgbaImage {
+    let deg = degrees.rem_euclid(360.0);
+    if deg == 90.0 {
+        return imageops::rotate90(img);
+    }
+    if deg == 180.0 {
+        return imageops::rotate180(img);
+    }
+    if deg == 270.0 {
+        return imageops::rotate270(img);
+    }
+
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let (sin, cos) = deg.to_radians().sin_cos();
+    let out_w = (w * cos.abs() + h * sin.abs()).ceil() as u32;
+    let out_h = (w * sin.abs() + h * cos.abs()).ceil() as u32;
+
+    let (cx, cy) = (w / 2.0, h / 2.0);
+    let (ocx, ocy) = (out_w as f32 / 2.0, out_h as f32 / 2.0);
+
+    RgbaImage::from_fn(out_w, out_h, |x, y| {
+        // 出力ピクセル中心を逆回転して元画像上の座標を求める
+        let dx = x as f32 + 0.5 - ocx;
+        let dy = y as f32 + 0.5 - ocy;
+        let sx = dx * cos + dy * sin + cx - 0.5;
+        let sy = -dx * sin + dy * cos + cy - 0.5;
+        sample_bilinear(img, sx, sy)
+    })
+}
+
+/// 範囲外は透明として扱うバイリニア補間（α で重み付けして色のにじみを防ぐ）
+fn sample_bilinear(img: &RgbaImage, x: f32, y: f32) -> Rgba<u8> {
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let fx = x - x0;
+    let fy = y - y0;
+
+    let pixel = |px: f32, py: f32| -> [f32; 4] {
+        if px < 0.0 || py < 0.0 || px >= img.width() as f32 || py >= img.height() as f32 {
+            return [0.0; 4];
+        }
+        let p = img.get_pixel(px as u32, py as u32).0;
+        [p[0] as f32, p[1] as f32, p[2] as f32, p[3] as f32]
+    };
+
+    let samples = [
+        (pixel(x0, y0), (1.0 - fx) * (1.0 - fy)),
+        (pixel(x0 + 1.0, y0), fx * (1.0 - fy)),
+        (pixel(x0, y0 + 1.0), (1.0 - fx) * fy),
+        (pixel(x0 + 1.0, y0 + 1.0), fx * fy),
+    ];
+
+    let mut rgb = [0.0f32; 3];
+    let mut alpha = 0.0f32;
+    for (p, weight) in samples {
+        let a = p[3] * weight;
+        alpha += a;
+        for c in 0..3 {
+            rgb[c] += p[c] * a;
+        }
+    }
+
+    if alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    Rgba([
+        (rgb[0] / alpha).round().clamp(0.0, 255.0) as u8,
+        (rgb[1] / alpha).round().clamp(0.0, 255.0) as u8,
+        (rgb[2] / alpha).round().clamp(0.0, 255.0) as u8,
+        alpha.round().clamp(0.0, 255.0) as u8,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_keeps_canvas_size() {
+        let img = RgbaImage::from_pixel(8, 6, Rgba([255, 0, 0, 255]));
+        let t = LayerTransform {
+            translate_x: Some(2),
+            translate_y: None,
+            scale: Some(0.5),
+            rotate_degrees: Some(30.0),
+        };
+        let out = apply_transform(&img, &t);
+        assert_eq!(out.dimensions(), (8, 6));
+        assert_eq!(out.get_pixel(0, 0).0[3], 0);
+        assert_eq!(out.get_pixel(6, 3).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn translate_moves_pixels() {
+        let mut img = RgbaImage::new(4, 4);
+        img.put_pixel(0, 0, Rgba([0, 0, 255, 255]));
+        let t = LayerTransform {
+            translate_x: Some(1),
+            translate_y: Some(2),
+            scale: None,
+            rotate_degrees: None,
+        };
+        let out = apply_transform(&img, &t);
+        assert_eq!(out.get_pixel(1, 2).0, [0, 0, 255, 255]);
+        assert_eq!(out.get_pixel(0, 0).0[3], 0);
+    }
+}
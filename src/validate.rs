@@ -0,0 +1,550 @@
+use crate::config::{Config, LayerConfig, TraitValue};
+use crate::error::{LayeredNftError, Result};
+use crate::generator::{collect_layer_files, layer_dimensions, layer_options};
+use crate::path_template::PathTemplate;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// 生成は続けられるが、設定の書き間違いの可能性があるもの
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationWarning {
+    /// 問題のある設定項目（例: `layers[2].rarity`）
+    pub field: String,
+    pub message: String,
+}
+
+/// 生成を始める前に直す必要があるもの
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// 問題のある設定項目（例: `constraints.forbidden_pairs[0].a`）
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// レイヤーディレクトリを走査した結果
+struct LayerEntries {
+    /// 選択肢になるファイル / バリアントのファイル名（rarity のキーと照合する）
+    names: HashSet<String>,
+    /// 拡張子を除いた名前（トレイト値と照合する）
+    stems: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Report {
+    warnings: Vec<ValidationWarning>,
+    errors: Vec<ValidationError>,
+}
+
+impl Report {
+    fn warn(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.warnings.push(ValidationWarning { field: field.into(), message: message.into() });
+    }
+
+    fn error(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(ValidationError { field: field.into(), message: message.into() });
+    }
+}
+
+impl Config {
+    /// 設定の値をファイルシステムと突き合わせ、生成前に整合性を検証する
+    ///
+    /// 読み込み時の形式チェックとは別に、レイヤーディレクトリの有無や、
+    /// 禁則などで参照しているトレイトが実在するかを確認する。
+    /// エラーが1つでもあれば Err にすべてのエラーを、なければ Ok に警告を返す。
+    pub fn validate(config: &Config) -> std::result::Result<Vec<ValidationWarning>, Vec<ValidationError>> {
+        let mut report = Report::default();
+        let animation = config.output.animation.is_some();
+
+        let mut layers: HashMap<&str, (&LayerConfig, Option<LayerEntries>)> = HashMap::new();
+        for (i, layer) in config.layers.iter().enumerate() {
+            let field = format!("layers[{}]", i);
+            if layers.contains_key(layer.name.as_str()) {
+                report.error(&field, format!("レイヤー名 {:?} が重複しています", layer.name));
+                continue;
+            }
+            let entries = scan_layer(layer, animation, &field, &mut report);
+            if let Some(entries) = &entries {
+                check_layer_keys(layer, entries, &field, &mut report);
+            }
+            layers.insert(layer.name.as_str(), (layer, entries));
+        }
+
+        let combinations = config
+            .layers
+            .iter()
+            .filter_map(|l| layers.get(l.name.as_str()))
+            .map(|(layer, entries)| {
+                let files = entries.as_ref().map_or(0, |e| e.names.len()) as u128;
+                match layer.optional_probability {
+                    Some(p) if p <= 0.0 => 1,
+                    Some(p) if p < 1.0 => files + 1,
+                    _ => files,
+                }
+            })
+            .product::<u128>();
+        let can_be_empty = config.layers.iter().all(|l| l.optional_probability.is_some_and(|p| p < 1.0));
+        let combinations = combinations - u128::from(can_be_empty);
+        if report.errors.is_empty() && config.count as u128 > combinations {
+            report.error(
+                "count",
+                format!(
+                    "生成数 {} が理論上の最大組み合わせ数 {} を超えています",
+                    config.count, combinations
+                ),
+            );
+        }
+
+        if let Some(c) = &config.constraints {
+            let mut check = |field: String, t: &TraitValue| check_trait(&layers, &field, t, &mut report);
+            for (i, p) in c.forbidden_pairs.iter().flatten().enumerate() {
+                check(format!("constraints.forbidden_pairs[{}].a", i), &p.a);
+                check(format!("constraints.forbidden_pairs[{}].b", i), &p.b);
+            }
+            for (i, t) in c.required_attributes.iter().flatten().enumerate() {
+                check(format!("constraints.required_attributes[{}]", i), t);
+            }
+            for (i, g) in c.forbidden_groups.iter().flatten().enumerate() {
+                for (j, m) in g.members.iter().enumerate() {
+                    check(format!("constraints.forbidden_groups[{}].members[{}]", i, j), m);
+                }
+            }
+            for (i, p) in c.required_pairs.iter().flatten().enumerate() {
+                check(format!("constraints.required_pairs[{}].a", i), &p.a);
+                check(format!("constraints.required_pairs[{}].b", i), &p.b);
+            }
+            for (i, g) in c.exclusive_groups.iter().flatten().enumerate() {
+                for (j, m) in g.members.iter().enumerate() {
+                    check(format!("constraints.exclusive_groups[{}].members[{}]", i, j), m);
+                }
+            }
+            for (i, g) in c.required_group.iter().flatten().enumerate() {
+                for (j, m) in g.members.iter().enumerate() {
+                    check(format!("constraints.required_group[{}].members[{}]", i, j), m);
+                }
+            }
+            for (i, cond) in c.conditional_constraints.iter().flatten().enumerate() {
+                check(format!("constraints.conditional_constraints[{}].condition", i), &cond.condition);
+                for value in &cond.allowed_values {
+                    let t = TraitValue::new(cond.then_layer.clone(), value.clone());
+                    check(format!("constraints.conditional_constraints[{}].allowed_values", i), &t);
+                }
+            }
+            for (i, pattern) in c.excluded_patterns.iter().flatten().enumerate() {
+                for (trait_type, value) in pattern {
+                    let t = TraitValue::new(trait_type.clone(), value.clone());
+                    check(format!("constraints.excluded_patterns[{}]", i), &t);
+                }
+            }
+            for (i, tier) in c.tiers.iter().flatten().enumerate() {
+                for (j, t) in tier.required_traits.iter().enumerate() {
+                    check(format!("constraints.tiers[{}].required_traits[{}]", i, j), t);
+                }
+            }
+        }
+
+        if let Some(wm) = &config.output.watermark
+            && !Path::new(&wm.image_path).is_file()
+        {
+            report.error(
+                "output.watermark.image_path",
+                format!("ウォーターマーク画像が見つかりません: {}", wm.image_path),
+            );
+        }
+
+        if let Some(frame) = &config.output.frame
+            && !Path::new(&frame.image_path).is_file()
+        {
+            report.error("output.frame.image_path", format!("枠の画像が見つかりません: {}", frame.image_path));
+        }
+
+        if let Some(dir) = &config.output.metadata_overrides_dir
+            && !Path::new(dir).is_dir()
+        {
+            report.error(
+                "output.metadata_overrides_dir",
+                format!("メタデータの差し替え用ディレクトリが見つかりません: {}", dir),
+            );
+        }
+
+        for (i, layer) in config.layers.iter().enumerate() {
+            if let Some(mask) = &layer.mask_path
+                && !Path::new(mask).is_file()
+            {
+                report.error(format!("layers[{}].mask_path", i), format!("マスク画像が見つかりません: {}", mask));
+            }
+        }
+
+        for (field, template) in [
+            ("output.image_path_template", &config.output.image_path_template),
+            ("output.metadata_path_template", &config.output.metadata_path_template),
+        ] {
+            let Some(template) = template.as_deref().and_then(|t| PathTemplate::parse(t).ok()) else { continue; };
+            for name in template.layer_names() {
+                if !layers.contains_key(name) {
+                    report.error(field, format!("{{layer:{}}} に一致するレイヤー（layers[].name）がありません", name));
+                }
+            }
+        }
+        if config.output.metadata_path_template.is_some() {
+            report.warn(
+                "output.metadata_path_template",
+                "check / stats / --resume など既存のメタデータを読む機能は {id}.json の配置を前提にしているため、使えなくなります",
+            );
+        }
+
+        if report.errors.is_empty() {
+            Ok(report.warnings)
+        } else {
+            Err(report.errors)
+        }
+    }
+}
+
+impl Config {
+    /// 全レイヤーディレクトリの PNG のヘッダーを読み、壊れたファイルやサイズの揃っていないファイルを生成前に見つける
+    ///
+    /// 画像全体はデコードせず、`image::image_dimensions` で形式とサイズだけを確認する。
+    /// 同じレイヤーディレクトリ内では最も多いサイズを基準にし、異なるサイズのファイルを警告にする
+    /// （合成時の SizeMismatch を生成ループに入る前に見つけるため）。
+    /// 存在しないディレクトリは Config::validate がエラーにするので、ここでは読み飛ばす。
+    /// required_aspect_ratio が指定されていれば、縦横比の合わないファイルをすべて挙げてエラーにする
+    pub fn validate_layer_files(&self) -> Result<Vec<ValidationWarning>> {
+        let mut report = Report::default();
+        let mut wrong_ratio: Vec<(PathBuf, (u32, u32))> = Vec::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            let dir = Path::new(&layer.directory);
+            if !dir.is_dir() {
+                continue;
+            }
+            // 生成時と同じく PNG と SVG（アニメーションのフレームを含む）を対象にする
+            let paths = collect_layer_files(dir).map_err(|e| LayeredNftError::io(dir)(e))?;
+            let field = format!("layers[{}].directory", i);
+
+            let results: Vec<(PathBuf, Result<(u32, u32)>)> = paths
+                .into_par_iter()
+                .map(|path| {
+                    let dims = layer_dimensions(&path);
+                    (path, dims)
+                })
+                .collect();
+            let mut sizes: Vec<(&Path, (u32, u32))> = Vec::new();
+            for (path, dims) in &results {
+                match dims {
+                    Ok(dims) => {
+                        if self.required_aspect_ratio.as_ref().is_some_and(|r| !r.matches(*dims)) {
+                            wrong_ratio.push((path.clone(), *dims));
+                        }
+                        sizes.push((path, *dims));
+                    }
+                    Err(e) => report.warn(
+                        &field,
+                        format!("画像を読み込めません（壊れているか PNG / SVG ではありません）: {} ({})", path.display(), e),
+                    ),
+                }
+            }
+
+            let mut counts: HashMap<(u32, u32), usize> = HashMap::new();
+            for (_, dims) in &sizes {
+                *counts.entry(*dims).or_default() += 1;
+            }
+            // 同数なら小さいサイズを基準にして、結果を毎回同じにする
+            let Some(expected) = counts.iter().max_by_key(|(dims, n)| (**n, std::cmp::Reverse(**dims))).map(|(d, _)| *d)
+            else {
+                continue;
+            };
+            for (path, dims) in sizes.into_iter().filter(|(_, dims)| *dims != expected) {
+                report.warn(
+                    &field,
+                    format!(
+                        "{} のサイズ {}x{} がレイヤー {:?} のほかのファイル（{}x{}）と異なります",
+                        path.display(),
+                        dims.0,
+                        dims.1,
+                        layer.name,
+                        expected.0,
+                        expected.1
+                    ),
+                );
+            }
+        }
+
+        if let Some(ratio) = &self.required_aspect_ratio
+            && !wrong_ratio.is_empty()
+        {
+            let files: Vec<String> = wrong_ratio
+                .iter()
+                .map(|(path, (w, h))| format!("  {} ({}x{})", path.display(), w, h))
+                .collect();
+            return Err(LayeredNftError::InvalidConfig(format!(
+                "required_aspect_ratio {}:{} に合わないレイヤーファイルが {} 件あります:\n{}",
+                ratio.width,
+                ratio.height,
+                files.len(),
+                files.join("\n")
+            )));
+        }
+        Ok(report.warnings)
+    }
+}
+
+/// レイヤーディレクトリを走査し、選択肢のファイル名を集める（ディレクトリがなければエラー）
+fn scan_layer(layer: &LayerConfig, animation: bool, field: &str, report: &mut Report) -> Option<LayerEntries> {
+    let dir = Path::new(&layer.directory);
+    if !dir.is_dir() {
+        report.error(
+            format!("{}.directory", field),
+            format!("レイヤー {:?} のディレクトリが存在しません: {}", layer.name, layer.directory),
+        );
+        return None;
+    }
+
+    // 生成時と同じ選択肢（アニメーション時は直下のファイルとフレームを含むサブディレクトリ）
+    let paths = match layer_options(dir, animation) {
+        Ok(paths) => paths,
+        Err(e) => {
+            report.error(
+                format!("{}.directory", field),
+                format!("レイヤー {:?} のディレクトリを読み込めません: {} ({})", layer.name, layer.directory, e),
+            );
+            return None;
+        }
+    };
+
+    if paths.is_empty() {
+        report.error(
+            format!("{}.directory", field),
+            format!("レイヤー {:?} に PNG / SVG ファイルがありません: {}", layer.name, layer.directory),
+        );
+        return None;
+    }
+
+    let name_of = |p: &Path, stem: bool| {
+        let s = if stem { p.file_stem() } else { p.file_name() };
+        s.and_then(|s| s.to_str()).unwrap_or_default().to_string()
+    };
+    let mut names: HashSet<String> = paths.iter().map(|p| name_of(p, false)).collect();
+    if layer.group_by_directory == Some(true) {
+        // トレイト値にグループ名は含まれないため、グループをまたいで同じファイル名（拡張子なし）は使えない
+        let mut seen: HashMap<String, &Path> = HashMap::new();
+        for path in &paths {
+            if let Some(other) = seen.insert(name_of(path, true), path) {
+                report.error(
+                    format!("{}.group_by_directory", field),
+                    format!(
+                        "グループをまたいで同じファイル名は使えません（トレイト値が区別できません）: {} と {}",
+                        other.display(),
+                        path.display()
+                    ),
+                );
+            }
+        }
+        // サブディレクトリ名はグループの重みとして rarity のキーに使える
+        for path in &paths {
+            match path.strip_prefix(dir).ok().and_then(|r| r.parent()).and_then(|g| g.components().next()) {
+                Some(group) => {
+                    names.insert(group.as_os_str().to_string_lossy().to_string());
+                }
+                None => report.error(
+                    format!("{}.group_by_directory", field),
+                    format!("画像はサブディレクトリに置いてください（直下のファイルはどのグループにも属しません）: {}", path.display()),
+                ),
+            }
+        }
+    }
+    Some(LayerEntries {
+        names,
+        stems: paths.iter().map(|p| name_of(p, true)).collect(),
+    })
+}
+
+/// rarity / rarity_glob / numeric_traits / display_names のキーが実在するファイルを指しているか
+fn check_layer_keys(layer: &LayerConfig, entries: &LayerEntries, field: &str, report: &mut Report) {
+    for (name, rarity) in [("rarity", &layer.rarity), ("rarity_percent", &layer.rarity_percent)] {
+        for key in rarity.iter().flatten().map(|(k, _)| k) {
+            if !entries.names.contains(key) {
+                report.warn(
+                    format!("{}.{}", field, name),
+                    format!("{:?} に一致するファイルがレイヤー {:?} にありません（重みは使われません）", key, layer.name),
+                );
+            }
+        }
+    }
+    for rule in layer.rarity_glob.iter().flatten() {
+        let Ok(pattern) = glob::Pattern::new(&rule.pattern) else { continue; };
+        if !entries.names.iter().any(|name| pattern.matches(name)) {
+            report.warn(
+                format!("{}.rarity_glob", field),
+                format!("パターン {:?} に一致するファイルがレイヤー {:?} にありません", rule.pattern, layer.name),
+            );
+        }
+    }
+    for key in layer.numeric_traits.iter().flatten().map(|(k, _)| k) {
+        if !entries.stems.contains(key) {
+            report.warn(
+                format!("{}.numeric_traits", field),
+                format!("{:?} に一致するファイルがレイヤー {:?} にありません", key, layer.name),
+            );
+        }
+    }
+    let mut seen = HashMap::new();
+    for (key, name) in layer.display_names.iter().flatten() {
+        if !entries.stems.contains(key) {
+            report.warn(
+                format!("{}.display_names", field),
+                format!("{:?} に一致するファイルがレイヤー {:?} にありません（表示名は使われません）", key, layer.name),
+            );
+        }
+        // 同じ表示名が複数のファイルに付くと、メタデータ上で区別できなくなる
+        if let Some(other) = seen.insert(name, key) {
+            report.warn(
+                format!("{}.display_names", field),
+                format!("{:?} と {:?} に同じ表示名 {:?} が付いています", other, key, name),
+            );
+        }
+    }
+}
+
+/// 制約で参照しているトレイトが存在するか（trait_type がなければエラー、値がなければ警告）
+fn check_trait(
+    layers: &HashMap<&str, (&LayerConfig, Option<LayerEntries>)>,
+    field: &str,
+    t: &TraitValue,
+    report: &mut Report,
+) {
+    let Some((layer, entries)) = layers.get(t.trait_type.as_str()) else {
+        report.error(
+            field,
+            format!("trait_type {:?} に一致するレイヤー（layers[].name）がありません", t.trait_type),
+        );
+        return;
+    };
+    // 数値トレイトは値がファイル名と一致しないので照合しない
+    if layer.numeric_traits.is_some() {
+        return;
+    }
+    if let Some(entries) = entries
+        && !entries.stems.contains(&t.value)
+    {
+        report.warn(
+            field,
+            format!(
+                "レイヤー {:?} に値 {:?} のファイルがありません（この条件は一致しません）",
+                t.trait_type, t.value
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::config::{AspectRatio, ConfigBuilder, ForbiddenPair, LayerConfigBuilder};
+
+    fn base() -> ConfigBuilder {
+        ConfigBuilder::sample(&[])
+            .count(2)
+            .add_layer(LayerConfigBuilder::new("Eyeball", "layers/Eyeball").rarity("Blue.png", 1.0).build())
+    }
+
+    #[test]
+    fn unknown_rarity_key_warns() {
+        let cfg = base().build().unwrap();
+        let warnings = Config::validate(&cfg).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].field, "layers[0].rarity");
+    }
+
+    #[test]
+    fn unknown_trait_type_and_missing_dir_are_errors() {
+        let cfg = base()
+            .count(100)
+            .add_layer(LayerConfigBuilder::new("Hat", "layers/NoSuchDir").build())
+            .add_forbidden_pair(ForbiddenPair::new(
+                TraitValue::new("Eyeball", "Red"),
+                TraitValue::new("Mouth", "Smile"),
+            ))
+            .build()
+            .unwrap();
+        let errors = Config::validate(&cfg).unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["layers[1].directory", "constraints.forbidden_pairs[0].b"]);
+    }
+
+    #[test]
+    fn layer_files_with_bad_header_or_size_warn() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::copy("layers/Eyeball/Red.png", dir.join("Red.png")).unwrap();
+        fs::copy("layers/Eyeball/White.png", dir.join("White.png")).unwrap();
+        image::RgbaImage::new(3, 3).save(dir.join("Small.png")).unwrap();
+        fs::write(dir.join("Broken.png"), b"not a png").unwrap();
+        fs::write(
+            dir.join("Tiny.svg"),
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="5" height="5"></svg>"#,
+        )
+        .unwrap();
+
+        let cfg = ConfigBuilder::sample(&["Eyeball"])
+            .add_layer(LayerConfigBuilder::new("Mixed", dir.to_str().unwrap()).build())
+            .build()
+            .unwrap();
+        let warnings = cfg.validate_layer_files().unwrap();
+        let messages: Vec<&str> = warnings.iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(warnings.len(), 3, "{:?}", messages);
+        assert!(warnings.iter().all(|w| w.field == "layers[1].directory"));
+        assert!(messages[0].contains("Broken.png"));
+        assert!(messages[1].contains("Small.png"));
+        assert!(messages[2].contains("Tiny.svg"));
+    }
+
+    #[test]
+    fn layer_files_must_match_required_aspect_ratio() {
+        let mut cfg = base().build().unwrap();
+        cfg.required_aspect_ratio = Some(AspectRatio { width: 1, height: 1, tolerance: None });
+        assert!(cfg.validate_layer_files().is_ok());
+
+        cfg.required_aspect_ratio = Some(AspectRatio { width: 16, height: 9, tolerance: None });
+        let err = cfg.validate_layer_files().unwrap_err().to_string();
+        assert!(err.contains("2 件") && err.contains("Red.png") && err.contains("White.png"), "{}", err);
+
+        let ratio = AspectRatio { width: 16, height: 9, tolerance: None };
+        assert!(ratio.matches((1920, 1080)) && ratio.matches((1919, 1080)));
+        assert!(!ratio.matches((1080, 1080)));
+    }
+
+    #[test]
+    fn count_over_combinations_is_error() {
+        let cfg = base().count(3).build().unwrap();
+        let errors = Config::validate(&cfg).unwrap_err();
+        assert_eq!(errors[0].field, "count");
+    }
+
+    #[test]
+    fn count_excludes_the_pattern_with_every_layer_skipped() {
+        let optional = |count| {
+            ConfigBuilder::sample(&[])
+                .count(count)
+                .add_layer(LayerConfigBuilder::new("Eyeball", "layers/Eyeball").optional_probability(0.5).build())
+                .build()
+                .unwrap()
+        };
+        assert!(Config::validate(&optional(2)).is_ok());
+        let errors = Config::validate(&optional(3)).unwrap_err();
+        assert_eq!(errors[0].field, "count");
+    }
+}
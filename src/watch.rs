@@ -0,0 +1,319 @@
+use crate::config::Config;
+use crate::error::{LayeredNftError, Result};
+use crate::generator::is_layer_file;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+/// ある時点の設定とレイヤーファイルの状態（--watch で変更前後を比べる）
+#[derive(Debug)]
+pub struct Snapshot {
+    /// rarity を正規化する前の設定
+    config: Value,
+    /// レイヤー名 → レイヤー画像ごとの更新日時とサイズ
+    layers: Vec<(String, PathBuf, BTreeMap<PathBuf, FileStamp>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+/// 設定の1項目の変更（パスは `output.resize.width` や `layers[Eye color].rarity` の形）
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigChange {
+    Added { path: String, value: Value },
+    Removed { path: String, value: Value },
+    Changed { path: String, old: Value, new: Value },
+}
+
+/// 1レイヤー分のファイルの追加・削除・更新
+#[derive(Debug, Default, PartialEq)]
+pub struct LayerChanges {
+    pub layer: String,
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+}
+
+impl Snapshot {
+    /// 設定と各レイヤーディレクトリ以下の PNG / SVG の状態を記録する（Generator::new に渡す前の設定を使う）
+    pub fn capture(cfg: &Config) -> Result<Self> {
+        let config = serde_json::to_value(cfg).map_err(LayeredNftError::json("config"))?;
+        let layers = cfg
+            .layers
+            .iter()
+            .map(|layer| {
+                let dir = PathBuf::from(&layer.directory);
+                let mut files = BTreeMap::new();
+                for entry in WalkDir::new(&dir) {
+                    let entry = entry.map_err(|e| LayeredNftError::LayerIo { layer: layer.name.clone(), source: e.into() })?;
+                    if !entry.file_type().is_file() || !is_layer_file(entry.path()) {
+                        continue;
+                    }
+                    let meta = entry
+                        .metadata()
+                        .map_err(|e| LayeredNftError::LayerIo { layer: layer.name.clone(), source: e.into() })?;
+                    files.insert(entry.into_path(), FileStamp { modified: meta.modified().ok(), len: meta.len() });
+                }
+                Ok((layer.name.clone(), dir, files))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { config, layers })
+    }
+
+    /// 監視するレイヤーディレクトリ
+    pub fn layer_dirs(&self) -> Vec<PathBuf> {
+        self.layers.iter().map(|(_, dir, _)| dir.clone()).collect()
+    }
+
+    /// self から newer への設定の変更
+    pub fn config_changes(&self, newer: &Snapshot) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+        diff_values("", &self.config, &newer.config, &mut changes);
+        changes
+    }
+
+    /// 両方の時点にあるレイヤーについて、ファイルの追加・削除・更新を返す（変更のないレイヤーは含めない）
+    pub fn layer_changes(&self, newer: &Snapshot) -> Vec<LayerChanges> {
+        let mut all = Vec::new();
+        for (name, _, new_files) in &newer.layers {
+            let Some((_, _, old_files)) = self.layers.iter().find(|(n, _, _)| n == name) else { continue; };
+            let changes = LayerChanges {
+                layer: name.clone(),
+                added: new_files.keys().filter(|p| !old_files.contains_key(*p)).cloned().collect(),
+                removed: old_files.keys().filter(|p| !new_files.contains_key(*p)).cloned().collect(),
+                modified: new_files
+                    .iter()
+                    .filter(|(p, stamp)| old_files.get(*p).is_some_and(|old| old != *stamp))
+                    .map(|(p, _)| p.clone())
+                    .collect(),
+            };
+            if !changes.is_empty() {
+                all.push(changes);
+            }
+        }
+        all
+    }
+}
+
+impl LayerChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+
+    /// 既存トークンで使われていれば作り直しが必要なファイル（削除・更新されたもの）
+    pub fn touched(&self) -> impl Iterator<Item = &Path> {
+        self.removed.iter().chain(&self.modified).map(PathBuf::as_path)
+    }
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigChange::Added { path, value } => write!(f, "+ {}: {}", path, value),
+            ConfigChange::Removed { path, value } => write!(f, "- {}: {}", path, value),
+            ConfigChange::Changed { path, old, new } => write!(f, "~ {}: {} → {}", path, old, new),
+        }
+    }
+}
+
+/// 2つの設定値を再帰的に比べる。null は「未指定」として扱い、
+/// name を持つオブジェクトの配列（layers など）は順番ではなく name で対応付ける
+fn diff_values(path: &str, old: &Value, new: &Value, out: &mut Vec<ConfigChange>) {
+    let child = |key: &str| if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) };
+    match (old, new) {
+        (Value::Null, Value::Null) => {}
+        (Value::Null, value) => out.push(ConfigChange::Added { path: path.to_string(), value: value.clone() }),
+        (value, Value::Null) => out.push(ConfigChange::Removed { path: path.to_string(), value: value.clone() }),
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                diff_values(&child(key), value, b.get(key).unwrap_or(&Value::Null), out);
+            }
+            for (key, value) in b.iter().filter(|(key, _)| !a.contains_key(*key)) {
+                diff_values(&child(key), &Value::Null, value, out);
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => match (named_items(a), named_items(b)) {
+            (Some(a), Some(b)) => {
+                for (name, value) in &a {
+                    let newer = b.iter().find(|(n, _)| n == name).map_or(&Value::Null, |(_, v)| *v);
+                    diff_values(&format!("{}[{}]", path, name), value, newer, out);
+                }
+                for (name, value) in b.iter().filter(|(name, _)| !a.iter().any(|(n, _)| n == name)) {
+                    diff_values(&format!("{}[{}]", path, name), &Value::Null, value, out);
+                }
+            }
+            _ if a.len() == b.len() => {
+                for (i, (x, y)) in a.iter().zip(b).enumerate() {
+                    diff_values(&format!("{}[{}]", path, i), x, y, out);
+                }
+            }
+            _ => out.push(ConfigChange::Changed { path: path.to_string(), old: old.clone(), new: new.clone() }),
+        },
+        _ if old != new => out.push(ConfigChange::Changed { path: path.to_string(), old: old.clone(), new: new.clone() }),
+        _ => {}
+    }
+}
+
+/// すべての要素が一意な name を持つオブジェクトなら (name, 要素) の一覧
+fn named_items(items: &[Value]) -> Option<Vec<(&str, &Value)>> {
+    let named: Vec<(&str, &Value)> = items
+        .iter()
+        .map(|item| Some((item.get("name")?.as_str()?, item)))
+        .collect::<Option<_>>()?;
+    let unique = named.iter().enumerate().all(|(i, (name, _))| named[..i].iter().all(|(n, _)| n != name));
+    (!named.is_empty() && unique).then_some(named)
+}
+
+/// 監視対象の変更がまとまって届くのを待つ時間（エディタの保存やファイルのコピーは複数のイベントになる）
+#[cfg(feature = "watch")]
+const DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(300);
+
+/// 設定ファイルとレイヤーディレクトリを監視し、変更があるたびに on_change を呼ぶ（戻らない）
+///
+/// イベントループは tokio で回し、on_change はブロッキングスレッドで実行する（その間の変更は次の呼び出しにまとめる）。
+/// on_change には変更されたパスを渡し、戻り値の一覧で監視するレイヤーディレクトリを更新する。
+#[cfg(feature = "watch")]
+pub fn watch<F>(config_files: &[PathBuf], layer_dirs: Vec<PathBuf>, on_change: F) -> Result<()>
+where
+    F: FnMut(&[PathBuf]) -> Vec<PathBuf> + Send + 'static,
+{
+    use notify::{RecursiveMode, Watcher};
+    use std::collections::HashSet;
+    use tokio::sync::mpsc;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .map_err(LayeredNftError::io("tokio runtime"))?;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // 受信側が終了した後のイベントは捨ててよい
+        let _ = tx.send(event);
+    })?;
+
+    // エディタは保存時にファイルを置き換えることがあるので、設定ファイルは親ディレクトリごと監視する
+    let config_files: Vec<PathBuf> = config_files.iter().map(|p| canonical(p)).collect::<Result<_>>()?;
+    let mut config_dirs = HashSet::new();
+    for file in &config_files {
+        let dir = file.parent().unwrap_or(Path::new("/")).to_path_buf();
+        if config_dirs.insert(dir.clone()) {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        }
+    }
+    let mut watched_layers: Vec<PathBuf> = Vec::new();
+    let mut rewatch = |watcher: &mut notify::RecommendedWatcher, dirs: Vec<PathBuf>| -> Result<Vec<PathBuf>> {
+        let dirs: Vec<PathBuf> = dirs.iter().filter_map(|dir| canonical(dir).ok()).collect();
+        for old in watched_layers.iter().filter(|d| !dirs.contains(d)) {
+            watcher.unwatch(old)?;
+        }
+        for new in dirs.iter().filter(|d| !watched_layers.contains(d)) {
+            watcher.watch(new, RecursiveMode::Recursive)?;
+        }
+        watched_layers = dirs.clone();
+        Ok(dirs)
+    };
+    let mut layer_dirs = rewatch(&mut watcher, layer_dirs)?;
+
+    runtime.block_on(async move {
+        let mut on_change = on_change;
+        loop {
+            let mut changed = Vec::new();
+            // 最初の関連イベントを待ち、その後 DEBOUNCE の間イベントが途切れるまで集める
+            while changed.is_empty() {
+                let Some(event) = rx.recv().await else { return Ok(()); };
+                collect_paths(event?, &config_files, &layer_dirs, &mut changed);
+            }
+            while let Ok(Some(event)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+                collect_paths(event?, &config_files, &layer_dirs, &mut changed);
+            }
+            changed.sort();
+            changed.dedup();
+
+            let handle = tokio::task::spawn_blocking(move || {
+                let dirs = on_change(&changed);
+                (on_change, dirs)
+            });
+            let (returned, dirs) = match handle.await {
+                Ok(result) => result,
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            };
+            on_change = returned;
+            layer_dirs = rewatch(&mut watcher, dirs)?;
+        }
+    })
+}
+
+#[cfg(not(feature = "watch"))]
+pub fn watch<F>(_config_files: &[PathBuf], _layer_dirs: Vec<PathBuf>, _on_change: F) -> Result<()>
+where
+    F: FnMut(&[PathBuf]) -> Vec<PathBuf> + Send + 'static,
+{
+    Err(LayeredNftError::FeatureDisabled {
+        feature: "watch",
+        setting: "--watch".to_string(),
+    })
+}
+
+/// 設定ファイル・レイヤーディレクトリ内のパスの作成・更新・削除だけを拾う（読み込みなどのアクセスは無視する）
+#[cfg(feature = "watch")]
+fn collect_paths(event: notify::Event, config_files: &[PathBuf], layer_dirs: &[PathBuf], changed: &mut Vec<PathBuf>) {
+    if !(event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove()) {
+        return;
+    }
+    changed.extend(
+        event
+            .paths
+            .into_iter()
+            .filter(|path| config_files.contains(path) || layer_dirs.iter().any(|dir| path.starts_with(dir))),
+    );
+}
+
+#[cfg(feature = "watch")]
+fn canonical(path: &Path) -> Result<PathBuf> {
+    std::fs::canonicalize(path).map_err(LayeredNftError::io(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn diff_matches_layers_by_name_and_treats_null_as_unset() {
+        let old = json!({
+            "count": 10,
+            "output": { "resize": null, "format": "png" },
+            "layers": [
+                { "name": "Background", "directory": "layers/Background" },
+                { "name": "Eyeball", "directory": "layers/Eyeball", "opacity": null }
+            ]
+        });
+        let new = json!({
+            "count": 12,
+            "output": { "resize": { "width": 256 }, "format": "png" },
+            "layers": [
+                { "name": "Eyeball", "directory": "layers/Eyeball", "opacity": 0.5 },
+                { "name": "Shine", "directory": "layers/Shine" }
+            ]
+        });
+        let mut changes = Vec::new();
+        diff_values("", &old, &new, &mut changes);
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "~ count: 10 → 12",
+                "+ output.resize: {\"width\":256}",
+                "- layers[Background]: {\"name\":\"Background\",\"directory\":\"layers/Background\"}",
+                "+ layers[Eyeball].opacity: 0.5",
+                "+ layers[Shine]: {\"name\":\"Shine\",\"directory\":\"layers/Shine\"}",
+            ]
+        );
+    }
+}